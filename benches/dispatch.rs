@@ -0,0 +1,84 @@
+//! Micro benchmarks for the dispatch and result paths.
+//!
+//! The mappers here do almost nothing, so the time per item is
+//! dominated by the cost of moving values to the workers and back.
+//! Run with `cargo bench`.
+//!
+//! To compare channel backends, also run with `--features flume` and
+//! with `--no-default-features` for the std::sync::mpsc fallback.
+
+use {
+    plmap::{PipelineBuilder, PipelineMap, PlmapPool, Schedule},
+    std::{
+        hint::black_box,
+        time::{Duration, Instant},
+    },
+};
+
+const ITEMS: u64 = 200_000;
+const RUNS: usize = 5;
+
+/// Report the best time per item over a few runs of f.
+fn bench(name: &str, f: impl Fn() -> u64) {
+    black_box(f());
+    let mut best = Duration::from_secs(u64::MAX);
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        black_box(f());
+        best = best.min(start.elapsed());
+    }
+    let ns = best.as_nanos() as f64 / ITEMS as f64;
+    println!("{:<28} {:>8.1} ns/item", name, ns);
+}
+
+fn main() {
+    let backend = if cfg!(feature = "flume") {
+        "flume"
+    } else if cfg!(feature = "crossbeam-channel") {
+        "crossbeam-channel"
+    } else {
+        "std::sync::mpsc"
+    };
+    println!("channel backend: {}", backend);
+    bench("inline", || (0..ITEMS).plmap(0, |x| x + 1).sum());
+    // Many short pipelines, where thread spawning dominates.
+    bench("short_pipelines/4", || {
+        (0..ITEMS / 100)
+            .map(|_| (0..100u64).plmap(4, |x| x + 1).sum::<u64>())
+            .sum()
+    });
+    let pool = PlmapPool::new(4);
+    bench("short_pipelines_pool/4", || {
+        (0..ITEMS / 100)
+            .map(|_| (0..100u64).plmap_on(&pool, |x| x + 1).sum::<u64>())
+            .sum()
+    });
+    for &w in &[1, 2, 4, 8] {
+        bench(&format!("plmap/{}", w), || {
+            (0..ITEMS).plmap(w, |x| x + 1).sum()
+        });
+        bench(&format!("plmap_next/{}", w), || {
+            // A for loop calls next rather than fold.
+            let mut sum = 0;
+            for v in (0..ITEMS).plmap(w, |x| x + 1) {
+                sum += v;
+            }
+            sum
+        });
+        bench(&format!("stealing/{}", w), || {
+            PipelineBuilder::new(w)
+                .schedule(Schedule::Stealing)
+                .build(0..ITEMS, |x| x + 1)
+                .sum()
+        });
+        bench(&format!("in_flight_64/{}", w), || {
+            PipelineBuilder::new(w)
+                .in_flight(64)
+                .build(0..ITEMS, |x| x + 1)
+                .sum()
+        });
+        bench(&format!("batched_256/{}", w), || {
+            (0..ITEMS).plmap_batched(w, 256, |x| x + 1).sum()
+        });
+    }
+}
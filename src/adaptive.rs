@@ -0,0 +1,68 @@
+/// AdaptiveDepth tunes the in-flight depth of a pipeline from
+/// observations of whether the consumer had to wait for results.
+#[derive(Debug)]
+pub(crate) struct AdaptiveDepth {
+    min: usize,
+    max: usize,
+    ready_streak: usize,
+}
+
+impl AdaptiveDepth {
+    pub(crate) fn new(min: usize, max: usize) -> AdaptiveDepth {
+        AdaptiveDepth {
+            min,
+            max,
+            ready_streak: 0,
+        }
+    }
+
+    /// The smallest and largest depths it tunes between.
+    pub(crate) fn bounds(&self) -> (usize, usize) {
+        (self.min, self.max)
+    }
+
+    /// The consumer found the next result already waiting, after a
+    /// full window of these the depth is reduced as the consumer is
+    /// not keeping up with the workers anyway.
+    pub(crate) fn on_ready(&mut self, depth: usize) -> usize {
+        self.ready_streak += 1;
+        if self.ready_streak >= depth && depth > self.min {
+            self.ready_streak = 0;
+            depth - 1
+        } else {
+            depth
+        }
+    }
+
+    /// The consumer had to wait for the next result, if there is more
+    /// input a deeper window gives the other workers something to do
+    /// while they wait on the head of the line.
+    pub(crate) fn on_wait(&mut self, depth: usize, input_remaining: bool) -> usize {
+        self.ready_streak = 0;
+        if input_remaining && depth < self.max {
+            depth + 1
+        } else {
+            depth
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adaptive_depth_bounds() {
+        let mut a = AdaptiveDepth::new(2, 4);
+        let mut depth = 3;
+        for _ in 0..10 {
+            depth = a.on_wait(depth, true);
+        }
+        assert_eq!(depth, 4);
+        assert_eq!(a.on_wait(depth, false), 4);
+        for _ in 0..100 {
+            depth = a.on_ready(depth);
+        }
+        assert_eq!(depth, 2);
+    }
+}
@@ -0,0 +1,96 @@
+//! AsyncIterator impls, for consuming pipelines with the unstable
+//! async iteration support. They behave exactly as the Stream impls.
+
+#[cfg(feature = "stream")]
+use {super::stream::StreamPipeline, futures_core::Stream};
+use {
+    super::{buffered::Buffered, mapper::Mapper, pipeline::Pipeline},
+    core::async_iter::AsyncIterator,
+    std::{
+        pin::Pin,
+        task::{Context, Poll},
+    },
+};
+
+impl<I, M> AsyncIterator for Pipeline<I, M>
+where
+    I: Iterator,
+    I::Item: Send,
+    M: Mapper<I::Item> + Clone + Send,
+    M::Out: Send,
+    Self: Unpin,
+{
+    type Item = <M as Mapper<I::Item>>::Out;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_next(cx)
+    }
+}
+
+impl<T> AsyncIterator for Buffered<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.get_mut().poll_next(cx)
+    }
+}
+
+#[cfg(feature = "stream")]
+impl<S, M> AsyncIterator for StreamPipeline<S, M>
+where
+    S: Stream,
+    S::Item: Send,
+    M: Mapper<S::Item> + Clone + Send,
+    M::Out: Send,
+    Self: Unpin,
+{
+    type Item = <M as Mapper<S::Item>>::Out;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Stream::poll_next(self, cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::PipelineMap,
+        std::{
+            sync::Arc,
+            task::{Wake, Waker},
+            thread,
+        },
+    };
+
+    struct Unpark(thread::Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn collect<A: AsyncIterator + Unpin>(mut a: A) -> Vec<A::Item> {
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut v = Vec::new();
+        loop {
+            match Pin::new(&mut a).poll_next(&mut cx) {
+                Poll::Ready(Some(x)) => v.push(x),
+                Poll::Ready(None) => return v,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_async_iterator() {
+        for w in 0..3 {
+            let v = collect((0..100).plmap(w, |x| x * 2));
+            assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+        }
+        let v = collect(Pipeline::new(2, |x| x * 2, 0..100).pl_buffer(4));
+        assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,179 @@
+use {
+    super::{chan, mapper::Mapper, pipeline::Pipeline, workers::Workers},
+    std::{collections::VecDeque, iter::FusedIterator},
+};
+
+/// BatchedPipeline is a pipeline that sends values to workers in
+/// batches rather than one at a time, usually created by calling
+/// plmap_batched on an iterator.
+///
+/// When the mapper is cheap, such as when mapping over large numbers of
+/// small Copy values, most of the cost of a pipeline is in sending
+/// values to and from the workers. Batching pays that cost once per
+/// batch instead, and the batch buffers are recycled between the
+/// consumer and the workers so steady state operation does not
+/// allocate.
+pub struct BatchedPipeline<I, M>
+where
+    I: Iterator,
+    I::Item: Send + 'static,
+    M: Mapper<I::Item> + Clone + Send + 'static,
+    M::Out: Send + 'static,
+{
+    batches: Pipeline<Batches<I>, BatchMapper<I::Item, M>>,
+    current: VecDeque<M::Out>,
+    recycle: chan::Sender<VecDeque<M::Out>>,
+}
+
+impl<I, M> BatchedPipeline<I, M>
+where
+    I: Iterator,
+    I::Item: Send + 'static,
+    M: Mapper<I::Item> + Clone + Send + 'static,
+    M::Out: Send + 'static,
+{
+    /// Create a pipeline mapping input in batches of batch_size.
+    ///
+    /// # Panics
+    ///
+    /// Panics if batch_size is zero.
+    pub fn new(
+        n_workers: impl Into<Workers>,
+        batch_size: usize,
+        mapper: M,
+        input: I,
+    ) -> BatchedPipeline<I, M> {
+        assert!(batch_size > 0, "batch_size must be greater than zero");
+        let (in_recycle, in_buffers) = chan::unbounded();
+        let (out_recycle, out_buffers) = chan::unbounded();
+        let batches = Batches {
+            input,
+            batch_size,
+            buffers: in_buffers,
+        };
+        let mapper = BatchMapper {
+            mapper,
+            batch_size,
+            recycle: in_recycle,
+            buffers: out_buffers,
+        };
+        BatchedPipeline {
+            batches: Pipeline::new(n_workers, mapper, batches),
+            current: VecDeque::new(),
+            recycle: out_recycle,
+        }
+    }
+}
+
+impl<I, M> Iterator for BatchedPipeline<I, M>
+where
+    I: Iterator,
+    I::Item: Send + 'static,
+    M: Mapper<I::Item> + Clone + Send + 'static,
+    M::Out: Send + 'static,
+{
+    type Item = <M as Mapper<I::Item>>::Out;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(v) = self.current.pop_front() {
+                return Some(v);
+            }
+            let batch = self.batches.next()?;
+            let spent = std::mem::replace(&mut self.current, batch);
+            let _ = self.recycle.send(spent);
+        }
+    }
+}
+
+impl<I, M> FusedIterator for BatchedPipeline<I, M>
+where
+    I: Iterator,
+    I::Item: Send + 'static,
+    M: Mapper<I::Item> + Clone + Send + 'static,
+    M::Out: Send + 'static,
+{
+}
+
+/// Batches groups input values into recycled buffers.
+struct Batches<I: Iterator> {
+    input: I,
+    batch_size: usize,
+    buffers: chan::Receiver<Vec<I::Item>>,
+}
+
+impl<I: Iterator> Iterator for Batches<I> {
+    type Item = Vec<I::Item>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut batch = self
+            .buffers
+            .try_recv()
+            .unwrap_or_else(|_| Vec::with_capacity(self.batch_size));
+        batch.extend(self.input.by_ref().take(self.batch_size));
+        if batch.is_empty() {
+            None
+        } else {
+            Some(batch)
+        }
+    }
+}
+
+/// BatchMapper maps a batch of values, returning the input buffer for
+/// reuse once it has been drained.
+struct BatchMapper<In, M: Mapper<In>> {
+    mapper: M,
+    batch_size: usize,
+    recycle: chan::Sender<Vec<In>>,
+    buffers: chan::Receiver<VecDeque<M::Out>>,
+}
+
+impl<In, M> Clone for BatchMapper<In, M>
+where
+    M: Mapper<In> + Clone,
+{
+    fn clone(&self) -> Self {
+        BatchMapper {
+            mapper: self.mapper.clone(),
+            batch_size: self.batch_size,
+            recycle: self.recycle.clone(),
+            buffers: self.buffers.clone(),
+        }
+    }
+}
+
+impl<In, M> Mapper<Vec<In>> for BatchMapper<In, M>
+where
+    M: Mapper<In>,
+{
+    type Out = VecDeque<M::Out>;
+
+    fn apply(&mut self, mut batch: Vec<In>) -> Self::Out {
+        let mut out = self
+            .buffers
+            .try_recv()
+            .unwrap_or_else(|_| VecDeque::with_capacity(self.batch_size));
+        for v in batch.drain(..) {
+            out.push_back(self.mapper.apply(v));
+        }
+        let _ = self.recycle.send(batch);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::PipelineMap;
+
+    #[test]
+    fn test_batched_pipeline() {
+        for w in 0..3 {
+            for &batch_size in &[1, 7, 16, 1000] {
+                let v: Vec<u64> = (0..1000u64)
+                    .plmap_batched(w, batch_size, |x| x * 2)
+                    .collect();
+                assert_eq!(v, (0..1000).map(|x| x * 2).collect::<Vec<_>>());
+            }
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use {
+    super::{
+        mapper::Mapper,
+        pipeline::{Pipeline, PipelineConfig},
+        stats::{PipelineReport, PipelineState},
+    },
+    std::{fmt, iter::FusedIterator, task::Poll},
+};
+
+/// BoxedPipeline is a Pipeline with its input iterator and mapper types
+/// erased, so that it can be stored in a struct field or returned from
+/// a trait method with only its output type spelled out. Unlike a
+/// Box<dyn Iterator>, the pipeline's own methods are still available.
+/// Created by Pipeline::boxed.
+///
+/// Taking values from the back is not supported, as not every input
+/// iterator is double ended.
+///
+/// # Examples
+///
+/// ```
+/// use plmap::{BoxedPipeline, PipelineMap};
+///
+/// struct Doubler {
+///     pipeline: BoxedPipeline<i32>,
+/// }
+///
+/// let mut d = Doubler {
+///     pipeline: vec![1, 2, 3].plmap(2, |x| x * 2).boxed(),
+/// };
+/// assert_eq!(d.pipeline.next(), Some(2));
+/// assert_eq!(d.pipeline.in_flight() + d.pipeline.queue_len(), 2);
+/// assert_eq!(d.pipeline.by_ref().sum::<i32>(), 10);
+/// ```
+pub struct BoxedPipeline<Out>(Box<dyn ErasedPipeline<Out> + Send>);
+
+/// The methods of a Pipeline which don't depend on its input and
+/// mapper types.
+trait ErasedPipeline<Out>: Iterator<Item = Out> + fmt::Debug {
+    fn stats(&self) -> PipelineReport;
+    fn config(&self) -> PipelineConfig;
+    fn in_flight(&self) -> usize;
+    fn queue_len(&self) -> usize;
+    fn dump_state(&mut self) -> PipelineState;
+    fn try_next(&mut self) -> Poll<Option<Out>>;
+    fn peek(&mut self) -> Option<&Out>;
+    fn skip_unmapped(&mut self, n: usize);
+    fn finish(self: Box<Self>) -> PipelineReport;
+}
+
+impl<I, M> ErasedPipeline<M::Out> for Pipeline<I, M>
+where
+    I: Iterator,
+    I::Item: Send + 'static,
+    M: Mapper<I::Item> + Clone + Send + 'static,
+    M::Out: Send + 'static,
+{
+    fn stats(&self) -> PipelineReport {
+        Pipeline::stats(self)
+    }
+
+    fn config(&self) -> PipelineConfig {
+        Pipeline::config(self)
+    }
+
+    fn in_flight(&self) -> usize {
+        Pipeline::in_flight(self)
+    }
+
+    fn queue_len(&self) -> usize {
+        Pipeline::queue_len(self)
+    }
+
+    fn dump_state(&mut self) -> PipelineState {
+        Pipeline::dump_state(self)
+    }
+
+    fn try_next(&mut self) -> Poll<Option<M::Out>> {
+        Pipeline::try_next(self)
+    }
+
+    fn peek(&mut self) -> Option<&M::Out> {
+        Pipeline::peek(self)
+    }
+
+    fn skip_unmapped(&mut self, n: usize) {
+        Pipeline::skip_unmapped(self, n)
+    }
+
+    fn finish(self: Box<Self>) -> PipelineReport {
+        Pipeline::finish(*self)
+    }
+}
+
+impl<I, M> Pipeline<I, M>
+where
+    I: Iterator + Send + 'static,
+    I::Item: Send + 'static,
+    M: Mapper<I::Item> + Clone + Send + 'static,
+    M::Out: Send + 'static,
+{
+    /// Erase the pipeline's input and mapper types, see BoxedPipeline.
+    pub fn boxed(self) -> BoxedPipeline<M::Out> {
+        BoxedPipeline(Box::new(self))
+    }
+}
+
+impl<Out> BoxedPipeline<Out> {
+    /// See Pipeline::stats.
+    pub fn stats(&self) -> PipelineReport {
+        self.0.stats()
+    }
+
+    /// See Pipeline::config.
+    pub fn config(&self) -> PipelineConfig {
+        self.0.config()
+    }
+
+    /// See Pipeline::in_flight.
+    pub fn in_flight(&self) -> usize {
+        self.0.in_flight()
+    }
+
+    /// See Pipeline::queue_len.
+    pub fn queue_len(&self) -> usize {
+        self.0.queue_len()
+    }
+
+    /// See Pipeline::dump_state.
+    pub fn dump_state(&mut self) -> PipelineState {
+        self.0.dump_state()
+    }
+
+    /// See Pipeline::try_next.
+    pub fn try_next(&mut self) -> Poll<Option<Out>> {
+        self.0.try_next()
+    }
+
+    /// See Pipeline::peek.
+    pub fn peek(&mut self) -> Option<&Out> {
+        self.0.peek()
+    }
+
+    /// See Pipeline::pl_skip.
+    pub fn pl_skip(mut self, n: usize) -> Self {
+        self.0.skip_unmapped(n);
+        self
+    }
+
+    /// See Pipeline::pl_nth.
+    pub fn pl_nth(&mut self, n: usize) -> Option<Out> {
+        self.0.skip_unmapped(n);
+        self.0.next()
+    }
+
+    /// See Pipeline::finish.
+    pub fn finish(self) -> PipelineReport {
+        self.0.finish()
+    }
+}
+
+impl<Out> fmt::Debug for BoxedPipeline<Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("BoxedPipeline").field(&self.0).finish()
+    }
+}
+
+impl<Out> Iterator for BoxedPipeline<Out> {
+    type Item = Out;
+
+    fn next(&mut self) -> Option<Out> {
+        self.0.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl<Out> FusedIterator for BoxedPipeline<Out> {}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::PipelineMap};
+
+    #[test]
+    fn test_boxed_pipeline() {
+        for w in 0..3 {
+            let boxed: Vec<BoxedPipeline<i32>> = vec![
+                (0..100).plmap(w, |x| x * 2).boxed(),
+                vec![0, 1, 2].plmap(w, |x: i32| x + 1).boxed(),
+            ];
+            let mut boxed = boxed.into_iter();
+            let mut p = boxed.next().unwrap();
+            assert_eq!(p.next(), Some(0));
+            assert_eq!(p.pl_nth(9), Some(20));
+            let mut p = p.pl_skip(9);
+            assert_eq!(p.peek(), Some(&40));
+            assert_eq!(p.next(), Some(40));
+            assert!(format!("{:?}", p).starts_with("BoxedPipeline(Pipeline {"));
+            assert_eq!(p.by_ref().count(), 79);
+            assert_eq!(p.next(), None);
+            let report = p.finish();
+            assert!(report.workers.len() <= w);
+            assert_eq!(boxed.next().unwrap().collect::<Vec<_>>(), [1, 2, 3]);
+        }
+
+        // Boxed pipelines can be sent to other threads.
+        let p = (0..10).plmap(2, |x| x * 2).boxed();
+        let total = std::thread::spawn(move || p.sum::<i32>()).join().unwrap();
+        assert_eq!(total, 90);
+    }
+}
@@ -0,0 +1,154 @@
+use {
+    super::chan,
+    std::{iter::FusedIterator, panic, thread},
+};
+
+/// Buffered runs an iterator on its own thread, which keeps up to a
+/// fixed number of values buffered ahead of the consumer.
+///
+/// The background thread stops when the iterator is exhausted, or the
+/// next time it produces a value after the Buffered is dropped. A panic
+/// on the background thread is resumed on the consumer once the values
+/// before it have been consumed.
+pub struct Buffered<T> {
+    rx: chan::Receiver<T>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl<T> Buffered<T>
+where
+    T: Send + 'static,
+{
+    /// Start running iter on a background thread, buffering up to n
+    /// values.
+    pub fn new<I>(n: usize, iter: I) -> Buffered<T>
+    where
+        I: Iterator<Item = T> + Send + 'static,
+    {
+        let (tx, rx) = chan::bounded(n);
+        let handle = thread::spawn(move || {
+            for v in iter {
+                if tx.send(v).is_err() {
+                    break;
+                }
+            }
+        });
+        Buffered {
+            rx,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl<T> Buffered<T> {
+    /// Called once the channel disconnects, resuming any panic on the
+    /// background thread.
+    fn finish(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            if let Err(payload) = handle.join() {
+                panic::resume_unwind(payload);
+            }
+        }
+    }
+
+    /// Like next, but instead of waiting for a value, cx is woken once
+    /// one arrives.
+    #[cfg(any(feature = "stream", feature = "async_iterator"))]
+    pub(crate) fn poll_next(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<T>> {
+        use {chan::TryRecvError, std::task::Poll};
+
+        match self.rx.try_recv_or_register(cx.waker()) {
+            Ok(v) => Poll::Ready(Some(v)),
+            Err(TryRecvError::Empty) => Poll::Pending,
+            Err(TryRecvError::Disconnected) => {
+                self.finish();
+                Poll::Ready(None)
+            }
+        }
+    }
+}
+
+impl<T> Iterator for Buffered<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        match self.rx.recv() {
+            Ok(v) => Some(v),
+            Err(_) => {
+                self.finish();
+                None
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.rx.len(), None)
+    }
+}
+
+impl<T> FusedIterator for Buffered<T> {}
+
+// Buffered's fields are never pinned, whatever the channel backend, so
+// it can be polled as a Stream without boxing.
+impl<T> Unpin for Buffered<T> {}
+
+/// Prefetch can be imported to add the pl_prefetch function to iterators.
+pub trait Prefetch: Iterator + Sized + Send + 'static
+where
+    Self::Item: Send + 'static,
+{
+    /// Run this iterator on a background thread, keeping up to n values
+    /// ready for the consumer.
+    ///
+    /// This is useful when the input of a pipeline is itself slow to
+    /// produce values, as pulling input then overlaps with mapping
+    /// instead of alternating with it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::{PipelineMap, Prefetch};
+    ///
+    /// let total: i32 = (0..100).pl_prefetch(16).plmap(4, |x| x * 2).sum();
+    /// assert_eq!(total, 9900);
+    /// ```
+    fn pl_prefetch(self, n: usize) -> Buffered<Self::Item> {
+        Buffered::new(n, self)
+    }
+}
+
+impl<I> Prefetch for I
+where
+    I: Iterator + Send + 'static,
+    I::Item: Send + 'static,
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffered() {
+        for n in 0..3 {
+            let v: Vec<i32> = (0..100).pl_prefetch(n).collect();
+            assert_eq!(v, (0..100).collect::<Vec<_>>());
+        }
+
+        let mut b = (0..10)
+            .inspect(|&x| {
+                if x == 5 {
+                    panic!("boom");
+                }
+            })
+            .pl_prefetch(2);
+        for i in 0..5 {
+            assert_eq!(b.next(), Some(i));
+        }
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| b.next()));
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,772 @@
+#[cfg(feature = "numa")]
+use super::numa::Placement;
+#[cfg(feature = "testing")]
+use super::testing::MockExecutor;
+#[cfg(feature = "tokio")]
+use tokio_util::sync::CancellationToken;
+#[cfg(feature = "stream")]
+use {super::stream::StreamPipeline, futures_core::Stream};
+use {
+    super::{
+        chan::{Builtin, Channel, Custom},
+        fair::{Priority, Share},
+        mapper::Mapper,
+        pipeline::Pipeline,
+        pool::PlmapPool,
+        schedule::Schedule,
+        scoped_pipeline::ScopedPipeline,
+        semaphore::Semaphore,
+        spawner::{SpawnFn, Spawner, ThreadSpawner, Worker, HAS_THREADS},
+        stats::{CheckpointFn, CompleteFn, PipelineReport, ProgressFn, SlowFn},
+        wait::WaitStrategy,
+        workers::Workers,
+    },
+    std::{sync::Arc, time::Duration},
+};
+
+/// PipelineBuilder configures and starts a Pipeline, for when the
+/// defaults used by plmap are not suitable.
+///
+/// # Examples
+///
+/// ```
+/// use plmap::PipelineBuilder;
+///
+/// // Keep up to 16 values in flight across 4 workers.
+/// let pipeline = PipelineBuilder::new(4)
+///     .in_flight(16)
+///     .build(0..100, |x| x * 2);
+/// assert_eq!(pipeline.sum::<i32>(), 9900);
+/// ```
+#[derive(Clone, Debug)]
+pub struct PipelineBuilder {
+    pub(crate) n_workers: usize,
+    pub(crate) in_flight: Option<usize>,
+    pub(crate) adaptive_in_flight: Option<(usize, usize)>,
+    pub(crate) schedule: Schedule,
+    pub(crate) wait: WaitStrategy,
+    pub(crate) eager: bool,
+    pub(crate) reorder_window: usize,
+    pub(crate) pool: Option<PlmapPool>,
+    pub(crate) spawn_fn: Option<SpawnFn>,
+    pub(crate) weight: u32,
+    pub(crate) priority: Priority,
+    #[cfg(feature = "numa")]
+    pub(crate) placement: Placement,
+    #[cfg(feature = "tokio")]
+    pub(crate) cancellation: Option<CancellationToken>,
+    pub(crate) trace_items: Option<usize>,
+    pub(crate) metrics_prefix: String,
+    pub(crate) progress: Option<ProgressFn>,
+    pub(crate) track_latency: bool,
+    pub(crate) on_complete: Option<CompleteFn>,
+    pub(crate) checkpoint: Option<CheckpointFn>,
+    pub(crate) throttle: Option<Duration>,
+    pub(crate) semaphore: Option<Arc<dyn Semaphore>>,
+    #[cfg(feature = "signal")]
+    pub(crate) ctrl_c: bool,
+    pub(crate) resume_from: usize,
+    pub(crate) slow: Option<SlowFn>,
+    #[cfg(feature = "testing")]
+    pub(crate) simulate: Option<u64>,
+    #[cfg(feature = "testing")]
+    pub(crate) mock: Option<MockExecutor>,
+}
+
+impl PipelineBuilder {
+    /// Create a builder for a pipeline with n_workers worker threads,
+    /// Workers::Inline or zero means values are mapped on the consuming
+    /// thread.
+    ///
+    /// With the sequential feature, or under Miri, there are always
+    /// zero workers, so that code using pipelines can be tested under
+    /// Miri without it running the workers' threads.
+    pub fn new(n_workers: impl Into<Workers>) -> PipelineBuilder {
+        PipelineBuilder {
+            n_workers: if cfg!(any(feature = "sequential", miri)) {
+                0
+            } else {
+                n_workers.into().count()
+            },
+            in_flight: None,
+            adaptive_in_flight: None,
+            schedule: Schedule::Shared,
+            wait: WaitStrategy::Block,
+            eager: false,
+            reorder_window: 0,
+            pool: None,
+            spawn_fn: None,
+            weight: 1,
+            priority: Priority::Normal,
+            #[cfg(feature = "numa")]
+            placement: Placement::Any,
+            #[cfg(feature = "tokio")]
+            cancellation: None,
+            trace_items: None,
+            metrics_prefix: "plmap".to_string(),
+            progress: None,
+            track_latency: false,
+            on_complete: None,
+            checkpoint: None,
+            throttle: None,
+            semaphore: None,
+            #[cfg(feature = "signal")]
+            ctrl_c: false,
+            resume_from: 0,
+            slow: None,
+            #[cfg(feature = "testing")]
+            simulate: None,
+            #[cfg(feature = "testing")]
+            mock: None,
+        }
+    }
+
+    /// Set how many values may be dispatched but not yet consumed,
+    /// the default is one more than the number of workers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if n is zero.
+    pub fn in_flight(mut self, n: usize) -> PipelineBuilder {
+        assert!(n > 0, "in_flight must be greater than zero");
+        self.in_flight = Some(n);
+        self.adaptive_in_flight = None;
+        self
+    }
+
+    /// Let the pipeline tune how many values are in flight between min
+    /// and max as it runs.
+    ///
+    /// The depth grows when the consumer has to wait for the next
+    /// result while there is still input to dispatch, and shrinks when
+    /// results are consistently ready before the consumer asks for
+    /// them. The initial depth is the in_flight default clamped to the
+    /// bounds.
+    ///
+    /// # Panics
+    ///
+    /// Panics if min is zero or greater than max.
+    pub fn adaptive_in_flight(mut self, min: usize, max: usize) -> PipelineBuilder {
+        assert!(min > 0, "adaptive_in_flight min must be greater than zero");
+        assert!(min <= max, "adaptive_in_flight min must not exceed max");
+        self.adaptive_in_flight = Some((min, max));
+        self.in_flight = None;
+        self
+    }
+
+    /// Set how dispatched values are assigned to workers, see Schedule.
+    pub fn schedule(mut self, schedule: Schedule) -> PipelineBuilder {
+        self.schedule = schedule;
+        self
+    }
+
+    /// Set how workers and the consumer wait for each other, see
+    /// WaitStrategy.
+    pub fn wait_strategy(mut self, wait: WaitStrategy) -> PipelineBuilder {
+        self.wait = wait;
+        self
+    }
+
+    /// Start pulling input and dispatching it to workers as soon as
+    /// the pipeline is built, rather than on the first call to next.
+    pub fn eager(mut self) -> PipelineBuilder {
+        self.eager = true;
+        self
+    }
+
+    /// Allow results to be yielded up to k positions ahead of their
+    /// place in the input order, so quick values are not held up
+    /// behind a slow one.
+    ///
+    /// A window of zero, the default, preserves the input order. The
+    /// window only applies to values taken with next, values taken from
+    /// the back of the pipeline with next_back are always in order.
+    pub fn reorder_window(mut self, k: usize) -> PipelineBuilder {
+        self.reorder_window = k;
+        self
+    }
+
+    /// Yield results as soon as they are ready, in any order. This is
+    /// a reorder window without bound.
+    pub fn unordered(self) -> PipelineBuilder {
+        self.reorder_window(usize::MAX)
+    }
+
+    /// Leave at least interval between dispatching one value and the
+    /// next, however many workers are free, so that load on whatever
+    /// the mapper talks to is evenly paced rather than bursty. Values
+    /// mapped on the consumer are paced too.
+    ///
+    /// The consumer waits out the interval itself, so it can take up
+    /// to interval longer to get a result that is already ready.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use {plmap::PipelineBuilder, std::time::Duration};
+    ///
+    /// // At most 100 values a second.
+    /// let total: i32 = PipelineBuilder::new(4)
+    ///     .throttle(Duration::from_millis(10))
+    ///     .build(0..10, |x| x * 2)
+    ///     .sum();
+    /// assert_eq!(total, 90);
+    /// ```
+    pub fn throttle(mut self, interval: Duration) -> PipelineBuilder {
+        self.throttle = Some(interval);
+        self
+    }
+
+    /// Acquire a permit from semaphore before dispatching each value,
+    /// releasing it once the value's result comes back, so that
+    /// pipelines sharing a semaphore share its limit, see
+    /// CountingSemaphore.
+    ///
+    /// Unlike acquiring the permit in the mapper, workers are not
+    /// tied up waiting for one. While some of its values are in
+    /// flight a pipeline waits for its own results rather than for a
+    /// permit, and values mapped on the consumer hold a permit while
+    /// they are mapped. The permits of values still in flight when the
+    /// pipeline is dropped are released then.
+    pub fn semaphore<S>(mut self, semaphore: Arc<S>) -> PipelineBuilder
+    where
+        S: Semaphore + 'static,
+    {
+        self.semaphore = Some(semaphore);
+        self
+    }
+
+    /// Map values as jobs on a shared pool instead of spawning worker
+    /// threads, n_workers then limits how many values of this pipeline
+    /// run at once.
+    ///
+    /// The schedule and wait strategy only apply to a pipeline's own
+    /// threads, and so are ignored when running on a pool.
+    pub fn pool(mut self, pool: &PlmapPool) -> PipelineBuilder {
+        self.pool = Some(pool.clone());
+        self
+    }
+
+    /// Set this pipeline's share of a shared pool, relative to the
+    /// weights of the other pipelines running on it. The default is 1.
+    ///
+    /// While several pipelines have values waiting for the pool, each
+    /// gets pool time in proportion to its weight, so a busy pipeline
+    /// can't starve the others.
+    ///
+    /// # Panics
+    ///
+    /// Panics if weight is zero.
+    pub fn weight(mut self, weight: u32) -> PipelineBuilder {
+        assert!(weight > 0, "weight must be greater than zero");
+        self.weight = weight;
+        self
+    }
+
+    /// Set this pipeline's priority on a shared pool, see Priority.
+    pub fn priority(mut self, priority: Priority) -> PipelineBuilder {
+        self.priority = priority;
+        self
+    }
+
+    /// Start worker threads with spawn instead of std::thread::spawn,
+    /// for environments where threads must be created some other way.
+    ///
+    /// spawn is called with a name for each worker and the worker
+    /// itself, which it must run on a thread of its own. For more
+    /// control, such as borrowing from the caller's stack, implement
+    /// Spawner and use build_with.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::PipelineBuilder;
+    ///
+    /// let total: i32 = PipelineBuilder::new(4)
+    ///     .spawn_fn(|name, worker| {
+    ///         std::thread::Builder::new().name(name).spawn(worker).unwrap();
+    ///     })
+    ///     .build(0..100, |x| x * 2)
+    ///     .sum();
+    /// assert_eq!(total, 9900);
+    /// ```
+    pub fn spawn_fn<F>(mut self, spawn: F) -> PipelineBuilder
+    where
+        F: Fn(String, Worker) + Send + Sync + 'static,
+    {
+        self.spawn_fn = Some(SpawnFn(Arc::new(spawn)));
+        self
+    }
+
+    /// Call f on the consuming thread every n results, and once more
+    /// for the last result, with the number of results taken, the
+    /// total expected if the input knows its exact length, and the time
+    /// since the pipeline started.
+    ///
+    /// Unlike counting with enumerate, the counts come from the
+    /// pipeline itself, so a pipeline driven by fold or a driver
+    /// thread reports too.
+    ///
+    /// # Panics
+    ///
+    /// Panics if n is zero.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::PipelineBuilder;
+    ///
+    /// let total: i32 = PipelineBuilder::new(4)
+    ///     .on_progress(25, |done, total, elapsed| {
+    ///         eprintln!("{}/{} in {:?}", done, total.unwrap(), elapsed);
+    ///     })
+    ///     .build(0..100, |x| x * 2)
+    ///     .sum();
+    /// assert_eq!(total, 9900);
+    /// ```
+    pub fn on_progress<F>(mut self, n: usize, f: F) -> PipelineBuilder
+    where
+        F: Fn(usize, Option<usize>, Duration) + Send + Sync + 'static,
+    {
+        assert!(n > 0, "n must be greater than zero");
+        self.progress = Some(ProgressFn(n, Arc::new(f)));
+        self
+    }
+
+    /// Call f once the pipeline has run to completion: its input is
+    /// exhausted, every result has been taken and its workers have
+    /// returned. f gets the final report, as from Pipeline::finish.
+    ///
+    /// f is called at most once, on the consumer's thread, and not at
+    /// all if the pipeline is dropped or cancelled before completing.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::PipelineBuilder;
+    ///
+    /// let total: i32 = PipelineBuilder::new(4)
+    ///     .on_complete(|report| eprintln!("done in {:?}", report.elapsed))
+    ///     .build(0..100, |x| x * 2)
+    ///     .sum();
+    /// assert_eq!(total, 9900);
+    /// ```
+    pub fn on_complete<F>(mut self, f: F) -> PipelineBuilder
+    where
+        F: Fn(PipelineReport) + Send + Sync + 'static,
+    {
+        self.on_complete = Some(CompleteFn(Arc::new(f)));
+        self
+    }
+
+    /// Call f on the consuming thread with a checkpoint once at least
+    /// every n values, and once more when the pipeline completes. The
+    /// checkpoint is the index of the first input value whose result
+    /// has not been taken, every value before it is done with, so
+    /// passing it to resume_from on a later run carries on from there.
+    ///
+    /// With a reorder window, results taken ahead of the checkpoint
+    /// are not covered by it, and would be mapped again on resuming.
+    ///
+    /// # Panics
+    ///
+    /// Panics if n is zero.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use {
+    ///     plmap::PipelineBuilder,
+    ///     std::sync::{Arc, Mutex},
+    /// };
+    ///
+    /// let saved = Arc::new(Mutex::new(0));
+    /// let s = saved.clone();
+    /// let mut p = PipelineBuilder::new(4)
+    ///     .on_checkpoint(10, move |index| *s.lock().unwrap() = index)
+    ///     .build(0..100, |x| x * 2);
+    /// assert_eq!(p.by_ref().take(25).count(), 25);
+    /// drop(p);
+    ///
+    /// // Later, after a restart.
+    /// let resume = *saved.lock().unwrap();
+    /// assert_eq!(resume, 20);
+    /// let p = PipelineBuilder::new(4)
+    ///     .resume_from(resume)
+    ///     .build(0..100, |x| x * 2);
+    /// assert_eq!(p.count(), 80);
+    /// ```
+    pub fn on_checkpoint<F>(mut self, n: usize, f: F) -> PipelineBuilder
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        assert!(n > 0, "n must be greater than zero");
+        self.checkpoint = Some(CheckpointFn(n, Arc::new(f)));
+        self
+    }
+
+    /// Stop the pipeline gracefully on Ctrl-C: no more input is
+    /// dispatched, the results of values already in flight are still
+    /// yielded in order, then the pipeline ends, so the consumer can
+    /// finish up as if the input had ended.
+    ///
+    /// The handler is only installed while the pipeline lives, and
+    /// replaces Ctrl-C's default of exiting the process, so further
+    /// presses do nothing until the pipeline is dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::PipelineBuilder;
+    ///
+    /// let p = PipelineBuilder::new(4)
+    ///     .cancel_on_ctrl_c()
+    ///     .build(0..100, |x| x * 2);
+    /// // Fewer results if interrupted.
+    /// for x in p {
+    ///     println!("{}", x);
+    /// }
+    /// ```
+    #[cfg(feature = "signal")]
+    pub fn cancel_on_ctrl_c(mut self) -> PipelineBuilder {
+        self.ctrl_c = true;
+        self
+    }
+
+    /// Skip the first index values of the input without mapping them,
+    /// such as those done with before a checkpoint, see on_checkpoint.
+    /// Checkpoints keep counting from the start of the input.
+    pub fn resume_from(mut self, index: usize) -> PipelineBuilder {
+        self.resume_from = index;
+        self
+    }
+
+    /// Call f with the index of the value, the worker thread mapping it
+    /// and how long it has taken so far, when a value has been mapping
+    /// for longer than threshold. Each value is reported once, shortly
+    /// after it crosses the threshold, so that pathological inputs can
+    /// be found while they are still running.
+    ///
+    /// The pipeline's worker threads are watched by another thread, f
+    /// is called there. Values mapped on the consumer or on a pool are
+    /// not watched.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use {plmap::PipelineBuilder, std::time::Duration};
+    ///
+    /// let total: i32 = PipelineBuilder::new(4)
+    ///     .warn_slow(Duration::from_secs(10), |item, worker, elapsed| {
+    ///         eprintln!("item {} on worker {} for {:?}", item, worker, elapsed);
+    ///     })
+    ///     .build(0..100, |x| x * 2)
+    ///     .sum();
+    /// assert_eq!(total, 9900);
+    /// ```
+    pub fn warn_slow<F>(mut self, threshold: Duration, f: F) -> PipelineBuilder
+    where
+        F: Fn(usize, usize, Duration) + Send + Sync + 'static,
+    {
+        self.slow = Some(SlowFn(threshold, Arc::new(f)));
+        self
+    }
+
+    /// Track how long each value takes from being dispatched until a
+    /// worker thread has mapped it, in a histogram reported as the
+    /// latency of Pipeline::stats. Values mapped on the consumer or on
+    /// a pool are not tracked.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::PipelineBuilder;
+    ///
+    /// let mut p = PipelineBuilder::new(4)
+    ///     .track_latency()
+    ///     .build(0..100, |x| x * 2);
+    /// assert_eq!(p.by_ref().sum::<i32>(), 9900);
+    /// let latency = p.finish().latency.unwrap();
+    /// assert_eq!(latency.count(), 100);
+    /// eprintln!("p99 {:?}", latency.percentile(99.0));
+    /// ```
+    pub fn track_latency(mut self) -> PipelineBuilder {
+        self.track_latency = true;
+        self
+    }
+
+    /// Show the pipeline's progress on an indicatif progress bar drawn
+    /// with style, see on_progress. The bar's length is the input's
+    /// exact length, when it has one.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use {indicatif::ProgressStyle, plmap::PipelineBuilder};
+    ///
+    /// let total: i32 = PipelineBuilder::new(4)
+    ///     .with_progress_bar(ProgressStyle::default_bar())
+    ///     .build(0..100, |x| x * 2)
+    ///     .sum();
+    /// assert_eq!(total, 9900);
+    /// ```
+    #[cfg(feature = "indicatif")]
+    pub fn with_progress_bar(self, style: indicatif::ProgressStyle) -> PipelineBuilder {
+        self.progress_bar(indicatif::ProgressBar::no_length().with_style(style))
+    }
+
+    #[cfg(feature = "indicatif")]
+    pub(crate) fn progress_bar(self, bar: indicatif::ProgressBar) -> PipelineBuilder {
+        self.on_progress(1, move |done, total, _| {
+            if let Some(total) = total {
+                bar.set_length(total as u64);
+            }
+            bar.set_position(done as u64);
+            if Some(done) == total {
+                bar.finish();
+            }
+        })
+    }
+
+    /// Map values on the consumer, completing them in an order picked
+    /// by a PRNG seeded with seed instead of on worker threads, so that
+    /// an interleaving that breaks the code consuming the pipeline can
+    /// be replayed from its seed.
+    ///
+    /// Dispatched values are queued, each is either completed straight
+    /// away or left until the consumer needs a result, when queued
+    /// values are completed in a random order until it arrives. Results
+    /// still reach the consumer in order, or within the reorder window.
+    /// Pools and spawners are not used.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::PipelineBuilder;
+    ///
+    /// for seed in 0..100 {
+    ///     let v: Vec<i32> = PipelineBuilder::new(4)
+    ///         .reorder_window(2)
+    ///         .simulate(seed)
+    ///         .build(0..10, |x| x * 2)
+    ///         .collect();
+    ///     assert_eq!(v.len(), 10, "seed {}", seed);
+    /// }
+    /// ```
+    #[cfg(feature = "testing")]
+    pub fn simulate(mut self, seed: u64) -> PipelineBuilder {
+        self.simulate = Some(seed);
+        self
+    }
+
+    /// Map values only when a test completes them with the given
+    /// MockExecutor, so that it can step the pipeline a value at a time
+    /// and check what the consumer sees in between.
+    ///
+    /// Only pipelines started with build are mocked, and pools and
+    /// spawners are not used.
+    #[cfg(feature = "testing")]
+    pub fn mock(mut self, mock: &MockExecutor) -> PipelineBuilder {
+        self.mock = Some(mock.clone());
+        self
+    }
+
+    /// Set how workers are placed on NUMA nodes, see Placement.
+    #[cfg(feature = "numa")]
+    pub fn placement(mut self, placement: Placement) -> PipelineBuilder {
+        self.placement = placement;
+        self
+    }
+
+    /// Stop the pipeline when token is cancelled: no more input is
+    /// dispatched, results still in flight are dropped and the
+    /// pipeline ends, as an iterator or as a Stream. A Stream waiting
+    /// for a result is woken straight away, while next only notices
+    /// once the result it is waiting for has been mapped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use {plmap::PipelineBuilder, tokio_util::sync::CancellationToken};
+    ///
+    /// let token = CancellationToken::new();
+    /// let mut p = PipelineBuilder::new(4)
+    ///     .cancellation_token(token.clone())
+    ///     .build(0.., |x: u64| x * 2);
+    /// assert_eq!(p.next(), Some(0));
+    /// token.cancel();
+    /// assert_eq!(p.next(), None);
+    /// ```
+    #[cfg(feature = "tokio")]
+    pub fn cancellation_token(mut self, token: CancellationToken) -> PipelineBuilder {
+        self.cancellation = Some(token);
+        self
+    }
+
+    /// Trace every nth item mapped by a worker thread in a plmap::item
+    /// span, with the item and worker index as fields. One means every
+    /// item, which can be costly for cheap mappers.
+    ///
+    /// Pipelines always get a plmap::pipeline span, and each worker
+    /// thread a plmap::worker span inside it, lasting as long as they
+    /// do.
+    ///
+    /// # Panics
+    ///
+    /// Panics if n is zero.
+    #[cfg(feature = "tracing")]
+    pub fn trace_items(mut self, n: usize) -> PipelineBuilder {
+        assert!(n > 0, "n must be greater than zero");
+        self.trace_items = Some(n);
+        self
+    }
+
+    /// Report the pipeline's metrics under names starting with prefix
+    /// instead of "plmap", so that pipelines can be told apart:
+    ///
+    /// - {prefix}_items_dispatched counts values handed to the mapper.
+    /// - {prefix}_items_completed counts results taken by the consumer.
+    /// - {prefix}_in_flight is the number of values in between.
+    /// - {prefix}_worker_busy_ratio is the fraction of its lifetime a
+    ///   worker thread has spent mapping, labelled with its index.
+    /// - {prefix}_queue_wait_seconds is a histogram of how long values
+    ///   waited for a worker thread.
+    ///
+    /// Worker metrics are only reported by the pipeline's own threads,
+    /// not by pools.
+    #[cfg(feature = "metrics")]
+    pub fn metrics_prefix(mut self, prefix: impl Into<String>) -> PipelineBuilder {
+        self.metrics_prefix = prefix.into();
+        self
+    }
+
+    /// Start a pipeline mapping input with mapper.
+    pub fn build<I, M>(self, input: I, mapper: M) -> Pipeline<I, M>
+    where
+        I: Iterator,
+        I::Item: Send + 'static,
+        M: Mapper<I::Item> + Clone + Send + 'static,
+        M::Out: Send + 'static,
+    {
+        Pipeline::start(self, mapper, input)
+    }
+
+    /// Start a pipeline mapping input with mapper, with workers started
+    /// by spawner instead of on new threads.
+    ///
+    /// The pool option is ignored, as the spawner decides where the
+    /// workers run.
+    pub fn build_with<'env, S, I, M>(mut self, spawner: S, input: I, mapper: M) -> Pipeline<I, M>
+    where
+        S: Spawner<'env>,
+        I: Iterator,
+        I::Item: Send + 'env,
+        M: Mapper<I::Item> + Clone + Send + 'env,
+        M::Out: Send + 'env,
+    {
+        // Workers can't block without threads, see Pipeline::start.
+        if !HAS_THREADS {
+            self.n_workers = 0;
+        }
+        Pipeline::start_with::<_, Builtin>(&self, spawner, mapper, input)
+    }
+
+    /// Start a pipeline mapping input with mapper, like build, but with
+    /// values sent to the workers and results sent back over channels
+    /// of C instead of the built-in ones, see Channel.
+    ///
+    /// As with build_with, the pool option is ignored.
+    pub fn build_with_channel<C, I, M>(mut self, input: I, mapper: M) -> Pipeline<I, M>
+    where
+        C: Channel + 'static,
+        I: Iterator,
+        I::Item: Send + 'static,
+        M: Mapper<I::Item> + Clone + Send + 'static,
+        M::Out: Send + 'static,
+    {
+        if !HAS_THREADS {
+            self.n_workers = 0;
+        }
+        match self.spawn_fn {
+            Some(ref spawn) => {
+                Pipeline::start_with::<_, Custom<C>>(&self, spawn.spawner(), mapper, input)
+            }
+            None => Pipeline::start_with::<_, Custom<C>>(&self, ThreadSpawner, mapper, input),
+        }
+    }
+
+    /// Start a scoped pipeline mapping input with mapper, with workers
+    /// spawned on worker_scope, so that every option of the builder is
+    /// available to scoped pipelines too, see ScopedPipeline.
+    ///
+    /// As with build_with, the pool option is ignored.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::PipelineBuilder;
+    ///
+    /// let v: Vec<i32> = (0..100).collect();
+    /// std::thread::scope(|s| {
+    ///     let p = PipelineBuilder::new(4)
+    ///         .in_flight(16)
+    ///         .build_scoped(s, v.iter(), |x: &i32| x * 2);
+    ///     assert_eq!(p.config().in_flight_capacity, 16);
+    ///     assert_eq!(p.sum::<i32>(), 9900);
+    /// });
+    /// ```
+    pub fn build_scoped<'scope, 'env, S, I, M>(
+        self,
+        worker_scope: &'scope S,
+        input: I,
+        mapper: M,
+    ) -> ScopedPipeline<'scope, 'env, I, M>
+    where
+        &'scope S: Spawner<'env>,
+        I: Iterator,
+        I::Item: Send + 'env,
+        M: Mapper<I::Item> + Clone + Send + 'env,
+        M::Out: Send + 'env,
+    {
+        ScopedPipeline::with_builder(worker_scope, self, mapper, input)
+    }
+
+    /// Start a pipeline mapping the values of an async stream with
+    /// mapper, see StreamPipelineMap.
+    #[cfg(feature = "stream")]
+    pub fn build_stream<S, M>(self, input: S, mapper: M) -> StreamPipeline<S, M>
+    where
+        S: Stream,
+        S::Item: Send + 'static,
+        M: Mapper<S::Item> + Clone + Send + 'static,
+        M::Out: Send + 'static,
+    {
+        StreamPipeline::start(self, mapper, input)
+    }
+
+    /// The pipeline's claim on a shared pool.
+    pub(crate) fn share(&self) -> Share {
+        Share {
+            weight: self.weight,
+            priority: self.priority,
+        }
+    }
+
+    /// The number of values in flight the pipeline starts with.
+    pub(crate) fn initial_in_flight(&self) -> usize {
+        let default = self.n_workers + 1;
+        match (self.in_flight, self.adaptive_in_flight) {
+            (Some(n), _) => n,
+            (None, Some((min, max))) => default.max(min).min(max),
+            (None, None) => default,
+        }
+    }
+
+    /// The most values that can ever be in flight.
+    pub(crate) fn max_in_flight(&self) -> usize {
+        match self.adaptive_in_flight {
+            Some((_, max)) => max,
+            None => self.initial_in_flight(),
+        }
+    }
+}
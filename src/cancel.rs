@@ -0,0 +1,111 @@
+use {
+    std::{future::Future, pin::Pin, task::Context},
+    tokio_util::sync::{CancellationToken, WaitForCancellationFutureOwned},
+};
+
+/// Cancellation watches the CancellationToken a pipeline was built
+/// with, see PipelineBuilder::cancellation_token.
+pub(crate) struct Cancellation {
+    token: CancellationToken,
+    /// Created on the first poll, so pipelines that are only iterated
+    /// never allocate it.
+    wait: Option<Pin<Box<WaitForCancellationFutureOwned>>>,
+}
+
+impl Cancellation {
+    pub(crate) fn new(token: CancellationToken) -> Cancellation {
+        Cancellation { token, wait: None }
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Whether the token is cancelled, if not cx is woken when it is.
+    pub(crate) fn poll(&mut self, cx: &mut Context<'_>) -> bool {
+        let token = &self.token;
+        let wait = self
+            .wait
+            .get_or_insert_with(|| Box::pin(token.clone().cancelled_owned()));
+        wait.as_mut().poll(cx).is_ready()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::PipelineBuilder,
+        std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        tokio_util::sync::CancellationToken,
+    };
+
+    #[test]
+    fn test_cancellation_token() {
+        for w in 0..3 {
+            let pulled = Arc::new(AtomicUsize::new(0));
+            let p = pulled.clone();
+            let token = CancellationToken::new();
+            let mut pipeline = PipelineBuilder::new(w)
+                .cancellation_token(token.clone())
+                .build(
+                    (0..).inspect(move |_| {
+                        p.fetch_add(1, Ordering::SeqCst);
+                    }),
+                    |x: u64| x * 2,
+                );
+            assert_eq!(pipeline.next(), Some(0));
+            token.cancel();
+            let n = pulled.load(Ordering::SeqCst);
+            assert_eq!(pipeline.next(), None);
+            assert_eq!(pulled.load(Ordering::SeqCst), n);
+        }
+    }
+
+    #[cfg(feature = "stream")]
+    #[test]
+    fn test_cancellation_token_stream() {
+        use {
+            futures_core::Stream,
+            std::{
+                future::poll_fn,
+                pin::Pin,
+                task::Poll,
+                thread,
+                time::{Duration, Instant},
+            },
+        };
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let token = CancellationToken::new();
+        let mut pipeline = PipelineBuilder::new(2)
+            .cancellation_token(token.clone())
+            .build(0..10, |x: u64| {
+                thread::sleep(Duration::from_millis(if x == 0 { 0 } else { 300 }));
+                x
+            });
+        let t = token.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            t.cancel();
+        });
+        // Only the first result is quick, the stream must end on
+        // cancellation rather than when the slow ones are mapped.
+        let start = Instant::now();
+        let mut v = Vec::new();
+        rt.block_on(poll_fn(|cx| loop {
+            match Pin::new(&mut pipeline).poll_next(cx) {
+                Poll::Ready(Some(x)) => v.push(x),
+                Poll::Ready(None) => return Poll::Ready(()),
+                Poll::Pending => return Poll::Pending,
+            }
+        }));
+        assert!(start.elapsed() < Duration::from_millis(250));
+        assert!(v.len() <= 1);
+        assert!(token.is_cancelled());
+    }
+}
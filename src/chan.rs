@@ -0,0 +1,1067 @@
+//! The channels connecting the consumer and workers.
+//!
+//! Each channel backend implements Channel, and the rest of the crate
+//! uses whichever Backend the enabled features choose through the
+//! Sender and Receiver wrappers: crossbeam-channel by default, flume
+//! with the flume feature, or std::sync::mpsc when neither is enabled.
+//! Under loom or shuttle the model backend is always used, and under
+//! Miri std::sync::mpsc, keeping crossbeam's internals out of the
+//! interpreter.
+//! Errors are always the std::sync::mpsc ones, whatever the backend.
+//!
+//! Channel and its halves are public so that a pipeline's work queues
+//! and results can also go over a user's channels, see
+//! PipelineBuilder::build_with_channel. Those are boxed behind the same
+//! wrappers, with Channels choosing between the two when a pipeline
+//! starts. Wake stays private, the wrappers do the waking whatever the
+//! channel.
+
+pub(crate) use std::sync::mpsc::{RecvError, RecvTimeoutError, SendError, TryRecvError};
+use {
+    super::sync::{Arc, AtomicBool, CachePadded, Mutex, Ordering},
+    std::{any::Any, marker::PhantomData, task::Waker, time::Duration},
+};
+
+/// ChannelSender is the sending half of a Channel.
+pub trait ChannelSender<T>: Clone {
+    /// Send v, waiting for room on a full bounded channel. v is given
+    /// back if every receiver has been dropped.
+    fn send(&self, v: T) -> Result<(), SendError<T>>;
+}
+
+/// ChannelReceiver is the receiving half of a Channel, it must be
+/// possible for clones to receive concurrently, each value going to
+/// one of them.
+pub trait ChannelReceiver<T>: Clone {
+    /// Wait for the next value. An error means the channel is empty
+    /// and every sender has been dropped.
+    fn recv(&self) -> Result<T, RecvError>;
+
+    /// Take the next value without waiting. It may also report Empty
+    /// while another clone is taking a value.
+    fn try_recv(&self) -> Result<T, TryRecvError>;
+
+    /// Like recv, but giving up after timeout.
+    fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError>;
+
+    /// The number of values waiting in the channel.
+    fn len(&self) -> usize;
+
+    /// Whether no values are waiting in the channel.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Channel is a kind of channel a pipeline can send values to its
+/// workers and results back over, see
+/// PipelineBuilder::build_with_channel. Implement it to use channels
+/// of your own, such as instrumented ones or ones in shared memory.
+///
+/// A pipeline's work queues are bounded, with room for its in-flight
+/// window, and cloned receivers are shared by the workers. Results
+/// come back over an unbounded channel. Disconnects must be reported
+/// as the std::sync::mpsc errors: a pipeline shuts its workers down by
+/// dropping its senders.
+///
+/// # Examples
+///
+/// ```
+/// use {
+///     plmap::{Channel, ChannelReceiver, ChannelSender, PipelineBuilder},
+///     std::{
+///         sync::{
+///             atomic::{AtomicUsize, Ordering},
+///             mpsc::{self, RecvError, RecvTimeoutError, SendError, TryRecvError},
+///             Arc, Mutex,
+///         },
+///         time::Duration,
+///     },
+/// };
+///
+/// static SENT: AtomicUsize = AtomicUsize::new(0);
+///
+/// /// Counted is std's mpsc, with the receiver behind a mutex so it can
+/// /// be shared, counting every value sent.
+/// struct Counted;
+///
+/// enum Inner<T> {
+///     Bounded(mpsc::SyncSender<T>),
+///     Unbounded(mpsc::Sender<T>),
+/// }
+///
+/// struct Tx<T>(Inner<T>, Arc<AtomicUsize>);
+///
+/// struct Rx<T>(Arc<Mutex<mpsc::Receiver<T>>>, Arc<AtomicUsize>);
+///
+/// impl<T> Clone for Tx<T> {
+///     fn clone(&self) -> Self {
+///         let inner = match &self.0 {
+///             Inner::Bounded(tx) => Inner::Bounded(tx.clone()),
+///             Inner::Unbounded(tx) => Inner::Unbounded(tx.clone()),
+///         };
+///         Tx(inner, self.1.clone())
+///     }
+/// }
+///
+/// impl<T> Clone for Rx<T> {
+///     fn clone(&self) -> Self {
+///         Rx(self.0.clone(), self.1.clone())
+///     }
+/// }
+///
+/// impl<T: Send> ChannelSender<T> for Tx<T> {
+///     fn send(&self, v: T) -> Result<(), SendError<T>> {
+///         self.1.fetch_add(1, Ordering::SeqCst);
+///         let result = match &self.0 {
+///             Inner::Bounded(tx) => tx.send(v),
+///             Inner::Unbounded(tx) => tx.send(v),
+///         };
+///         match result {
+///             Ok(()) => SENT.fetch_add(1, Ordering::Relaxed),
+///             Err(_) => self.1.fetch_sub(1, Ordering::SeqCst),
+///         };
+///         result
+///     }
+/// }
+///
+/// impl<T> Rx<T> {
+///     fn took<E>(&self, result: Result<T, E>) -> Result<T, E> {
+///         if result.is_ok() {
+///             self.1.fetch_sub(1, Ordering::SeqCst);
+///         }
+///         result
+///     }
+/// }
+///
+/// impl<T: Send> ChannelReceiver<T> for Rx<T> {
+///     fn recv(&self) -> Result<T, RecvError> {
+///         self.took(self.0.lock().unwrap().recv())
+///     }
+///
+///     fn try_recv(&self) -> Result<T, TryRecvError> {
+///         match self.0.try_lock() {
+///             Ok(rx) => self.took(rx.try_recv()),
+///             Err(_) => Err(TryRecvError::Empty),
+///         }
+///     }
+///
+///     fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+///         self.took(self.0.lock().unwrap().recv_timeout(timeout))
+///     }
+///
+///     fn len(&self) -> usize {
+///         self.1.load(Ordering::SeqCst)
+///     }
+/// }
+///
+/// impl Channel for Counted {
+///     type Sender<T: Send> = Tx<T>;
+///     type Receiver<T: Send> = Rx<T>;
+///
+///     fn bounded<T: Send>(cap: usize) -> (Tx<T>, Rx<T>) {
+///         let (tx, rx) = mpsc::sync_channel(cap);
+///         let len = Arc::new(AtomicUsize::new(0));
+///         (Tx(Inner::Bounded(tx), len.clone()), Rx(Arc::new(Mutex::new(rx)), len))
+///     }
+///
+///     fn unbounded<T: Send>() -> (Tx<T>, Rx<T>) {
+///         let (tx, rx) = mpsc::channel();
+///         let len = Arc::new(AtomicUsize::new(0));
+///         (Tx(Inner::Unbounded(tx), len.clone()), Rx(Arc::new(Mutex::new(rx)), len))
+///     }
+/// }
+///
+/// let total: u64 = PipelineBuilder::new(4)
+///     .build_with_channel::<Counted, _, _>(0..100, |x: u64| x * 2)
+///     .sum();
+/// assert_eq!(total, 9900);
+/// // Each value went to a worker and its result came back.
+/// assert_eq!(SENT.load(Ordering::Relaxed), 200);
+/// ```
+pub trait Channel {
+    type Sender<T: Send>: ChannelSender<T> + Send + Sync;
+    type Receiver<T: Send>: ChannelReceiver<T> + Send + Sync;
+
+    /// A channel holding at most cap values, zero means sends wait
+    /// for a receiver.
+    fn bounded<T: Send>(cap: usize) -> (Self::Sender<T>, Self::Receiver<T>);
+
+    fn unbounded<T: Send>() -> (Self::Sender<T>, Self::Receiver<T>);
+
+    /// Receive from whichever of rxs has a value first, returning its
+    /// index. An error means that receiver was disconnected.
+    ///
+    /// By default this polls, sleeping on the first receiver for a
+    /// short while between rounds.
+    fn select<T: Send>(rxs: &[&Self::Receiver<T>]) -> (usize, Result<T, RecvError>) {
+        loop {
+            for (i, rx) in rxs.iter().enumerate() {
+                match rx.try_recv() {
+                    Ok(v) => return (i, Ok(v)),
+                    Err(TryRecvError::Disconnected) => return (i, Err(RecvError)),
+                    Err(TryRecvError::Empty) => (),
+                }
+            }
+            match rxs[0].recv_timeout(Duration::from_millis(1)) {
+                Ok(v) => return (0, Ok(v)),
+                Err(RecvTimeoutError::Disconnected) => return (0, Err(RecvError)),
+                Err(RecvTimeoutError::Timeout) => (),
+            }
+        }
+    }
+}
+
+#[cfg(any(loom, shuttle))]
+pub(crate) use model_chan::{
+    Model as Backend, Receiver as BackendReceiver, Sender as BackendSender,
+};
+#[cfg(all(
+    not(any(loom, shuttle)),
+    any(miri, not(any(feature = "crossbeam-channel", feature = "flume")))
+))]
+pub(crate) use std_chan::{
+    Receiver as BackendReceiver, Sender as BackendSender, StdMpsc as Backend,
+};
+#[cfg(all(
+    feature = "crossbeam-channel",
+    not(any(feature = "flume", loom, shuttle, miri))
+))]
+pub(crate) use {
+    crossbeam_chan::Crossbeam as Backend,
+    crossbeam_channel::{Receiver as BackendReceiver, Sender as BackendSender},
+};
+#[cfg(all(feature = "flume", not(any(loom, shuttle, miri))))]
+pub(crate) use {
+    flume::{Receiver as BackendReceiver, Sender as BackendSender},
+    flume_chan::Flume as Backend,
+};
+
+/// Sender wraps the sending half of a Backend channel or of a user's
+/// Channel, calling it through ChannelSender so that inherent methods
+/// of the same name are never used. It is only None while being
+/// dropped.
+pub(crate) struct Sender<T>(Option<SenderKind<T>>, Arc<CachePadded<Wake>>);
+
+/// Receiver wraps a Backend or user's receiver, see Sender.
+pub(crate) struct Receiver<T>(ReceiverKind<T>, Arc<CachePadded<Wake>>);
+
+enum SenderKind<T> {
+    Backend(BackendSender<T>),
+    Custom(Box<dyn DynSender<T>>),
+}
+
+enum ReceiverKind<T> {
+    Backend(BackendReceiver<T>),
+    Custom(Box<dyn DynReceiver<T>>),
+}
+
+/// DynSender is ChannelSender for a user's Channel, boxed.
+trait DynSender<T>: Send + Sync {
+    fn send(&self, v: T) -> Result<(), SendError<T>>;
+    fn clone_box(&self) -> Box<dyn DynSender<T>>;
+}
+
+/// DynReceiver is ChannelReceiver for a user's Channel, boxed.
+trait DynReceiver<T>: Send + Sync {
+    fn recv(&self) -> Result<T, RecvError>;
+    fn try_recv(&self) -> Result<T, TryRecvError>;
+    fn len(&self) -> usize;
+    fn clone_box(&self) -> Box<dyn DynReceiver<T>>;
+    fn as_any(&self) -> &dyn Any;
+    /// Channel::select, every one of rxs being of the same Channel as
+    /// this receiver.
+    fn select(&self, rxs: &[&dyn DynReceiver<T>]) -> (usize, Result<T, RecvError>);
+}
+
+struct CustomSender<C: Channel, T: Send>(C::Sender<T>);
+
+struct CustomReceiver<C: Channel, T: Send>(C::Receiver<T>);
+
+impl<C, T> DynSender<T> for CustomSender<C, T>
+where
+    C: Channel + 'static,
+    T: Send + 'static,
+{
+    fn send(&self, v: T) -> Result<(), SendError<T>> {
+        self.0.send(v)
+    }
+
+    fn clone_box(&self) -> Box<dyn DynSender<T>> {
+        Box::new(CustomSender::<C, T>(self.0.clone()))
+    }
+}
+
+impl<C, T> DynReceiver<T> for CustomReceiver<C, T>
+where
+    C: Channel + 'static,
+    T: Send + 'static,
+{
+    fn recv(&self) -> Result<T, RecvError> {
+        self.0.recv()
+    }
+
+    fn try_recv(&self) -> Result<T, TryRecvError> {
+        self.0.try_recv()
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn clone_box(&self) -> Box<dyn DynReceiver<T>> {
+        Box::new(CustomReceiver::<C, T>(self.0.clone()))
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn select(&self, rxs: &[&dyn DynReceiver<T>]) -> (usize, Result<T, RecvError>) {
+        let rxs: Vec<&C::Receiver<T>> = rxs
+            .iter()
+            .map(|rx| &rx.as_any().downcast_ref::<Self>().unwrap().0)
+            .collect();
+        C::select(&rxs)
+    }
+}
+
+/// Wake holds the waker of a task waiting on a channel, see
+/// Receiver::register. Every send checks it, so it is padded away
+/// from the channel's own state.
+#[derive(Default)]
+struct Wake {
+    registered: AtomicBool,
+    waker: Mutex<Option<Waker>>,
+}
+
+impl Wake {
+    /// Wake the registered waker, if any. Most sends find none
+    /// registered, so check with a load before taking the line to swap.
+    fn wake(&self) {
+        if self.registered.load(Ordering::Relaxed) && self.registered.swap(false, Ordering::AcqRel)
+        {
+            if let Some(waker) = self.waker.lock().unwrap().take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Channels makes the channels of a pipeline, on the Backend or on a
+/// user's Channel, for values that live for 'env.
+pub(crate) trait Channels<'env> {
+    fn bounded<T: Send + 'env>(cap: usize) -> (Sender<T>, Receiver<T>);
+    fn unbounded<T: Send + 'env>() -> (Sender<T>, Receiver<T>);
+}
+
+/// Builtin makes Backend channels.
+pub(crate) struct Builtin;
+
+impl<'env> Channels<'env> for Builtin {
+    fn bounded<T: Send + 'env>(cap: usize) -> (Sender<T>, Receiver<T>) {
+        bounded(cap)
+    }
+
+    fn unbounded<T: Send + 'env>() -> (Sender<T>, Receiver<T>) {
+        unbounded()
+    }
+}
+
+/// Custom makes channels of a user's Channel C, which are boxed, so
+/// they can only carry values that live forever.
+pub(crate) struct Custom<C>(PhantomData<C>);
+
+impl<C: Channel + 'static> Channels<'static> for Custom<C> {
+    fn bounded<T: Send + 'static>(cap: usize) -> (Sender<T>, Receiver<T>) {
+        let (tx, rx) = C::bounded(cap);
+        custom::<C, T>(tx, rx)
+    }
+
+    fn unbounded<T: Send + 'static>() -> (Sender<T>, Receiver<T>) {
+        let (tx, rx) = C::unbounded();
+        custom::<C, T>(tx, rx)
+    }
+}
+
+fn custom<C, T>(tx: C::Sender<T>, rx: C::Receiver<T>) -> (Sender<T>, Receiver<T>)
+where
+    C: Channel + 'static,
+    T: Send + 'static,
+{
+    let wake = Arc::new(CachePadded(Wake::default()));
+    (
+        Sender(
+            Some(SenderKind::Custom(Box::new(CustomSender::<C, T>(tx)))),
+            wake.clone(),
+        ),
+        Receiver(
+            ReceiverKind::Custom(Box::new(CustomReceiver::<C, T>(rx))),
+            wake,
+        ),
+    )
+}
+
+pub(crate) fn bounded<T: Send>(cap: usize) -> (Sender<T>, Receiver<T>) {
+    let (tx, rx) = Backend::bounded(cap);
+    let wake = Arc::new(CachePadded(Wake::default()));
+    (
+        Sender(Some(SenderKind::Backend(tx)), wake.clone()),
+        Receiver(ReceiverKind::Backend(rx), wake),
+    )
+}
+
+pub(crate) fn unbounded<T: Send>() -> (Sender<T>, Receiver<T>) {
+    let (tx, rx) = Backend::unbounded();
+    let wake = Arc::new(CachePadded(Wake::default()));
+    (
+        Sender(Some(SenderKind::Backend(tx)), wake.clone()),
+        Receiver(ReceiverKind::Backend(rx), wake),
+    )
+}
+
+/// Receive from whichever of rxs has a value first, returning its
+/// index. An error means that receiver was disconnected. All of rxs
+/// must come from the same Channels.
+pub(crate) fn select<T: Send>(rxs: &[&Receiver<T>]) -> (usize, Result<T, RecvError>) {
+    match &rxs[0].0 {
+        ReceiverKind::Backend(_) => {
+            let rxs: Vec<_> = rxs
+                .iter()
+                .map(|rx| match &rx.0 {
+                    ReceiverKind::Backend(rx) => rx,
+                    ReceiverKind::Custom(_) => unreachable!(),
+                })
+                .collect();
+            Backend::select(&rxs)
+        }
+        ReceiverKind::Custom(first) => {
+            let rxs: Vec<_> = rxs
+                .iter()
+                .map(|rx| match &rx.0 {
+                    ReceiverKind::Custom(rx) => &**rx,
+                    ReceiverKind::Backend(_) => unreachable!(),
+                })
+                .collect();
+            first.select(&rxs)
+        }
+    }
+}
+
+impl<T> Sender<T> {
+    pub(crate) fn send(&self, v: T) -> Result<(), SendError<T>> {
+        match self.0.as_ref().unwrap() {
+            SenderKind::Backend(tx) => ChannelSender::send(tx, v)?,
+            SenderKind::Custom(tx) => tx.send(v)?,
+        }
+        self.1.wake();
+        Ok(())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // This may be the last sender, a receiver waiting for a value
+        // must get to see the disconnect.
+        self.0.take();
+        self.1.wake();
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        let tx = match self.0.as_ref().unwrap() {
+            SenderKind::Backend(tx) => SenderKind::Backend(tx.clone()),
+            SenderKind::Custom(tx) => SenderKind::Custom(tx.clone_box()),
+        };
+        Sender(Some(tx), self.1.clone())
+    }
+}
+
+impl<T> Receiver<T> {
+    pub(crate) fn recv(&self) -> Result<T, RecvError> {
+        match &self.0 {
+            ReceiverKind::Backend(rx) => ChannelReceiver::recv(rx),
+            ReceiverKind::Custom(rx) => rx.recv(),
+        }
+    }
+
+    pub(crate) fn try_recv(&self) -> Result<T, TryRecvError> {
+        match &self.0 {
+            ReceiverKind::Backend(rx) => ChannelReceiver::try_recv(rx),
+            ReceiverKind::Custom(rx) => rx.try_recv(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match &self.0 {
+            ReceiverKind::Backend(rx) => ChannelReceiver::len(rx),
+            ReceiverKind::Custom(rx) => rx.len(),
+        }
+    }
+
+    /// Like try_recv, but if the channel is empty waker is woken by the
+    /// next send, for receiving from async code without blocking.
+    pub(crate) fn try_recv_or_register(&self, waker: &Waker) -> Result<T, TryRecvError> {
+        match self.try_recv() {
+            Err(TryRecvError::Empty) => (),
+            result => return result,
+        }
+        *self.1.waker.lock().unwrap() = Some(waker.clone());
+        // A swap rather than a store, loom only orders a plain store
+        // after a racing Wake::wake swap if they are otherwise ordered.
+        self.1.registered.swap(true, Ordering::SeqCst);
+        // A value sent before the waker was registered would not wake
+        // it, so check again.
+        self.try_recv()
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        let rx = match &self.0 {
+            ReceiverKind::Backend(rx) => ReceiverKind::Backend(rx.clone()),
+            ReceiverKind::Custom(rx) => ReceiverKind::Custom(rx.clone_box()),
+        };
+        Receiver(rx, self.1.clone())
+    }
+}
+
+// Flume takes precedence, but both are tested when enabled.
+#[cfg(all(
+    feature = "crossbeam-channel",
+    any(test, not(any(feature = "flume", loom, shuttle, miri)))
+))]
+mod crossbeam_chan {
+    use {
+        super::*,
+        crossbeam_channel::{self as cb, Select},
+    };
+
+    pub(crate) struct Crossbeam;
+
+    impl Channel for Crossbeam {
+        type Sender<T: Send> = cb::Sender<T>;
+        type Receiver<T: Send> = cb::Receiver<T>;
+
+        fn bounded<T: Send>(cap: usize) -> (cb::Sender<T>, cb::Receiver<T>) {
+            cb::bounded(cap)
+        }
+
+        fn unbounded<T: Send>() -> (cb::Sender<T>, cb::Receiver<T>) {
+            cb::unbounded()
+        }
+
+        fn select<T: Send>(rxs: &[&cb::Receiver<T>]) -> (usize, Result<T, RecvError>) {
+            let mut sel = Select::new();
+            for rx in rxs {
+                sel.recv(rx);
+            }
+            let op = sel.select();
+            let i = op.index();
+            (i, op.recv(rxs[i]).map_err(|_| RecvError))
+        }
+    }
+
+    impl<T> ChannelSender<T> for cb::Sender<T> {
+        fn send(&self, v: T) -> Result<(), SendError<T>> {
+            cb::Sender::send(self, v).map_err(|cb::SendError(v)| SendError(v))
+        }
+    }
+
+    impl<T> ChannelReceiver<T> for cb::Receiver<T> {
+        fn recv(&self) -> Result<T, RecvError> {
+            cb::Receiver::recv(self).map_err(|_| RecvError)
+        }
+
+        fn try_recv(&self) -> Result<T, TryRecvError> {
+            cb::Receiver::try_recv(self).map_err(|err| match err {
+                cb::TryRecvError::Empty => TryRecvError::Empty,
+                cb::TryRecvError::Disconnected => TryRecvError::Disconnected,
+            })
+        }
+
+        fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+            cb::Receiver::recv_timeout(self, timeout).map_err(|err| match err {
+                cb::RecvTimeoutError::Timeout => RecvTimeoutError::Timeout,
+                cb::RecvTimeoutError::Disconnected => RecvTimeoutError::Disconnected,
+            })
+        }
+
+        fn len(&self) -> usize {
+            cb::Receiver::len(self)
+        }
+    }
+}
+
+/// Flume uses the default, polling, select, as its Selector doesn't
+/// wake senders blocked on a full bounded channel and can hang.
+#[cfg(all(feature = "flume", any(test, not(any(loom, shuttle, miri)))))]
+mod flume_chan {
+    use super::*;
+
+    pub(crate) struct Flume;
+
+    impl Channel for Flume {
+        type Sender<T: Send> = flume::Sender<T>;
+        type Receiver<T: Send> = flume::Receiver<T>;
+
+        fn bounded<T: Send>(cap: usize) -> (flume::Sender<T>, flume::Receiver<T>) {
+            flume::bounded(cap)
+        }
+
+        fn unbounded<T: Send>() -> (flume::Sender<T>, flume::Receiver<T>) {
+            flume::unbounded()
+        }
+    }
+
+    impl<T> ChannelSender<T> for flume::Sender<T> {
+        fn send(&self, v: T) -> Result<(), SendError<T>> {
+            flume::Sender::send(self, v).map_err(|flume::SendError(v)| SendError(v))
+        }
+    }
+
+    impl<T> ChannelReceiver<T> for flume::Receiver<T> {
+        fn recv(&self) -> Result<T, RecvError> {
+            flume::Receiver::recv(self).map_err(|_| RecvError)
+        }
+
+        fn try_recv(&self) -> Result<T, TryRecvError> {
+            flume::Receiver::try_recv(self).map_err(|err| match err {
+                flume::TryRecvError::Empty => TryRecvError::Empty,
+                flume::TryRecvError::Disconnected => TryRecvError::Disconnected,
+            })
+        }
+
+        fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+            flume::Receiver::recv_timeout(self, timeout).map_err(|err| match err {
+                flume::RecvTimeoutError::Timeout => RecvTimeoutError::Timeout,
+                flume::RecvTimeoutError::Disconnected => RecvTimeoutError::Disconnected,
+            })
+        }
+
+        fn len(&self) -> usize {
+            flume::Receiver::len(self)
+        }
+    }
+}
+
+#[cfg(all(
+    not(any(loom, shuttle)),
+    any(miri, not(any(feature = "crossbeam-channel", feature = "flume")))
+))]
+mod std_chan {
+    use {
+        super::*,
+        std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc, Arc, Mutex, TryLockError,
+        },
+    };
+
+    pub(crate) struct StdMpsc;
+
+    impl Channel for StdMpsc {
+        type Sender<T: Send> = Sender<T>;
+        type Receiver<T: Send> = Receiver<T>;
+
+        fn bounded<T: Send>(cap: usize) -> (Sender<T>, Receiver<T>) {
+            let (tx, rx) = mpsc::sync_channel(cap);
+            channel(Inner::Bounded(tx), rx)
+        }
+
+        fn unbounded<T: Send>() -> (Sender<T>, Receiver<T>) {
+            let (tx, rx) = mpsc::channel();
+            channel(Inner::Unbounded(tx), rx)
+        }
+    }
+
+    enum Inner<T> {
+        Bounded(mpsc::SyncSender<T>),
+        Unbounded(mpsc::Sender<T>),
+    }
+
+    /// Sender is an mpsc sender that counts the values in the channel.
+    pub(crate) struct Sender<T> {
+        inner: Inner<T>,
+        len: Arc<AtomicUsize>,
+    }
+
+    /// Receiver is an mpsc receiver behind a mutex, so that workers can
+    /// share it. Whoever holds the lock takes the next value.
+    pub(crate) struct Receiver<T> {
+        inner: Arc<Mutex<mpsc::Receiver<T>>>,
+        len: Arc<AtomicUsize>,
+    }
+
+    fn channel<T>(tx: Inner<T>, rx: mpsc::Receiver<T>) -> (Sender<T>, Receiver<T>) {
+        let len = Arc::new(AtomicUsize::new(0));
+        (
+            Sender {
+                inner: tx,
+                len: len.clone(),
+            },
+            Receiver {
+                inner: Arc::new(Mutex::new(rx)),
+                len,
+            },
+        )
+    }
+
+    impl<T> ChannelSender<T> for Sender<T> {
+        fn send(&self, v: T) -> Result<(), SendError<T>> {
+            // Counted first so a receiver never sees more values than
+            // have been counted.
+            self.len.fetch_add(1, Ordering::SeqCst);
+            let result = match &self.inner {
+                Inner::Bounded(tx) => tx.send(v),
+                Inner::Unbounded(tx) => tx.send(v),
+            };
+            if result.is_err() {
+                self.len.fetch_sub(1, Ordering::SeqCst);
+            }
+            result
+        }
+    }
+
+    impl<T> Clone for Sender<T> {
+        fn clone(&self) -> Self {
+            Sender {
+                inner: match &self.inner {
+                    Inner::Bounded(tx) => Inner::Bounded(tx.clone()),
+                    Inner::Unbounded(tx) => Inner::Unbounded(tx.clone()),
+                },
+                len: self.len.clone(),
+            }
+        }
+    }
+
+    impl<T> Receiver<T> {
+        fn took<E>(&self, result: Result<T, E>) -> Result<T, E> {
+            if result.is_ok() {
+                self.len.fetch_sub(1, Ordering::SeqCst);
+            }
+            result
+        }
+    }
+
+    impl<T> ChannelReceiver<T> for Receiver<T> {
+        fn recv(&self) -> Result<T, RecvError> {
+            let rx = self.inner.lock().unwrap();
+            self.took(rx.recv())
+        }
+
+        /// Like recv, but gives up if another receiver holds the lock,
+        /// as it is already waiting for the next value.
+        fn try_recv(&self) -> Result<T, TryRecvError> {
+            let rx = match self.inner.try_lock() {
+                Ok(rx) => rx,
+                Err(TryLockError::WouldBlock) => return Err(TryRecvError::Empty),
+                Err(TryLockError::Poisoned(err)) => panic!("{}", err),
+            };
+            self.took(rx.try_recv())
+        }
+
+        fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+            let rx = self.inner.lock().unwrap();
+            self.took(rx.recv_timeout(timeout))
+        }
+
+        fn len(&self) -> usize {
+            self.len.load(Ordering::SeqCst)
+        }
+    }
+
+    impl<T> Clone for Receiver<T> {
+        fn clone(&self) -> Self {
+            Receiver {
+                inner: self.inner.clone(),
+                len: self.len.clone(),
+            }
+        }
+    }
+}
+
+/// Model is a mutex and condvar channel built from loom's or shuttle's
+/// primitives, which they can model check, unlike the other backends.
+/// Time is not modelled, a receive that times out yields to other
+/// threads first.
+#[cfg(any(loom, shuttle))]
+mod model_chan {
+    use {
+        super::*,
+        crate::sync::{thread, Condvar},
+        std::collections::VecDeque,
+    };
+
+    pub(crate) struct Model;
+
+    impl Channel for Model {
+        type Sender<T: Send> = Sender<T>;
+        type Receiver<T: Send> = Receiver<T>;
+
+        fn bounded<T: Send>(cap: usize) -> (Sender<T>, Receiver<T>) {
+            channel(Some(cap))
+        }
+
+        fn unbounded<T: Send>() -> (Sender<T>, Receiver<T>) {
+            channel(None)
+        }
+    }
+
+    struct State<T> {
+        queue: VecDeque<T>,
+        cap: Option<usize>,
+        senders: usize,
+        receivers: usize,
+        /// How many values have ever been sent and received, a sender
+        /// on a zero capacity channel waits for its value to be
+        /// received.
+        sent: usize,
+        received: usize,
+    }
+
+    struct Shared<T> {
+        state: Mutex<State<T>>,
+        changed: Condvar,
+    }
+
+    pub(crate) struct Sender<T>(Arc<Shared<T>>);
+
+    pub(crate) struct Receiver<T>(Arc<Shared<T>>);
+
+    fn channel<T>(cap: Option<usize>) -> (Sender<T>, Receiver<T>) {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                cap,
+                senders: 1,
+                receivers: 1,
+                sent: 0,
+                received: 0,
+            }),
+            changed: Condvar::new(),
+        });
+        (Sender(shared.clone()), Receiver(shared))
+    }
+
+    impl<T> ChannelSender<T> for Sender<T> {
+        fn send(&self, v: T) -> Result<(), SendError<T>> {
+            let mut state = self.0.state.lock().unwrap();
+            loop {
+                if state.receivers == 0 {
+                    return Err(SendError(v));
+                }
+                match state.cap {
+                    Some(cap) if state.queue.len() >= cap.max(1) => {
+                        state = self.0.changed.wait(state).unwrap();
+                    }
+                    _ => break,
+                }
+            }
+            state.queue.push_back(v);
+            state.sent += 1;
+            let seq = state.sent;
+            self.0.changed.notify_all();
+            if state.cap == Some(0) {
+                while state.received < seq && state.receivers > 0 {
+                    state = self.0.changed.wait(state).unwrap();
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl<T> Clone for Sender<T> {
+        fn clone(&self) -> Self {
+            self.0.state.lock().unwrap().senders += 1;
+            Sender(self.0.clone())
+        }
+    }
+
+    impl<T> Drop for Sender<T> {
+        fn drop(&mut self) {
+            self.0.state.lock().unwrap().senders -= 1;
+            self.0.changed.notify_all();
+        }
+    }
+
+    impl<T> ChannelReceiver<T> for Receiver<T> {
+        fn recv(&self) -> Result<T, RecvError> {
+            let mut state = self.0.state.lock().unwrap();
+            loop {
+                if let Some(v) = state.queue.pop_front() {
+                    state.received += 1;
+                    self.0.changed.notify_all();
+                    return Ok(v);
+                }
+                if state.senders == 0 {
+                    return Err(RecvError);
+                }
+                state = self.0.changed.wait(state).unwrap();
+            }
+        }
+
+        fn try_recv(&self) -> Result<T, TryRecvError> {
+            let mut state = self.0.state.lock().unwrap();
+            match state.queue.pop_front() {
+                Some(v) => {
+                    state.received += 1;
+                    self.0.changed.notify_all();
+                    Ok(v)
+                }
+                None if state.senders == 0 => Err(TryRecvError::Disconnected),
+                None => Err(TryRecvError::Empty),
+            }
+        }
+
+        fn recv_timeout(&self, _timeout: Duration) -> Result<T, RecvTimeoutError> {
+            match self.try_recv() {
+                Err(TryRecvError::Empty) => thread::yield_now(),
+                result => return result.map_err(|_| RecvTimeoutError::Disconnected),
+            }
+            self.try_recv().map_err(|err| match err {
+                TryRecvError::Empty => RecvTimeoutError::Timeout,
+                TryRecvError::Disconnected => RecvTimeoutError::Disconnected,
+            })
+        }
+
+        fn len(&self) -> usize {
+            self.0.state.lock().unwrap().queue.len()
+        }
+    }
+
+    impl<T> Clone for Receiver<T> {
+        fn clone(&self) -> Self {
+            self.0.state.lock().unwrap().receivers += 1;
+            Receiver(self.0.clone())
+        }
+    }
+
+    impl<T> Drop for Receiver<T> {
+        fn drop(&mut self) {
+            self.0.state.lock().unwrap().receivers -= 1;
+            self.0.changed.notify_all();
+        }
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use {
+        super::*,
+        crate::sync::thread,
+        std::{future, task::Poll},
+    };
+
+    #[test]
+    fn loom_bounded_send_recv() {
+        loom::model(|| {
+            let (tx, rx) = bounded(1);
+            thread::spawn(move || {
+                tx.send(1).unwrap();
+                tx.send(2).unwrap();
+            });
+            assert_eq!(rx.recv(), Ok(1));
+            assert_eq!(rx.recv(), Ok(2));
+            assert_eq!(rx.recv(), Err(RecvError));
+        });
+    }
+
+    #[test]
+    fn loom_rendezvous_disconnect() {
+        loom::model(|| {
+            let (tx, rx) = bounded::<i32>(0);
+            let t = thread::spawn(move || tx.send(1));
+            drop(rx);
+            // The send either completed first or sees the receiver gone.
+            let _ = t.join().unwrap();
+        });
+    }
+
+    /// A waker registered by try_recv_or_register is never missed,
+    /// whether a send or the sender's drop races with it.
+    #[test]
+    fn loom_register_wake() {
+        loom::model(|| {
+            let (tx, rx) = unbounded();
+            thread::spawn(move || {
+                tx.send(1).unwrap();
+            });
+            let mut got = Vec::new();
+            loom::future::block_on(future::poll_fn(|cx| loop {
+                match rx.try_recv_or_register(cx.waker()) {
+                    Ok(v) => got.push(v),
+                    Err(TryRecvError::Empty) => return Poll::Pending,
+                    Err(TryRecvError::Disconnected) => return Poll::Ready(()),
+                }
+            }));
+            assert_eq!(got, [1]);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Exercise the backend channels directly.
+    fn check_channel<C: Channel>() {
+        let (tx, rx) = C::bounded(2);
+        let rx2 = rx.clone();
+        tx.send(1).unwrap();
+        tx.send(2).unwrap();
+        assert_eq!(rx.len(), 2);
+        assert_eq!(rx2.recv(), Ok(1));
+        assert_eq!(rx.try_recv(), Ok(2));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(1)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        drop(tx);
+        assert_eq!(rx2.recv(), Err(RecvError));
+
+        let (tx_a, a) = C::unbounded();
+        let (tx_b, b) = C::unbounded();
+        tx_b.send('b').unwrap();
+        assert_eq!(C::select(&[&a, &b]), (1, Ok('b')));
+        tx_a.send('a').unwrap();
+        assert_eq!(C::select(&[&a, &b]), (0, Ok('a')));
+        drop(tx_b);
+        assert_eq!(C::select(&[&a, &b]), (1, Err(RecvError)));
+        drop(a);
+        assert_eq!(tx_a.send('a'), Err(SendError('a')));
+    }
+
+    #[test]
+    fn test_custom_channel() {
+        use crate::{PipelineBuilder, Schedule};
+
+        for schedule in [Schedule::Shared, Schedule::RoundRobin, Schedule::Stealing] {
+            for w in 0..3 {
+                let v: Vec<u64> = PipelineBuilder::new(w)
+                    .schedule(schedule)
+                    .build_with_channel::<Backend, _, _>(0..100, |x: u64| x * 2)
+                    .collect();
+                assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+            }
+        }
+
+        let (tx, rx) = Custom::<Backend>::bounded(1);
+        let (tx2, rx2) = Custom::<Backend>::bounded(1);
+        tx2.send(2).unwrap();
+        assert_eq!(select(&[&rx, &rx2]), (1, Ok(2)));
+        drop(tx);
+        assert_eq!(select(&[&rx, &rx2]), (0, Err(RecvError)));
+    }
+
+    #[test]
+    fn test_chan() {
+        check_channel::<Backend>();
+        #[cfg(feature = "crossbeam-channel")]
+        check_channel::<crossbeam_chan::Crossbeam>();
+        #[cfg(feature = "flume")]
+        check_channel::<flume_chan::Flume>();
+    }
+}
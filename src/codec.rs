@@ -0,0 +1,164 @@
+use {
+    super::mapper::Mapper,
+    bincode::Options,
+    std::{
+        convert::TryFrom,
+        io::{self, Read, Write},
+    },
+};
+
+/// The largest frame read unless a limit is given, see
+/// TcpServer::max_frame_len.
+pub(crate) const MAX_FRAME_LEN: usize = 64 << 20;
+
+/// Codec converts values to and from bytes, for sending them to
+/// workers in other processes or on other machines.
+pub trait Codec<T> {
+    fn encode(&self, v: &T) -> io::Result<Vec<u8>>;
+    fn decode(&self, buf: &[u8]) -> io::Result<T>;
+}
+
+/// Bincode encodes values with serde and bincode.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bincode;
+
+impl<T> Codec<T> for Bincode
+where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+{
+    fn encode(&self, v: &T) -> io::Result<Vec<u8>> {
+        bincode_options()
+            .serialize(v)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    fn decode(&self, buf: &[u8]) -> io::Result<T> {
+        // A value can't take more bytes than buf holds, the limit stops
+        // a bad length inside it from allocating more.
+        bincode_options()
+            .with_limit(buf.len() as u64)
+            .deserialize(buf)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+/// The options bincode::serialize uses, so the encoding is the same.
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+}
+
+/// Write buf as a frame, prefixed with its length as a little endian
+/// u32.
+pub(crate) fn write_frame(w: &mut impl Write, buf: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(buf.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+    w.write_all(&len.to_le_bytes())?;
+    w.write_all(buf)?;
+    w.flush()
+}
+
+/// Read a frame written by write_frame, None means the stream ended
+/// cleanly between frames. A frame longer than max_len is an error,
+/// before anything is allocated for it.
+pub(crate) fn read_frame(r: &mut impl Read, max_len: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    let mut filled = 0;
+    while filled < len.len() {
+        match r.read(&mut len[filled..])? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => filled += n,
+        }
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if len > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes is over the limit of {}", len, max_len),
+        ));
+    }
+    let mut buf = vec![0; len];
+    r.read_exact(&mut buf)?;
+    Ok(Some(buf))
+}
+
+/// Send v as a frame and read back the result.
+pub(crate) fn call<In, Out, C>(
+    w: &mut impl Write,
+    r: &mut impl Read,
+    codec: &C,
+    v: &In,
+) -> io::Result<Out>
+where
+    C: Codec<In> + Codec<Out>,
+{
+    write_frame(w, &codec.encode(v)?)?;
+    match read_frame(r, MAX_FRAME_LEN)? {
+        Some(buf) => codec.decode(&buf),
+        None => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "worker hung up",
+        )),
+    }
+}
+
+/// Map values read as frames from r, writing each result as a frame
+/// to w, until r ends.
+pub(crate) fn serve_frames<In, M, C>(
+    r: &mut impl Read,
+    w: &mut impl Write,
+    codec: &C,
+    mapper: &mut M,
+    max_frame_len: usize,
+) -> io::Result<()>
+where
+    M: Mapper<In>,
+    C: Codec<In> + Codec<M::Out>,
+{
+    while let Some(buf) = read_frame(r, max_frame_len)? {
+        let v: In = codec.decode(&buf)?;
+        let out = mapper.apply(v);
+        write_frame(w, &codec.encode(&out)?)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::io::Cursor};
+
+    #[test]
+    fn test_frames() {
+        let mut w = Vec::new();
+        for v in &[vec![1u64, 2, 3], vec![], vec![u64::MAX]] {
+            write_frame(&mut w, &Bincode.encode(v).unwrap()).unwrap();
+        }
+        let mut r = Cursor::new(&w);
+        let mut decoded: Vec<Vec<u64>> = Vec::new();
+        while let Some(buf) = read_frame(&mut r, MAX_FRAME_LEN).unwrap() {
+            decoded.push(Bincode.decode(&buf).unwrap());
+        }
+        assert_eq!(decoded, vec![vec![1, 2, 3], vec![], vec![u64::MAX]]);
+
+        let mut truncated = Cursor::new(&w[..w.len() - 1]);
+        read_frame(&mut truncated, MAX_FRAME_LEN).unwrap();
+        read_frame(&mut truncated, MAX_FRAME_LEN).unwrap();
+        assert!(read_frame(&mut truncated, MAX_FRAME_LEN).is_err());
+
+        // An oversized length is refused without reading on.
+        let mut r = Cursor::new(u32::MAX.to_le_bytes());
+        let err = read_frame(&mut r, 1024).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let mut w = Vec::new();
+        write_frame(&mut w, &[0; 16]).unwrap();
+        assert!(read_frame(&mut Cursor::new(&w), 15).is_err());
+        assert!(read_frame(&mut Cursor::new(&w), 16).unwrap().is_some());
+
+        // A length inside a value can't claim more than the frame.
+        let mut buf = Bincode.encode(&vec![1u64, 2, 3]).unwrap();
+        buf[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(Codec::<Vec<u64>>::decode(&Bincode, &buf).is_err());
+    }
+}
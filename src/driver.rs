@@ -0,0 +1,277 @@
+#[cfg(feature = "crossbeam-channel")]
+use super::receiver::CancelToken;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+};
+
+/// DriverHandle controls the driver thread of a pipeline that was
+/// turned into a channel, see Pipeline::into_receiver and
+/// Pipeline::into_std_receiver.
+///
+/// Dropping the handle detaches the driver, which keeps running until
+/// the pipeline is exhausted or the receiver is dropped.
+pub struct DriverHandle {
+    abort: Arc<Abort>,
+    handle: thread::JoinHandle<()>,
+}
+
+/// Abort is how a DriverHandle tells its driver to stop, passed to
+/// DriverSender::send so that a sender can give up waiting for room.
+pub struct Abort {
+    flag: AtomicBool,
+    /// Wakes a driver waiting to send on a crossbeam channel.
+    #[cfg(feature = "crossbeam-channel")]
+    token: CancelToken,
+}
+
+/// DriverSender is the sending half of a channel a driver can send
+/// results to, see Pipeline::drive_into. It is implemented for
+/// crossbeam_channel::Sender, the std::sync::mpsc senders and, with the
+/// tokio feature, the tokio::sync::mpsc senders.
+///
+/// # Examples
+///
+/// ```
+/// use {
+///     plmap::{Abort, DriverSender, PipelineMap},
+///     std::sync::{Arc, Mutex},
+/// };
+///
+/// /// Collects results into a shared vector.
+/// struct Collect(Arc<Mutex<Vec<i32>>>);
+///
+/// impl DriverSender<i32> for Collect {
+///     fn send(&self, v: i32, abort: &Abort) -> bool {
+///         if abort.is_aborted() {
+///             return false;
+///         }
+///         self.0.lock().unwrap().push(v);
+///         true
+///     }
+/// }
+///
+/// let results = Arc::new(Mutex::new(Vec::new()));
+/// let handle = (0..10).plmap(2, |x| x * 2).drive_into(Collect(results.clone()));
+/// handle.join().unwrap();
+/// assert_eq!(results.lock().unwrap().len(), 10);
+/// ```
+pub trait DriverSender<T>: Send + 'static {
+    /// Send v, returning false if the driver should stop, because the
+    /// receiver is gone or the driver was aborted.
+    fn send(&self, v: T, abort: &Abort) -> bool;
+}
+
+impl Abort {
+    /// Whether the driver has been told to stop, see
+    /// DriverHandle::abort.
+    pub fn is_aborted(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+}
+
+impl fmt::Debug for Abort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Abort")
+            .field("aborted", &self.is_aborted())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "crossbeam-channel")]
+impl<T: Send + 'static> DriverSender<T> for crossbeam_channel::Sender<T> {
+    fn send(&self, v: T, abort: &Abort) -> bool {
+        crossbeam_channel::select! {
+            send(self, v) -> result => result.is_ok(),
+            recv(abort.token.channel()) -> _ => false,
+        }
+    }
+}
+
+impl<T: Send + 'static> DriverSender<T> for mpsc::Sender<T> {
+    fn send(&self, v: T, abort: &Abort) -> bool {
+        !abort.is_aborted() && mpsc::Sender::send(self, v).is_ok()
+    }
+}
+
+impl<T: Send + 'static> DriverSender<T> for mpsc::SyncSender<T> {
+    fn send(&self, v: T, abort: &Abort) -> bool {
+        !abort.is_aborted() && mpsc::SyncSender::send(self, v).is_ok()
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<T: Send + 'static> DriverSender<T> for tokio::sync::mpsc::Sender<T> {
+    fn send(&self, v: T, abort: &Abort) -> bool {
+        !abort.is_aborted() && self.blocking_send(v).is_ok()
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<T: Send + 'static> DriverSender<T> for tokio::sync::mpsc::UnboundedSender<T> {
+    fn send(&self, v: T, abort: &Abort) -> bool {
+        !abort.is_aborted() && tokio::sync::mpsc::UnboundedSender::send(self, v).is_ok()
+    }
+}
+
+impl DriverHandle {
+    /// Start a thread sending the values of iter to tx.
+    pub(crate) fn spawn<I, S>(iter: I, tx: S) -> DriverHandle
+    where
+        I: Iterator + Send + 'static,
+        I::Item: Send + 'static,
+        S: DriverSender<I::Item>,
+    {
+        let abort = Arc::new(Abort {
+            flag: AtomicBool::new(false),
+            #[cfg(feature = "crossbeam-channel")]
+            token: CancelToken::new(),
+        });
+        let a = abort.clone();
+        let handle = thread::spawn(move || {
+            for v in iter {
+                if a.is_aborted() || !tx.send(v, &a) {
+                    break;
+                }
+            }
+        });
+        DriverHandle { abort, handle }
+    }
+
+    /// Stop the driver, dropping the pipeline. A driver sending to a
+    /// full crossbeam channel stops straight away, otherwise it stops
+    /// once the next result is ready or has been sent. Results already
+    /// sent can still be received.
+    pub fn abort(&self) {
+        self.abort.flag.store(true, Ordering::SeqCst);
+        #[cfg(feature = "crossbeam-channel")]
+        self.abort.token.cancel();
+    }
+
+    /// Whether the driver has stopped.
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Wait for the driver to stop. An error holds the panic of a
+    /// mapper, as with thread::JoinHandle::join.
+    pub fn join(self) -> thread::Result<()> {
+        self.handle.join()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::PipelineMap};
+
+    #[cfg(feature = "crossbeam-channel")]
+    #[test]
+    fn test_into_receiver() {
+        use std::{sync::atomic::AtomicUsize, time::Duration};
+
+        for w in 0..3 {
+            let (rx, handle) = (0..100).plmap(w, |x| x * 2).into_receiver(4);
+            let v: Vec<i32> = thread::spawn(move || rx.iter().collect()).join().unwrap();
+            assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+            assert!(handle.join().is_ok());
+        }
+
+        // A driver blocked on a full channel stops when aborted.
+        let mapped = Arc::new(AtomicUsize::new(0));
+        let m = mapped.clone();
+        let (rx, handle) = (0..)
+            .plmap(1, move |x: u64| {
+                m.fetch_add(1, Ordering::SeqCst);
+                x
+            })
+            .into_receiver(2);
+        assert_eq!(rx.recv(), Ok(0));
+        thread::sleep(Duration::from_millis(20));
+        handle.abort();
+        assert!(handle.join().is_ok());
+        assert!(mapped.load(Ordering::SeqCst) < 10);
+        assert_eq!(rx.iter().count(), 2);
+
+        // Mapper panics come back from join.
+        let (rx, handle) = (0..10)
+            .plmap(2, |x| {
+                if x == 5 {
+                    panic!("boom");
+                }
+                x
+            })
+            .into_receiver(1);
+        assert_eq!(rx.iter().count(), 5);
+        assert!(handle.join().is_err());
+    }
+
+    #[test]
+    fn test_into_std_receiver() {
+        for w in 0..3 {
+            let (rx, handle) = (0..100).plmap(w, |x| x * 2).into_std_receiver();
+            let v: Vec<i32> = thread::spawn(move || rx.iter().collect()).join().unwrap();
+            assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+            assert!(handle.join().is_ok());
+
+            let (rx, handle) = (0..100).plmap(w, |x| x * 2).into_std_sync_receiver(4);
+            assert_eq!(rx.iter().sum::<i32>(), 9900);
+            assert!(handle.join().is_ok());
+        }
+
+        // Aborting stops the driver once its blocked send completes.
+        let (rx, handle) = (0..).plmap(1, |x: u64| x).into_std_sync_receiver(2);
+        assert_eq!(rx.recv(), Ok(0));
+        handle.abort();
+        let rest = rx.iter().count();
+        assert!(rest <= 3, "{} results after abort", rest);
+        assert!(handle.join().is_ok());
+    }
+
+    #[test]
+    fn test_drive_into() {
+        use std::{
+            sync::atomic::AtomicUsize,
+            time::{Duration, Instant},
+        };
+
+        // Results from several pipelines arrive in order per pipeline.
+        let (tx, rx) = mpsc::sync_channel(2);
+        let a = (0..50).plmap(2, |x| x).drive_into(tx.clone());
+        let b = (50..100).plmap(2, |x| x).drive_into(tx);
+        let mut v: Vec<i32> = rx.iter().collect();
+        let (lo, hi): (Vec<i32>, Vec<i32>) = v.iter().partition(|&&x| x < 50);
+        assert_eq!(lo, (0..50).collect::<Vec<_>>());
+        assert_eq!(hi, (50..100).collect::<Vec<_>>());
+        v.sort_unstable();
+        assert_eq!(v, (0..100).collect::<Vec<_>>());
+        assert!(a.join().is_ok());
+        assert!(b.join().is_ok());
+
+        // A full channel stops dispatch.
+        let mapped = Arc::new(AtomicUsize::new(0));
+        let m = mapped.clone();
+        let (tx, rx) = mpsc::sync_channel(2);
+        let handle = (0..)
+            .plmap(2, move |x: u64| {
+                m.fetch_add(1, Ordering::SeqCst);
+                x
+            })
+            .drive_into(tx);
+        let start = Instant::now();
+        while rx.try_recv().is_err() && start.elapsed() < Duration::from_secs(5) {
+            thread::yield_now();
+        }
+        thread::sleep(Duration::from_millis(50));
+        let n = mapped.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(mapped.load(Ordering::SeqCst), n);
+        assert!(n < 20, "{} mapped", n);
+        handle.abort();
+        drop(rx);
+        assert!(handle.join().is_ok());
+    }
+}
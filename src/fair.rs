@@ -0,0 +1,198 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, VecDeque},
+    sync::{Condvar, Mutex},
+};
+
+/// Priority decides which pipelines sharing a pool go first.
+///
+/// Whenever a pool thread is free it runs a value from the highest
+/// priority pipeline with values waiting, lower priority pipelines only
+/// get the threads nothing more urgent needs. Values that have started
+/// are never interrupted. Pipelines of the same priority share the pool
+/// by weight.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Bulk work that can wait, such as background indexing.
+    Low,
+    /// The default.
+    #[default]
+    Normal,
+    /// Latency sensitive work, such as anything a user is waiting on.
+    High,
+}
+
+/// Share is a client's claim on the pool.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Share {
+    pub(crate) weight: u32,
+    pub(crate) priority: Priority,
+}
+
+/// The stride of a client with weight one, strides of heavier clients
+/// are proportionally shorter.
+const STRIDE: u64 = 1 << 20;
+
+/// FairQueue holds the jobs queued on a pool, choosing whose job runs
+/// next by priority, then by stride scheduling.
+///
+/// Within a priority, each client, usually a pipeline, has a pass which advances by its
+/// stride every time one of its jobs is taken, and the client with the
+/// lowest pass goes next. Clients with jobs waiting therefore get
+/// turns in proportion to their weight. A client that goes idle can't
+/// bank turns, its pass is brought up to the current virtual time when
+/// it queues work again.
+pub(crate) struct FairQueue<T> {
+    state: Mutex<State<T>>,
+    ready: Condvar,
+}
+
+struct State<T> {
+    clients: HashMap<usize, Client<T>>,
+    /// The virtual time of each priority.
+    vtime: [u64; 3],
+    len: usize,
+    closed: bool,
+}
+
+struct Client<T> {
+    jobs: VecDeque<T>,
+    pass: u64,
+    stride: u64,
+    priority: Priority,
+}
+
+impl<T> FairQueue<T> {
+    pub(crate) fn new() -> FairQueue<T> {
+        FairQueue {
+            state: Mutex::new(State {
+                clients: HashMap::new(),
+                vtime: [0; 3],
+                len: 0,
+                closed: false,
+            }),
+            ready: Condvar::new(),
+        }
+    }
+
+    /// Queue a job for client, the weight must be greater than zero.
+    pub(crate) fn push(&self, client: usize, share: Share, job: T) {
+        let mut state = self.state.lock().unwrap();
+        let vtime = state.vtime[share.priority as usize];
+        let client = state.clients.entry(client).or_insert_with(|| Client {
+            jobs: VecDeque::new(),
+            pass: vtime,
+            stride: 0,
+            priority: share.priority,
+        });
+        if client.jobs.is_empty() {
+            client.pass = client.pass.max(vtime);
+        }
+        client.stride = STRIDE / u64::from(share.weight);
+        client.jobs.push_back(job);
+        state.len += 1;
+        drop(state);
+        self.ready.notify_one();
+    }
+
+    /// Take the next job without waiting.
+    #[cfg(any(test, feature = "rayon"))]
+    pub(crate) fn try_pop(&self) -> Option<T> {
+        self.state.lock().unwrap().pop()
+    }
+
+    /// Take the next job, waiting for one to be queued. Returns None
+    /// once the queue is closed and empty.
+    pub(crate) fn pop(&self) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(job) = state.pop() {
+                return Some(job);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.ready.wait(state).unwrap();
+        }
+    }
+
+    /// Wake everything waiting in pop, which returns None once the
+    /// remaining jobs have been taken.
+    pub(crate) fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.state.lock().unwrap().len
+    }
+}
+
+impl<T> State<T> {
+    fn pop(&mut self) -> Option<T> {
+        let (&id, _) = self
+            .clients
+            .iter()
+            .filter(|(_, client)| !client.jobs.is_empty())
+            .min_by_key(|(_, client)| (Reverse(client.priority), client.pass))?;
+        let client = self.clients.get_mut(&id).unwrap();
+        let job = client.jobs.pop_front().unwrap();
+        self.vtime[client.priority as usize] = client.pass;
+        client.pass += client.stride;
+        self.len -= 1;
+        // Idle clients that are not ahead of virtual time would be
+        // reset to it anyway, so there is no need to remember them.
+        let vtime = self.vtime;
+        self.clients.retain(|_, client| {
+            !client.jobs.is_empty() || client.pass > vtime[client.priority as usize]
+        });
+        Some(job)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fair_queue() {
+        let share = |weight| Share {
+            weight,
+            priority: Priority::Normal,
+        };
+        let q = FairQueue::new();
+        for i in 0..40 {
+            q.push(1, share(3), ('a', i));
+            q.push(2, share(1), ('b', i));
+        }
+        assert_eq!(q.len(), 80);
+        let first: Vec<char> = (0..40).map(|_| q.try_pop().unwrap().0).collect();
+        let a = first.iter().filter(|&&c| c == 'a').count();
+        assert!((29..=31).contains(&a), "a ran {} times", a);
+
+        // Each client's jobs stay in order.
+        let mut last = HashMap::new();
+        while let Some((c, i)) = q.try_pop() {
+            assert!(last.insert(c, i).is_none_or(|prev| prev < i));
+        }
+        q.close();
+        assert_eq!(q.pop(), None);
+    }
+
+    #[test]
+    fn test_fair_queue_priority() {
+        let q = FairQueue::new();
+        let share = |priority| Share {
+            weight: 1,
+            priority,
+        };
+        for i in 0..3 {
+            q.push(1, share(Priority::Low), ('l', i));
+            q.push(2, share(Priority::Normal), ('n', i));
+        }
+        assert_eq!(q.try_pop(), Some(('n', 0)));
+        q.push(3, share(Priority::High), ('h', 0));
+        let order: Vec<char> = std::iter::from_fn(|| q.try_pop()).map(|(c, _)| c).collect();
+        assert_eq!(order, vec!['h', 'n', 'n', 'l', 'l', 'l']);
+    }
+}
@@ -0,0 +1,207 @@
+//! Helpers for mapping the contents of readers in parallel.
+
+use {
+    super::{mapper::Mapper, pipeline::Pipeline, workers::Workers},
+    std::io::{self, BufRead, Read, Write},
+};
+
+/// MapOk is a Mapper applying a mapper to what was read by lines_plmap
+/// or transform_blocks, passing read errors through untouched.
+#[derive(Clone, Copy, Debug)]
+pub struct MapOk<M>(M);
+
+impl<T, M> Mapper<io::Result<T>> for MapOk<M>
+where
+    M: Mapper<T>,
+{
+    type Out = io::Result<M::Out>;
+
+    fn apply(&mut self, v: io::Result<T>) -> Self::Out {
+        v.map(|v| self.0.apply(v))
+    }
+}
+
+/// Blocks reads a reader block_size bytes at a time, the last block
+/// may be shorter.
+struct Blocks<R> {
+    reader: R,
+    block_size: usize,
+    done: bool,
+}
+
+impl<R: Read> Iterator for Blocks<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        if self.done {
+            return None;
+        }
+        let mut block = Vec::with_capacity(self.block_size);
+        match (&mut self.reader)
+            .take(self.block_size as u64)
+            .read_to_end(&mut block)
+        {
+            Ok(n) if n < self.block_size => {
+                self.done = true;
+                if n == 0 {
+                    return None;
+                }
+            }
+            Ok(_) => {}
+            Err(err) => {
+                self.done = true;
+                return Some(Err(err));
+            }
+        }
+        Some(Ok(block))
+    }
+}
+
+/// Map the lines of reader with mapper on n_workers threads, yielding
+/// the results in the order the lines appear in.
+///
+/// Lines are read on the consuming thread as the pipeline pulls its
+/// input, without their line endings, as with BufRead::lines. A read
+/// error is yielded in place of the line it stopped, reading carries
+/// on after it as BufRead::lines does.
+///
+/// # Examples
+///
+/// ```
+/// use std::io::Cursor;
+///
+/// let log = Cursor::new("GET /a 200\nGET /b 404\nPOST /c 200\n");
+/// let statuses: Vec<u16> = plmap::io::lines_plmap(log, 2, |line: String| {
+///     line.rsplit(' ').next().unwrap().parse().unwrap()
+/// })
+/// .collect::<std::io::Result<_>>()
+/// .unwrap();
+/// assert_eq!(statuses, [200, 404, 200]);
+/// ```
+pub fn lines_plmap<R, M>(
+    reader: R,
+    n_workers: impl Into<Workers>,
+    mapper: M,
+) -> Pipeline<io::Lines<R>, MapOk<M>>
+where
+    R: BufRead,
+    M: Mapper<String> + Clone + Send + 'static,
+    M::Out: Send + 'static,
+{
+    Pipeline::new(n_workers, MapOk(mapper), reader.lines())
+}
+
+/// Read reader block_size bytes at a time, map each block with mapper
+/// on n_workers threads, and write the results to writer in the order
+/// the blocks were read, returning the number of bytes written. Every
+/// block is block_size bytes except perhaps the last.
+///
+/// Only the pipeline's in-flight blocks and their results are held in
+/// memory at once, so the reader and writer can be far larger. The
+/// first read or write error stops the transform and is returned,
+/// after anything already written.
+///
+/// # Panics
+///
+/// Panics if block_size is zero.
+///
+/// # Examples
+///
+/// ```
+/// let input = vec![7u8; 10_000];
+/// let mut output = Vec::new();
+/// let n = plmap::io::transform_blocks(&input[..], &mut output, 4096, 4, |block: Vec<u8>| {
+///     block.iter().map(|b| b ^ 0xff).collect::<Vec<u8>>()
+/// })
+/// .unwrap();
+/// assert_eq!(n, 10_000);
+/// assert!(output.iter().all(|&b| b == 0xf8));
+/// ```
+pub fn transform_blocks<R, W, M>(
+    reader: R,
+    mut writer: W,
+    block_size: usize,
+    n_workers: impl Into<Workers>,
+    mapper: M,
+) -> io::Result<u64>
+where
+    R: Read,
+    W: Write,
+    M: Mapper<Vec<u8>> + Clone + Send + 'static,
+    M::Out: AsRef<[u8]> + Send + 'static,
+{
+    assert!(block_size > 0, "block_size must not be zero");
+    let blocks = Blocks {
+        reader,
+        block_size,
+        done: false,
+    };
+    let mut written = 0;
+    for out in Pipeline::new(n_workers, MapOk(mapper), blocks) {
+        let out = out?;
+        writer.write_all(out.as_ref())?;
+        written += out.as_ref().len() as u64;
+    }
+    writer.flush()?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::io::Cursor};
+
+    #[test]
+    fn test_lines_plmap() {
+        let text: String = (0..1000).map(|x| format!("{}\n", x)).collect();
+        for w in 0..3 {
+            let v: Vec<i32> = lines_plmap(Cursor::new(text.as_bytes()), w, |l: String| {
+                l.parse::<i32>().unwrap() * 2
+            })
+            .collect::<io::Result<_>>()
+            .unwrap();
+            assert_eq!(v, (0..1000).map(|x| x * 2).collect::<Vec<_>>());
+        }
+
+        // Invalid UTF-8 is a read error, later lines are still mapped.
+        let bytes: &[u8] = b"a\n\xff\nbc\r\n";
+        let v: Vec<_> = lines_plmap(bytes, 2, |l: String| l.len()).collect();
+        assert_eq!(v.len(), 3);
+        assert_eq!(v[0].as_ref().unwrap(), &1);
+        assert_eq!(
+            v[1].as_ref().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(v[2].as_ref().unwrap(), &2);
+    }
+
+    #[test]
+    fn test_transform_blocks() {
+        let input: Vec<u8> = (0..10_000).map(|x| x as u8).collect();
+        for w in 0..3 {
+            for block_size in [1, 100, 4096, 10_000, 20_000] {
+                let mut output = Vec::new();
+                let n =
+                    transform_blocks(&input[..], &mut output, block_size, w, move |b: Vec<u8>| {
+                        assert!(b.len() <= block_size);
+                        b.into_iter()
+                            .map(|x| x.wrapping_add(1))
+                            .collect::<Vec<u8>>()
+                    })
+                    .unwrap();
+                assert_eq!(n, 10_000);
+                assert_eq!(
+                    output,
+                    input.iter().map(|x| x.wrapping_add(1)).collect::<Vec<_>>()
+                );
+            }
+        }
+
+        // Outputs need not be the same size as the blocks.
+        let mut output = Vec::new();
+        let n = transform_blocks(&input[..], &mut output, 1000, 2, |b: Vec<u8>| {
+            b.len().to_string()
+        })
+        .unwrap();
+        assert_eq!((n, output), (40, b"1000".repeat(10)));
+    }
+}
@@ -0,0 +1,135 @@
+use {
+    super::pipeline::Slot,
+    std::{
+        collections::{HashMap, VecDeque},
+        hash::Hash,
+    },
+};
+
+/// KeyLimit holds back values whose key already has as many values in
+/// flight as allowed, see Pipeline::limit_per_key.
+pub(crate) trait KeyLimit<In>: Send {
+    /// Count v against its key's limit and return it to be dispatched,
+    /// or hold it back if the key is at its limit.
+    fn admit(&mut self, slot: Slot, v: In) -> Option<(Slot, In)>;
+
+    /// The result of the value dispatched to slot has arrived, return
+    /// a held value which can now be dispatched, if any.
+    fn release(&mut self, slot: Slot) -> Option<(Slot, In)>;
+
+    /// The number of values held back.
+    fn held(&self) -> usize;
+}
+
+pub(crate) struct KeyLimiter<In, K, F> {
+    limit: usize,
+    key: F,
+    in_flight: HashMap<K, usize>,
+    keys: HashMap<Slot, K>,
+    /// Held values, in the order they were pulled from the input.
+    held: VecDeque<(Slot, K, In)>,
+}
+
+impl<In, K, F> KeyLimiter<In, K, F> {
+    pub(crate) fn new(limit: usize, key: F) -> KeyLimiter<In, K, F> {
+        KeyLimiter {
+            limit,
+            key,
+            in_flight: HashMap::new(),
+            keys: HashMap::new(),
+            held: VecDeque::new(),
+        }
+    }
+}
+
+impl<In, K, F> KeyLimiter<In, K, F>
+where
+    K: Hash + Eq + Clone,
+{
+    fn start(&mut self, slot: Slot, k: K) {
+        *self.in_flight.entry(k.clone()).or_insert(0) += 1;
+        self.keys.insert(slot, k);
+    }
+}
+
+impl<In, K, F> KeyLimit<In> for KeyLimiter<In, K, F>
+where
+    In: Send,
+    K: Hash + Eq + Clone + Send,
+    F: FnMut(&In) -> K + Send,
+{
+    fn admit(&mut self, slot: Slot, v: In) -> Option<(Slot, In)> {
+        let k = (self.key)(&v);
+        if self.in_flight.get(&k).copied().unwrap_or(0) < self.limit {
+            self.start(slot, k);
+            Some((slot, v))
+        } else {
+            self.held.push_back((slot, k, v));
+            None
+        }
+    }
+
+    fn release(&mut self, slot: Slot) -> Option<(Slot, In)> {
+        let k = self.keys.remove(&slot)?;
+        match self.in_flight.get_mut(&k) {
+            Some(n) if *n > 1 => *n -= 1,
+            _ => {
+                self.in_flight.remove(&k);
+            }
+        }
+        let i = self.held.iter().position(|(_, held, _)| *held == k)?;
+        let (slot, k, v) = self.held.remove(i).unwrap();
+        self.start(slot, k);
+        Some((slot, v))
+    }
+
+    fn held(&self) -> usize {
+        self.held.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::PipelineMap,
+        std::{
+            collections::HashMap,
+            sync::{Arc, Mutex},
+            thread,
+            time::Duration,
+        },
+    };
+
+    #[test]
+    fn test_limit_per_key() {
+        for w in 0..3 {
+            let running = Arc::new(Mutex::new(HashMap::new()));
+            let peak = Arc::new(Mutex::new(HashMap::new()));
+            let (r, p) = (running.clone(), peak.clone());
+            let v: Vec<i32> = (0..40)
+                .plmap(w, move |x: i32| {
+                    let key = x % 2;
+                    {
+                        let mut running = r.lock().unwrap();
+                        let n = running.entry(key).or_insert(0);
+                        *n += 1;
+                        let mut peak = p.lock().unwrap();
+                        let m = peak.entry(key).or_insert(0);
+                        *m = (*m).max(*n);
+                    }
+                    // Keys with an even value are slow.
+                    if key == 0 {
+                        thread::sleep(Duration::from_millis(2));
+                    }
+                    *r.lock().unwrap().get_mut(&key).unwrap() -= 1;
+                    x * 2
+                })
+                .limit_per_key(1, |x: &i32| x % 2)
+                .collect();
+            assert_eq!(v, (0..40).map(|x| x * 2).collect::<Vec<_>>());
+            for (_, &n) in peak.lock().unwrap().iter() {
+                assert_eq!(n, 1);
+            }
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::{convert::TryFrom, time::Duration};
+
+/// Values below this are counted exactly, above it each power of two is
+/// split into SUB_BUCKETS buckets, so counts are within about 3% of the
+/// true value.
+const SUB_BITS: u32 = 5;
+const SUB_BUCKETS: u64 = 1 << SUB_BITS;
+const EXACT: u64 = SUB_BUCKETS * 2;
+const BUCKETS: usize = (EXACT + (63 - SUB_BITS as u64) * SUB_BUCKETS) as usize;
+
+/// LatencyHistogram counts durations in the manner of an HDR
+/// histogram: buckets are narrow for short durations and wide for
+/// long ones, so percentiles far into the tail are as precise, relative
+/// to their size, as the median. See PipelineBuilder::track_latency.
+#[derive(Clone)]
+pub struct LatencyHistogram {
+    counts: Vec<u64>,
+    count: u64,
+    total: u128,
+    min: u64,
+    max: u64,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> LatencyHistogram {
+        LatencyHistogram {
+            counts: vec![0; BUCKETS],
+            count: 0,
+            total: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+
+    pub fn record(&mut self, d: Duration) {
+        let ns = u64::try_from(d.as_nanos()).unwrap_or(u64::MAX);
+        self.counts[bucket(ns)] += 1;
+        self.count += 1;
+        self.total += ns as u128;
+        self.min = self.min.min(ns);
+        self.max = self.max.max(ns);
+    }
+
+    /// Add the counts of other to this histogram.
+    pub fn merge(&mut self, other: &LatencyHistogram) {
+        for (a, b) in self.counts.iter_mut().zip(&other.counts) {
+            *a += b;
+        }
+        self.count += other.count;
+        self.total += other.total;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    /// The number of durations recorded.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        (self.count > 0).then(|| Duration::from_nanos(self.min))
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        (self.count > 0).then(|| Duration::from_nanos(self.max))
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        (self.count > 0).then(|| Duration::from_nanos((self.total / self.count as u128) as u64))
+    }
+
+    /// The duration that p percent of recorded durations are at or
+    /// under, e.g. 99.9 for the 99.9th percentile.
+    ///
+    /// # Panics
+    ///
+    /// Panics if p is not between 0 and 100.
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        assert!((0.0..=100.0).contains(&p), "p must be between 0 and 100");
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((p / 100.0 * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, &n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                let ns = bucket_max(i).clamp(self.min, self.max);
+                return Some(Duration::from_nanos(ns));
+            }
+        }
+        unreachable!()
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram::new()
+    }
+}
+
+impl std::fmt::Debug for LatencyHistogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LatencyHistogram")
+            .field("count", &self.count)
+            .field("min", &self.min())
+            .field("p50", &self.percentile(50.0))
+            .field("p99", &self.percentile(99.0))
+            .field("max", &self.max())
+            .finish()
+    }
+}
+
+fn bucket(ns: u64) -> usize {
+    if ns < EXACT {
+        return ns as usize;
+    }
+    let shift = 63 - ns.leading_zeros() - SUB_BITS;
+    let sub = (ns >> shift) - SUB_BUCKETS;
+    (EXACT + (shift as u64 - 1) * SUB_BUCKETS + sub) as usize
+}
+
+/// The largest value counted in bucket i.
+fn bucket_max(i: usize) -> u64 {
+    let i = i as u64;
+    if i < EXACT {
+        return i;
+    }
+    let shift = (i - EXACT) / SUB_BUCKETS + 1;
+    let sub = (i - EXACT) % SUB_BUCKETS + SUB_BUCKETS;
+    (sub << shift) | ((1 << shift) - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets() {
+        let mut last = 0;
+        for &ns in &[0, 1, 63, 64, 65, 127, 128, 1000, 1 << 40, u64::MAX] {
+            let i = bucket(ns);
+            assert!(i >= last && i < BUCKETS);
+            assert!(bucket_max(i) >= ns);
+            // Within the relative precision.
+            assert!(bucket_max(i) - ns <= ns / SUB_BUCKETS);
+            last = i;
+        }
+    }
+
+    #[test]
+    fn test_latency_histogram() {
+        let mut h = LatencyHistogram::new();
+        assert_eq!(h.percentile(50.0), None);
+        for ms in 1..=1000 {
+            h.record(Duration::from_millis(ms));
+        }
+        assert_eq!(h.count(), 1000);
+        assert_eq!(h.min(), Some(Duration::from_millis(1)));
+        assert_eq!(h.max(), Some(Duration::from_millis(1000)));
+        for &(p, ms) in &[(50.0, 500.0), (99.0, 990.0), (99.9, 999.0)] {
+            let got = h.percentile(p).unwrap().as_secs_f64() * 1000.0;
+            assert!((got - ms).abs() / ms < 0.04, "p{} = {}ms", p, got);
+        }
+        assert_eq!(h.percentile(100.0), h.max());
+
+        let mut tail = LatencyHistogram::new();
+        tail.record(Duration::from_secs(5));
+        h.merge(&tail);
+        assert_eq!(h.count(), 1001);
+        assert_eq!(h.max(), Some(Duration::from_secs(5)));
+    }
+}
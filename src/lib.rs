@@ -25,12 +25,12 @@
 //! use plmap::ScopedPipelineMap;
 //!
 //! fn example() {
-//!     crossbeam_utils::thread::scope(|s| {
+//!     std::thread::scope(|s| {
 //!        // Using a thread scope let's you use non 'static lifetimes.
 //!        for (i, v) in (0..100).scoped_plmap(s, 5, |x| x * 2).enumerate() {
 //!             println!("i={}", i);
 //!        }
-//!     }).unwrap()
+//!     })
 //! }
 //! ```
 //!
@@ -55,11 +55,130 @@
 //!     }
 //! }
 //! ```
+//!
+//! # Platform support
+//!
+//! On targets that can't start threads, such as wasm32-unknown-unknown
+//! built without the atomics target feature as for most browsers,
+//! pipelines map inline on the consumer, so the same code runs in
+//! native and browser builds, just without the parallelism. This holds
+//! whatever the spawner or spawn_fn, the channels and locks workers use
+//! can't block there. There is no Web Worker backend.
+//!
+//! The crate needs std, there is no no_std mode. Pipelines catch
+//! panics in the mapper with std::panic::catch_unwind to resume them on
+//! the consumer, and the pools and queues are built on std's Mutex,
+//! Condvar and thread_local. Where std is available but threads and
+//! channels come from somewhere else, such as an RTOS, workers can be
+//! started through PipelineBuilder::spawn_fn or a Spawner, and values
+//! sent over the platform's channels by implementing Channel for them,
+//! see PipelineBuilder::build_with_channel.
+
+#![cfg_attr(feature = "async_iterator", feature(async_iterator))]
+
+// Declared first so its macros are visible to the other modules.
+#[macro_use]
+mod logging;
 
+mod adaptive;
+#[cfg(feature = "async_iterator")]
+mod async_iter;
+mod batched;
+mod boxed;
+mod buffered;
+mod builder;
+#[cfg(feature = "tokio")]
+mod cancel;
+mod chan;
+#[cfg(any(feature = "procs", feature = "remote"))]
+mod codec;
+mod driver;
+mod fair;
+pub mod io;
+mod keyed;
+mod latency;
+mod map_output;
 mod mapper;
+mod metered;
+#[cfg(feature = "numa")]
+mod numa;
+mod persistent;
 mod pipeline;
+mod pool;
+pub mod prelude;
+mod prioritized;
+#[cfg(feature = "procs")]
+mod procs;
+#[cfg(feature = "crossbeam-channel")]
+mod receiver;
+#[cfg(feature = "remote")]
+mod remote;
+mod reorder;
+mod rng;
+mod sample;
+mod schedule;
 mod scoped_pipeline;
+mod semaphore;
+#[cfg(feature = "signal")]
+mod signal;
+mod spawner;
+mod spec;
+#[cfg(feature = "spill")]
+mod spill;
+mod split;
+mod stats;
+#[cfg(feature = "stream")]
+mod stream;
+mod sync;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod throttle;
+mod time;
+#[cfg(feature = "tokio")]
+mod tokio_chan;
+mod trace;
+mod wait;
+mod workers;
 
+pub use batched::*;
+pub use boxed::BoxedPipeline;
+pub use buffered::*;
+pub use builder::*;
+pub use chan::{Channel, ChannelReceiver, ChannelSender};
+#[cfg(any(feature = "procs", feature = "remote"))]
+pub use codec::{Bincode, Codec};
+pub use driver::{Abort, DriverHandle, DriverSender};
+pub use fair::Priority;
+pub use latency::LatencyHistogram;
+pub use map_output::MapOutput;
 pub use mapper::*;
+#[cfg(feature = "numa")]
+pub use numa::Placement;
+pub use persistent::PersistentPipeline;
 pub use pipeline::*;
+pub use pool::PlmapPool;
+pub use prioritized::{KeyOrdered, Lifo, Prioritized};
+#[cfg(feature = "procs")]
+pub use procs::{is_worker, serve, ProcessMap, ProcessMapper, WorkerCommand};
+#[cfg(feature = "crossbeam-channel")]
+pub use receiver::{CancelToken, ReceiverInput};
+#[cfg(feature = "remote")]
+pub use remote::{serve_tcp, RemoteMap, RemoteMapper, TcpServer};
+pub use sample::{Sample, SampleMapper, Sampled, SampledInput};
+pub use schedule::Schedule;
 pub use scoped_pipeline::*;
+pub use semaphore::{CountingSemaphore, Semaphore};
+#[cfg(feature = "smol")]
+pub use spawner::SmolSpawner;
+pub use spawner::{Spawner, ThreadSpawner, Worker};
+pub use spec::PipelineSpec;
+#[cfg(feature = "spill")]
+pub use spill::{spill, Spill, Spilled};
+pub use split::{FeedInput, Feeder, Outputs};
+pub use stats::{PipelineReport, PipelineState, WorkerState, WorkerStats};
+#[cfg(feature = "stream")]
+pub use stream::{plmap_stream, Close, Forward, StreamPipeline, StreamPipelineMap};
+#[cfg(feature = "tokio")]
+pub use tokio_chan::TokioInput;
+pub use wait::WaitStrategy;
+pub use workers::Workers;
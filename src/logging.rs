@@ -0,0 +1,89 @@
+//! Log records of worker and pipeline lifecycle events, see the log
+//! feature.
+//!
+//! Like the stand-ins in trace, without the feature these macros expand
+//! to nothing, so the rest of the crate never needs to check.
+
+/// Log a debug record under the plmap target.
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "log")]
+        log::debug!(target: "plmap", $($arg)+);
+    };
+}
+
+#[cfg(all(test, feature = "log"))]
+mod tests {
+    use {
+        crate::{PipelineBuilder, PipelineMap},
+        log::{Level, Metadata, Record},
+        std::{
+            panic::{self, AssertUnwindSafe},
+            sync::Mutex,
+        },
+    };
+
+    /// Keeps every plmap record, the logger is global so these include
+    /// records from other tests.
+    struct Records(Mutex<Vec<String>>);
+
+    impl log::Log for Records {
+        fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+            metadata.target() == "plmap" && metadata.level() <= Level::Debug
+        }
+
+        fn log(&self, record: &Record<'_>) {
+            if self.enabled(record.metadata()) {
+                self.0.lock().unwrap().push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static RECORDS: Records = Records(Mutex::new(Vec::new()));
+
+    fn logged(msg: &str) -> bool {
+        RECORDS.0.lock().unwrap().iter().any(|r| r == msg)
+    }
+
+    #[test]
+    fn test_log_records() {
+        log::set_logger(&RECORDS).unwrap();
+        log::set_max_level(log::LevelFilter::Debug);
+
+        let total: i32 = (0..100).plmap(3, |x| x * 2).sum();
+        assert_eq!(total, 9900);
+        assert!(logged("pipeline started with 3 worker threads"));
+        assert!(logged("worker 2 started"));
+        assert!(logged("worker 2 exited"));
+        assert!(logged("pipeline shutting down with 0 values in flight"));
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            (0..10)
+                .plmap(1, |x| {
+                    if x == 7 {
+                        panic!("boom");
+                    }
+                    x
+                })
+                .count()
+        }));
+        assert!(result.is_err());
+        assert!(logged("worker 0 caught a panic mapping item 7"));
+
+        let mut p = PipelineBuilder::new(4)
+            .adaptive_in_flight(1, 8)
+            .build(0..100, |x| {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+                x
+            });
+        assert_eq!(p.by_ref().count(), 100);
+        assert!(RECORDS
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|r| r.starts_with("in-flight depth 5 -> 6")));
+    }
+}
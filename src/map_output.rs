@@ -0,0 +1,173 @@
+use {
+    super::{mapper::Mapper, pipeline::Pipeline, stats::PipelineReport},
+    std::{fmt, iter::FusedIterator},
+};
+
+/// MapOutput applies a function to each of a pipeline's results on the
+/// consuming thread, once they are back in order. Created by
+/// Pipeline::map_output.
+///
+/// Unlike Iterator::map, the pipeline's own methods stay available
+/// through MapOutput, and fold is still specialized.
+pub struct MapOutput<I, M, F>
+where
+    I: Iterator,
+    I::Item: Send,
+    M: Mapper<I::Item> + Clone + Send,
+    M::Out: Send,
+{
+    pipeline: Pipeline<I, M>,
+    f: F,
+}
+
+impl<I, M> Pipeline<I, M>
+where
+    I: Iterator,
+    I::Item: Send,
+    M: Mapper<I::Item> + Clone + Send,
+    M::Out: Send,
+{
+    /// Apply f to each result on the consuming thread, after the
+    /// results have been put back in order, see MapOutput.
+    ///
+    /// This suits cheap conversions, such as turning what the workers
+    /// return into the consumer's own types, which are not worth
+    /// another pipeline stage.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::PipelineMap;
+    ///
+    /// let mut p = (0..100).plmap(4, |x: i32| x * 2).map_output(|x| x.to_string());
+    /// assert_eq!(p.next(), Some("0".to_string()));
+    /// assert_eq!(p.pipeline_mut().peek(), Some(&2));
+    /// assert_eq!(p.count(), 99);
+    /// ```
+    pub fn map_output<F, T>(self, f: F) -> MapOutput<I, M, F>
+    where
+        F: FnMut(M::Out) -> T,
+    {
+        MapOutput { pipeline: self, f }
+    }
+}
+
+impl<I, M, F> MapOutput<I, M, F>
+where
+    I: Iterator,
+    I::Item: Send,
+    M: Mapper<I::Item> + Clone + Send,
+    M::Out: Send,
+{
+    /// The pipeline whose results are being mapped.
+    pub fn pipeline(&self) -> &Pipeline<I, M> {
+        &self.pipeline
+    }
+
+    /// The pipeline whose results are being mapped. Results taken
+    /// through it skip f.
+    pub fn pipeline_mut(&mut self) -> &mut Pipeline<I, M> {
+        &mut self.pipeline
+    }
+
+    /// The number of values being mapped, see Pipeline::in_flight.
+    pub fn in_flight(&self) -> usize {
+        self.pipeline.in_flight()
+    }
+
+    /// The number of results ready but not yet taken, see
+    /// Pipeline::queue_len.
+    pub fn queue_len(&self) -> usize {
+        self.pipeline.queue_len()
+    }
+
+    /// Stop the pipeline and report where its time went, see
+    /// Pipeline::finish.
+    pub fn finish(self) -> PipelineReport {
+        self.pipeline.finish()
+    }
+}
+
+impl<I, M, F> fmt::Debug for MapOutput<I, M, F>
+where
+    I: Iterator,
+    I::Item: Send,
+    M: Mapper<I::Item> + Clone + Send,
+    M::Out: Send,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("MapOutput").field(&self.pipeline).finish()
+    }
+}
+
+impl<I, M, F, T> Iterator for MapOutput<I, M, F>
+where
+    I: Iterator,
+    I::Item: Send,
+    M: Mapper<I::Item> + Clone + Send,
+    M::Out: Send,
+    F: FnMut(M::Out) -> T,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.pipeline.next().map(&mut self.f)
+    }
+
+    fn fold<B, G>(self, init: B, mut g: G) -> B
+    where
+        G: FnMut(B, T) -> B,
+    {
+        let mut f = self.f;
+        self.pipeline.fold(init, |acc, v| g(acc, f(v)))
+    }
+}
+
+impl<I, M, F, T> DoubleEndedIterator for MapOutput<I, M, F>
+where
+    I: DoubleEndedIterator,
+    I::Item: Send,
+    M: Mapper<I::Item> + Clone + Send,
+    M::Out: Send,
+    F: FnMut(M::Out) -> T,
+{
+    fn next_back(&mut self) -> Option<T> {
+        self.pipeline.next_back().map(&mut self.f)
+    }
+}
+
+impl<I, M, F, T> FusedIterator for MapOutput<I, M, F>
+where
+    I: Iterator,
+    I::Item: Send,
+    M: Mapper<I::Item> + Clone + Send,
+    M::Out: Send,
+    F: FnMut(M::Out) -> T,
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use {crate::PipelineMap, std::thread};
+
+    #[test]
+    fn test_map_output() {
+        for w in 0..3 {
+            let consumer = thread::current().id();
+            let mut p = (0..100).plmap(w, |x| x * 2).map_output(|x| {
+                assert_eq!(thread::current().id(), consumer);
+                x as i64 + 1
+            });
+            assert_eq!(p.next(), Some(1));
+            assert_eq!(p.next_back(), Some(199));
+            assert!(format!("{:?}", p).starts_with("MapOutput(Pipeline {"));
+            let v: Vec<i64> = p.collect();
+            assert_eq!(v, (1..99).map(|x| x * 2 + 1).collect::<Vec<_>>());
+
+            let mut p = (0..10).plmap(w, |x| x * 2).map_output(|x| x + 1);
+            assert_eq!(p.by_ref().sum::<i32>(), 100);
+            assert_eq!(p.in_flight() + p.queue_len(), 0);
+            assert!(p.finish().workers.len() <= w);
+        }
+    }
+}
@@ -21,3 +21,108 @@ where
         self(x)
     }
 }
+
+/// WithContext is a Mapper which carries a context, such as a request
+/// id or a tracing span, alongside each value, see with_context.
+#[derive(Clone, Copy, Debug)]
+pub struct WithContext<F>(F);
+
+/// Map (context, value) pairs with f, which is given the value and a
+/// reference to its context, to (context, output) pairs, so that the
+/// work done on an item can be tied back to where it came from.
+///
+/// # Examples
+///
+/// ```
+/// use plmap::{with_context, PipelineMap};
+///
+/// let requests = vec![("a", 1), ("b", 2), ("c", 3)];
+/// let results: Vec<_> = requests
+///     .into_iter()
+///     .plmap(2, with_context(|id: &&str, x: i32| {
+///         eprintln!("request {}: mapping {}", id, x);
+///         x * 2
+///     }))
+///     .collect();
+/// assert_eq!(results, [("a", 2), ("b", 4), ("c", 6)]);
+/// ```
+pub fn with_context<C, In, Out, F>(f: F) -> WithContext<F>
+where
+    F: FnMut(&C, In) -> Out,
+{
+    WithContext(f)
+}
+
+impl<C, In, Out, F> Mapper<(C, In)> for WithContext<F>
+where
+    F: FnMut(&C, In) -> Out,
+{
+    type Out = (C, Out);
+
+    fn apply(&mut self, (context, v): (C, In)) -> Self::Out {
+        let out = (self.0)(&context, v);
+        (context, out)
+    }
+}
+
+/// Enumerated is a Mapper which maps (index, value) pairs with another
+/// mapper, keeping each result paired with its index, see enumerated.
+#[derive(Clone, Copy, Debug)]
+pub struct Enumerated<M>(M);
+
+/// Map (index, value) pairs, such as those from Iterator::enumerate,
+/// with m to (index, output) pairs. As the index travels with the
+/// value through the workers, it still identifies the source of each
+/// result when they are yielded out of order.
+///
+/// # Examples
+///
+/// ```
+/// use plmap::{enumerated, PipelineBuilder};
+///
+/// let mut results: Vec<_> = PipelineBuilder::new(4)
+///     .unordered()
+///     .build((10..20).enumerate(), enumerated(|x: i32| x * 2))
+///     .collect();
+/// results.sort();
+/// assert_eq!(results[3], (3, 26));
+/// ```
+pub fn enumerated<M>(m: M) -> Enumerated<M> {
+    Enumerated(m)
+}
+
+impl<In, M> Mapper<(usize, In)> for Enumerated<M>
+where
+    M: Mapper<In>,
+{
+    type Out = (usize, M::Out);
+
+    fn apply(&mut self, (i, v): (usize, In)) -> Self::Out {
+        (i, self.0.apply(v))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::PipelineMap};
+
+    #[test]
+    fn test_with_context() {
+        for w in 0..3 {
+            let results: Vec<(String, i32)> = (0..50)
+                .map(|x| (format!("item-{}", x), x))
+                .plmap(
+                    w,
+                    with_context(|ctx: &String, x: i32| {
+                        assert_eq!(*ctx, format!("item-{}", x));
+                        x * 2
+                    }),
+                )
+                .collect();
+            for (x, (ctx, v)) in results.into_iter().enumerate() {
+                assert_eq!(ctx, format!("item-{}", x));
+                assert_eq!(v, x as i32 * 2);
+            }
+        }
+    }
+}
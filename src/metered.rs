@@ -0,0 +1,255 @@
+//! Pipeline metrics reported through the metrics crate, see the
+//! metrics feature.
+//!
+//! Without the feature the types here are stand-ins which do nothing,
+//! like those in trace.
+
+use super::time::Instant;
+#[cfg(feature = "metrics")]
+use {
+    metrics::{Counter, Gauge, Histogram},
+    std::time::Duration,
+};
+
+/// Metrics are the consumer side metrics of a pipeline, named
+/// "{prefix}_items_dispatched", "{prefix}_items_completed" and
+/// "{prefix}_in_flight".
+pub(crate) struct Metrics {
+    #[cfg(feature = "metrics")]
+    prefix: String,
+    #[cfg(feature = "metrics")]
+    dispatched: Counter,
+    #[cfg(feature = "metrics")]
+    completed: Counter,
+    #[cfg(feature = "metrics")]
+    in_flight: Gauge,
+}
+
+impl Metrics {
+    pub(crate) fn new(_prefix: &str) -> Metrics {
+        Metrics {
+            #[cfg(feature = "metrics")]
+            prefix: _prefix.to_string(),
+            #[cfg(feature = "metrics")]
+            dispatched: metrics::counter!(format!("{}_items_dispatched", _prefix)),
+            #[cfg(feature = "metrics")]
+            completed: metrics::counter!(format!("{}_items_completed", _prefix)),
+            #[cfg(feature = "metrics")]
+            in_flight: metrics::gauge!(format!("{}_in_flight", _prefix)),
+        }
+    }
+
+    /// Count a value handed to the mapper, with n now in flight.
+    pub(crate) fn dispatched(&self, _n: usize) {
+        #[cfg(feature = "metrics")]
+        {
+            self.dispatched.increment(1);
+            self.in_flight.set(_n as f64);
+        }
+    }
+
+    /// Count a result handed to the consumer, with n still in flight.
+    pub(crate) fn completed(&self, _n: usize) {
+        #[cfg(feature = "metrics")]
+        {
+            self.completed.increment(1);
+            self.in_flight.set(_n as f64);
+        }
+    }
+
+    /// The metrics of worker thread number worker. Like trace's
+    /// WorkerSpan, these are created on the thread starting the
+    /// pipeline so they reach the same recorder.
+    pub(crate) fn worker(&self, _worker: usize) -> WorkerMetrics {
+        WorkerMetrics {
+            #[cfg(feature = "metrics")]
+            busy_ratio: metrics::gauge!(
+                format!("{}_worker_busy_ratio", self.prefix),
+                "worker" => _worker.to_string()
+            ),
+            #[cfg(feature = "metrics")]
+            queue_wait: metrics::histogram!(format!("{}_queue_wait_seconds", self.prefix)),
+            #[cfg(feature = "metrics")]
+            started: Instant::now(),
+            #[cfg(feature = "metrics")]
+            busy: Duration::ZERO,
+        }
+    }
+}
+
+/// Stamp records when a value was dispatched, for measuring how long it
+/// waits in a work queue and how long it takes to complete. It is only
+/// taken when something will read it.
+#[derive(Clone, Copy)]
+pub(crate) struct Stamp(pub(crate) Option<Instant>);
+
+impl Stamp {
+    /// Stamp a value with the time, if latency is being tracked or
+    /// metrics are on.
+    pub(crate) fn now(latency: bool) -> Stamp {
+        Stamp((latency || cfg!(feature = "metrics")).then(Instant::now))
+    }
+}
+
+/// WorkerMetrics are the metrics of a worker thread, the fraction of
+/// its lifetime spent mapping as "{prefix}_worker_busy_ratio", labelled
+/// with the worker index, and the time values waited in its queue as
+/// "{prefix}_queue_wait_seconds".
+pub(crate) struct WorkerMetrics {
+    #[cfg(feature = "metrics")]
+    busy_ratio: Gauge,
+    #[cfg(feature = "metrics")]
+    queue_wait: Histogram,
+    #[cfg(feature = "metrics")]
+    started: Instant,
+    #[cfg(feature = "metrics")]
+    busy: Duration,
+}
+
+impl WorkerMetrics {
+    /// Map a value dispatched at stamp with f.
+    pub(crate) fn map<R>(&mut self, _stamp: Stamp, f: impl FnOnce() -> R) -> R {
+        #[cfg(feature = "metrics")]
+        {
+            let start = Instant::now();
+            if let Some(at) = _stamp.0 {
+                self.queue_wait
+                    .record(start.saturating_duration_since(at).as_secs_f64());
+            }
+            let v = f();
+            let end = Instant::now();
+            self.busy += end - start;
+            let lifetime = (end - self.started).as_secs_f64();
+            if lifetime > 0.0 {
+                self.busy_ratio.set(self.busy.as_secs_f64() / lifetime);
+            }
+            v
+        }
+        #[cfg(not(feature = "metrics"))]
+        f()
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use {
+        crate::{PipelineBuilder, PipelineMap},
+        metrics::{
+            Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key, KeyName, Metadata,
+            Recorder, SharedString, Unit,
+        },
+        std::{
+            collections::HashMap,
+            sync::{Arc, Mutex},
+        },
+    };
+
+    /// A metric's latest value, or for counters and histograms the
+    /// total, and how many times it was updated.
+    #[derive(Default)]
+    struct Value(Mutex<(f64, usize)>);
+
+    impl Value {
+        fn update(&self, f: impl FnOnce(f64) -> f64) {
+            let mut v = self.0.lock().unwrap();
+            *v = (f(v.0), v.1 + 1);
+        }
+    }
+
+    impl CounterFn for Value {
+        fn increment(&self, n: u64) {
+            self.update(|v| v + n as f64)
+        }
+
+        fn absolute(&self, n: u64) {
+            self.update(|_| n as f64)
+        }
+    }
+
+    impl GaugeFn for Value {
+        fn increment(&self, n: f64) {
+            self.update(|v| v + n)
+        }
+
+        fn decrement(&self, n: f64) {
+            self.update(|v| v - n)
+        }
+
+        fn set(&self, n: f64) {
+            self.update(|_| n)
+        }
+    }
+
+    impl HistogramFn for Value {
+        fn record(&self, n: f64) {
+            self.update(|v| v + n)
+        }
+    }
+
+    /// Records metrics by name, ignoring labels.
+    #[derive(Clone, Default)]
+    struct Metrics(Arc<Mutex<HashMap<String, Arc<Value>>>>);
+
+    impl Metrics {
+        fn value(&self, key: &Key) -> Arc<Value> {
+            let mut values = self.0.lock().unwrap();
+            values.entry(key.name().to_string()).or_default().clone()
+        }
+
+        fn get(&self, name: &str) -> (f64, usize) {
+            let values = self.0.lock().unwrap();
+            let v = values.get(name).expect(name);
+            let v = *v.0.lock().unwrap();
+            v
+        }
+    }
+
+    impl Recorder for Metrics {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            Counter::from_arc(self.value(key))
+        }
+
+        fn register_gauge(&self, key: &Key, _: &Metadata<'_>) -> Gauge {
+            Gauge::from_arc(self.value(key))
+        }
+
+        fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+            Histogram::from_arc(self.value(key))
+        }
+    }
+
+    #[test]
+    fn test_metrics() {
+        for w in 0..3 {
+            let recorder = Metrics::default();
+            metrics::with_local_recorder(&recorder, || {
+                let total: i32 = (0..100).plmap(w, |x| x * 2).sum();
+                assert_eq!(total, 9900);
+            });
+            assert_eq!(recorder.get("plmap_items_dispatched").0, 100.0);
+            assert_eq!(recorder.get("plmap_items_completed").0, 100.0);
+            assert_eq!(recorder.get("plmap_in_flight").0, 0.0);
+            if w > 0 {
+                assert_eq!(recorder.get("plmap_queue_wait_seconds").1, 100);
+                let (ratio, _) = recorder.get("plmap_worker_busy_ratio");
+                assert!((0.0..=1.0).contains(&ratio));
+            }
+        }
+
+        let recorder = Metrics::default();
+        metrics::with_local_recorder(&recorder, || {
+            let total: i32 = PipelineBuilder::new(2)
+                .metrics_prefix("resize")
+                .build(0..10, |x| x)
+                .sum();
+            assert_eq!(total, 45);
+        });
+        assert_eq!(recorder.get("resize_items_completed").0, 10.0);
+    }
+}
@@ -0,0 +1,121 @@
+use std::fs;
+
+/// Placement controls how worker threads are placed on the NUMA
+/// nodes of the machine.
+///
+/// Workers are pinned to the cpus of their node before the mapper is
+/// first run, so memory the mapper allocates as scratch space is
+/// allocated node locally by the kernel's first touch policy. Placement
+/// only has an effect on linux, elsewhere workers are left for the OS to
+/// schedule.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Placement {
+    /// Leave worker placement to the OS. This is the default.
+    #[default]
+    Any,
+    /// Place workers on each node in turn, spreading memory bandwidth
+    /// use across the machine.
+    Spread,
+    /// Fill the cpus of each node before moving on to the next,
+    /// keeping workers close together.
+    Pack,
+}
+
+/// Topology is the list of cpus belonging to each NUMA node.
+#[derive(Debug)]
+pub(crate) struct Topology {
+    nodes: Vec<Vec<usize>>,
+}
+
+impl Topology {
+    /// Read the topology of this machine, an empty topology means it
+    /// could not be determined.
+    pub(crate) fn detect() -> Topology {
+        let mut nodes = Vec::new();
+        for node in 0.. {
+            let path = format!("/sys/devices/system/node/node{}/cpulist", node);
+            match fs::read_to_string(path) {
+                Ok(cpulist) => nodes.push(parse_cpulist(&cpulist)),
+                Err(_) => break,
+            }
+        }
+        nodes.retain(|cpus| !cpus.is_empty());
+        Topology { nodes }
+    }
+
+    /// The cpus the given worker should be pinned to, if any.
+    pub(crate) fn cpus_for(&self, placement: Placement, worker: usize) -> Option<&[usize]> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        match placement {
+            Placement::Any => None,
+            Placement::Spread => Some(&self.nodes[worker % self.nodes.len()]),
+            Placement::Pack => {
+                let n_cpus: usize = self.nodes.iter().map(|cpus| cpus.len()).sum();
+                let mut slot = worker % n_cpus;
+                for cpus in self.nodes.iter() {
+                    if slot < cpus.len() {
+                        return Some(cpus);
+                    }
+                    slot -= cpus.len();
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Parse the kernel's cpu list format, e.g. "0-3,8-11".
+fn parse_cpulist(cpulist: &str) -> Vec<usize> {
+    let mut cpus = Vec::new();
+    for range in cpulist.trim().split(',').filter(|r| !r.is_empty()) {
+        let mut bounds = range.splitn(2, '-').map(|n| n.parse::<usize>());
+        match (bounds.next(), bounds.next()) {
+            (Some(Ok(lo)), Some(Ok(hi))) => cpus.extend(lo..=hi),
+            (Some(Ok(cpu)), None) => cpus.push(cpu),
+            _ => (),
+        }
+    }
+    cpus
+}
+
+/// Restrict the calling thread to the given cpus.
+#[cfg(target_os = "linux")]
+pub(crate) fn pin_current_thread(cpus: &[usize]) {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        for &cpu in cpus {
+            libc::CPU_SET(cpu, &mut set);
+        }
+        // Failure just leaves the thread where the OS put it.
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn pin_current_thread(_cpus: &[usize]) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_numa_placement() {
+        assert_eq!(parse_cpulist("0-3,8,10-11\n"), vec![0, 1, 2, 3, 8, 10, 11]);
+        assert_eq!(parse_cpulist("\n"), Vec::<usize>::new());
+
+        let topology = Topology {
+            nodes: vec![vec![0, 1], vec![2, 3]],
+        };
+        let nodes = |placement| -> Vec<usize> {
+            (0..6)
+                .map(|w| topology.cpus_for(placement, w).unwrap()[0] / 2)
+                .collect()
+        };
+        assert_eq!(nodes(Placement::Spread), vec![0, 1, 0, 1, 0, 1]);
+        assert_eq!(nodes(Placement::Pack), vec![0, 0, 1, 1, 0, 0]);
+        assert_eq!(topology.cpus_for(Placement::Any, 0), None);
+    }
+}
@@ -0,0 +1,196 @@
+use {
+    super::{
+        builder::PipelineBuilder,
+        chan,
+        mapper::Mapper,
+        pipeline::{Pipeline, Slot},
+        workers::Workers,
+    },
+    std::{
+        panic::{self, AssertUnwindSafe},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+    },
+};
+
+/// PersistentPipeline keeps a set of worker threads, each with its own
+/// mapper, alive between runs.
+///
+/// Each call to feed runs a new pipeline on the same workers, so
+/// mappers that are expensive to set up, such as those holding loaded
+/// models or open connections, are only created once. The worker
+/// threads exit once the PersistentPipeline and every pipeline fed
+/// through it have been dropped.
+///
+/// # Examples
+///
+/// ```
+/// use plmap::PersistentPipeline;
+///
+/// let p = PersistentPipeline::new(4, |x: i32| x * 2);
+/// for _ in 0..10 {
+///     let total: i32 = p.feed(0..100).sum();
+///     assert_eq!(total, 9900);
+/// }
+/// ```
+pub struct PersistentPipeline<In, M>
+where
+    M: Mapper<In>,
+{
+    builder: PipelineBuilder,
+    jobs: chan::Sender<Job<Slot, In, M::Out>>,
+    /// Without workers, see the sequential feature, or under Miri, each
+    /// feed maps on the consumer with a clone of this.
+    mapper: M,
+}
+
+impl<In, M> PersistentPipeline<In, M>
+where
+    In: Send + 'static,
+    M: Mapper<In> + Clone + Send + 'static,
+    M::Out: Send + 'static,
+{
+    /// Start n_workers worker threads, each with a clone of mapper.
+    ///
+    /// # Panics
+    ///
+    /// Panics if n_workers is zero.
+    pub fn new(n_workers: impl Into<Workers>, mapper: M) -> PersistentPipeline<In, M> {
+        let n_workers = n_workers.into().count();
+        assert!(
+            n_workers > 0,
+            "a persistent pipeline needs at least one worker"
+        );
+        let builder = PipelineBuilder::new(n_workers);
+        let (jobs, jobs_rx) = chan::unbounded::<Job<Slot, In, M::Out>>();
+        for _ in 0..builder.n_workers {
+            let jobs_rx = jobs_rx.clone();
+            let mut mapper = mapper.clone();
+            thread::spawn(move || {
+                while let Ok(Job { tag, v, feed }) = jobs_rx.recv() {
+                    // Values from pipelines that have been dropped are
+                    // not worth mapping.
+                    if feed.closed.load(Ordering::Relaxed) {
+                        continue;
+                    }
+                    // Panics are passed on to the consumer, to be
+                    // resumed when it reaches this value.
+                    let out = panic::catch_unwind(AssertUnwindSafe(|| mapper.apply(v)));
+                    let _ = feed.results.send((tag, out));
+                }
+            });
+        }
+        PersistentPipeline {
+            builder,
+            jobs,
+            mapper,
+        }
+    }
+
+    /// Map the values of input on the persistent workers, yielding the
+    /// results in order.
+    ///
+    /// Pipelines may be fed concurrently, in which case they share the
+    /// workers.
+    pub fn feed<I>(&self, input: I) -> Pipeline<I::IntoIter, M>
+    where
+        I: IntoIterator<Item = In>,
+    {
+        Pipeline::start_persistent(&self.builder, &self.jobs, &self.mapper, input.into_iter())
+    }
+}
+
+/// Job is a value to be mapped by a persistent worker, along with where
+/// to send the result.
+pub(crate) struct Job<T, In, Out> {
+    tag: T,
+    v: In,
+    feed: Arc<Feed<T, Out>>,
+}
+
+/// Feed is the state shared between the workers and one pipeline fed
+/// through them.
+struct Feed<T, Out> {
+    results: chan::Sender<(T, thread::Result<Out>)>,
+    closed: AtomicBool,
+}
+
+/// PersistentDispatcher sends a pipeline's values to the workers of a
+/// PersistentPipeline.
+pub(crate) struct PersistentDispatcher<T, In, Out> {
+    jobs: chan::Sender<Job<T, In, Out>>,
+    feed: Arc<Feed<T, Out>>,
+}
+
+impl<T, In, Out> PersistentDispatcher<T, In, Out> {
+    pub(crate) fn new(
+        jobs: chan::Sender<Job<T, In, Out>>,
+        results: chan::Sender<(T, thread::Result<Out>)>,
+    ) -> PersistentDispatcher<T, In, Out> {
+        PersistentDispatcher {
+            jobs,
+            feed: Arc::new(Feed {
+                results,
+                closed: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    pub(crate) fn send(&self, tag: T, v: In) {
+        let job = Job {
+            tag,
+            v,
+            feed: self.feed.clone(),
+        };
+        self.jobs.send(job).unwrap();
+    }
+
+    /// Have the workers skip values that have not started.
+    pub(crate) fn close(&self) {
+        self.feed.closed.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_persistent_pipeline() {
+        use std::sync::atomic::AtomicUsize;
+
+        // Count how many mappers are ever created.
+        static CLONES: AtomicUsize = AtomicUsize::new(0);
+        struct Counted;
+        impl Clone for Counted {
+            fn clone(&self) -> Counted {
+                CLONES.fetch_add(1, Ordering::SeqCst);
+                Counted
+            }
+        }
+        impl Mapper<i32> for Counted {
+            type Out = i32;
+            fn apply(&mut self, x: i32) -> i32 {
+                x * 2
+            }
+        }
+
+        let p = PersistentPipeline::new(3, Counted);
+        for _ in 0..10 {
+            let v: Vec<i32> = p.feed(0..100).collect();
+            assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+        }
+        assert_eq!(CLONES.load(Ordering::SeqCst), 3);
+
+        // Concurrent and abandoned feeds.
+        let mut a = p.feed(0..100);
+        let mut b = p.feed(vec![1, 2, 3]).rev();
+        assert_eq!(a.next(), Some(0));
+        assert_eq!(b.next(), Some(6));
+        drop(a);
+        assert_eq!(b.collect::<Vec<_>>(), vec![4, 2]);
+    }
+}
@@ -1,132 +1,2246 @@
+#[cfg(feature = "tokio")]
+use super::cancel::Cancellation;
+#[cfg(feature = "numa")]
+use super::numa;
+#[cfg(feature = "signal")]
+use super::signal::Interrupt;
+#[cfg(feature = "testing")]
+use {super::testing::Simulation, std::sync::Mutex};
 use {
-    super::mapper::Mapper,
-    std::{collections::VecDeque, thread},
+    super::{
+        adaptive::AdaptiveDepth,
+        batched::BatchedPipeline,
+        buffered::Buffered,
+        builder::PipelineBuilder,
+        chan::{self, Builtin, Channels},
+        driver::{DriverHandle, DriverSender},
+        keyed::{KeyLimit, KeyLimiter},
+        mapper::{enumerated, Enumerated, Mapper},
+        metered::{Metrics, Stamp},
+        persistent::{self, PersistentDispatcher},
+        pool::{JobDispatch, PlmapPool, PoolDispatcher},
+        prioritized::{self, KeyOrdered, Lifo, Prioritized},
+        reorder::ReorderBuffer,
+        sample::{Sample, SampleMapper, SampledInput},
+        schedule::{self, Dispatcher, Schedule},
+        semaphore::Semaphore,
+        spawner::{self, Spawner, ThreadSpawner},
+        stats::{
+            Checkpoint, CompleteFn, PipelineReport, PipelineState, Progress, SlowWatch, Stats,
+        },
+        throttle::Throttle,
+        time::Instant,
+        trace,
+        wait::WaitStrategy,
+        workers::Workers,
+    },
+    std::{
+        fmt,
+        hash::Hash,
+        iter::{self, FromIterator, FusedIterator},
+        panic::{self, AssertUnwindSafe},
+        sync::{mpsc, Arc},
+        task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+        thread,
+        time::Duration,
+    },
 };
 
+/// Slot identifies where the result of a dispatched value belongs,
+/// values pulled from the back of a DoubleEndedIterator are numbered
+/// separately from those pulled from the front.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Slot {
+    Front(usize),
+    Back(usize),
+}
+
+impl Slot {
+    /// The item's position in the input, counting from whichever end
+    /// it was taken from.
+    pub(crate) fn index(self) -> usize {
+        match self {
+            Slot::Front(seq) | Slot::Back(seq) => seq,
+        }
+    }
+}
+
+/// Instruments are what a pipeline reports on itself with.
+struct Instruments {
+    span: trace::Span,
+    metrics: Metrics,
+    stats: Stats,
+}
+
+impl Instruments {
+    fn new(builder: &PipelineBuilder) -> Instruments {
+        Instruments {
+            span: trace::pipeline_span(builder.n_workers),
+            metrics: Metrics::new(&builder.metrics_prefix),
+            stats: Stats::new(builder.track_latency),
+        }
+    }
+}
+
+/// Which result the consumer is waiting for.
+#[derive(Clone, Copy)]
+enum Take {
+    /// The first ready result within the given distance of the front.
+    Front(usize),
+    FrontTail,
+    BackHead,
+    BackTail,
+}
+
+/// How values are mapped.
+enum Mapping<In, M>
+where
+    M: Mapper<In>,
+{
+    /// On the consumer thread with the pipeline's own mapper, when
+    /// there are no workers.
+    Inline,
+    /// On the pipeline's own threads, the done channel disconnects once
+    /// every worker has returned.
+    Threads {
+        dispatch: Dispatcher<(Slot, Stamp, In)>,
+        done: chan::Receiver<()>,
+    },
+    /// As jobs on a shared pool.
+    Pool(Box<dyn JobDispatch<Slot, In>>),
+    /// On the threads of a PersistentPipeline.
+    Persistent(PersistentDispatcher<Slot, In, M::Out>),
+    /// On the consumer, in an order picked by a seeded PRNG, see
+    /// PipelineBuilder::simulate.
+    #[cfg(feature = "testing")]
+    Simulated(Simulation<Slot, In, M>),
+    /// On whichever thread completes them with a MockExecutor, see
+    /// PipelineBuilder::mock.
+    #[cfg(feature = "testing")]
+    Mocked(Arc<Mutex<Simulation<Slot, In, M>>>),
+}
+
+/// What Pipeline::into_inner does with values that were dispatched
+/// but whose results have not been taken.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InFlight {
+    /// Wait for their results, returning them in PipelineParts.
+    Drain,
+    /// Drop them, the workers stop at their next result.
+    Discard,
+}
+
+/// How a pipeline was configured once started, see Pipeline::config.
+/// Settings which the pipeline can override are the ones in effect,
+/// e.g. n_workers is zero for a pipeline mapping inline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PipelineConfig {
+    /// The number of workers mapping values, zero when mapping inline.
+    pub n_workers: usize,
+    /// The most values that can be in flight at once.
+    pub in_flight_capacity: usize,
+    /// The bounds of the in-flight depth, if it is adaptive, see
+    /// PipelineBuilder::adaptive_in_flight.
+    pub adaptive_in_flight: Option<(usize, usize)>,
+    pub schedule: Schedule,
+    pub wait: WaitStrategy,
+    /// How far ahead of the next result others may be taken, see
+    /// PipelineBuilder::reorder_window.
+    pub reorder_window: usize,
+}
+
+/// What remains of a pipeline stopped with Pipeline::into_inner.
+#[derive(Debug)]
+pub struct PipelineParts<I, M, Out> {
+    /// The input, positioned after the last value the pipeline pulled.
+    pub input: I,
+    /// The mapper the pipeline was built with, None for pipelines fed
+    /// to a PersistentPipeline, whose mappers stay with it.
+    pub mapper: Option<M>,
+    /// Results of values pulled from the front of the input, in input
+    /// order. They come before input, empty unless drained.
+    pub results: Vec<Out>,
+    /// Results of values pulled from the back of the input with
+    /// next_back, in input order. They come after input, empty unless
+    /// drained.
+    pub back_results: Vec<Out>,
+}
+
 /// Pipeline is a wrapper around a worker pool and implements
 /// iterator. Usually they should be created via the PipelineMap
 /// extension trait and calling plmap on an iterator.
+///
+/// A Pipeline is Send whenever its input iterator is, so it can be
+/// built on one thread and consumed on another.
+///
+/// ```
+/// use plmap::PipelineMap;
+///
+/// let p = (0..100).plmap(4, |x| x * 2);
+/// let total = std::thread::spawn(move || p.sum::<i32>()).join().unwrap();
+/// assert_eq!(total, 9900);
+/// ```
+///
+/// An input that can't be sent makes the pipeline unsendable too.
+///
+/// ```compile_fail
+/// use {plmap::PipelineMap, std::rc::Rc};
+///
+/// let counts = Rc::new(());
+/// let input = (0..100).inspect(move |_| drop(counts.clone()));
+/// let p = input.plmap(4, |x| x * 2);
+/// std::thread::spawn(move || p.count());
+/// ```
 pub struct Pipeline<I, M>
+where
+    I: Iterator,
+    I::Item: Send,
+    M: Mapper<I::Item> + Clone + Send,
+    M::Out: Send,
+{
+    /// None once taken by into_inner.
+    input: Option<I>,
+    input_done: bool,
+    /// Whether the source of input values has ended, which for a
+    /// pipeline fed by push_input is only once end_input is called,
+    /// see feed.
+    input_ended: bool,
+    /// Input values come from push_input rather than the input
+    /// iterator.
+    fed: bool,
+    /// The mapper the pipeline was built with, workers map with clones.
+    /// None once taken by into_inner, or when fed to a
+    /// PersistentPipeline, which keeps its mappers.
+    mapper: Option<M>,
+    n_workers: usize,
+    schedule: Schedule,
+    front: ReorderBuffer<thread::Result<M::Out>>,
+    back: ReorderBuffer<thread::Result<M::Out>>,
+    results: chan::Receiver<(Slot, thread::Result<M::Out>)>,
+    workers: Mapping<I::Item, M>,
+    /// The next result, once taken by peek.
+    peeked: Option<M::Out>,
+    depth: usize,
+    adaptive: Option<AdaptiveDepth>,
+    throttle: Option<Throttle>,
+    /// Holds back values whose key is at its limit, see limit_per_key.
+    key_limit: Option<Box<dyn KeyLimit<I::Item>>>,
+    /// See PipelineBuilder::semaphore.
+    semaphore: Option<Arc<dyn Semaphore>>,
+    /// The number of permits taken from semaphore for values in flight.
+    permits: usize,
+    wait: WaitStrategy,
+    window: usize,
+    /// Woken when a result arrives while try_next is pending, see
+    /// ready_receiver.
+    ready: Option<Waker>,
+    #[cfg(feature = "tokio")]
+    cancel: Option<Cancellation>,
+    /// Set by Ctrl-C, see PipelineBuilder::cancel_on_ctrl_c.
+    #[cfg(feature = "signal")]
+    interrupt: Option<Interrupt>,
+    /// Spans the pipeline's lifetime.
+    _span: trace::Span,
+    metrics: Metrics,
+    stats: Stats,
+    progress: Option<Progress>,
+    checkpoint: Option<Checkpoint>,
+    /// Front input values consumed without a slot in the front reorder
+    /// buffer, by mapping inline or skipping, plus those skipped by
+    /// resume_from. Along with the slots taken from the front buffer,
+    /// the length of the input prefix that is done with.
+    unslotted: usize,
+    on_complete: Option<CompleteFn>,
+    /// Watches for slow values while the pipeline lives, see
+    /// PipelineBuilder::warn_slow.
+    _slow_watch: Option<SlowWatch>,
+}
+
+impl<I, M> Pipeline<I, M>
 where
     I: Iterator,
     I::Item: Send + 'static,
     M: Mapper<I::Item> + Clone + Send + 'static,
     M::Out: Send + 'static,
 {
-    mapper: M,
-    input: I,
-    queue: VecDeque<crossbeam_channel::Receiver<M::Out>>,
-    dispatch: crossbeam_channel::Sender<(I::Item, crossbeam_channel::Sender<M::Out>)>,
-    workers: Vec<thread::JoinHandle<()>>,
+    pub fn new(n_workers: impl Into<Workers>, mapper: M, input: I) -> Pipeline<I, M> {
+        PipelineBuilder::new(n_workers).build(input, mapper)
+    }
+
+    pub(crate) fn start(mut builder: PipelineBuilder, mapper: M, input: I) -> Pipeline<I, M> {
+        // Without threads, e.g. in browsers, the channels and locks
+        // workers use can't block, so every pipeline maps inline.
+        if !spawner::HAS_THREADS {
+            builder.n_workers = 0;
+        }
+        #[cfg(feature = "testing")]
+        if let Some(ref mock) = builder.mock {
+            let (results_tx, results) = chan::unbounded();
+            let sim = Arc::new(Mutex::new(Simulation::mock(mapper.clone(), results_tx)));
+            mock.attach(sim.clone());
+            let workers = Mapping::Mocked(sim);
+            return Self::with_workers(&builder, workers, results, input, Some(mapper));
+        }
+        #[cfg(feature = "testing")]
+        if builder.simulate.is_some() {
+            builder.pool = None;
+        }
+        if let Some(ref pool) = builder.pool {
+            // A pipeline started by a mapper already running on the
+            // pool would hold a pool thread while waiting on jobs
+            // queued behind it, with enough of them the pool
+            // deadlocks, so it runs inline instead.
+            if pool.is_current() {
+                builder.n_workers = 0;
+            }
+        }
+        match builder.pool {
+            Some(ref pool) if builder.n_workers > 0 => {
+                let (results_tx, results) = chan::unbounded();
+                let mappers = (0..builder.n_workers).map(|_| mapper.clone()).collect();
+                let dispatch =
+                    PoolDispatcher::new(pool.clone(), builder.share(), mappers, results_tx);
+                let workers = Mapping::Pool(Box::new(dispatch));
+                Self::with_workers(&builder, workers, results, input, Some(mapper))
+            }
+            _ => match builder.spawn_fn {
+                Some(ref spawn) => {
+                    Self::start_with::<_, Builtin>(&builder, spawn.spawner(), mapper, input)
+                }
+                None => Self::start_with::<_, Builtin>(&builder, ThreadSpawner, mapper, input),
+            },
+        }
+    }
+
+    /// Start a pipeline feeding input to the threads of a
+    /// PersistentPipeline, or mapping it with a clone of mapper on the
+    /// consumer when the builder has no workers.
+    pub(crate) fn start_persistent(
+        builder: &PipelineBuilder,
+        jobs: &chan::Sender<persistent::Job<Slot, I::Item, M::Out>>,
+        mapper: &M,
+        input: I,
+    ) -> Pipeline<I, M> {
+        let (results_tx, results) = chan::unbounded();
+        if builder.n_workers == 0 {
+            return Self::with_workers(
+                builder,
+                Mapping::Inline,
+                results,
+                input,
+                Some(mapper.clone()),
+            );
+        }
+        let workers = Mapping::Persistent(PersistentDispatcher::new(jobs.clone(), results_tx));
+        Self::with_workers(builder, workers, results, input, None)
+    }
+
+    /// Run the pipeline on a driver thread which keeps up to n
+    /// results ready for the consumer.
+    ///
+    /// Without this, the pipeline only makes progress while the
+    /// consumer is waiting on it, so a consumer that is busy with each
+    /// result in bursts stalls the workers. See Buffered for how the
+    /// driver thread is shut down.
+    pub fn pl_buffer(self, n: usize) -> Buffered<<M as Mapper<I::Item>>::Out>
+    where
+        I: Send + 'static,
+    {
+        Buffered::new(n, self)
+    }
+
+    /// Run the pipeline on a driver thread which sends the results to
+    /// the returned channel, buffering up to n of them.
+    ///
+    /// Unlike iterating over the pipeline, this lets the results be
+    /// received on any thread, or waited for with select! alongside
+    /// other channels. The channel disconnects once every result has
+    /// been sent, or if a mapper panics, in which case the handle's
+    /// join returns the panic.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::PipelineMap;
+    ///
+    /// let (rx, handle) = (0..100).plmap(4, |x| x * 2).into_receiver(16);
+    /// let total: i32 = std::thread::spawn(move || rx.iter().sum()).join().unwrap();
+    /// assert_eq!(total, 9900);
+    /// handle.join().unwrap();
+    /// ```
+    #[cfg(feature = "crossbeam-channel")]
+    pub fn into_receiver(
+        self,
+        n: usize,
+    ) -> (
+        crossbeam_channel::Receiver<<M as Mapper<I::Item>>::Out>,
+        DriverHandle,
+    )
+    where
+        I: Send + 'static,
+    {
+        let (tx, rx) = crossbeam_channel::bounded(n);
+        (rx, self.drive_into(tx))
+    }
+
+    /// Like into_receiver, but sending the results to an unbounded
+    /// std::sync::mpsc channel, for code built around std channels,
+    /// such as GUI event loops.
+    ///
+    /// The driver never waits for the consumer, so a slow consumer
+    /// lets results pile up in the channel.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::PipelineMap;
+    ///
+    /// let (rx, handle) = (0..100).plmap(4, |x| x * 2).into_std_receiver();
+    /// let total: i32 = rx.iter().sum();
+    /// assert_eq!(total, 9900);
+    /// handle.join().unwrap();
+    /// ```
+    pub fn into_std_receiver(self) -> (mpsc::Receiver<<M as Mapper<I::Item>>::Out>, DriverHandle)
+    where
+        I: Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        (rx, self.drive_into(tx))
+    }
+
+    /// Like into_std_receiver, but sending to a std::sync::mpsc
+    /// sync_channel which buffers up to n results.
+    ///
+    /// A driver waiting on a full channel only notices abort once the
+    /// consumer makes room.
+    pub fn into_std_sync_receiver(
+        self,
+        n: usize,
+    ) -> (mpsc::Receiver<<M as Mapper<I::Item>>::Out>, DriverHandle)
+    where
+        I: Send + 'static,
+    {
+        let (tx, rx) = mpsc::sync_channel(n);
+        (rx, self.drive_into(tx))
+    }
+
+    /// Run the pipeline on a driver thread which sends the results, in
+    /// order, to tx.
+    ///
+    /// The driver only takes the next result once the last one has
+    /// been sent, so when a bounded tx is full the pipeline stops
+    /// dispatching input once its in flight limit is reached, giving
+    /// backpressure from the consumer all the way back to the input.
+    /// Several pipelines can drive into clones of the same sender.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::PipelineMap;
+    ///
+    /// let (tx, rx) = std::sync::mpsc::sync_channel(4);
+    /// let handle = (0..100).plmap(4, |x| x * 2).drive_into(tx);
+    /// let total: i32 = rx.iter().sum();
+    /// assert_eq!(total, 9900);
+    /// handle.join().unwrap();
+    /// ```
+    pub fn drive_into<S>(self, tx: S) -> DriverHandle
+    where
+        I: Send + 'static,
+        S: DriverSender<<M as Mapper<I::Item>>::Out>,
+    {
+        DriverHandle::spawn(self, tx)
+    }
 }
 
 impl<I, M> Pipeline<I, M>
 where
     I: Iterator,
-    I::Item: Send + 'static,
-    M: Mapper<I::Item> + Clone + Send + 'static,
-    M::Out: Send + 'static,
+    I::Item: Send,
+    M: Mapper<I::Item> + Clone + Send,
+    M::Out: Send,
+{
+    /// Start a pipeline with workers started by spawner, sending values
+    /// and results over channels made by K.
+    pub(crate) fn start_with<'env, S, K>(
+        builder: &PipelineBuilder,
+        spawner: S,
+        mapper: M,
+        input: I,
+    ) -> Pipeline<I, M>
+    where
+        S: Spawner<'env>,
+        K: Channels<'env>,
+        I::Item: 'env,
+        M: 'env,
+        M::Out: 'env,
+    {
+        let (results_tx, results) = K::unbounded();
+        #[cfg(feature = "testing")]
+        if let Some(seed) = builder.simulate {
+            let workers = Mapping::Simulated(Simulation::new(seed, mapper.clone(), results_tx));
+            return Self::with_workers(builder, workers, results, input, Some(mapper));
+        }
+        let mut instruments = Instruments::new(builder);
+        let workers = if builder.n_workers == 0 {
+            Mapping::Inline
+        } else {
+            Self::spawn_threads::<S, K>(builder, spawner, &mapper, results_tx, &mut instruments)
+        };
+        Self::with_instruments(builder, workers, results, input, Some(mapper), instruments)
+    }
+
+    fn with_workers(
+        builder: &PipelineBuilder,
+        workers: Mapping<I::Item, M>,
+        results: chan::Receiver<(Slot, thread::Result<M::Out>)>,
+        input: I,
+        mapper: Option<M>,
+    ) -> Pipeline<I, M> {
+        let instruments = Instruments::new(builder);
+        Self::with_instruments(builder, workers, results, input, mapper, instruments)
+    }
+
+    /// Like with_workers, with the instruments the workers were started
+    /// with.
+    fn with_instruments(
+        builder: &PipelineBuilder,
+        workers: Mapping<I::Item, M>,
+        results: chan::Receiver<(Slot, thread::Result<M::Out>)>,
+        mut input: I,
+        mapper: Option<M>,
+        instruments: Instruments,
+    ) -> Pipeline<I, M> {
+        let Instruments {
+            span,
+            metrics,
+            stats,
+        } = instruments;
+        if builder.resume_from > 0 {
+            input.nth(builder.resume_from - 1);
+        }
+        let total = match input.size_hint() {
+            (lo, Some(hi)) if lo == hi => Some(hi),
+            _ => None,
+        };
+        let progress = builder.progress.clone().map(|f| Progress::new(f, total));
+        let slow_watch = match builder.slow {
+            Some(ref slow)
+                if spawner::HAS_THREADS && matches!(workers, Mapping::Threads { .. }) =>
+            {
+                Some(stats.watch_slow(slow.clone()))
+            }
+            _ => None,
+        };
+        let depth = builder.initial_in_flight();
+        let mut pipeline = Pipeline {
+            input: Some(input),
+            input_done: false,
+            input_ended: false,
+            fed: false,
+            mapper,
+            n_workers: builder.n_workers,
+            schedule: builder.schedule,
+            results,
+            workers,
+            peeked: None,
+            front: ReorderBuffer::with_capacity(depth),
+            back: ReorderBuffer::with_capacity(0),
+            depth,
+            adaptive: builder
+                .adaptive_in_flight
+                .map(|(min, max)| AdaptiveDepth::new(min, max)),
+            throttle: builder.throttle.map(Throttle::new),
+            key_limit: None,
+            semaphore: builder.semaphore.clone(),
+            permits: 0,
+            wait: builder.wait,
+            window: builder.reorder_window,
+            ready: None,
+            #[cfg(feature = "tokio")]
+            cancel: builder.cancellation.clone().map(Cancellation::new),
+            #[cfg(feature = "signal")]
+            interrupt: builder.ctrl_c.then(Interrupt::register),
+            _span: span,
+            metrics,
+            stats,
+            progress,
+            checkpoint: builder
+                .checkpoint
+                .clone()
+                .map(|f| Checkpoint::new(f, builder.resume_from)),
+            unslotted: builder.resume_from,
+            on_complete: builder.on_complete.clone(),
+            _slow_watch: slow_watch,
+        };
+        if builder.eager && !pipeline.is_inline() {
+            pipeline.fill();
+        }
+        pipeline
+    }
+
+    fn spawn_threads<'env, S, K>(
+        builder: &PipelineBuilder,
+        spawner: S,
+        mapper: &M,
+        results_tx: chan::Sender<(Slot, thread::Result<M::Out>)>,
+        instruments: &mut Instruments,
+    ) -> Mapping<I::Item, M>
+    where
+        S: Spawner<'env>,
+        K: Channels<'env>,
+        I::Item: 'env,
+        M: 'env,
+        M::Out: 'env,
+    {
+        let n_workers = builder.n_workers;
+        let wait = builder.wait;
+        let trace_items = builder.trace_items;
+        let (dispatch, work_queues) = schedule::work_queues::<K, (Slot, Stamp, I::Item)>(
+            builder.schedule,
+            n_workers,
+            builder.max_in_flight(),
+        );
+        let (done_tx, done) = chan::bounded(0);
+        debug!("pipeline started with {} worker threads", n_workers);
+        #[cfg(feature = "numa")]
+        let mut placements = {
+            let topology = numa::Topology::detect();
+            (0..n_workers)
+                .map(|i| topology.cpus_for(builder.placement, i).map(|c| c.to_vec()))
+                .collect::<Vec<_>>()
+                .into_iter()
+        };
+
+        for (worker, work_queue) in work_queues.into_iter().enumerate() {
+            let mapper = mapper.clone();
+            let results_tx = results_tx.clone();
+            let done_tx: chan::Sender<()> = done_tx.clone();
+            let worker_span = trace::WorkerSpan::new(&instruments.span, worker);
+            let mut worker_metrics = instruments.metrics.worker(worker);
+            let worker_stats = instruments.stats.worker();
+            #[cfg(feature = "numa")]
+            let cpus = placements.next().unwrap();
+            spawner.spawn(move || {
+                // Declared first so it is dropped last, after the
+                // mapper.
+                let _done_tx = done_tx;
+                let mut mapper = mapper;
+                #[cfg(feature = "numa")]
+                if let Some(cpus) = cpus {
+                    numa::pin_current_thread(&cpus);
+                }
+                debug!("worker {} started", worker);
+                worker_span.run(|| {
+                    while let Some((slot, stamp, in_val)) = work_queue.recv(wait) {
+                        // Panics are passed on to the consumer, to be
+                        // resumed when it reaches this value.
+                        let out_val = worker_metrics.map(stamp, || {
+                            trace::in_item_span(trace_items, slot.index(), worker, || {
+                                worker_stats.map(slot.index(), stamp, || {
+                                    panic::catch_unwind(AssertUnwindSafe(|| mapper.apply(in_val)))
+                                })
+                            })
+                        });
+                        if out_val.is_err() {
+                            debug!(
+                                "worker {} caught a panic mapping item {}",
+                                worker,
+                                slot.index()
+                            );
+                        }
+                        if results_tx.send((slot, out_val)).is_err() {
+                            break;
+                        }
+                    }
+                });
+                debug!("worker {} exited", worker);
+            });
+        }
+
+        Mapping::Threads { dispatch, done }
+    }
+
+    /// A snapshot of where the pipeline's time has gone so far, see
+    /// PipelineReport.
+    pub fn stats(&self) -> PipelineReport {
+        self.stats.report()
+    }
+
+    /// The number of workers mapping values, zero when values are
+    /// mapped inline on the consumer.
+    pub fn n_workers(&self) -> usize {
+        self.n_workers
+    }
+
+    /// The most values that can be in flight at once, the upper bound
+    /// for an adaptive depth.
+    pub fn in_flight_capacity(&self) -> usize {
+        match self.adaptive {
+            Some(ref a) => a.bounds().1,
+            None => self.depth,
+        }
+    }
+
+    /// The configuration the pipeline is running with, see
+    /// PipelineConfig.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::{PipelineBuilder, Schedule};
+    ///
+    /// let p = PipelineBuilder::new(4)
+    ///     .in_flight(16)
+    ///     .schedule(Schedule::Stealing)
+    ///     .build(0..100, |x| x * 2);
+    /// let config = p.config();
+    /// assert_eq!((config.n_workers, config.in_flight_capacity), (4, 16));
+    /// assert_eq!(config.schedule, Schedule::Stealing);
+    /// ```
+    pub fn config(&self) -> PipelineConfig {
+        PipelineConfig {
+            n_workers: self.n_workers,
+            in_flight_capacity: self.in_flight_capacity(),
+            adaptive_in_flight: self.adaptive.as_ref().map(|a| a.bounds()),
+            schedule: self.schedule,
+            wait: self.wait,
+            reorder_window: self.window,
+        }
+    }
+
+    /// The number of values dispatched to the mapper whose results have
+    /// not come back yet. Like queue_len it is a snapshot, meant for
+    /// logging when a pipeline seems stuck.
+    pub fn in_flight(&self) -> usize {
+        let held = self.key_limit.as_ref().map_or(0, |l| l.held());
+        self.unconsumed().saturating_sub(self.buffered() + held)
+    }
+
+    /// The number of results which are ready but have not been taken
+    /// by the consumer, see in_flight. Results held back waiting for
+    /// an earlier one count as ready, as does a peeked result.
+    pub fn queue_len(&self) -> usize {
+        self.buffered() + self.peeked.is_some() as usize
+    }
+
+    /// The number of ready results held in the reorder buffers and the
+    /// results channel.
+    fn buffered(&self) -> usize {
+        let queued = self.front.ready_len() + self.back.ready_len() + self.results.len();
+        // Results of skipped values are dropped when they arrive.
+        queued.min(self.unconsumed())
+    }
+
+    /// A snapshot of what the pipeline is doing, see PipelineState.
+    /// Only the pipeline's own worker threads are reported on, not
+    /// pools.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::PipelineMap;
+    ///
+    /// let mut p = (0..100).plmap(4, |x| x * 2);
+    /// assert_eq!(p.next(), Some(0));
+    /// let state = p.dump_state();
+    /// for (i, w) in state.workers.iter().enumerate() {
+    ///     if let (Some(item), Some(busy_for)) = (w.item, w.busy_for) {
+    ///         eprintln!("worker {} on item {} for {:?}", i, item, busy_for);
+    ///     }
+    /// }
+    /// ```
+    pub fn dump_state(&mut self) -> PipelineState {
+        // Results which have arrived go to the reorder buffers, so it
+        // is known which of them the consumer would be waiting for.
+        while let Ok(result) = self.results.try_recv() {
+            self.insert_result(result);
+        }
+        let reorder_len = self.front.ready_len() + self.back.ready_len();
+        let head_ready = if self.front.is_empty() {
+            self.back.back_ready()
+        } else {
+            self.front.front_ready(self.window)
+        };
+        PipelineState {
+            workers: self.stats.worker_states(),
+            in_flight: self.in_flight(),
+            reorder_len,
+            head_of_line_blocked: !head_ready && reorder_len > 0,
+            input_done: self.input_ended,
+        }
+    }
+
+    /// Stop the pipeline and report where its time went, see
+    /// PipelineReport. Values still in flight are dropped, the workers
+    /// have returned by the time the report is made.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::PipelineMap;
+    ///
+    /// let mut p = (0..100).plmap(4, |x| x * 2);
+    /// let total: i32 = p.by_ref().sum();
+    /// assert_eq!(total, 9900);
+    /// let report = p.finish();
+    /// assert_eq!(report.workers.iter().map(|w| w.items).sum::<u64>(), 100);
+    /// ```
+    pub fn finish(mut self) -> PipelineReport {
+        let stats = std::mem::replace(&mut self.stats, Stats::new(false));
+        drop(self);
+        stats.report()
+    }
+
+    /// Stop the pipeline, returning the input it has not pulled yet and
+    /// the mapper it was built with, see PipelineParts. Values already
+    /// dispatched are either waited for or dropped, per in_flight.
+    ///
+    /// Once a pipeline is done with, the remaining input can be kept
+    /// for a later run, without losing or repeating any values when
+    /// in_flight is InFlight::Drain. Panics if a drained value's mapper
+    /// panicked, as next would.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::{InFlight, PipelineMap};
+    ///
+    /// let mut p = (0..100).plmap(4, |x| x * 2);
+    /// assert_eq!(p.next(), Some(0));
+    /// let parts = p.into_inner(InFlight::Drain);
+    /// let rest = parts.input.map(parts.mapper.unwrap());
+    /// let rest: Vec<i32> = parts.results.into_iter().chain(rest).collect();
+    /// assert_eq!(rest, (1..100).map(|x| x * 2).collect::<Vec<_>>());
+    /// ```
+    pub fn into_inner(mut self, in_flight: InFlight) -> PipelineParts<I, M, M::Out> {
+        let mut results: Vec<_> = self.peeked.take().into_iter().collect();
+        let mut back_results = Vec::new();
+        if in_flight == InFlight::Drain {
+            while !self.front.is_empty() {
+                match self.take_result(Take::Front(0)) {
+                    Some(v) => results.push(v),
+                    None => break,
+                }
+            }
+            while !self.back.is_empty() {
+                match self.take_result(Take::BackTail) {
+                    Some(v) => back_results.push(v),
+                    None => break,
+                }
+            }
+        }
+        let parts = PipelineParts {
+            input: self.input.take().unwrap(),
+            mapper: self.mapper.take(),
+            results,
+            back_results,
+        };
+        drop(self);
+        parts
+    }
+
+    /// Allow at most limit values with the same key, as computed by
+    /// key, to be mapped at once.
+    ///
+    /// A value whose key is at its limit is held back, taking its
+    /// place in the in-flight window, until a result for that key
+    /// comes back, while values after it with other keys are still
+    /// dispatched. Results are yielded in order as usual. Values
+    /// dispatched by an eager pipeline before the limit is set are not
+    /// counted, and pipelines mapping inline map one value at a time
+    /// anyway.
+    ///
+    /// # Panics
+    ///
+    /// Panics if limit is zero.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::PipelineMap;
+    ///
+    /// // Fetch at most two urls from each host at a time.
+    /// let urls = vec!["a.com/1", "a.com/2", "b.com/1", "a.com/3", "c.com/1"];
+    /// let fetched: Vec<String> = urls
+    ///     .into_iter()
+    ///     .plmap(4, |url: &str| format!("fetched {}", url))
+    ///     .limit_per_key(2, |url: &&str| url.split('/').next().unwrap().to_string())
+    ///     .collect();
+    /// assert_eq!(fetched[3], "fetched a.com/3");
+    /// ```
+    pub fn limit_per_key<K, F>(mut self, limit: usize, key: F) -> Self
+    where
+        K: Hash + Eq + Clone + Send + 'static,
+        I::Item: 'static,
+        F: FnMut(&I::Item) -> K + Send + 'static,
+    {
+        assert!(limit > 0, "limit must be greater than zero");
+        self.key_limit = Some(Box::new(KeyLimiter::new(limit, key)));
+        self
+    }
+
+    /// Skip the next n outputs without mapping them.
+    ///
+    /// Results that are already in flight are discarded, the rest of
+    /// the skipped values are pulled from the input and dropped
+    /// without ever being dispatched to a worker. Unlike
+    /// Iterator::skip, this means the mapper is not run for most
+    /// skipped values, so it should only be used with mappers that
+    /// are free of side effects.
+    pub fn pl_skip(mut self, n: usize) -> Self {
+        self.skip_unmapped(n);
+        self
+    }
+
+    /// Like Iterator::nth, but values before the nth are skipped
+    /// without mapping them, see pl_skip.
+    pub fn pl_nth(&mut self, n: usize) -> Option<<M as Mapper<I::Item>>::Out> {
+        self.skip_unmapped(n);
+        self.next()
+    }
+
+    /// Return a reference to the next result without consuming it,
+    /// waiting for it to be mapped if need be.
+    ///
+    /// Unlike wrapping the pipeline in Peekable, the in-flight window
+    /// is topped up each time, so the workers keep mapping the values
+    /// after the peeked one while it waits to be consumed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::PipelineMap;
+    ///
+    /// let mut p = (0..100).plmap(4, |x| x * 2);
+    /// assert_eq!(p.peek(), Some(&0));
+    /// assert_eq!(p.peek(), Some(&0));
+    /// assert_eq!(p.next(), Some(0));
+    /// assert_eq!(p.peek(), Some(&2));
+    /// assert_eq!(p.sum::<i32>(), 9900);
+    /// ```
+    pub fn peek(&mut self) -> Option<&<M as Mapper<I::Item>>::Out> {
+        self.peek_mut().map(|v| &*v)
+    }
+
+    /// Like peek, but the result can be modified before it is consumed.
+    pub fn peek_mut(&mut self) -> Option<&mut <M as Mapper<I::Item>>::Out> {
+        if self.peeked.is_none() {
+            self.peeked = self.next();
+        }
+        if !self.is_inline() {
+            self.fill();
+        }
+        self.peeked.as_mut()
+    }
+
+    /// Like next, but returns Poll::Pending instead of waiting when the
+    /// next result has not been mapped yet. Pulling input and inline
+    /// mapping still block.
+    pub fn try_next(&mut self) -> Poll<Option<<M as Mapper<I::Item>>::Out>> {
+        let waker = self.ready.take().unwrap_or_else(noop_waker);
+        let v = self.poll_next(&mut Context::from_waker(&waker));
+        if v.is_ready() {
+            // The result after this one may already be waiting, with
+            // nothing left to announce it.
+            waker.wake_by_ref();
+        }
+        self.ready = Some(waker);
+        v
+    }
+
+    /// Take the results that are ready, for at most budget, without
+    /// waiting for results still being mapped, so that a UI or game
+    /// loop can consume a pipeline once a frame while the workers keep
+    /// running. Returns None once the pipeline has ended and there was
+    /// nothing left to take.
+    ///
+    /// As with try_next, pulling input and inline mapping still block,
+    /// and the budget is only checked between results.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use {plmap::PipelineMap, std::time::Duration};
+    ///
+    /// let mut p = (0..100).plmap(4, |x| x * 2);
+    /// let mut total = 0;
+    /// // Once a frame.
+    /// while let Some(results) = p.drain_budgeted(Duration::from_millis(2)) {
+    ///     total += results.iter().sum::<i32>();
+    /// }
+    /// assert_eq!(total, 9900);
+    /// ```
+    pub fn drain_budgeted(&mut self, budget: Duration) -> Option<Vec<<M as Mapper<I::Item>>::Out>> {
+        let start = Instant::now();
+        let mut results = Vec::new();
+        while start.elapsed() < budget {
+            match self.try_next() {
+                Poll::Ready(Some(v)) => results.push(v),
+                Poll::Ready(None) if results.is_empty() => return None,
+                Poll::Ready(None) | Poll::Pending => break,
+            }
+        }
+        Some(results)
+    }
+
+    /// A channel which gets a message when the pipeline may have a
+    /// result ready, so that it can be waited on with select! alongside
+    /// other channels.
+    ///
+    /// After each message call try_next, Poll::Pending means a result
+    /// arrived out of order and another message will follow. Messages
+    /// do not pile up, the channel holds at most one.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use {crossbeam_channel::select, plmap::PipelineMap, std::task::Poll};
+    ///
+    /// let mut p = (0..100).plmap(4, |x| x * 2);
+    /// let ready = p.ready_receiver();
+    /// let ticks = crossbeam_channel::tick(std::time::Duration::from_millis(10));
+    /// let mut total = 0;
+    /// loop {
+    ///     select! {
+    ///         recv(ready) -> _ => match p.try_next() {
+    ///             Poll::Ready(Some(v)) => total += v,
+    ///             Poll::Ready(None) => break,
+    ///             Poll::Pending => (),
+    ///         },
+    ///         recv(ticks) -> _ => (),
+    ///     }
+    /// }
+    /// assert_eq!(total, 9900);
+    /// ```
+    #[cfg(feature = "crossbeam-channel")]
+    pub fn ready_receiver(&mut self) -> crossbeam_channel::Receiver<()> {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        let waker = Waker::from(std::sync::Arc::new(ReadySignal(tx)));
+        // Start the caller off with a try_next, which registers the
+        // waker if nothing is ready.
+        waker.wake_by_ref();
+        self.ready = Some(waker);
+        rx
+    }
+
+    pub(crate) fn skip_unmapped(&mut self, mut n: usize) {
+        if n > 0 && self.peeked.take().is_some() {
+            n -= 1;
+        }
+        while n > 0 && self.front.pop_front().is_some() {
+            n -= 1;
+        }
+        while n > 0 && self.next_input().is_some() {
+            self.unslotted += 1;
+            n -= 1;
+        }
+        while n > 0 && self.back.pop_back().is_some() {
+            n -= 1;
+        }
+    }
+
+    fn is_inline(&self) -> bool {
+        matches!(self.workers, Mapping::Inline)
+    }
+
+    fn map_inline(&mut self, v: I::Item) -> M::Out {
+        let out = self.apply_inline(v);
+        self.completed();
+        out
+    }
+
+    /// Map v on the consumer, without taking the result.
+    fn apply_inline(&mut self, v: I::Item) -> M::Out {
+        if let Some(throttle) = &mut self.throttle {
+            throttle.wait();
+        }
+        self.metrics.dispatched(self.unconsumed());
+        debug_assert!(self.is_inline());
+        let _permit = self.semaphore.as_deref().map(Permit::acquire);
+        self.mapper.as_mut().unwrap().apply(v)
+    }
+
+    /// Record that the consumer has taken a result.
+    fn completed(&mut self) {
+        self.metrics.completed(self.unconsumed());
+        if let Some(progress) = &mut self.progress {
+            progress.completed();
+        }
+        if let Some(checkpoint) = &mut self.checkpoint {
+            checkpoint.done(self.unslotted + self.front.head());
+        }
+    }
+
+    /// Record that every result has been taken.
+    fn exhausted(&mut self) {
+        if let Some(progress) = &mut self.progress {
+            progress.finish();
+        }
+        if let Some(checkpoint) = &mut self.checkpoint {
+            checkpoint.finish(self.unslotted + self.front.head());
+        }
+        if let Some(on_complete) = self.on_complete.take() {
+            self.shut_down();
+            (on_complete.0)(self.stats.report());
+        }
+    }
+
+    /// Stop the workers, waiting for the pipeline's own threads to
+    /// return.
+    fn shut_down(&mut self) {
+        if !self.is_inline() {
+            debug!(
+                "pipeline shutting down with {} values in flight",
+                self.in_flight()
+            );
+        }
+        match &mut self.workers {
+            Mapping::Inline => (),
+            Mapping::Threads { dispatch, done } => {
+                dispatch.close();
+                // Workers stop at their next result rather than working
+                // through everything already dispatched.
+                let (_, dummy) = chan::bounded(0);
+                self.results = dummy;
+                join_workers(done);
+            }
+            Mapping::Pool(dispatch) => dispatch.close(),
+            Mapping::Persistent(dispatch) => dispatch.close(),
+            #[cfg(feature = "testing")]
+            Mapping::Simulated(sim) => sim.close(),
+            #[cfg(feature = "testing")]
+            Mapping::Mocked(sim) => sim.lock().unwrap().close(),
+        }
+    }
+
+    /// Like shut_down, but instead of waiting for the workers to
+    /// return, cx is woken once they have. Dropping the pipeline
+    /// afterwards doesn't block.
+    #[cfg(feature = "stream")]
+    pub(crate) fn poll_shut_down(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        use chan::TryRecvError;
+
+        if let Mapping::Threads { dispatch, done } = &mut self.workers {
+            dispatch.close();
+            let (_, dummy) = chan::bounded(0);
+            self.results = dummy;
+            loop {
+                match done.try_recv_or_register(cx.waker()) {
+                    Ok(()) => (),
+                    Err(TryRecvError::Empty) => return Poll::Pending,
+                    Err(TryRecvError::Disconnected) => break,
+                }
+            }
+        }
+        Poll::Ready(())
+    }
+
+    fn dispatch(&mut self, slot: Slot, v: I::Item) {
+        let admitted = match &mut self.key_limit {
+            Some(limit) => limit.admit(slot, v),
+            None => Some((slot, v)),
+        };
+        if let Some((slot, v)) = admitted {
+            self.send_work(slot, v);
+        }
+    }
+
+    fn send_work(&mut self, slot: Slot, v: I::Item) {
+        if let Some(throttle) = &mut self.throttle {
+            throttle.wait();
+        }
+        self.metrics.dispatched(self.unconsumed());
+        match &mut self.workers {
+            Mapping::Inline => unreachable!(),
+            Mapping::Threads { dispatch, .. } => dispatch.send((slot, self.stats.stamp(), v)),
+            Mapping::Pool(dispatch) => dispatch.send(slot, v),
+            Mapping::Persistent(dispatch) => dispatch.send(slot, v),
+            #[cfg(feature = "testing")]
+            Mapping::Simulated(sim) => sim.dispatch(slot, v),
+            #[cfg(feature = "testing")]
+            Mapping::Mocked(sim) => sim.lock().unwrap().dispatch(slot, v),
+        }
+    }
+
+    /// Let a simulation complete a value, as if a worker finished it.
+    fn simulate_step(&mut self) {
+        #[cfg(feature = "testing")]
+        if let Mapping::Simulated(sim) = &mut self.workers {
+            sim.step();
+        }
+    }
+
+    /// Panic if the pipeline is mocked, rather than wait for a result
+    /// only the test can complete.
+    fn check_stalled(&self) {
+        #[cfg(feature = "testing")]
+        if let Mapping::Mocked(sim) = &self.workers {
+            let dispatched = sim.lock().unwrap().dispatched();
+            panic!(
+                "the pipeline is waiting for one of items {:?}, complete it with MockExecutor::complete",
+                dispatched
+            );
+        }
+    }
+
+    /// The number of values dispatched whose results have not been
+    /// taken by the consumer.
+    fn unconsumed(&self) -> usize {
+        self.front.len() + self.back.len()
+    }
+
+    /// Dispatch input values until the in-flight window is full.
+    ///
+    /// At least one value is always dispatched if the front queue is
+    /// empty, values being mapped from the back of the input can't be
+    /// allowed to starve the front.
+    fn fill(&mut self) {
+        while self.unconsumed() < self.depth || self.front.is_empty() {
+            if !self.acquire_permit(self.front.is_empty()) {
+                break;
+            }
+            match self.next_input() {
+                Some(v) => {
+                    let seq = self.front.push();
+                    self.dispatch(Slot::Front(seq), v);
+                }
+                None => {
+                    self.release_permit();
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Take a permit from the semaphore, if any, for a value about to
+    /// be dispatched, false if there is none to be had and must is not
+    /// set.
+    ///
+    /// While values of this pipeline are in flight the semaphore is
+    /// only tried, then unless must is set the caller waits for
+    /// results instead, whose permits may be all there are. If must is
+    /// set, results are received until a permit comes free.
+    fn acquire_permit(&mut self, must: bool) -> bool {
+        let semaphore = match &self.semaphore {
+            Some(semaphore) => semaphore.clone(),
+            None => return true,
+        };
+        loop {
+            if semaphore.try_acquire() {
+                break;
+            }
+            if self.permits == 0 {
+                semaphore.acquire();
+                break;
+            }
+            if !must {
+                return false;
+            }
+            self.simulate_step();
+            let result = match self.results.try_recv() {
+                Ok(result) => result,
+                Err(_) => {
+                    self.check_stalled();
+                    let (wait, results) = (self.wait, &self.results);
+                    self.stats
+                        .wait(|| wait.recv(results))
+                        .expect("pipeline workers have shut down")
+                }
+            };
+            self.insert_result(result);
+        }
+        self.permits += 1;
+        true
+    }
+
+    fn release_permit(&mut self) {
+        if let Some(semaphore) = &self.semaphore {
+            if self.permits > 0 {
+                self.permits -= 1;
+                semaphore.release();
+            }
+        }
+    }
+
+    /// Wait for a result and take it from the reorder buffers,
+    /// letting the adaptive controller observe whether the consumer had
+    /// to wait.
+    fn take_result(&mut self, take: Take) -> Option<M::Out> {
+        if self.is_cancelled() {
+            return None;
+        }
+        let buffer = match take {
+            Take::Front(_) | Take::FrontTail => &self.front,
+            Take::BackHead | Take::BackTail => &self.back,
+        };
+        if buffer.is_empty() {
+            if self.input_ended && self.unconsumed() == 0 {
+                self.exhausted();
+            }
+            return None;
+        }
+
+        let mut waited = false;
+        while !self.is_ready(take) {
+            self.simulate_step();
+            let result = match self.results.try_recv() {
+                Ok(result) => result,
+                Err(_) => {
+                    self.check_stalled();
+                    waited = true;
+                    // Only possible if the spawner dropped every
+                    // worker without running it, e.g. because its
+                    // runtime is shutting down.
+                    let (wait, results) = (self.wait, &self.results);
+                    self.stats
+                        .wait(|| wait.recv(results))
+                        .expect("pipeline workers have shut down")
+                }
+            };
+            self.insert_result(result);
+        }
+
+        if let Some(adaptive) = self.adaptive.as_mut() {
+            let depth = if waited {
+                adaptive.on_wait(self.depth, !self.input_ended)
+            } else {
+                adaptive.on_ready(self.depth)
+            };
+            if depth != self.depth {
+                debug!(
+                    "in-flight depth {} -> {}, the consumer {}",
+                    self.depth,
+                    depth,
+                    if waited {
+                        "waited"
+                    } else {
+                        "found results ready"
+                    }
+                );
+            }
+            self.depth = depth;
+        }
+
+        let v = match take {
+            Take::Front(window) => self.front.take_ready(window).map(Some),
+            Take::FrontTail => self.front.pop_back(),
+            Take::BackHead => self.back.pop_front(),
+            Take::BackTail => self.back.pop_back(),
+        };
+        self.completed();
+        match v.unwrap().unwrap() {
+            Ok(v) => Some(v),
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+
+    fn is_ready(&self, take: Take) -> bool {
+        match take {
+            Take::Front(window) => self.front.front_ready(window),
+            Take::FrontTail => self.front.back_ready(),
+            Take::BackHead => self.back.front_ready(0),
+            Take::BackTail => self.back.back_ready(),
+        }
+    }
+
+    fn insert_result(&mut self, (slot, v): (Slot, thread::Result<M::Out>)) {
+        self.release_permit();
+        if let Some((slot, v)) = self.key_limit.as_mut().and_then(|l| l.release(slot)) {
+            self.send_work(slot, v);
+        }
+        match slot {
+            Slot::Front(seq) => self.front.insert(seq, v),
+            Slot::Back(seq) => self.back.insert(seq, v),
+        }
+    }
+
+    /// Like next, but instead of waiting for a result, cx is woken once
+    /// one arrives. Pulling input and inline mapping still block.
+    pub(crate) fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<M::Out>> {
+        use chan::TryRecvError;
+
+        if self.is_cancelled() {
+            return Poll::Ready(None);
+        }
+        if let Some(v) = self.peeked.take() {
+            return Poll::Ready(Some(v));
+        }
+        if self.is_inline() && self.front.is_empty() {
+            return Poll::Ready(self.next());
+        }
+
+        self.fill();
+        let take = if self.front.is_empty() {
+            Take::BackTail
+        } else {
+            Take::Front(self.window)
+        };
+        let buffer = match take {
+            Take::Front(_) => &self.front,
+            _ => &self.back,
+        };
+        if !buffer.is_empty() {
+            while !self.is_ready(take) {
+                self.simulate_step();
+                match self.results.try_recv_or_register(cx.waker()) {
+                    Ok(result) => self.insert_result(result),
+                    Err(TryRecvError::Empty) if self.poll_cancelled(cx) => {
+                        return Poll::Ready(None)
+                    }
+                    Err(TryRecvError::Empty) => return Poll::Pending,
+                    Err(TryRecvError::Disconnected) => panic!("pipeline workers have shut down"),
+                }
+            }
+        }
+        Poll::Ready(self.take_result(take))
+    }
+
+    /// Whether a value pushed with push_input would fit in the
+    /// in-flight window, see fill.
+    #[cfg(feature = "stream")]
+    pub(crate) fn has_room(&self) -> bool {
+        self.unconsumed() < self.depth || self.front.is_empty()
+    }
+
+    /// Dispatch v as if it came next from the input, for feeding a
+    /// pipeline from something other than its input iterator. Without
+    /// workers v is mapped straight away.
+    #[cfg(feature = "stream")]
+    pub(crate) fn push_input(&mut self, v: I::Item) {
+        assert!(
+            self.fed && !self.input_ended,
+            "push_input on a pipeline whose input has ended"
+        );
+        let seq = self.front.push();
+        if self.is_inline() {
+            // The result is taken, and counted, with the others.
+            let out = panic::catch_unwind(AssertUnwindSafe(|| self.apply_inline(v)));
+            self.front.insert(seq, out);
+        } else {
+            self.acquire_permit(true);
+            self.dispatch(Slot::Front(seq), v);
+        }
+    }
+
+    /// The Interrupt of a pipeline built with
+    /// PipelineBuilder::cancel_on_ctrl_c.
+    #[cfg(all(test, feature = "signal"))]
+    pub(crate) fn interrupt(&self) -> &Interrupt {
+        self.interrupt.as_ref().unwrap()
+    }
+
+    /// Whether the pipeline's cancellation token, if any, has been
+    /// cancelled, see PipelineBuilder::cancellation_token.
+    pub(crate) fn is_cancelled(&self) -> bool {
+        #[cfg(feature = "tokio")]
+        if let Some(cancel) = &self.cancel {
+            return cancel.is_cancelled();
+        }
+        false
+    }
+
+    /// Whether input should no longer be pulled, as the pipeline has
+    /// been cancelled or interrupted, see
+    /// PipelineBuilder::cancel_on_ctrl_c.
+    fn is_input_stopped(&self) -> bool {
+        #[cfg(feature = "signal")]
+        if let Some(interrupt) = &self.interrupt {
+            if interrupt.is_set() {
+                return true;
+            }
+        }
+        self.is_cancelled()
+    }
+
+    /// Like is_cancelled, but cx is woken on cancellation.
+    pub(crate) fn poll_cancelled(&mut self, _cx: &mut Context<'_>) -> bool {
+        #[cfg(feature = "tokio")]
+        if let Some(cancel) = &mut self.cancel {
+            return cancel.poll(_cx);
+        }
+        false
+    }
+
+    /// Take input values from push_input instead of the input
+    /// iterator, the pipeline is only done once end_input is called.
+    #[cfg(feature = "stream")]
+    pub(crate) fn feed(&mut self) {
+        self.fed = true;
+        // An eager pipeline may already have found its iterator empty.
+        self.input_ended = false;
+    }
+
+    /// Record that no more values will be pushed with push_input.
+    #[cfg(feature = "stream")]
+    pub(crate) fn end_input(&mut self) {
+        self.input_ended = true;
+    }
+
+    /// Record that the input iterator has returned None.
+    fn input_exhausted(&mut self) {
+        self.input_done = true;
+        if !self.fed {
+            self.input_ended = true;
+        }
+    }
+
+    /// Pull the next input value, never polling the input again once it
+    /// has returned None.
+    fn next_input(&mut self) -> Option<I::Item> {
+        let v = if self.input_done || self.is_input_stopped() {
+            None
+        } else {
+            let input = self.input.as_mut().unwrap();
+            let v = self.stats.input(|| input.next());
+            if v.is_none() {
+                self.input_exhausted();
+            }
+            v
+        };
+        if v.is_none() && self.input_ended && self.is_inline() {
+            self.exhausted();
+        }
+        v
+    }
+}
+
+/// Permit holds a permit from a semaphore while a value is mapped on
+/// the consumer, releasing it even if the mapper panics.
+struct Permit<'a>(&'a dyn Semaphore);
+
+impl<'a> Permit<'a> {
+    fn acquire(semaphore: &'a dyn Semaphore) -> Permit<'a> {
+        semaphore.acquire();
+        Permit(semaphore)
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+/// Wait for every worker of a pipeline to return, when the done
+/// channel disconnects. On a multi-threaded tokio runtime the wait
+/// goes through block_in_place, so the runtime's other tasks are moved
+/// off the waiting thread, async code elsewhere can use
+/// Pipeline::close instead.
+fn join_workers(done: &chan::Receiver<()>) {
+    if let Err(chan::TryRecvError::Disconnected) = done.try_recv() {
+        return;
+    }
+    let wait = || while done.recv().is_ok() {};
+    #[cfg(feature = "tokio")]
+    if let Ok(handle) = tokio::runtime::Handle::try_current() {
+        if handle.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread {
+            return tokio::task::block_in_place(wait);
+        }
+    }
+    wait()
+}
+
+impl<I, M> Drop for Pipeline<I, M>
+where
+    I: Iterator,
+    I::Item: Send,
+    M: Mapper<I::Item> + Clone + Send,
+    M::Out: Send,
+{
+    fn drop(&mut self) {
+        self.shut_down();
+        while self.permits > 0 {
+            self.release_permit();
+        }
+    }
+}
+
+impl<In, M> fmt::Debug for Mapping<In, M>
+where
+    M: Mapper<In>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Mapping::Inline => "Inline",
+            Mapping::Threads { .. } => "Threads",
+            Mapping::Pool(_) => "Pool",
+            Mapping::Persistent(_) => "Persistent",
+            #[cfg(feature = "testing")]
+            Mapping::Simulated(_) => "Simulated",
+            #[cfg(feature = "testing")]
+            Mapping::Mocked(_) => "Mocked",
+        })
+    }
+}
+
+impl<I, M> fmt::Debug for Pipeline<I, M>
+where
+    I: Iterator,
+    I::Item: Send,
+    M: Mapper<I::Item> + Clone + Send,
+    M::Out: Send,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pipeline")
+            .field("n_workers", &self.n_workers)
+            .field("workers", &self.workers)
+            .field("in_flight", &self.in_flight())
+            .field("queue_len", &self.queue_len())
+            .field("input_done", &self.input_done)
+            .field("depth", &self.depth)
+            .field("adaptive", &self.adaptive)
+            .field("schedule", &self.schedule)
+            .field("wait", &self.wait)
+            .field("reorder_window", &self.window)
+            .finish()
+    }
+}
+
+impl<I, M> Iterator for Pipeline<I, M>
+where
+    I: Iterator,
+    I::Item: Send,
+    M: Mapper<I::Item> + Clone + Send,
+    M::Out: Send,
+{
+    type Item = <M as Mapper<I::Item>>::Out;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(v) = self.peeked.take() {
+            return Some(v);
+        }
+        if self.is_inline() {
+            let v = self.next_input()?;
+            self.unslotted += 1;
+            return Some(self.map_inline(v));
+        }
+
+        self.fill();
+        if self.front.is_empty() {
+            self.take_result(Take::BackTail)
+        } else {
+            self.take_result(Take::Front(self.window))
+        }
+    }
+
+    // try_fold can't be specialized on stable rust, but fold covers
+    // for_each, sum, collect and friends.
+    fn fold<B, F>(mut self, init: B, mut f: F) -> B
+    where
+        F: FnMut(B, Self::Item) -> B,
+    {
+        let mut acc = match self.peeked.take() {
+            Some(v) => f(init, v),
+            None => init,
+        };
+
+        if self.is_inline() {
+            while let Some(v) = self.next_input() {
+                self.unslotted += 1;
+                acc = f(acc, self.map_inline(v));
+            }
+            return acc;
+        }
+
+        self.fill();
+        while let Some(v) = self.take_result(Take::Front(self.window)) {
+            // Refill the window before running f, so the workers
+            // stay busy while the consumer is.
+            self.fill();
+            acc = f(acc, v);
+        }
+        while let Some(v) = self.take_result(Take::BackTail) {
+            acc = f(acc, v);
+        }
+        acc
+    }
+}
+
+impl<I, M> DoubleEndedIterator for Pipeline<I, M>
+where
+    I: DoubleEndedIterator,
+    I::Item: Send,
+    M: Mapper<I::Item> + Clone + Send,
+    M::Out: Send,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.is_inline() {
+            if !self.input_done && !self.is_input_stopped() {
+                match self.input.as_mut().unwrap().next_back() {
+                    Some(v) => return Some(self.map_inline(v)),
+                    None => self.input_exhausted(),
+                }
+            }
+            let v = self.peeked.take();
+            if v.is_none() && self.input_ended {
+                self.exhausted();
+            }
+            return v;
+        }
+
+        while self.unconsumed() < self.depth || self.back.is_empty() {
+            if self.input_done || self.is_input_stopped() {
+                break;
+            }
+            if !self.acquire_permit(self.back.is_empty()) {
+                break;
+            }
+            match self.input.as_mut().unwrap().next_back() {
+                Some(v) => {
+                    let seq = self.back.push();
+                    self.dispatch(Slot::Back(seq), v);
+                }
+                None => {
+                    self.release_permit();
+                    self.input_exhausted();
+                }
+            }
+        }
+
+        let v = if self.back.is_empty() {
+            self.take_result(Take::FrontTail)
+        } else {
+            self.take_result(Take::BackHead)
+        };
+        // The peeked result is the last one left once the rest have
+        // been taken from the back.
+        v.or_else(|| self.peeked.take())
+    }
+}
+
+impl<I, M> FusedIterator for Pipeline<I, M>
+where
+    I: Iterator,
+    I::Item: Send,
+    M: Mapper<I::Item> + Clone + Send,
+    M::Out: Send,
 {
-    pub fn new(n_workers: usize, mapper: M, input: I) -> Pipeline<I, M> {
-        let (dispatch, dispatch_rx): (
-            crossbeam_channel::Sender<(_, crossbeam_channel::Sender<M::Out>)>,
-            _,
-        ) = crossbeam_channel::bounded(0);
-        let mut workers = Vec::with_capacity(n_workers);
-
-        for _ in 0..n_workers {
-            let mut mapper = mapper.clone();
-            let dispatch_rx = dispatch_rx.clone();
-            let handle = thread::spawn(move || {
-                while let Ok((in_val, respond)) = dispatch_rx.recv() {
-                    let out_val = mapper.apply(in_val);
-                    respond.send(out_val).unwrap();
-                }
-            });
-            workers.push(handle)
-        }
+}
 
-        Pipeline {
-            mapper,
-            input,
-            dispatch,
-            workers,
-            queue: VecDeque::with_capacity(n_workers + 1),
-        }
+/// ReadySignal wakes a select! waiting on a ready_receiver.
+#[cfg(feature = "crossbeam-channel")]
+struct ReadySignal(crossbeam_channel::Sender<()>);
+
+#[cfg(feature = "crossbeam-channel")]
+impl std::task::Wake for ReadySignal {
+    fn wake(self: std::sync::Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &std::sync::Arc<Self>) {
+        let _ = self.0.try_send(());
     }
 }
 
-impl<I, M> Drop for Pipeline<I, M>
+/// A waker that does nothing when woken, for try_next before anything
+/// has asked to be woken.
+fn noop_waker() -> Waker {
+    const VTABLE: RawWakerVTable = RawWakerVTable::new(|_| RAW, |_| (), |_| (), |_| ());
+    const RAW: RawWaker = RawWaker::new(std::ptr::null(), &VTABLE);
+    // Safety: the vtable's functions ignore the data pointer.
+    unsafe { Waker::from_raw(RAW) }
+}
+
+/// PipelineMap can be imported to add the plmap function to iterators,
+/// and to anything else that can be turned into one, such as vectors
+/// and arrays.
+///
+/// # Examples
+///
+/// ```
+/// use plmap::PipelineMap;
+///
+/// let v: Vec<i32> = vec![1, 2, 3].plmap(2, |x| x * 2).collect();
+/// assert_eq!(v, [2, 4, 6]);
+/// let v: Vec<i32> = [1, 2, 3].plmap(2, |x| x * 2).collect();
+/// assert_eq!(v, [2, 4, 6]);
+/// ```
+pub trait PipelineMap<I, M>
 where
     I: Iterator,
     I::Item: Send + 'static,
     M: Mapper<I::Item> + Clone + Send + 'static,
     M::Out: Send + 'static,
 {
-    fn drop(&mut self) {
-        let (dummy, _) = crossbeam_channel::bounded(1);
-        self.dispatch = dummy;
-        for worker in self.workers.drain(..) {
-            worker.join().unwrap();
-        }
-    }
+    fn plmap(self, n_workers: impl Into<Workers>, m: M) -> Pipeline<I, M>;
+
+    /// Like plmap, but values are mapped as jobs on a shared pool,
+    /// running as many at once as the pool has threads.
+    fn plmap_on(self, pool: &PlmapPool, m: M) -> Pipeline<I, M>;
+
+    /// Like plmap_on, using the process wide pool, see PlmapPool::global.
+    #[cfg(feature = "global-pool")]
+    fn plmap_global(self, m: M) -> Pipeline<I, M>;
+
+    /// Like plmap, but values are sent to workers in batches of
+    /// batch_size, see BatchedPipeline.
+    fn plmap_batched(
+        self,
+        n_workers: impl Into<Workers>,
+        batch_size: usize,
+        m: M,
+    ) -> BatchedPipeline<I, M>;
+
+    /// Like plmap followed by collecting into Result<C, E>, but no more
+    /// values are dispatched once an error is taken, and the values in
+    /// flight are discarded before it is returned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::PipelineMap;
+    ///
+    /// let v: Result<Vec<i32>, String> = (0..100).pl_try_collect(4, |x| Ok(x * 2));
+    /// assert_eq!(v.unwrap().len(), 100);
+    /// let v: Result<Vec<i32>, String> =
+    ///     (0..100).pl_try_collect(4, |x| if x == 10 { Err(format!("bad {}", x)) } else { Ok(x) });
+    /// assert_eq!(v, Err("bad 10".to_string()));
+    /// ```
+    fn pl_try_collect<C, T, E>(self, n_workers: impl Into<Workers>, m: M) -> Result<C, E>
+    where
+        M: Mapper<I::Item, Out = Result<T, E>>,
+        T: Send + 'static,
+        E: Send + 'static,
+        C: FromIterator<T>;
+
+    /// Map every value and collect the results in input order, taking
+    /// results as soon as they are ready, in any order, and putting
+    /// each in its place, so a slow value does not hold up the workers
+    /// from mapping those after it, see PipelineBuilder::unordered.
+    ///
+    /// The result vector is sized from the lower bound of the input's
+    /// size_hint, and grown as results arrive.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use {plmap::PipelineMap, std::time::Duration};
+    ///
+    /// let v: Vec<u64> = (0..8u64).pl_collect_stable(4, |x| {
+    ///     std::thread::sleep(Duration::from_millis(8 - x));
+    ///     x * 2
+    /// });
+    /// assert_eq!(v, [0, 2, 4, 6, 8, 10, 12, 14]);
+    /// ```
+    fn pl_collect_stable(self, n_workers: impl Into<Workers>, m: M) -> Vec<M::Out>;
+
+    /// Like plmap, but each result is paired with the index of the
+    /// value it was mapped from, see enumerated.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::PipelineMap;
+    ///
+    /// let v: Vec<(usize, i32)> = (10..13).pl_enumerate(2, |x| x * 2).collect();
+    /// assert_eq!(v, [(0, 20), (1, 22), (2, 24)]);
+    /// ```
+    fn pl_enumerate(
+        self,
+        n_workers: impl Into<Workers>,
+        m: M,
+    ) -> Pipeline<iter::Enumerate<I>, Enumerated<M>>;
+
+    /// Like plmap, but only the values picked by sample are mapped,
+    /// the rest are passed through unmapped or dropped, see Sample.
+    /// Values are picked on the consumer, in input order, so dropped
+    /// values are never dispatched.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::{PipelineMap, Sample, Sampled};
+    ///
+    /// let v: Vec<_> = (0..4).pl_sample(2, Sample::every(2), |x| x * 10).collect();
+    /// assert_eq!(
+    ///     v,
+    ///     [Sampled::Mapped(0), Sampled::Passed(1), Sampled::Mapped(20), Sampled::Passed(3)]
+    /// );
+    /// ```
+    fn pl_sample(
+        self,
+        n_workers: impl Into<Workers>,
+        sample: Sample,
+        m: M,
+    ) -> Pipeline<SampledInput<I>, SampleMapper<M>>;
+
+    /// Like plmap, but values are dispatched highest priority first, as
+    /// computed by priority, among the next lookahead values of the
+    /// input, see Prioritized. Results are yielded in the order the
+    /// values were dispatched, so values of the same priority come out
+    /// in input order, and a value is overtaken by fewer than
+    /// lookahead values read after it.
+    ///
+    /// The input is read up to lookahead values ahead of what has been
+    /// dispatched.
+    ///
+    /// # Panics
+    ///
+    /// Panics if lookahead is zero.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::PipelineMap;
+    ///
+    /// let requests = vec![("batch", 1), ("batch", 2), ("interactive", 3), ("batch", 4)];
+    /// let order: Vec<i32> = requests
+    ///     .into_iter()
+    ///     .pl_prioritized(2, 8, |r: &(&str, i32)| r.0 == "interactive", |r: (&str, i32)| r.1)
+    ///     .collect();
+    /// assert_eq!(order, [3, 1, 2, 4]);
+    /// ```
+    fn pl_prioritized<P, F>(
+        self,
+        n_workers: impl Into<Workers>,
+        lookahead: usize,
+        priority: F,
+        m: M,
+    ) -> Pipeline<Prioritized<I, F, P>, M>
+    where
+        F: FnMut(&I::Item) -> P,
+        P: Ord;
+
+    /// Like pl_enumerate, but of the next lookahead values of the
+    /// input the one read most recently is dispatched first, as from a
+    /// stack, which can make better use of caches when related values
+    /// arrive together. Results are yielded in the order the values
+    /// were dispatched, paired with their index in the input.
+    ///
+    /// Values read earlier wait while later ones keep arriving, up to
+    /// lookahead - 1 of them until the input runs out.
+    ///
+    /// # Panics
+    ///
+    /// Panics if lookahead is zero.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::PipelineMap;
+    ///
+    /// let v: Vec<(usize, char)> = "abcd"
+    ///     .chars()
+    ///     .pl_lifo(2, 4, |c: char| c.to_ascii_uppercase())
+    ///     .collect();
+    /// assert_eq!(v, [(3, 'D'), (2, 'C'), (1, 'B'), (0, 'A')]);
+    /// ```
+    fn pl_lifo(
+        self,
+        n_workers: impl Into<Workers>,
+        lookahead: usize,
+        m: M,
+    ) -> Pipeline<Lifo<I>, Enumerated<M>>;
+
+    /// Like plmap, but results are yielded in the order of a key, such
+    /// as a timestamp, for input that arrives slightly out of order,
+    /// see KeyOrdered. A value read up to lateness values after ones
+    /// with a greater key is still dispatched, and yielded, before
+    /// them. Values with the same key keep their input order.
+    ///
+    /// Only lateness + 1 values are held back at once, so a value that
+    /// is later than that comes out as soon as it is read, out of key
+    /// order.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::PipelineMap;
+    ///
+    /// let events = vec![(1, "a"), (3, "c"), (2, "b"), (4, "d")];
+    /// let v: Vec<String> = events
+    ///     .into_iter()
+    ///     .pl_ordered_by_key(2, 1, |e: &(u32, &str)| e.0, |e: (u32, &str)| e.1.to_uppercase())
+    ///     .collect();
+    /// assert_eq!(v, ["A", "B", "C", "D"]);
+    /// ```
+    fn pl_ordered_by_key<K, F>(
+        self,
+        n_workers: impl Into<Workers>,
+        lateness: usize,
+        key: F,
+        m: M,
+    ) -> Pipeline<KeyOrdered<I, F, K>, M>
+    where
+        F: FnMut(&I::Item) -> K,
+        K: Ord;
 }
 
-impl<I, M> Iterator for Pipeline<I, M>
+impl<T, M> PipelineMap<T::IntoIter, M> for T
 where
-    I: Iterator,
-    I::Item: Send + 'static,
-    M: Mapper<I::Item> + Clone + Send + 'static,
+    T: IntoIterator,
+    T::Item: Send + 'static,
+    M: Mapper<T::Item> + Clone + Send + 'static,
     M::Out: Send + 'static,
 {
-    type Item = <M as Mapper<I::Item>>::Out;
+    fn plmap(self, n_workers: impl Into<Workers>, m: M) -> Pipeline<T::IntoIter, M> {
+        Pipeline::new(n_workers, m, self.into_iter())
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.workers.is_empty() {
-            return self.input.next().map(|v| self.mapper.apply(v));
-        }
+    fn plmap_on(self, pool: &PlmapPool, m: M) -> Pipeline<T::IntoIter, M> {
+        PipelineBuilder::new(pool.n_threads())
+            .pool(pool)
+            .build(self.into_iter(), m)
+    }
 
-        while self.queue.len() < self.workers.len() + 1 {
-            match self.input.next() {
-                Some(v) => {
-                    let (tx, rx) = crossbeam_channel::bounded(1);
-                    self.queue.push_back(rx);
-                    self.dispatch.send((v, tx)).unwrap();
-                }
-                None => break,
+    #[cfg(feature = "global-pool")]
+    fn plmap_global(self, m: M) -> Pipeline<T::IntoIter, M> {
+        self.plmap_on(PlmapPool::global(), m)
+    }
+
+    fn plmap_batched(
+        self,
+        n_workers: impl Into<Workers>,
+        batch_size: usize,
+        m: M,
+    ) -> BatchedPipeline<T::IntoIter, M> {
+        BatchedPipeline::new(n_workers, batch_size, m, self.into_iter())
+    }
+
+    fn pl_try_collect<C, U, E>(self, n_workers: impl Into<Workers>, m: M) -> Result<C, E>
+    where
+        M: Mapper<T::Item, Out = Result<U, E>>,
+        U: Send + 'static,
+        E: Send + 'static,
+        C: FromIterator<U>,
+    {
+        try_collect(self.plmap(n_workers, m))
+    }
+
+    fn pl_collect_stable(self, n_workers: impl Into<Workers>, m: M) -> Vec<M::Out> {
+        let input = self.into_iter();
+        let mut slots: Vec<Option<M::Out>> = Vec::with_capacity(input.size_hint().0);
+        let p = PipelineBuilder::new(n_workers)
+            .unordered()
+            .build(input.enumerate(), enumerated(m));
+        for (i, out) in p {
+            if i >= slots.len() {
+                slots.resize_with(i + 1, || None);
             }
+            slots[i] = Some(out);
         }
+        slots
+            .into_iter()
+            .map(|out| out.expect("every value has a result"))
+            .collect()
+    }
 
-        self.queue.pop_front().map(|rx| rx.recv().unwrap())
+    fn pl_enumerate(
+        self,
+        n_workers: impl Into<Workers>,
+        m: M,
+    ) -> Pipeline<iter::Enumerate<T::IntoIter>, Enumerated<M>> {
+        Pipeline::new(n_workers, enumerated(m), self.into_iter().enumerate())
+    }
+
+    fn pl_sample(
+        self,
+        n_workers: impl Into<Workers>,
+        sample: Sample,
+        m: M,
+    ) -> Pipeline<SampledInput<T::IntoIter>, SampleMapper<M>> {
+        let input = SampledInput::new(self.into_iter(), sample);
+        Pipeline::new(n_workers, SampleMapper(m), input)
+    }
+
+    fn pl_prioritized<P, F>(
+        self,
+        n_workers: impl Into<Workers>,
+        lookahead: usize,
+        priority: F,
+        m: M,
+    ) -> Pipeline<Prioritized<T::IntoIter, F, P>, M>
+    where
+        F: FnMut(&T::Item) -> P,
+        P: Ord,
+    {
+        Pipeline::new(
+            n_workers,
+            m,
+            Prioritized::new(self.into_iter(), lookahead, priority),
+        )
+    }
+
+    fn pl_lifo(
+        self,
+        n_workers: impl Into<Workers>,
+        lookahead: usize,
+        m: M,
+    ) -> Pipeline<Lifo<T::IntoIter>, Enumerated<M>> {
+        let input = self.into_iter().enumerate();
+        Pipeline::new(
+            n_workers,
+            enumerated(m),
+            Prioritized::new(input, lookahead, prioritized::newest as fn(&_) -> usize),
+        )
+    }
+
+    fn pl_ordered_by_key<K, F>(
+        self,
+        n_workers: impl Into<Workers>,
+        lateness: usize,
+        key: F,
+        m: M,
+    ) -> Pipeline<KeyOrdered<T::IntoIter, F, K>, M>
+    where
+        F: FnMut(&T::Item) -> K,
+        K: Ord,
+    {
+        Pipeline::new(
+            n_workers,
+            m,
+            KeyOrdered::new(self.into_iter(), lateness, key),
+        )
     }
 }
 
-/// PipelineMap can be imported to add the plmap function to iterators.
-pub trait PipelineMap<I, M>
+/// Collect the results of p until the first error, see
+/// PipelineMap::pl_try_collect.
+pub(crate) fn try_collect<I, M, C, T, E>(mut p: Pipeline<I, M>) -> Result<C, E>
 where
     I: Iterator,
-    I::Item: Send + 'static,
-    M: Mapper<I::Item> + Clone + Send + 'static,
-    M::Out: Send + 'static,
+    I::Item: Send,
+    M: Mapper<I::Item, Out = Result<T, E>> + Clone + Send,
+    T: Send,
+    E: Send,
+    C: FromIterator<T>,
 {
-    fn plmap(self, n_workers: usize, m: M) -> Pipeline<I, M>;
+    let mut err = None;
+    let c = iter::from_fn(|| match p.next()? {
+        Ok(v) => Some(v),
+        Err(e) => {
+            err = Some(e);
+            None
+        }
+    })
+    .collect();
+    match err {
+        Some(e) => {
+            p.into_inner(InFlight::Discard);
+            Err(e)
+        }
+        None => Ok(c),
+    }
 }
 
-impl<I, M> PipelineMap<I, M> for I
-where
-    I: Iterator,
-    <I as Iterator>::Item: Send + 'static,
-    M: Mapper<I::Item> + Clone + Send + 'static,
-    <M as Mapper<I::Item>>::Out: Send + 'static,
-{
-    fn plmap(self, n_workers: usize, m: M) -> Pipeline<I, M> {
-        Pipeline::new(n_workers, m, self)
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+
+    /// Model a pipeline with a bounded number of preemptions, unbounded
+    /// exploration of the worker threads takes too long.
+    fn model(f: impl Fn() + Sync + Send + 'static) {
+        let mut builder = loom::model::Builder::new();
+        builder.preemption_bound = Some(3);
+        builder.check(f);
+    }
+
+    #[test]
+    fn loom_pipeline_order() {
+        model(|| {
+            let results: Vec<i32> = (0..3).plmap(2, |x| x * 2).collect();
+            assert_eq!(results, [0, 2, 4]);
+        });
+    }
+
+    #[test]
+    fn loom_pipeline_drop_in_flight() {
+        model(|| {
+            let mut pipeline = (0..3).plmap(1, |x| x * 2);
+            assert_eq!(pipeline.next(), Some(0));
+            drop(pipeline);
+        });
+    }
+}
+
+#[cfg(all(test, shuttle, not(loom)))]
+mod shuttle_tests {
+    use {super::*, std::panic};
+
+    const ITERATIONS: usize = 1000;
+
+    #[test]
+    fn shuttle_pipeline_order() {
+        shuttle::check_random(
+            || {
+                for w in 1..4 {
+                    let results: Vec<i32> = (0..10).plmap(w, |x| x * 2).collect();
+                    assert_eq!(results, (0..10).map(|x| x * 2).collect::<Vec<_>>());
+                }
+            },
+            ITERATIONS,
+        );
+    }
+
+    #[test]
+    fn shuttle_pipeline_drop_in_flight() {
+        shuttle::check_random(
+            || {
+                let mut pipeline = (0..10).plmap(3, |x| x * 2);
+                assert_eq!(pipeline.next(), Some(0));
+                assert_eq!(pipeline.next(), Some(2));
+                drop(pipeline);
+            },
+            ITERATIONS,
+        );
+    }
+
+    #[test]
+    fn shuttle_pipeline_panic() {
+        shuttle::check_random(
+            || {
+                let mut pipeline = (0..10).plmap(3, |x| {
+                    if x == 5 {
+                        panic!("boom");
+                    }
+                    x
+                });
+                for i in 0..5 {
+                    assert_eq!(pipeline.next(), Some(i));
+                }
+                let payload =
+                    panic::catch_unwind(panic::AssertUnwindSafe(|| pipeline.next())).unwrap_err();
+                assert_eq!(payload.downcast_ref::<&str>(), Some(&"boom"));
+            },
+            ITERATIONS,
+        );
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use {
+        super::*,
+        crate::{Schedule, WaitStrategy},
+    };
+
+    // Fails to build, rather than to run, if Pipeline stops being Send
+    // for some combination of features.
+    #[test]
+    fn test_pipeline_is_send() {
+        fn assert_send<T: Send>() {}
+        #[allow(dead_code)]
+        fn pipeline_is_send<I, M>()
+        where
+            I: Iterator + Send,
+            I::Item: Send,
+            M: Mapper<I::Item> + Clone + Send,
+            M::Out: Send,
+        {
+            assert_send::<Pipeline<I, M>>();
+            assert_send::<PipelineParts<I, M, M::Out>>();
+        }
+
+        for w in 0..3 {
+            let p = (0..100).plmap(w, |x| x * 2);
+            let v = std::thread::spawn(move || p.collect::<Vec<_>>())
+                .join()
+                .unwrap();
+            assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+        }
+    }
 
     #[test]
     fn test_parallel_pipeline() {
@@ -138,4 +2252,613 @@ mod tests {
             assert_eq!((0..100).plmap(w, |x| x * 2).count(), 100);
         }
     }
+
+    // Yields None after every third value, but keeps going if polled again.
+    struct Unfused(i32);
+
+    impl Iterator for Unfused {
+        type Item = i32;
+
+        fn next(&mut self) -> Option<i32> {
+            self.0 += 1;
+            if self.0 % 3 == 0 {
+                None
+            } else {
+                Some(self.0)
+            }
+        }
+    }
+
+    #[test]
+    fn test_pipeline_fused() {
+        for w in 0..3 {
+            let mut p = Unfused(0).plmap(w, |x| x * 2);
+            assert_eq!(p.next(), Some(2));
+            assert_eq!(p.next(), Some(4));
+            for _ in 0..5 {
+                assert_eq!(p.next(), None);
+            }
+        }
+    }
+
+    #[test]
+    fn test_pipeline_peek() {
+        for w in 0..3 {
+            let mut p = (0..10).plmap(w, |x| x * 2);
+            assert_eq!(p.peek(), Some(&0));
+            assert_eq!(p.peek(), Some(&0));
+            if !p.is_inline() {
+                // The window is topped up behind the peeked result.
+                assert_eq!(p.unconsumed(), p.depth);
+            }
+            assert_eq!(p.next(), Some(0));
+            *p.peek_mut().unwrap() += 1;
+            assert_eq!(p.next(), Some(3));
+            assert_eq!(p.peek(), Some(&4));
+            assert_eq!(p.pl_nth(1), Some(6));
+            assert_eq!(p.peek(), Some(&8));
+            assert_eq!(p.next_back(), Some(18));
+            assert_eq!(p.peek(), Some(&8));
+            assert_eq!(p.by_ref().rev().collect::<Vec<_>>(), [16, 14, 12, 10, 8]);
+            assert_eq!(p.peek(), None);
+
+            let mut p = (0..10).plmap(w, |x| x * 2);
+            assert_eq!(p.peek(), Some(&0));
+            assert_eq!(p.sum::<i32>(), 90);
+
+            let mut p = (0..10).plmap(w, |x| x * 2);
+            assert_eq!(p.peek(), Some(&0));
+            let parts = p.into_inner(InFlight::Drain);
+            let mut rest = parts.results;
+            rest.extend(parts.input.map(parts.mapper.unwrap()));
+            assert_eq!(rest, (0..10).map(|x| x * 2).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_pipeline_into_inner() {
+        for w in 0..3 {
+            let mut p = (0..100).plmap(w, |x| x * 2);
+            assert_eq!(p.next(), Some(0));
+            assert_eq!(p.next_back(), Some(198));
+            assert_eq!(p.next_back(), Some(196));
+            let parts = p.into_inner(InFlight::Drain);
+            let mut rest = parts.results;
+            rest.extend(parts.input.map(parts.mapper.unwrap()));
+            rest.extend(parts.back_results);
+            assert_eq!(rest, (1..98).map(|x| x * 2).collect::<Vec<_>>());
+
+            let mut p = (0..100).plmap(w, |x| x * 2);
+            assert_eq!(p.next(), Some(0));
+            // Only the values that were in flight are lost.
+            let next = if p.is_inline() { 1 } else { p.depth as i32 };
+            let mut parts = p.into_inner(InFlight::Discard);
+            assert!(parts.results.is_empty() && parts.back_results.is_empty());
+            assert_eq!(parts.input.next(), Some(next));
+        }
+    }
+
+    #[test]
+    fn test_pipeline_config() {
+        let p = (0..10).plmap(0, |x| x);
+        assert_eq!((p.n_workers(), p.in_flight_capacity()), (0, 1));
+        let p = PipelineBuilder::new(3)
+            .adaptive_in_flight(2, 8)
+            .wait_strategy(WaitStrategy::Yield)
+            .build(0..10, |x| x);
+        let config = p.config();
+        assert_eq!(config.n_workers, 3);
+        assert_eq!(config.in_flight_capacity, 8);
+        assert_eq!(config.adaptive_in_flight, Some((2, 8)));
+        assert_eq!(config.wait, WaitStrategy::Yield);
+        assert_eq!(p.boxed().config(), config);
+    }
+
+    #[test]
+    fn test_pipeline_enumerate() {
+        for w in 0..3 {
+            let v: Vec<(usize, i32)> = (0..100).pl_enumerate(w, |x| x * 2).collect();
+            assert_eq!(v, (0..100).map(|x| (x as usize, x * 2)).collect::<Vec<_>>());
+
+            let mut p = (0..100).pl_enumerate(w, |x| x * 2).pl_skip(10);
+            assert_eq!(p.next(), Some((10, 20)));
+            assert_eq!(p.next_back(), Some((99, 198)));
+        }
+    }
+
+    #[test]
+    fn test_pipeline_try_collect() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        for w in 0..3 {
+            let v: Result<Vec<i32>, i32> = (0..100).pl_try_collect(w, |x| Ok(x * 2));
+            assert_eq!(v, Ok((0..100).map(|x| x * 2).collect()));
+
+            let calls = Arc::new(AtomicUsize::new(0));
+            let c = calls.clone();
+            let v: Result<Vec<i32>, i32> = (0..1000).pl_try_collect(w, move |x| {
+                c.fetch_add(1, Ordering::SeqCst);
+                if x == 10 {
+                    Err(x)
+                } else {
+                    Ok(x)
+                }
+            });
+            assert_eq!(v, Err(10));
+            assert!(calls.load(Ordering::SeqCst) < 100);
+        }
+    }
+
+    #[test]
+    fn test_pipeline_collect_stable() {
+        for w in 0..3 {
+            let v = (0..100u64).pl_collect_stable(w, |x| {
+                if x % 10 == 0 {
+                    thread::sleep(Duration::from_millis(2));
+                }
+                x * 2
+            });
+            assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+
+            // Without a size hint.
+            let v = (0..50)
+                .filter(|x| x % 2 == 0)
+                .pl_collect_stable(w, |x: i32| x + 1);
+            assert_eq!(v, (0..50).step_by(2).map(|x| x + 1).collect::<Vec<_>>());
+            assert!(iter::empty::<i32>().pl_collect_stable(w, |x| x).is_empty());
+
+            // The upper bound of the size hint is not allocated up front.
+            let v = (0..usize::MAX)
+                .take_while(|&x| x < 10)
+                .pl_collect_stable(w, |x| x);
+            assert_eq!(v, (0..10).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_pipeline_into_iterator() {
+        let expected: Vec<i32> = (0..10).map(|x| x * 2).collect();
+        for w in 0..3 {
+            let v: Vec<i32> = (0..10).collect::<Vec<_>>().plmap(w, |x| x * 2).collect();
+            assert_eq!(v, expected);
+            let v: Vec<i32> = [0, 1, 2].plmap(w, |x| x * 2).collect();
+            assert_eq!(v, [0, 2, 4]);
+            let v: Vec<i32> = Some(3).plmap(w, |x| x * 2).collect();
+            assert_eq!(v, [6]);
+            let v: Vec<i32> = vec![0, 1, 2, 3].plmap_batched(w, 3, |x| x * 2).collect();
+            assert_eq!(v, [0, 2, 4, 6]);
+        }
+    }
+
+    #[test]
+    fn test_pipeline_skip_unmapped() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        for w in 0..3 {
+            let calls = Arc::new(AtomicUsize::new(0));
+            let c = calls.clone();
+            let mut p = (0..100)
+                .plmap(w, move |x| {
+                    c.fetch_add(1, Ordering::SeqCst);
+                    x * 2
+                })
+                .pl_skip(50);
+            assert_eq!(p.next(), Some(100));
+            assert_eq!(p.pl_nth(10), Some(122));
+            assert_eq!(p.pl_nth(100), None);
+            drop(p);
+            assert!(calls.load(Ordering::SeqCst) <= 2 * (w + 1));
+        }
+    }
+
+    #[test]
+    fn test_pipeline_fold() {
+        for w in 0..3 {
+            let sum: i32 = (0..100).plmap(w, |x| x * 2).sum();
+            assert_eq!(sum, 9900);
+            let v: Vec<i32> = (0..100).plmap(w, |x| x * 2).collect();
+            assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+            let mut p = (0..100).plmap(w, |x| x * 2);
+            p.next();
+            assert_eq!(p.fold(0, |n, _| n + 1), 99);
+        }
+    }
+
+    #[test]
+    fn test_pipeline_double_ended() {
+        for w in 0..3 {
+            let v: Vec<i32> = (0..100).plmap(w, |x| x * 2).rev().collect();
+            assert_eq!(v, (0..100).rev().map(|x| x * 2).collect::<Vec<_>>());
+
+            let mut p = (0..101).plmap(w, |x| x * 2);
+            for i in 0..50 {
+                assert_eq!(p.next(), Some(i * 2));
+                assert_eq!(p.next_back(), Some((100 - i) * 2));
+            }
+            assert_eq!(p.next_back(), Some(100));
+            assert_eq!(p.next(), None);
+            assert_eq!(p.next_back(), None);
+
+            let mut p = (0..10).plmap(w, |x| x * 2);
+            assert_eq!(p.next_back(), Some(18));
+            assert_eq!(
+                p.collect::<Vec<_>>(),
+                (0..9).map(|x| x * 2).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn test_pipeline_in_flight() {
+        use std::{thread, time::Duration};
+
+        for n in 1..20 {
+            let v: Vec<i32> = PipelineBuilder::new(2)
+                .in_flight(n)
+                .build(0..100, |x| x * 2)
+                .collect();
+            assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+        }
+
+        // A slow head of line item should deepen the window.
+        let mut p = PipelineBuilder::new(4)
+            .adaptive_in_flight(2, 8)
+            .build(0..100, |x| {
+                if x % 10 == 0 {
+                    thread::sleep(Duration::from_millis(5));
+                }
+                x * 2
+            });
+        let mut max_depth = 0;
+        for i in 0..100 {
+            assert_eq!(p.next(), Some(i * 2));
+            max_depth = max_depth.max(p.depth);
+            assert!(p.depth >= 2 && p.depth <= 8);
+        }
+        assert!(max_depth > 5);
+        assert_eq!(p.next(), None);
+    }
+
+    #[test]
+    fn test_pipeline_in_flight_and_queue_len() {
+        use std::{
+            sync::{Arc, Barrier},
+            thread,
+            time::{Duration, Instant},
+        };
+
+        let barrier = Arc::new(Barrier::new(2));
+        let b = barrier.clone();
+        let mut p = PipelineBuilder::new(2).in_flight(4).build(0..4, move |x| {
+            if x == 0 {
+                b.wait();
+            }
+            x
+        });
+        assert_eq!((p.in_flight(), p.queue_len()), (0, 0));
+        p.fill();
+        // The head is stuck, the rest come back and wait behind it.
+        let start = Instant::now();
+        while p.queue_len() < 3 && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!((p.in_flight(), p.queue_len()), (1, 3));
+        barrier.wait();
+        assert_eq!(p.next(), Some(0));
+        assert_eq!((p.in_flight(), p.queue_len()), (0, 3));
+        assert_eq!(p.count(), 3);
+
+        let mut p = (0..4).plmap(0, |x| x);
+        assert_eq!(p.next(), Some(0));
+        assert_eq!((p.in_flight(), p.queue_len()), (0, 0));
+    }
+
+    #[test]
+    fn test_pipeline_debug() {
+        let mut p = PipelineBuilder::new(2).in_flight(3).build(0..4, |x| x);
+        assert_eq!(p.next(), Some(0));
+        let s = format!("{:?}", p);
+        assert!(
+            s.starts_with("Pipeline { n_workers: 2, workers: Threads,"),
+            "{}",
+            s
+        );
+        assert!(s.contains("input_done: false, depth: 3,"), "{}", s);
+        assert!(
+            s.contains("schedule: Shared, wait: Block, reorder_window: 0 }"),
+            "{}",
+            s
+        );
+
+        let mut p = (0..1).plmap(0, |x| x);
+        assert_eq!(p.by_ref().count(), 1);
+        let s = format!("{:?}", p);
+        assert!(s.contains("workers: Inline, in_flight: 0, queue_len: 0, input_done: true,"));
+    }
+
+    #[cfg(any(feature = "sequential", miri))]
+    #[test]
+    fn test_sequential() {
+        use crate::{PersistentPipeline, PlmapPool, ScopedPipelineMap};
+
+        let consumer = thread::current().id();
+        let on_consumer = move |x: i32| {
+            assert_eq!(thread::current().id(), consumer);
+            x * 2
+        };
+        let expected: Vec<i32> = (0..100).map(|x| x * 2).collect();
+        assert_eq!((0..100).plmap(4, on_consumer).collect::<Vec<_>>(), expected);
+        let p = PipelineBuilder::new(4).build(0..100, on_consumer);
+        assert!(format!("{:?}", p).contains("workers: Inline"));
+        assert_eq!(p.collect::<Vec<_>>(), expected);
+        let pool = PlmapPool::new(2);
+        assert_eq!(
+            (0..100).plmap_on(&pool, on_consumer).collect::<Vec<_>>(),
+            expected
+        );
+        thread::scope(|s| {
+            let v: Vec<i32> = (0..100).scoped_plmap(s, 4, on_consumer).collect();
+            assert_eq!(v, expected);
+        });
+        let persistent = PersistentPipeline::new(2, on_consumer);
+        assert_eq!(persistent.feed(0..100).collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_pipeline_schedules() {
+        use std::{
+            sync::{mpsc, Arc, Mutex},
+            time::Duration,
+        };
+
+        for &schedule in &[Schedule::Shared, Schedule::RoundRobin, Schedule::Stealing] {
+            for w in 0..3 {
+                let v: Vec<i32> = PipelineBuilder::new(w)
+                    .schedule(schedule)
+                    .in_flight(7)
+                    .build(0..100, |x| x * 2)
+                    .collect();
+                assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+            }
+        }
+
+        // With two workers value 2 is queued behind value 0, which
+        // waits for it, stealing lets the other worker take it.
+        let (mapped, wait) = mpsc::channel();
+        let wait = Arc::new(Mutex::new(wait));
+        let v: Vec<bool> = PipelineBuilder::new(2)
+            .schedule(Schedule::Stealing)
+            .in_flight(10)
+            .build(0..10, move |x| match x {
+                0 => wait
+                    .lock()
+                    .unwrap()
+                    .recv_timeout(Duration::from_secs(5))
+                    .is_ok(),
+                2 => mapped.send(()).is_ok(),
+                _ => true,
+            })
+            .collect();
+        assert_eq!(v, [true; 10]);
+    }
+
+    #[test]
+    fn test_pipeline_wait_strategies() {
+        let strategies = [
+            WaitStrategy::Block,
+            WaitStrategy::SpinThenPark(100),
+            WaitStrategy::Yield,
+        ];
+        for &wait in &strategies {
+            for &schedule in &[Schedule::Shared, Schedule::Stealing] {
+                let v: Vec<i32> = PipelineBuilder::new(3)
+                    .schedule(schedule)
+                    .wait_strategy(wait)
+                    .build(0..100, |x| x * 2)
+                    .collect();
+                assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+            }
+        }
+    }
+
+    #[test]
+    fn test_pipeline_panic() {
+        for w in 1..3 {
+            let result = panic::catch_unwind(|| {
+                (0..100)
+                    .plmap(w, |x| {
+                        if x == 50 {
+                            panic!("boom");
+                        }
+                        x
+                    })
+                    .count()
+            });
+            let payload = result.unwrap_err();
+            assert_eq!(payload.downcast_ref::<&str>(), Some(&"boom"));
+        }
+
+        // Values before the panic are unaffected.
+        let mut p = (0..100).plmap(2, |x| {
+            if x == 50 {
+                panic!("boom");
+            }
+            x
+        });
+        for i in 0..50 {
+            assert_eq!(p.next(), Some(i));
+        }
+    }
+
+    #[test]
+    fn test_pipeline_buffer() {
+        use std::{
+            sync::{
+                atomic::{AtomicUsize, Ordering},
+                Arc,
+            },
+            time::{Duration, Instant},
+        };
+
+        let mapped = Arc::new(AtomicUsize::new(0));
+        let m = mapped.clone();
+        let mut b = (0..100)
+            .plmap(2, move |x| {
+                m.fetch_add(1, Ordering::SeqCst);
+                x * 2
+            })
+            .pl_buffer(20);
+        // The pipeline runs ahead without the consumer.
+        let start = Instant::now();
+        while mapped.load(Ordering::SeqCst) < 20 && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(mapped.load(Ordering::SeqCst) >= 20);
+        assert_eq!(b.next(), Some(0));
+        assert_eq!(b.sum::<i32>(), 9900);
+    }
+
+    #[test]
+    fn test_pipeline_eager() {
+        use std::{
+            sync::{
+                atomic::{AtomicUsize, Ordering},
+                Arc,
+            },
+            time::{Duration, Instant},
+        };
+
+        let mapped = Arc::new(AtomicUsize::new(0));
+        let m = mapped.clone();
+        let p = PipelineBuilder::new(2).eager().build(0..100, move |x| {
+            m.fetch_add(1, Ordering::SeqCst);
+            x * 2
+        });
+        // Values are dispatched before the first call to next.
+        let start = Instant::now();
+        while mapped.load(Ordering::SeqCst) == 0 && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(mapped.load(Ordering::SeqCst) >= 1);
+        assert_eq!(p.sum::<i32>(), 9900);
+    }
+
+    #[test]
+    fn test_pipeline_reorder_window() {
+        use std::{thread, time::Duration};
+
+        let slow = |x: i32| {
+            if x % 10 == 0 {
+                thread::sleep(Duration::from_millis(20));
+            }
+            x
+        };
+        for &k in &[0, 1, 3, usize::MAX] {
+            let v: Vec<i32> = PipelineBuilder::new(4)
+                .in_flight(8)
+                .reorder_window(k)
+                .build(0..100, slow)
+                .collect();
+            let mut sorted = v.clone();
+            sorted.sort_unstable();
+            assert_eq!(sorted, (0..100).collect::<Vec<_>>());
+            for (i, x) in v.iter().enumerate() {
+                // Nothing is yielded more than k places early.
+                assert!((*x as usize) <= i.saturating_add(k));
+            }
+            if k == 0 {
+                assert_eq!(v, sorted);
+            }
+        }
+
+        let v: Vec<i32> = PipelineBuilder::new(4)
+            .in_flight(8)
+            .unordered()
+            .build(0..20, slow)
+            .collect();
+        // The quick values overtake the slow first one.
+        assert_ne!(v[0], 0);
+    }
+
+    #[cfg(feature = "crossbeam-channel")]
+    #[test]
+    fn test_pipeline_ready_receiver() {
+        use std::time::Duration;
+
+        for w in 0..3 {
+            // Out of order results must not lose a wakeup.
+            let mut p = (0..50).plmap(w, |x: u64| {
+                thread::sleep(Duration::from_micros((50 - x) * 50));
+                x
+            });
+            let ready = p.ready_receiver();
+            let mut v = Vec::new();
+            loop {
+                ready
+                    .recv_timeout(Duration::from_secs(5))
+                    .expect("lost wakeup");
+                match p.try_next() {
+                    Poll::Ready(Some(x)) => v.push(x),
+                    Poll::Ready(None) => break,
+                    Poll::Pending => assert!(w > 0),
+                }
+            }
+            assert_eq!(v, (0..50).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_pipeline_try_next() {
+        let mut p = (0..3).plmap(1, |x| {
+            thread::sleep(std::time::Duration::from_millis(20));
+            x
+        });
+        assert_eq!(p.try_next(), Poll::Pending);
+        assert_eq!(p.next(), Some(0));
+        assert_eq!(p.collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_pipeline_drain_budgeted() {
+        use std::{
+            sync::{mpsc, Arc, Mutex},
+            time::{Duration, Instant},
+        };
+
+        // Values 2 and 3 are held until open is dropped.
+        let (open, gate) = mpsc::channel::<()>();
+        let gate = Arc::new(Mutex::new(gate));
+        let mut p = PipelineBuilder::new(2).eager().build(0..4, move |x| {
+            if x >= 2 {
+                let _ = gate.lock().unwrap().recv();
+            }
+            x
+        });
+        let mut ready = Vec::new();
+        let start = Instant::now();
+        while ready.len() < 2 && start.elapsed() < Duration::from_secs(5) {
+            ready.extend(p.drain_budgeted(Duration::from_millis(20)).unwrap());
+            thread::sleep(Duration::from_millis(1));
+        }
+        // The held values are not waited for.
+        let held = p.drain_budgeted(Duration::from_millis(20));
+        // Open the gate before checking, a failure would otherwise hang
+        // dropping the pipeline.
+        drop(open);
+        assert_eq!(ready, [0, 1]);
+        assert_eq!(held, Some(vec![]));
+        assert_eq!(p.drain_budgeted(Duration::ZERO), Some(vec![]));
+        let mut rest = Vec::new();
+        while let Some(v) = p.drain_budgeted(Duration::from_secs(1)) {
+            rest.extend(v);
+        }
+        assert_eq!(rest, [2, 3]);
+        assert_eq!(p.drain_budgeted(Duration::from_secs(1)), None);
+    }
 }
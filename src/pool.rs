@@ -0,0 +1,490 @@
+use {
+    super::{
+        chan,
+        fair::{FairQueue, Share},
+        mapper::Mapper,
+    },
+    std::{
+        cell::Cell,
+        collections::{HashMap, VecDeque},
+        fmt,
+        panic::{self, AssertUnwindSafe},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex, OnceLock,
+        },
+        thread,
+    },
+};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// PlmapPool is a set of long lived worker threads that can be shared
+/// by many pipelines, avoiding the cost of spawning and joining
+/// threads for every pipeline.
+///
+/// Pipelines are run on a pool with plmap_on, or
+/// PipelineBuilder::pool. Each value is run as a separate job on the
+/// pool, so pipelines sharing a pool interleave their work, each
+/// getting a share of the pool in proportion to its weight, see
+/// PipelineBuilder::weight, or ahead of them if it has a higher
+/// priority, see PipelineBuilder::priority. A pipeline
+/// started from a mapper already running on the pool maps its values
+/// inline, so nesting pipelines on a pool can't deadlock it.
+/// Cloning a pool gives another handle to the same threads, which exit
+/// once every handle has been dropped and the queued jobs are done.
+///
+/// # Examples
+///
+/// ```
+/// use plmap::{PlmapPool, PipelineMap};
+///
+/// let pool = PlmapPool::new(4);
+/// for _ in 0..10 {
+///     let total: i32 = (0..100).plmap_on(&pool, |x| x * 2).sum();
+///     assert_eq!(total, 9900);
+/// }
+/// ```
+#[derive(Clone)]
+pub struct PlmapPool {
+    shared: Arc<Shared>,
+    n_threads: usize,
+}
+
+/// Shared is dropped with the last handle to a pool, closing its
+/// queue.
+struct Shared {
+    queue: Arc<FairQueue<Job>>,
+    executor: Executor,
+}
+
+/// Where a pool's jobs run.
+enum Executor {
+    /// The pool's own threads, which know the id of their pool.
+    Threads(usize),
+    /// Tasks on a rayon pool, each of which runs the next queued job.
+    #[cfg(feature = "rayon")]
+    Rayon(Arc<rayon::ThreadPool>),
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        self.queue.close();
+    }
+}
+
+impl PlmapPool {
+    /// Create a pool with n_threads worker threads.
+    ///
+    /// # Panics
+    ///
+    /// Panics if n_threads is zero.
+    pub fn new(n_threads: usize) -> PlmapPool {
+        assert!(n_threads > 0, "a pool needs at least one thread");
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let queue = Arc::new(FairQueue::<Job>::new());
+        for _ in 0..n_threads {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                CURRENT_POOL.with(|current| current.set(id));
+                while let Some(job) = queue.pop() {
+                    job();
+                }
+            });
+        }
+        PlmapPool {
+            shared: Arc::new(Shared {
+                queue,
+                executor: Executor::Threads(id),
+            }),
+            n_threads,
+        }
+    }
+
+    /// Create a pool that runs its jobs as tasks on an existing rayon
+    /// thread pool, so pipelines share the threads of the rest of the
+    /// program instead of oversubscribing the machine.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use {
+    ///     plmap::{PipelineMap, PlmapPool},
+    ///     std::sync::Arc,
+    /// };
+    ///
+    /// let rayon_pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+    /// let pool = PlmapPool::from_rayon(Arc::new(rayon_pool));
+    /// let total: i32 = (0..100).plmap_on(&pool, |x| x * 2).sum();
+    /// assert_eq!(total, 9900);
+    /// ```
+    #[cfg(feature = "rayon")]
+    pub fn from_rayon(pool: Arc<rayon::ThreadPool>) -> PlmapPool {
+        PlmapPool {
+            n_threads: pool.current_num_threads(),
+            shared: Arc::new(Shared {
+                queue: Arc::new(FairQueue::new()),
+                executor: Executor::Rayon(pool),
+            }),
+        }
+    }
+
+    /// The process wide pool, created on first use with a thread for
+    /// each cpu.
+    ///
+    /// Libraries can run pipelines on this pool without multiplying
+    /// the number of threads each time they are called.
+    #[cfg(feature = "global-pool")]
+    pub fn global() -> &'static PlmapPool {
+        static GLOBAL: OnceLock<PlmapPool> = OnceLock::new();
+        GLOBAL.get_or_init(|| {
+            let n_threads = thread::available_parallelism().map_or(1, |n| n.get());
+            PlmapPool::new(n_threads)
+        })
+    }
+
+    /// Get the pool registered under name, creating it with n_threads
+    /// worker threads if there is none.
+    ///
+    /// Named pools let separate parts of a program share capacity on
+    /// purpose, e.g. everything doing disk io might share an "io" pool.
+    /// The first caller decides the size of the pool, later callers get
+    /// the existing pool whatever n_threads they ask for. Named pools
+    /// live for the rest of the process.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool is created and n_threads is zero.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::{PlmapPool, PipelineMap};
+    ///
+    /// PlmapPool::named("cpu", 4);
+    /// // Elsewhere in the program.
+    /// let pool = PlmapPool::lookup("cpu").unwrap();
+    /// let total: i32 = (0..100).plmap_on(&pool, |x| x * 2).sum();
+    /// assert_eq!(total, 9900);
+    /// ```
+    pub fn named(name: &str, n_threads: usize) -> PlmapPool {
+        registry()
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_insert_with(|| PlmapPool::new(n_threads))
+            .clone()
+    }
+
+    /// Get the pool registered under name, if any.
+    pub fn lookup(name: &str) -> Option<PlmapPool> {
+        registry().lock().unwrap().get(name).cloned()
+    }
+
+    /// The number of worker threads in the pool.
+    pub fn n_threads(&self) -> usize {
+        self.n_threads
+    }
+
+    /// Whether the calling thread is one of this pool's threads.
+    pub(crate) fn is_current(&self) -> bool {
+        match &self.shared.executor {
+            Executor::Threads(id) => CURRENT_POOL.with(|current| current.get() == *id),
+            #[cfg(feature = "rayon")]
+            Executor::Rayon(pool) => pool.current_thread_index().is_some(),
+        }
+    }
+
+    /// Queue a job on behalf of client, see FairQueue.
+    fn execute(&self, client: usize, share: Share, job: Job) {
+        self.shared.queue.push(client, share, job);
+        match &self.shared.executor {
+            Executor::Threads(_) => (),
+            #[cfg(feature = "rayon")]
+            Executor::Rayon(pool) => {
+                let queue = self.shared.queue.clone();
+                pool.spawn(move || {
+                    if let Some(job) = queue.try_pop() {
+                        job();
+                    }
+                });
+            }
+        }
+    }
+}
+
+thread_local! {
+    /// The id of the pool the current thread belongs to, or zero.
+    static CURRENT_POOL: Cell<usize> = const { Cell::new(0) };
+}
+
+fn registry() -> &'static Mutex<HashMap<String, PlmapPool>> {
+    static REGISTRY: OnceLock<Mutex<HashMap<String, PlmapPool>>> = OnceLock::new();
+    REGISTRY.get_or_init(Default::default)
+}
+
+impl fmt::Debug for PlmapPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PlmapPool")
+            .field("n_threads", &self.n_threads)
+            .field("queued_jobs", &self.shared.queue.len())
+            .finish()
+    }
+}
+
+/// PoolDispatcher runs a pipeline's values as jobs on a pool.
+///
+/// Each pipeline has a fixed set of mappers, a value is only submitted
+/// as a job once a mapper is free to run it, so a pipeline never has
+/// more jobs queued on the pool than it has mappers. Values dispatched
+/// while all the mappers are busy wait in a pending queue, and are
+/// picked up by the next job to finish.
+pub(crate) struct PoolDispatcher<T, In, M>
+where
+    M: Mapper<In>,
+{
+    pool: PlmapPool,
+    client: usize,
+    share: Share,
+    state: Arc<Mutex<PoolState<T, In, M>>>,
+    results: chan::Sender<(T, thread::Result<M::Out>)>,
+}
+
+struct PoolState<T, In, M> {
+    pending: VecDeque<(T, In)>,
+    idle: Vec<M>,
+}
+
+impl<T, In, M> PoolDispatcher<T, In, M>
+where
+    T: Send + 'static,
+    In: Send + 'static,
+    M: Mapper<In> + Send + 'static,
+    M::Out: Send + 'static,
+{
+    pub(crate) fn new(
+        pool: PlmapPool,
+        share: Share,
+        mappers: Vec<M>,
+        results: chan::Sender<(T, thread::Result<M::Out>)>,
+    ) -> PoolDispatcher<T, In, M> {
+        static NEXT_CLIENT: AtomicUsize = AtomicUsize::new(0);
+        PoolDispatcher {
+            pool,
+            client: NEXT_CLIENT.fetch_add(1, Ordering::Relaxed),
+            share,
+            state: Arc::new(Mutex::new(PoolState {
+                pending: VecDeque::new(),
+                idle: mappers,
+            })),
+            results,
+        }
+    }
+
+    fn submit(&self, mut mapper: M, tag: T, v: In) {
+        let next = PoolDispatcher {
+            pool: self.pool.clone(),
+            client: self.client,
+            share: self.share,
+            state: self.state.clone(),
+            results: self.results.clone(),
+        };
+        self.pool.execute(
+            self.client,
+            self.share,
+            Box::new(move || {
+                // Panics are passed on to the consumer, to be resumed when
+                // it reaches this value.
+                let out = panic::catch_unwind(AssertUnwindSafe(|| mapper.apply(v)));
+                let _ = next.results.send((tag, out));
+                let mut state = next.state.lock().unwrap();
+                match state.pending.pop_front() {
+                    // Submit a new job rather than looping here, so other
+                    // pipelines on the pool get their turn.
+                    Some((tag, v)) => {
+                        drop(state);
+                        next.submit(mapper, tag, v);
+                    }
+                    None => state.idle.push(mapper),
+                }
+            }),
+        );
+    }
+}
+
+/// JobDispatch hides the mapper type of a PoolDispatcher, so pipelines
+/// can hold one without the 'static bounds that pool jobs need.
+pub(crate) trait JobDispatch<T, In>: Send {
+    fn send(&self, tag: T, v: In);
+
+    /// Drop values that have not started, jobs already running finish
+    /// in the background.
+    fn close(&self);
+}
+
+impl<T, In, M> JobDispatch<T, In> for PoolDispatcher<T, In, M>
+where
+    T: Send + 'static,
+    In: Send + 'static,
+    M: Mapper<In> + Send + 'static,
+    M::Out: Send + 'static,
+{
+    fn send(&self, tag: T, v: In) {
+        let mut state = self.state.lock().unwrap();
+        match state.idle.pop() {
+            Some(mapper) => {
+                drop(state);
+                self.submit(mapper, tag, v);
+            }
+            None => state.pending.push_back((tag, v)),
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().pending.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::PipelineMap};
+
+    #[test]
+    fn test_pool_shared_by_pipelines() {
+        let pool = PlmapPool::new(3);
+        let mut a = (0..100).plmap_on(&pool, |x| x * 2);
+        let mut b = (0..100).plmap_on(&pool, |x| x * 3);
+        for i in 0..100 {
+            assert_eq!(a.next(), Some(i * 2));
+            assert_eq!(b.next(), Some(i * 3));
+        }
+        assert_eq!(a.next(), None);
+        assert_eq!(b.next(), None);
+    }
+
+    #[test]
+    fn test_pool_pipeline_options() {
+        use crate::PipelineBuilder;
+
+        let pool = PlmapPool::new(2);
+        for n in 1..4 {
+            let v: Vec<i32> = PipelineBuilder::new(n)
+                .pool(&pool)
+                .in_flight(10)
+                .build(0..100, |x| x * 2)
+                .rev()
+                .collect();
+            assert_eq!(v, (0..100).rev().map(|x| x * 2).collect::<Vec<_>>());
+        }
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            (0..100)
+                .plmap_on(&pool, |x| {
+                    if x == 50 {
+                        panic!("boom");
+                    }
+                    x
+                })
+                .count()
+        }));
+        assert!(result.is_err());
+        // The pool survives a panicking mapper.
+        assert_eq!((0..100).plmap_on(&pool, |x| x).count(), 100);
+    }
+
+    #[test]
+    fn test_pool_weights() {
+        use crate::PipelineBuilder;
+
+        let pool = PlmapPool::new(1);
+        // Hold the pool's thread until both pipelines have queued jobs.
+        let (start, started) = chan::bounded::<()>(0);
+        let share = Share {
+            weight: 1,
+            priority: Default::default(),
+        };
+        pool.execute(
+            usize::MAX,
+            share,
+            Box::new(move || {
+                let _ = started.recv();
+            }),
+        );
+
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let pipeline = |name, weight| {
+            let order = order.clone();
+            PipelineBuilder::new(4)
+                .pool(&pool)
+                .weight(weight)
+                .in_flight(100)
+                .eager()
+                .build(0..100, move |_| order.lock().unwrap().push(name))
+        };
+        let a = pipeline('a', 3);
+        let b = pipeline('b', 1);
+        drop(start);
+        assert_eq!(a.count(), 100);
+        assert_eq!(b.count(), 100);
+        let order = order.lock().unwrap();
+        let a_first = order[..40].iter().filter(|&&c| c == 'a').count();
+        assert!((28..=32).contains(&a_first), "a ran {} of 40", a_first);
+    }
+
+    #[test]
+    fn test_pool_nested() {
+        // With one thread, a nested pipeline dispatching to the pool
+        // would wait forever on jobs queued behind its own.
+        let pool = PlmapPool::new(1);
+        let p = pool.clone();
+        let v: Vec<i32> = (0..10)
+            .plmap_on(&pool, move |x| (0..x).plmap_on(&p, |y| y).sum())
+            .collect();
+        assert_eq!(v, (0..10).map(|x| (0..x).sum()).collect::<Vec<_>>());
+        assert!(!pool.is_current());
+    }
+
+    #[test]
+    fn test_named_pools() {
+        assert!(PlmapPool::lookup("test-missing").is_none());
+        let io = PlmapPool::named("test-io", 2);
+        assert_eq!(PlmapPool::named("test-io", 8).n_threads(), 2);
+        assert_eq!(PlmapPool::named("test-cpu", 3).n_threads(), 3);
+        let pool = PlmapPool::lookup("test-io").unwrap();
+        assert_eq!(pool.n_threads(), io.n_threads());
+        assert_eq!((0..100).plmap_on(&pool, |x| x).count(), 100);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_rayon_pool() {
+        let rayon_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(3)
+            .build()
+            .unwrap();
+        let pool = PlmapPool::from_rayon(Arc::new(rayon_pool));
+        assert_eq!(pool.n_threads(), 3);
+        let v: Vec<i32> = (0..100).plmap_on(&pool, |x| x * 2).collect();
+        assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            (0..100)
+                .plmap_on(&pool, |x| {
+                    if x == 50 {
+                        panic!("boom");
+                    }
+                    x
+                })
+                .count()
+        }));
+        assert!(result.is_err());
+    }
+
+    #[cfg(feature = "global-pool")]
+    #[test]
+    fn test_global_pool() {
+        assert!(std::ptr::eq(PlmapPool::global(), PlmapPool::global()));
+        let total: i32 = (0..100).plmap_global(|x| x * 2).sum();
+        assert_eq!(total, 9900);
+    }
+}
@@ -0,0 +1,35 @@
+//! The traits and configuration types most code using plmap needs, so
+//! that a single glob import sets everything up.
+//!
+//! ```
+//! use plmap::prelude::*;
+//!
+//! let total: i32 = PipelineBuilder::new(Workers::Auto)
+//!     .schedule(Schedule::Stealing)
+//!     .build(0..100, |x| x * 2)
+//!     .sum();
+//! assert_eq!(total, 9900);
+//! assert_eq!((0..100).plmap(2, |x| x * 2).sum::<i32>(), 9900);
+//! ```
+//!
+//! StreamPipelineMap is left out, its plmap would be ambiguous with
+//! PipelineMap's for types that are both iterators and streams.
+
+#[cfg(feature = "numa")]
+pub use super::numa::Placement;
+#[cfg(feature = "procs")]
+pub use super::procs::ProcessMap;
+#[cfg(feature = "remote")]
+pub use super::remote::RemoteMap;
+pub use super::{
+    buffered::Prefetch,
+    builder::PipelineBuilder,
+    fair::Priority,
+    mapper::Mapper,
+    pipeline::{InFlight, Pipeline, PipelineMap},
+    schedule::Schedule,
+    scoped_pipeline::{ScopedPipeline, ScopedPipelineMap},
+    spec::PipelineSpec,
+    wait::WaitStrategy,
+    workers::Workers,
+};
@@ -0,0 +1,264 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    fmt,
+    iter::{Enumerate, FusedIterator},
+};
+
+/// Prioritized reads up to lookahead values ahead of its input and
+/// yields the one with the highest priority first, values with the
+/// same priority in input order, see PipelineMap::pl_prioritized.
+pub struct Prioritized<I: Iterator, F, P> {
+    lookahead: Lookahead<I, P>,
+    priority: F,
+}
+
+/// Lifo yields the most recently read of up to lookahead values read
+/// ahead of its input first, paired with its index in the input, see
+/// PipelineMap::pl_lifo.
+pub type Lifo<I> = Prioritized<Enumerate<I>, fn(&(usize, <I as Iterator>::Item)) -> usize, usize>;
+
+/// The priority of a value read by Lifo, later values first.
+pub(crate) fn newest<T>(v: &(usize, T)) -> usize {
+    v.0
+}
+
+/// KeyOrdered reads up to lateness values ahead of its input and
+/// yields the one with the smallest key first, values with the same
+/// key in input order, see PipelineMap::pl_ordered_by_key.
+pub struct KeyOrdered<I: Iterator, F, K> {
+    lookahead: Lookahead<I, Reverse<K>>,
+    key: F,
+}
+
+/// Lookahead buffers values read ahead of an input, to be taken
+/// highest priority first.
+struct Lookahead<I: Iterator, P> {
+    input: I,
+    input_done: bool,
+    n: usize,
+    buffered: BinaryHeap<Entry<I::Item, P>>,
+    seq: usize,
+}
+
+/// Entry orders a buffered value by its priority, then by how early
+/// it was read.
+struct Entry<T, P> {
+    priority: P,
+    seq: Reverse<usize>,
+    v: T,
+}
+
+impl<T, P: Ord> Ord for Entry<T, P> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (&self.priority, self.seq).cmp(&(&other.priority, other.seq))
+    }
+}
+
+impl<T, P: Ord> PartialOrd for Entry<T, P> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T, P: Ord> PartialEq for Entry<T, P> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T, P: Ord> Eq for Entry<T, P> {}
+
+impl<I: Iterator, P: Ord> Lookahead<I, P> {
+    fn new(input: I, n: usize) -> Lookahead<I, P> {
+        assert!(n > 0, "lookahead must be greater than zero");
+        Lookahead {
+            input,
+            input_done: false,
+            n,
+            buffered: BinaryHeap::with_capacity(n),
+            seq: 0,
+        }
+    }
+
+    /// Read ahead until n values are buffered, then take the one with
+    /// the highest priority.
+    fn next(&mut self, mut priority: impl FnMut(&I::Item) -> P) -> Option<I::Item> {
+        while !self.input_done && self.buffered.len() < self.n {
+            match self.input.next() {
+                Some(v) => {
+                    self.buffered.push(Entry {
+                        priority: priority(&v),
+                        seq: Reverse(self.seq),
+                        v,
+                    });
+                    self.seq += 1;
+                }
+                None => self.input_done = true,
+            }
+        }
+        self.buffered.pop().map(|e| e.v)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = self.buffered.len();
+        if self.input_done {
+            return (n, Some(n));
+        }
+        let (lo, hi) = self.input.size_hint();
+        (lo.saturating_add(n), hi.and_then(|hi| hi.checked_add(n)))
+    }
+}
+
+impl<I: Iterator, F, P: Ord> Prioritized<I, F, P> {
+    pub(crate) fn new(input: I, lookahead: usize, priority: F) -> Prioritized<I, F, P> {
+        Prioritized {
+            lookahead: Lookahead::new(input, lookahead),
+            priority,
+        }
+    }
+}
+
+impl<I, F, P> Iterator for Prioritized<I, F, P>
+where
+    I: Iterator,
+    F: FnMut(&I::Item) -> P,
+    P: Ord,
+{
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<I::Item> {
+        self.lookahead.next(&mut self.priority)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.lookahead.size_hint()
+    }
+}
+
+impl<I, F, P> FusedIterator for Prioritized<I, F, P>
+where
+    I: Iterator,
+    F: FnMut(&I::Item) -> P,
+    P: Ord,
+{
+}
+
+impl<I: Iterator + fmt::Debug, F, P> fmt::Debug for Prioritized<I, F, P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Prioritized")
+            .field("input", &self.lookahead.input)
+            .field("lookahead", &self.lookahead.n)
+            .field("buffered", &self.lookahead.buffered.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<I: Iterator, F, K: Ord> KeyOrdered<I, F, K> {
+    pub(crate) fn new(input: I, lateness: usize, key: F) -> KeyOrdered<I, F, K> {
+        KeyOrdered {
+            lookahead: Lookahead::new(input, lateness.saturating_add(1)),
+            key,
+        }
+    }
+}
+
+impl<I, F, K> Iterator for KeyOrdered<I, F, K>
+where
+    I: Iterator,
+    F: FnMut(&I::Item) -> K,
+    K: Ord,
+{
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<I::Item> {
+        let key = &mut self.key;
+        self.lookahead.next(|v| Reverse(key(v)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.lookahead.size_hint()
+    }
+}
+
+impl<I, F, K> FusedIterator for KeyOrdered<I, F, K>
+where
+    I: Iterator,
+    F: FnMut(&I::Item) -> K,
+    K: Ord,
+{
+}
+
+impl<I: Iterator + fmt::Debug, F, K> fmt::Debug for KeyOrdered<I, F, K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyOrdered")
+            .field("input", &self.lookahead.input)
+            .field("lateness", &(self.lookahead.n - 1))
+            .field("buffered", &self.lookahead.buffered.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::PipelineMap;
+
+    #[test]
+    fn test_pl_prioritized() {
+        for w in 0..3 {
+            // Odd values are urgent.
+            let v: Vec<i32> = (0..10)
+                .pl_prioritized(w, 4, |x: &i32| x % 2, |x| x * 10)
+                .collect();
+            assert_eq!(v, [10, 30, 50, 0, 70, 20, 90, 40, 60, 80]);
+
+            // A lookahead of one keeps the input order.
+            let v: Vec<i32> = (0..10)
+                .pl_prioritized(w, 1, |x: &i32| x % 2, |x| x * 10)
+                .collect();
+            assert_eq!(v, (0..10).map(|x| x * 10).collect::<Vec<_>>());
+
+            let mut v: Vec<i32> = (0..100)
+                .pl_prioritized(w, 1000, |x: &i32| x % 3, |x| x)
+                .collect();
+            assert_eq!(v[..3], [2, 5, 8]);
+            v.sort_unstable();
+            assert_eq!(v, (0..100).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_pl_lifo() {
+        for w in 0..3 {
+            let v: Vec<(usize, i32)> = (0..6).pl_lifo(w, 3, |x| x * 10).collect();
+            assert_eq!(v, [(2, 20), (3, 30), (4, 40), (5, 50), (1, 10), (0, 0)]);
+
+            let mut v: Vec<(usize, i32)> = (0..100).pl_lifo(w, 1000, |x| x).collect();
+            assert_eq!(v[0], (99, 99));
+            v.sort_unstable();
+            assert_eq!(v, (0..100).map(|x| (x, x as i32)).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_pl_ordered_by_key() {
+        // Timestamps arriving up to two places late.
+        let stamps = [1, 3, 2, 4, 7, 5, 6, 8, 9, 10];
+        for w in 0..3 {
+            let v: Vec<u64> = stamps
+                .iter()
+                .copied()
+                .pl_ordered_by_key(w, 2, |t: &u64| *t, |t| t * 100)
+                .collect();
+            assert_eq!(v, (1..=10).map(|t| t * 100).collect::<Vec<_>>());
+
+            // Equal keys keep their input order, and values later than
+            // the lateness come out as soon as they are read.
+            let v: Vec<(u64, char)> = vec![(2, 'a'), (1, 'b'), (2, 'c'), (3, 'd'), (0, 'e')]
+                .into_iter()
+                .pl_ordered_by_key(w, 1, |v: &(u64, char)| v.0, |v| v)
+                .collect();
+            assert_eq!(v, [(1, 'b'), (2, 'a'), (2, 'c'), (0, 'e'), (3, 'd')]);
+        }
+    }
+}
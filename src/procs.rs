@@ -0,0 +1,290 @@
+use {
+    super::{
+        codec::{self, Codec},
+        mapper::Mapper,
+        pipeline::Pipeline,
+        workers::Workers,
+    },
+    std::{
+        env,
+        ffi::{OsStr, OsString},
+        io::{self, BufReader},
+        marker::PhantomData,
+        process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    },
+};
+
+/// Set in the environment of worker processes started from
+/// WorkerCommand::current_exe.
+const WORKER_ENV: &str = "PLMAP_WORKER";
+
+/// WorkerCommand describes how to start a worker process, see
+/// ProcessMap.
+#[derive(Clone, Debug)]
+pub struct WorkerCommand {
+    /// None is the current executable.
+    program: Option<OsString>,
+    args: Vec<OsString>,
+    envs: Vec<(OsString, OsString)>,
+}
+
+impl WorkerCommand {
+    /// Run program as the worker.
+    pub fn new(program: impl AsRef<OsStr>) -> WorkerCommand {
+        WorkerCommand {
+            program: Some(program.as_ref().to_owned()),
+            args: Vec::new(),
+            envs: Vec::new(),
+        }
+    }
+
+    /// Run another copy of the current executable as the worker, which
+    /// should check is_worker early in main and call serve if it is.
+    pub fn current_exe() -> WorkerCommand {
+        WorkerCommand {
+            program: None,
+            args: Vec::new(),
+            envs: vec![(WORKER_ENV.into(), "1".into())],
+        }
+    }
+
+    /// Add an argument to the worker's command line.
+    pub fn arg(mut self, arg: impl AsRef<OsStr>) -> WorkerCommand {
+        self.args.push(arg.as_ref().to_owned());
+        self
+    }
+
+    /// Set an environment variable for the worker.
+    pub fn env(mut self, key: impl AsRef<OsStr>, val: impl AsRef<OsStr>) -> WorkerCommand {
+        self.envs
+            .push((key.as_ref().to_owned(), val.as_ref().to_owned()));
+        self
+    }
+
+    fn spawn(&self) -> io::Result<WorkerProcess> {
+        let program = match &self.program {
+            Some(program) => program.clone(),
+            None => env::current_exe()?.into_os_string(),
+        };
+        let mut child = Command::new(program)
+            .args(&self.args)
+            .envs(self.envs.iter().map(|(k, v)| (k, v)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        Ok(WorkerProcess {
+            stdin: child.stdin.take().unwrap(),
+            stdout: BufReader::new(child.stdout.take().unwrap()),
+            child,
+        })
+    }
+}
+
+/// Whether this process was started by WorkerCommand::current_exe.
+pub fn is_worker() -> bool {
+    env::var_os(WORKER_ENV).is_some()
+}
+
+/// Serve a pipeline from a worker process, mapping values read from
+/// stdin and writing the results to stdout until stdin is closed.
+///
+/// Nothing else may be written to stdout while serving, diagnostics
+/// should go to stderr, which worker processes share with the pipeline.
+pub fn serve<In, M, C>(codec: C, mapper: M) -> io::Result<()>
+where
+    M: Mapper<In>,
+    C: Codec<In> + Codec<M::Out>,
+{
+    let mut mapper = mapper;
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    codec::serve_frames(
+        &mut BufReader::new(stdin.lock()),
+        &mut stdout.lock(),
+        &codec,
+        &mut mapper,
+        codec::MAX_FRAME_LEN,
+    )
+}
+
+/// A running worker process.
+struct WorkerProcess {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Drop for WorkerProcess {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// ProcessMapper maps values by sending them to a worker process, each
+/// clone starts its own process the first time it is used.
+///
+/// If the worker process fails, e.g. by crashing, apply panics and the
+/// pipeline resumes the panic on the consumer when it reaches that
+/// value. The next value starts a new process.
+pub struct ProcessMapper<In, Out, C> {
+    command: WorkerCommand,
+    codec: C,
+    worker: Option<WorkerProcess>,
+    _types: PhantomData<fn(In) -> Out>,
+}
+
+impl<In, Out, C> ProcessMapper<In, Out, C>
+where
+    C: Codec<In> + Codec<Out>,
+{
+    pub fn new(command: WorkerCommand, codec: C) -> ProcessMapper<In, Out, C> {
+        ProcessMapper {
+            command,
+            codec,
+            worker: None,
+            _types: PhantomData,
+        }
+    }
+
+    fn call(&mut self, v: In) -> io::Result<Out> {
+        let worker = match &mut self.worker {
+            Some(worker) => worker,
+            None => self.worker.insert(self.command.spawn()?),
+        };
+        codec::call(&mut worker.stdin, &mut worker.stdout, &self.codec, &v)
+    }
+}
+
+impl<In, Out, C> Clone for ProcessMapper<In, Out, C>
+where
+    C: Clone,
+{
+    fn clone(&self) -> Self {
+        ProcessMapper {
+            command: self.command.clone(),
+            codec: self.codec.clone(),
+            worker: None,
+            _types: PhantomData,
+        }
+    }
+}
+
+impl<In, Out, C> Mapper<In> for ProcessMapper<In, Out, C>
+where
+    C: Codec<In> + Codec<Out>,
+{
+    type Out = Out;
+
+    fn apply(&mut self, v: In) -> Out {
+        match self.call(v) {
+            Ok(out) => out,
+            Err(err) => {
+                self.worker = None;
+                panic!("worker process failed: {}", err)
+            }
+        }
+    }
+}
+
+/// ProcessMap can be imported to add the plmap_procs function to
+/// iterators.
+pub trait ProcessMap: Iterator + Sized
+where
+    Self::Item: Send + 'static,
+{
+    /// Like plmap, but each worker maps values in a process of its own,
+    /// started with command, for mappers that must not share a process
+    /// such as those calling into libraries that are not thread safe.
+    /// Values and results are sent to and from the workers with codec.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use plmap::{Bincode, ProcessMap, WorkerCommand};
+    ///
+    /// fn main() {
+    ///     if plmap::is_worker() {
+    ///         plmap::serve(Bincode, |x: u64| x * 2).unwrap();
+    ///         return;
+    ///     }
+    ///     let total: u64 = (0..100u64)
+    ///         .plmap_procs::<u64, _>(4, WorkerCommand::current_exe(), Bincode)
+    ///         .sum();
+    ///     assert_eq!(total, 9900);
+    /// }
+    /// ```
+    fn plmap_procs<Out, C>(
+        self,
+        n_workers: impl Into<Workers>,
+        command: WorkerCommand,
+        codec: C,
+    ) -> Pipeline<Self, ProcessMapper<Self::Item, Out, C>>
+    where
+        Out: Send + 'static,
+        C: Codec<Self::Item> + Codec<Out> + Clone + Send + 'static,
+    {
+        Pipeline::new(n_workers, ProcessMapper::new(command, codec), self)
+    }
+}
+
+impl<I> ProcessMap for I
+where
+    I: Iterator,
+    I::Item: Send + 'static,
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::Bincode,
+        std::{io::Cursor, panic},
+    };
+
+    #[test]
+    fn test_serve() {
+        let mut input = Vec::new();
+        for x in 0..10u32 {
+            codec::write_frame(&mut input, &Bincode.encode(&x).unwrap()).unwrap();
+        }
+        let mut output = Vec::new();
+        let mut mapper = |x: u32| u64::from(x) * 2;
+        codec::serve_frames(
+            &mut Cursor::new(input),
+            &mut output,
+            &Bincode,
+            &mut mapper,
+            codec::MAX_FRAME_LEN,
+        )
+        .unwrap();
+        let mut r = Cursor::new(output);
+        let mut results = Vec::new();
+        while let Some(buf) = codec::read_frame(&mut r, codec::MAX_FRAME_LEN).unwrap() {
+            let v: u64 = Bincode.decode(&buf).unwrap();
+            results.push(v);
+        }
+        assert_eq!(results, (0..10).map(|x| x * 2).collect::<Vec<_>>());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_process_pipeline() {
+        // cat echoes each frame back, mapping values to themselves.
+        for w in 0..3 {
+            let v: Vec<String> = (0..100)
+                .map(|x| x.to_string())
+                .plmap_procs(w, WorkerCommand::new("cat"), Bincode)
+                .collect();
+            assert_eq!(v, (0..100).map(|x| x.to_string()).collect::<Vec<_>>());
+        }
+
+        let result = panic::catch_unwind(|| {
+            (0..10u32)
+                .plmap_procs::<u32, _>(2, WorkerCommand::new("true"), Bincode)
+                .count()
+        });
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,315 @@
+use {
+    super::{mapper::Mapper, pipeline::Pipeline, workers::Workers},
+    crossbeam_channel::{Receiver, Select, Sender},
+    std::{
+        fmt, panic,
+        sync::{Arc, Mutex},
+        thread,
+        time::Duration,
+    },
+};
+
+/// CancelToken is a cancellation signal that can be shared between
+/// threads, every clone is cancelled together.
+#[derive(Clone)]
+pub struct CancelToken {
+    inner: Arc<CancelInner>,
+}
+
+struct CancelInner {
+    /// Dropped on cancel, disconnecting cancelled.
+    trigger: Mutex<Option<Sender<()>>>,
+    cancelled: Receiver<()>,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        let (trigger, cancelled) = crossbeam_channel::bounded(0);
+        CancelToken {
+            inner: Arc::new(CancelInner {
+                trigger: Mutex::new(Some(trigger)),
+                cancelled,
+            }),
+        }
+    }
+
+    /// Cancel the token, waking anything waiting on it.
+    pub fn cancel(&self) {
+        self.inner.trigger.lock().unwrap().take();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.trigger.lock().unwrap().is_none()
+    }
+
+    /// A channel which disconnects when the token is cancelled.
+    pub(crate) fn channel(&self) -> &Receiver<()> {
+        &self.inner.cancelled
+    }
+}
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        CancelToken::new()
+    }
+}
+
+impl fmt::Debug for CancelToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CancelToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+/// ReceiverInput is pipeline input received from a channel, see
+/// Pipeline::from_receiver.
+///
+/// The input ends when every sender has been dropped, when no value
+/// arrives within the idle timeout, or when the cancel token is
+/// cancelled, so a pipeline waiting on stalled senders can always be
+/// stopped. Values left in the channel are not received.
+pub struct ReceiverInput<T> {
+    rx: Receiver<T>,
+    idle_timeout: Option<Duration>,
+    cancel: Option<CancelToken>,
+    /// The thread running the iterator, see spawn.
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl<T> ReceiverInput<T> {
+    pub fn new(rx: Receiver<T>) -> ReceiverInput<T> {
+        ReceiverInput {
+            rx,
+            idle_timeout: None,
+            cancel: None,
+            handle: None,
+        }
+    }
+
+    /// Run iter on a background thread, keeping up to n values ready,
+    /// so that an iterator which may block, such as one reading from a
+    /// socket, can be given an idle timeout or cancel token like a
+    /// channel. A pipeline pulling from it stays responsive while iter
+    /// is blocked.
+    ///
+    /// The background thread stops the next time iter produces a value
+    /// after the input has been dropped. A panic on it is resumed on
+    /// the consumer once the values before it have been received.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use {
+    ///     plmap::{Pipeline, ReceiverInput},
+    ///     std::time::Duration,
+    /// };
+    ///
+    /// // After 100 values the input blocks for a long time.
+    /// let slow = (0..).inspect(|&x| {
+    ///     if x == 100 {
+    ///         std::thread::sleep(Duration::from_secs(3600));
+    ///     }
+    /// });
+    /// let input = ReceiverInput::spawn(16, slow).idle_timeout(Duration::from_millis(50));
+    /// let total: i32 = Pipeline::from_receiver(4, |x| x * 2, input).sum();
+    /// assert_eq!(total, 9900);
+    /// ```
+    pub fn spawn<I>(n: usize, iter: I) -> ReceiverInput<T>
+    where
+        I: Iterator<Item = T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = crossbeam_channel::bounded(n);
+        let handle = thread::spawn(move || {
+            for v in iter {
+                if tx.send(v).is_err() {
+                    break;
+                }
+            }
+        });
+        ReceiverInput {
+            handle: Some(handle),
+            ..ReceiverInput::new(rx)
+        }
+    }
+
+    /// End the input if waiting for a value takes longer than timeout.
+    pub fn idle_timeout(mut self, timeout: Duration) -> ReceiverInput<T> {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// End the input when token is cancelled, including while waiting
+    /// for a value.
+    pub fn cancel_token(mut self, token: &CancelToken) -> ReceiverInput<T> {
+        self.cancel = Some(token.clone());
+        self
+    }
+}
+
+impl<T> From<Receiver<T>> for ReceiverInput<T> {
+    fn from(rx: Receiver<T>) -> ReceiverInput<T> {
+        ReceiverInput::new(rx)
+    }
+}
+
+impl<T> Iterator for ReceiverInput<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        let never = crossbeam_channel::never();
+        let cancelled = match &self.cancel {
+            Some(token) if token.is_cancelled() => return None,
+            Some(token) => token.channel(),
+            None => &never,
+        };
+        let mut sel = Select::new();
+        let value = sel.recv(&self.rx);
+        sel.recv(cancelled);
+        let op = match self.idle_timeout {
+            Some(timeout) => sel.select_timeout(timeout).ok()?,
+            None => sel.select(),
+        };
+        if op.index() == value {
+            let v = op.recv(&self.rx).ok();
+            if v.is_none() {
+                // Disconnected, so the background thread, if any, has
+                // returned.
+                if let Some(handle) = self.handle.take() {
+                    if let Err(payload) = handle.join() {
+                        panic::resume_unwind(payload);
+                    }
+                }
+            }
+            v
+        } else {
+            let _ = op.recv(cancelled);
+            None
+        }
+    }
+}
+
+impl<T, M> Pipeline<ReceiverInput<T>, M>
+where
+    T: Send + 'static,
+    M: Mapper<T> + Clone + Send + 'static,
+    M::Out: Send + 'static,
+{
+    /// Like Pipeline::new, mapping values received from a channel.
+    ///
+    /// Passing a ReceiverInput instead of a plain receiver sets an idle
+    /// timeout or cancel token, so that a consumer waiting on stalled
+    /// senders can be stopped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use {
+    ///     plmap::{CancelToken, Pipeline, ReceiverInput},
+    ///     std::time::Duration,
+    /// };
+    ///
+    /// let (tx, rx) = crossbeam_channel::unbounded();
+    /// for x in 0..100 {
+    ///     tx.send(x).unwrap();
+    /// }
+    /// // tx is never dropped, the input ends when it goes quiet.
+    /// let token = CancelToken::new();
+    /// let input = ReceiverInput::new(rx)
+    ///     .idle_timeout(Duration::from_millis(50))
+    ///     .cancel_token(&token);
+    /// let total: i32 = Pipeline::from_receiver(4, |x| x * 2, input).sum();
+    /// assert_eq!(total, 9900);
+    /// ```
+    pub fn from_receiver(
+        n_workers: impl Into<Workers>,
+        mapper: M,
+        input: impl Into<ReceiverInput<T>>,
+    ) -> Pipeline<ReceiverInput<T>, M> {
+        Pipeline::new(n_workers, mapper, input.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::{
+            thread,
+            time::{Duration, Instant},
+        },
+    };
+
+    #[test]
+    fn test_from_receiver() {
+        for w in 0..3 {
+            let (tx, rx) = crossbeam_channel::unbounded();
+            for x in 0..100 {
+                tx.send(x).unwrap();
+            }
+            drop(tx);
+            let v: Vec<i32> = Pipeline::from_receiver(w, |x| x * 2, rx).collect();
+            assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+        }
+
+        // Stalled senders time out.
+        let (tx, rx) = crossbeam_channel::unbounded();
+        tx.send(1).unwrap();
+        let input = ReceiverInput::new(rx).idle_timeout(Duration::from_millis(20));
+        let v: Vec<i32> = Pipeline::from_receiver(2, |x| x, input).collect();
+        assert_eq!(v, vec![1]);
+
+        // Or are cancelled.
+        let (_tx, rx) = crossbeam_channel::unbounded::<i32>();
+        let token = CancelToken::new();
+        let input = ReceiverInput::new(rx).cancel_token(&token);
+        let mut p = Pipeline::from_receiver(2, |x| x, input);
+        let start = Instant::now();
+        let t = token.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            t.cancel();
+        });
+        assert_eq!(p.next(), None);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn test_receiver_input_spawn() {
+        for w in 0..3 {
+            let v: Vec<i32> =
+                Pipeline::from_receiver(w, |x| x * 2, ReceiverInput::spawn(4, 0..100)).collect();
+            assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+        }
+
+        // A blocked iterator can be cancelled.
+        let blocked = (0..).inspect(|&x| {
+            if x == 3 {
+                thread::sleep(Duration::from_secs(3600));
+            }
+        });
+        let token = CancelToken::new();
+        let input = ReceiverInput::spawn(1, blocked).cancel_token(&token);
+        let mut p = Pipeline::from_receiver(2, |x: i32| x, input);
+        assert_eq!(p.next(), Some(0));
+        let t = token.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            t.cancel();
+        });
+        assert_eq!(p.by_ref().count(), 2);
+
+        // Panics on the background thread reach the consumer.
+        let panicky = (0..10).inspect(|&x| {
+            if x == 5 {
+                panic!("boom");
+            }
+        });
+        let mut input = ReceiverInput::spawn(2, panicky);
+        assert_eq!(input.by_ref().take(5).count(), 5);
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| input.next()));
+        assert!(result.is_err());
+    }
+}
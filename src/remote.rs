@@ -0,0 +1,372 @@
+use {
+    super::{
+        codec::{self, Codec},
+        mapper::Mapper,
+        pipeline::Pipeline,
+        semaphore::{CountingSemaphore, Semaphore},
+        workers::Workers,
+    },
+    std::{
+        io::{self, BufReader},
+        marker::PhantomData,
+        net::{TcpListener, TcpStream},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+    },
+};
+
+/// Serve pipelines on other machines, mapping values sent over
+/// connections accepted from listener, with the default limits of
+/// TcpServer.
+///
+/// Each connection is served on its own thread with its own clone of
+/// mapper, so a pipeline gets as much parallelism from an endpoint as
+/// it has workers connected to it. Values and results are sent as
+/// frames of a u32 little endian length followed by the encoded value.
+/// This only returns if accepting a connection fails.
+pub fn serve_tcp<In, M, C>(listener: TcpListener, codec: C, mapper: M) -> io::Result<()>
+where
+    In: 'static,
+    M: Mapper<In> + Clone + Send + 'static,
+    C: Codec<In> + Codec<M::Out> + Clone + Send + 'static,
+{
+    TcpServer::new().serve(listener, codec, mapper)
+}
+
+/// TcpServer serves pipelines on other machines like serve_tcp, with
+/// limits on what the peers that connect can make it do.
+///
+/// # Examples
+///
+/// ```
+/// use {
+///     plmap::{Bincode, RemoteMap, TcpServer},
+///     std::{net::TcpListener, thread},
+/// };
+///
+/// let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+/// let addr = listener.local_addr().unwrap().to_string();
+/// let server = TcpServer::new().max_frame_len(1024).max_connections(8);
+/// thread::spawn(move || server.serve(listener, Bincode, |x: u64| x * 2));
+///
+/// let total: u64 = (0..100u64)
+///     .plmap_remote::<u64, _>(4, &[&addr], Bincode)
+///     .sum();
+/// assert_eq!(total, 9900);
+/// ```
+#[derive(Clone, Debug)]
+pub struct TcpServer {
+    max_frame_len: usize,
+    max_connections: usize,
+}
+
+impl Default for TcpServer {
+    fn default() -> Self {
+        TcpServer::new()
+    }
+}
+
+impl TcpServer {
+    pub fn new() -> TcpServer {
+        TcpServer {
+            max_frame_len: codec::MAX_FRAME_LEN,
+            max_connections: 256,
+        }
+    }
+
+    /// The longest frame read from a connection in bytes, 64 MiB by
+    /// default. A longer length prefix closes the connection before
+    /// anything is allocated for it.
+    pub fn max_frame_len(mut self, n: usize) -> Self {
+        self.max_frame_len = n;
+        self
+    }
+
+    /// How many connections are served at once, 256 by default. Once
+    /// that many are open, the next is only accepted when one closes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if n is zero.
+    pub fn max_connections(mut self, n: usize) -> Self {
+        assert!(n > 0, "max_connections must be greater than zero");
+        self.max_connections = n;
+        self
+    }
+
+    /// Serve connections accepted from listener, see serve_tcp.
+    pub fn serve<In, M, C>(&self, listener: TcpListener, codec: C, mapper: M) -> io::Result<()>
+    where
+        In: 'static,
+        M: Mapper<In> + Clone + Send + 'static,
+        C: Codec<In> + Codec<M::Out> + Clone + Send + 'static,
+    {
+        let connections = Arc::new(CountingSemaphore::new(self.max_connections));
+        loop {
+            let permit = Permit::acquire(&connections);
+            let (stream, _) = listener.accept()?;
+            let codec = codec.clone();
+            let mut mapper = mapper.clone();
+            let max_frame_len = self.max_frame_len;
+            thread::spawn(move || {
+                let _permit = permit;
+                let _ = stream.set_nodelay(true);
+                let mut r = BufReader::new(stream.try_clone()?);
+                let mut w = stream;
+                codec::serve_frames(&mut r, &mut w, &codec, &mut mapper, max_frame_len)
+            });
+        }
+    }
+}
+
+/// Permit is one of a TcpServer's connections, given back when the
+/// connection's thread returns.
+struct Permit(Arc<CountingSemaphore>);
+
+impl Permit {
+    fn acquire(connections: &Arc<CountingSemaphore>) -> Permit {
+        connections.acquire();
+        Permit(connections.clone())
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+/// A connection to a remote endpoint.
+struct Connection {
+    w: TcpStream,
+    r: BufReader<TcpStream>,
+}
+
+/// RemoteMapper maps values by sending them to an endpoint served by
+/// serve_tcp. Each clone connects to the next endpoint in turn, the
+/// first time it is used.
+///
+/// If the connection fails, apply panics and the pipeline resumes the
+/// panic on the consumer when it reaches that value. The next value
+/// reconnects.
+pub struct RemoteMapper<In, Out, C> {
+    endpoints: Arc<Vec<String>>,
+    next: Arc<AtomicUsize>,
+    endpoint: usize,
+    codec: C,
+    conn: Option<Connection>,
+    _types: PhantomData<fn(In) -> Out>,
+}
+
+impl<In, Out, C> RemoteMapper<In, Out, C>
+where
+    C: Codec<In> + Codec<Out>,
+{
+    /// # Panics
+    ///
+    /// Panics if there are no endpoints.
+    pub fn new(endpoints: &[&str], codec: C) -> RemoteMapper<In, Out, C> {
+        assert!(!endpoints.is_empty(), "no endpoints to connect to");
+        RemoteMapper {
+            endpoints: Arc::new(endpoints.iter().map(|e| e.to_string()).collect()),
+            next: Arc::new(AtomicUsize::new(1)),
+            endpoint: 0,
+            codec,
+            conn: None,
+            _types: PhantomData,
+        }
+    }
+
+    fn call(&mut self, v: In) -> io::Result<Out> {
+        let conn = match &mut self.conn {
+            Some(conn) => conn,
+            None => {
+                let stream = TcpStream::connect(&self.endpoints[self.endpoint])?;
+                stream.set_nodelay(true)?;
+                self.conn.insert(Connection {
+                    r: BufReader::new(stream.try_clone()?),
+                    w: stream,
+                })
+            }
+        };
+        codec::call(&mut conn.w, &mut conn.r, &self.codec, &v)
+    }
+}
+
+impl<In, Out, C> Clone for RemoteMapper<In, Out, C>
+where
+    C: Clone,
+{
+    fn clone(&self) -> Self {
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        RemoteMapper {
+            endpoints: self.endpoints.clone(),
+            next: self.next.clone(),
+            endpoint: n % self.endpoints.len(),
+            codec: self.codec.clone(),
+            conn: None,
+            _types: PhantomData,
+        }
+    }
+}
+
+impl<In, Out, C> Mapper<In> for RemoteMapper<In, Out, C>
+where
+    C: Codec<In> + Codec<Out>,
+{
+    type Out = Out;
+
+    fn apply(&mut self, v: In) -> Out {
+        match self.call(v) {
+            Ok(out) => out,
+            Err(err) => {
+                self.conn = None;
+                panic!(
+                    "remote worker {} failed: {}",
+                    self.endpoints[self.endpoint], err
+                )
+            }
+        }
+    }
+}
+
+/// RemoteMap can be imported to add the plmap_remote function to
+/// iterators.
+pub trait RemoteMap: Iterator + Sized
+where
+    Self::Item: Send + 'static,
+{
+    /// Like plmap, but values are mapped by remote endpoints served by
+    /// serve_tcp, with the workers spread over the endpoints in turn.
+    /// Results are put back in order locally.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use {
+    ///     plmap::{Bincode, RemoteMap},
+    ///     std::{net::TcpListener, thread},
+    /// };
+    ///
+    /// // Usually on another machine.
+    /// let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    /// let addr = listener.local_addr().unwrap().to_string();
+    /// thread::spawn(move || plmap::serve_tcp(listener, Bincode, |x: u64| x * 2));
+    ///
+    /// let total: u64 = (0..100u64)
+    ///     .plmap_remote::<u64, _>(4, &[&addr], Bincode)
+    ///     .sum();
+    /// assert_eq!(total, 9900);
+    /// ```
+    fn plmap_remote<Out, C>(
+        self,
+        n_workers: impl Into<Workers>,
+        endpoints: &[&str],
+        codec: C,
+    ) -> Pipeline<Self, RemoteMapper<Self::Item, Out, C>>
+    where
+        Out: Send + 'static,
+        C: Codec<Self::Item> + Codec<Out> + Clone + Send + 'static,
+    {
+        Pipeline::new(n_workers, RemoteMapper::new(endpoints, codec), self)
+    }
+}
+
+impl<I> RemoteMap for I
+where
+    I: Iterator,
+    I::Item: Send + 'static,
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::Bincode,
+        std::{
+            io::{ErrorKind, Read, Write},
+            panic,
+            sync::Mutex,
+            time::Duration,
+        },
+    };
+
+    #[test]
+    fn test_remote_pipeline() {
+        let served = Arc::new(Mutex::new(Vec::new()));
+        let mut endpoints = Vec::new();
+        for i in 0..2 {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            endpoints.push(listener.local_addr().unwrap().to_string());
+            let served = served.clone();
+            thread::spawn(move || {
+                serve_tcp(listener, Bincode, move |x: u64| {
+                    served.lock().unwrap().push(i);
+                    x * 2
+                })
+            });
+        }
+        let endpoints: Vec<&str> = endpoints.iter().map(|e| e.as_str()).collect();
+
+        for w in 0..4 {
+            let v: Vec<u64> = (0..100u64).plmap_remote(w, &endpoints, Bincode).collect();
+            assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+        }
+        // With more than one worker both endpoints are used.
+        let served = served.lock().unwrap();
+        assert!(served.contains(&0) && served.contains(&1));
+
+        // Nothing is listening on a port that was just freed.
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let result = panic::catch_unwind(|| {
+            (0..10u64)
+                .plmap_remote::<u64, _>(2, &[&addr], Bincode)
+                .count()
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_tcp_server_limits() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = TcpServer::new().max_frame_len(64).max_connections(1);
+        thread::spawn(move || server.serve(listener, Bincode, |x: u64| x * 2));
+
+        // An oversized length prefix closes the connection rather than
+        // being allocated.
+        let mut conn = TcpStream::connect(&addr).unwrap();
+        conn.write_all(&u32::MAX.to_le_bytes()).unwrap();
+        let mut buf = [0; 1];
+        assert_eq!(conn.read(&mut buf).unwrap(), 0);
+        drop(conn);
+
+        // While the only connection is open, the next one isn't served.
+        let mut idle = TcpStream::connect(&addr).unwrap();
+        let out: u64 =
+            codec::call(&mut idle.try_clone().unwrap(), &mut idle, &Bincode, &1u64).unwrap();
+        assert_eq!(out, 2);
+        let mut waiting = TcpStream::connect(&addr).unwrap();
+        codec::write_frame(&mut waiting, &Bincode.encode(&2u64).unwrap()).unwrap();
+        waiting
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let err = waiting.read(&mut buf).unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::WouldBlock | ErrorKind::TimedOut
+        ));
+        drop(idle);
+        waiting.set_read_timeout(None).unwrap();
+        let out = codec::read_frame(&mut waiting, 64).unwrap().unwrap();
+        assert_eq!(Codec::<u64>::decode(&Bincode, &out).unwrap(), 4);
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::VecDeque;
+
+enum Entry<T> {
+    Pending,
+    Ready(T),
+    Taken,
+}
+
+/// ReorderBuffer holds the results of dispatched values, which may
+/// arrive in any order, until the consumer takes them.
+///
+/// Each dispatched value is given a sequence number and a slot, slots
+/// are usually taken from either end of the buffer, but may also be
+/// taken from within a window at the front.
+pub(crate) struct ReorderBuffer<T> {
+    head: usize,
+    slots: VecDeque<Entry<T>>,
+    taken: usize,
+}
+
+impl<T> ReorderBuffer<T> {
+    pub(crate) fn with_capacity(cap: usize) -> ReorderBuffer<T> {
+        ReorderBuffer {
+            head: 0,
+            slots: VecDeque::with_capacity(cap),
+            taken: 0,
+        }
+    }
+
+    /// The number of slots not yet taken, filled or not.
+    pub(crate) fn len(&self) -> usize {
+        self.slots.len() - self.taken
+    }
+
+    /// The number of slots filled but not yet taken.
+    pub(crate) fn ready_len(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|slot| matches!(slot, Entry::Ready(_)))
+            .count()
+    }
+
+    /// The number of slots taken from the front, which is the sequence
+    /// number of the first live slot.
+    pub(crate) fn head(&self) -> usize {
+        self.head
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Add a slot at the back, returning its sequence number.
+    pub(crate) fn push(&mut self) -> usize {
+        self.slots.push_back(Entry::Pending);
+        self.head + self.slots.len() - 1
+    }
+
+    /// Fill the slot with the given sequence number, results for slots
+    /// that were already taken are dropped.
+    pub(crate) fn insert(&mut self, seq: usize, v: T) {
+        if let Some(slot) = seq
+            .checked_sub(self.head)
+            .and_then(|i| self.slots.get_mut(i))
+        {
+            if let Entry::Pending = slot {
+                *slot = Entry::Ready(v);
+            }
+        }
+    }
+
+    /// Whether any of the first window + 1 slots has been filled.
+    pub(crate) fn front_ready(&self, window: usize) -> bool {
+        self.slots
+            .iter()
+            .take(window.saturating_add(1))
+            .any(|slot| matches!(slot, Entry::Ready(_)))
+    }
+
+    pub(crate) fn back_ready(&self) -> bool {
+        matches!(self.slots.back(), Some(Entry::Ready(_)))
+    }
+
+    /// Take the first filled slot within the first window + 1 slots.
+    pub(crate) fn take_ready(&mut self, window: usize) -> Option<T> {
+        let i = self
+            .slots
+            .iter()
+            .take(window.saturating_add(1))
+            .position(|slot| matches!(slot, Entry::Ready(_)))?;
+        let v = match std::mem::replace(&mut self.slots[i], Entry::Taken) {
+            Entry::Ready(v) => v,
+            _ => unreachable!(),
+        };
+        self.taken += 1;
+        self.compact();
+        Some(v)
+    }
+
+    /// Take the front slot, which is None if it has not been filled.
+    pub(crate) fn pop_front(&mut self) -> Option<Option<T>> {
+        let slot = self.slots.pop_front()?;
+        self.head += 1;
+        self.compact();
+        Some(slot.into_ready())
+    }
+
+    /// Take the back slot, which is None if it has not been filled.
+    pub(crate) fn pop_back(&mut self) -> Option<Option<T>> {
+        let slot = self.slots.pop_back()?;
+        self.compact();
+        Some(slot.into_ready())
+    }
+
+    /// Drop taken slots from the ends, so the ends are always live.
+    ///
+    /// Sequence numbers of slots dropped from the back are reused by
+    /// later pushes, which is fine as their results have already been
+    /// taken.
+    fn compact(&mut self) {
+        while let Some(Entry::Taken) = self.slots.front() {
+            self.slots.pop_front();
+            self.head += 1;
+            self.taken -= 1;
+        }
+        while let Some(Entry::Taken) = self.slots.back() {
+            self.slots.pop_back();
+            self.taken -= 1;
+        }
+    }
+}
+
+impl<T> Entry<T> {
+    fn into_ready(self) -> Option<T> {
+        match self {
+            Entry::Ready(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reorder_buffer() {
+        let mut b = ReorderBuffer::with_capacity(4);
+        for i in 0..4 {
+            assert_eq!(b.push(), i);
+        }
+        b.insert(2, 'c');
+        b.insert(0, 'a');
+        assert_eq!(b.ready_len(), 2);
+        assert!(b.front_ready(0));
+        assert!(!b.back_ready());
+        assert_eq!(b.pop_front(), Some(Some('a')));
+        assert_eq!(b.pop_front(), Some(None));
+        // Results for taken slots are ignored.
+        b.insert(1, 'b');
+        assert_eq!(b.pop_front(), Some(Some('c')));
+        assert_eq!(b.push(), 4);
+        b.insert(4, 'e');
+        assert!(b.back_ready());
+        assert_eq!(b.pop_back(), Some(Some('e')));
+        assert_eq!(b.len(), 1);
+        assert_eq!(b.pop_front(), Some(None));
+        assert!(b.is_empty());
+        assert_eq!(b.pop_front(), None);
+    }
+
+    #[test]
+    fn test_reorder_buffer_window() {
+        let mut b = ReorderBuffer::with_capacity(4);
+        for i in 0..4 {
+            assert_eq!(b.push(), i);
+        }
+        b.insert(2, 'c');
+        assert!(!b.front_ready(1));
+        assert!(b.front_ready(2));
+        assert_eq!(b.take_ready(1), None);
+        assert_eq!(b.take_ready(2), Some('c'));
+        assert_eq!(b.len(), 3);
+        b.insert(2, 'x');
+        b.insert(3, 'd');
+        assert_eq!(b.take_ready(usize::MAX), Some('d'));
+        // Taken slots at the ends are dropped.
+        b.insert(0, 'a');
+        b.insert(1, 'b');
+        assert_eq!(b.take_ready(0), Some('a'));
+        assert_eq!(b.take_ready(0), Some('b'));
+        assert!(b.is_empty());
+        // Slots 2 and 3 were dropped from the back, so their sequence
+        // numbers are reused.
+        assert_eq!(b.push(), 2);
+    }
+}
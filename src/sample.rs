@@ -0,0 +1,187 @@
+use {
+    super::{mapper::Mapper, rng::Rng},
+    std::iter::FusedIterator,
+};
+
+/// Sample picks which input values a sampling pipeline maps, see
+/// PipelineMap::pl_sample. Values that are not picked are passed
+/// through unmapped, or dropped with drop_rest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    pick: Pick,
+    seed: u64,
+    drop_rest: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Pick {
+    Every(usize),
+    Probability(f64),
+}
+
+impl Sample {
+    /// Pick every nth value, starting with the first.
+    ///
+    /// # Panics
+    ///
+    /// Panics if n is zero.
+    pub fn every(n: usize) -> Sample {
+        assert!(n > 0, "n must be greater than zero");
+        Sample {
+            pick: Pick::Every(n),
+            seed: 0,
+            drop_rest: false,
+        }
+    }
+
+    /// Pick each value with probability p, drawn from a PRNG seeded
+    /// with seed, so the same values are picked on every run.
+    pub fn probability(p: f64) -> Sample {
+        Sample {
+            pick: Pick::Probability(p),
+            seed: 0,
+            drop_rest: false,
+        }
+    }
+
+    /// Seed the PRNG used with probability, the default is zero.
+    pub fn seed(mut self, seed: u64) -> Sample {
+        self.seed = seed;
+        self
+    }
+
+    /// Drop the values that are not picked, instead of passing them
+    /// through.
+    pub fn drop_rest(mut self) -> Sample {
+        self.drop_rest = true;
+        self
+    }
+}
+
+/// Sampled is a result of a sampling pipeline, see
+/// PipelineMap::pl_sample.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Sampled<In, Out> {
+    /// The output of a picked value.
+    Mapped(Out),
+    /// A value that was not picked, as it came from the input.
+    Passed(In),
+}
+
+impl<In, Out> Sampled<In, Out> {
+    /// The output, if the value was picked.
+    pub fn mapped(self) -> Option<Out> {
+        match self {
+            Sampled::Mapped(out) => Some(out),
+            Sampled::Passed(_) => None,
+        }
+    }
+}
+
+/// SampledInput tags input values with whether they were picked by a
+/// Sample, as they are pulled by the consumer, see
+/// PipelineMap::pl_sample.
+#[derive(Debug)]
+pub struct SampledInput<I> {
+    input: I,
+    sample: Sample,
+    rng: Rng,
+    index: usize,
+}
+
+impl<I> SampledInput<I> {
+    pub(crate) fn new(input: I, sample: Sample) -> SampledInput<I> {
+        SampledInput {
+            input,
+            rng: Rng::new(sample.seed),
+            sample,
+            index: 0,
+        }
+    }
+
+    fn pick(&mut self) -> bool {
+        let index = self.index;
+        self.index += 1;
+        match self.sample.pick {
+            Pick::Every(n) => index % n == 0,
+            Pick::Probability(p) => self.rng.chance(p),
+        }
+    }
+}
+
+impl<I: Iterator> Iterator for SampledInput<I> {
+    type Item = (bool, I::Item);
+
+    fn next(&mut self) -> Option<(bool, I::Item)> {
+        loop {
+            let v = self.input.next()?;
+            let picked = self.pick();
+            if picked || !self.sample.drop_rest {
+                return Some((picked, v));
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (lo, hi) = self.input.size_hint();
+        if self.sample.drop_rest {
+            (0, hi)
+        } else {
+            (lo, hi)
+        }
+    }
+}
+
+impl<I: FusedIterator> FusedIterator for SampledInput<I> {}
+
+/// SampleMapper maps the values picked by a Sample with a mapper,
+/// passing the rest through, see PipelineMap::pl_sample.
+#[derive(Clone, Copy, Debug)]
+pub struct SampleMapper<M>(pub(crate) M);
+
+impl<In, M> Mapper<(bool, In)> for SampleMapper<M>
+where
+    M: Mapper<In>,
+{
+    type Out = Sampled<In, M::Out>;
+
+    fn apply(&mut self, (picked, v): (bool, In)) -> Self::Out {
+        if picked {
+            Sampled::Mapped(self.0.apply(v))
+        } else {
+            Sampled::Passed(v)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::PipelineMap};
+
+    #[test]
+    fn test_pl_sample() {
+        for w in 0..3 {
+            let v: Vec<_> = (0..10)
+                .pl_sample(w, Sample::every(3), |x| x * 100)
+                .collect();
+            assert_eq!(v[0], Sampled::Mapped(0));
+            assert_eq!(v[1], Sampled::Passed(1));
+            assert_eq!(v[3], Sampled::Mapped(300));
+            assert_eq!(v.len(), 10);
+
+            let v: Vec<i32> = (0..10)
+                .pl_sample(w, Sample::every(3).drop_rest(), |x| x * 100)
+                .filter_map(Sampled::mapped)
+                .collect();
+            assert_eq!(v, [0, 300, 600, 900]);
+        }
+
+        let sample = Sample::probability(0.25).seed(7).drop_rest();
+        let a: Vec<_> = (0..1000).pl_sample(2, sample, |x| x).collect();
+        let b: Vec<_> = (0..1000).pl_sample(2, sample, |x| x).collect();
+        assert_eq!(a, b);
+        assert!(a.len() > 150 && a.len() < 350, "{}", a.len());
+        let c: Vec<_> = (0..1000).pl_sample(2, sample.seed(8), |x| x).collect();
+        assert_ne!(a, c);
+    }
+}
@@ -0,0 +1,139 @@
+use super::{
+    chan::{self, Channels, Receiver, Sender, TryRecvError},
+    wait::WaitStrategy,
+};
+
+/// Schedule controls how dispatched values are assigned to workers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Schedule {
+    /// All workers take values from a single shared queue, an idle
+    /// worker always picks up the next value. This is the default.
+    #[default]
+    Shared,
+    /// Each worker has its own queue and values are assigned to them
+    /// in turn, regardless of how busy each worker is.
+    RoundRobin,
+    /// Like RoundRobin, but a worker with an empty queue takes values
+    /// queued for other workers, so a worker stuck on an expensive
+    /// value does not hold up the values queued behind it.
+    Stealing,
+}
+
+/// Dispatcher is the sending half of the worker queues.
+pub(crate) struct Dispatcher<T> {
+    queues: Vec<Sender<T>>,
+    next: usize,
+}
+
+impl<T> Dispatcher<T> {
+    pub(crate) fn send(&mut self, v: T) {
+        let queue = &self.queues[self.next];
+        self.next = (self.next + 1) % self.queues.len();
+        queue.send(v).unwrap();
+    }
+
+    /// Disconnect the queues, workers exit once they have drained
+    /// any values already queued for them.
+    pub(crate) fn close(&mut self) {
+        self.queues.clear();
+    }
+}
+
+/// WorkQueue is the receiving half of the worker queues, one per worker.
+pub(crate) struct WorkQueue<T> {
+    own: Receiver<T>,
+    steal_from: Vec<Receiver<T>>,
+}
+
+impl<T: Send> WorkQueue<T> {
+    /// Wait for the next value, None means the dispatcher was closed.
+    pub(crate) fn recv(&self, wait: WaitStrategy) -> Option<T> {
+        if self.steal_from.is_empty() {
+            return wait.recv(&self.own).ok();
+        }
+
+        if let Some(v) = self.try_recv() {
+            return v;
+        }
+        if let Some(v) = wait.wait(|| self.try_recv()) {
+            return v;
+        }
+
+        let rxs: Vec<&Receiver<T>> = Some(&self.own)
+            .into_iter()
+            .chain(self.steal_from.iter())
+            .collect();
+        match chan::select(&rxs).1 {
+            Ok(v) => Some(v),
+            // All the queues are closed together, only our own
+            // leftovers remain.
+            Err(_) => self.own.recv().ok(),
+        }
+    }
+
+    /// Take a value from our own queue, or failing that another
+    /// worker's, without blocking.
+    fn try_recv(&self) -> Option<Option<T>> {
+        match self.own.try_recv() {
+            Ok(v) => return Some(Some(v)),
+            Err(TryRecvError::Disconnected) => return Some(None),
+            Err(TryRecvError::Empty) => (),
+        }
+        self.steal_from
+            .iter()
+            .find_map(|other| other.try_recv().ok())
+            .map(Some)
+    }
+}
+
+/// Create the queues connecting a dispatcher to n_workers workers,
+/// each queue holds at most cap values. The channels are made by K.
+pub(crate) fn work_queues<'env, K, T>(
+    schedule: Schedule,
+    n_workers: usize,
+    cap: usize,
+) -> (Dispatcher<T>, Vec<WorkQueue<T>>)
+where
+    K: Channels<'env>,
+    T: Send + 'env,
+{
+    if schedule == Schedule::Shared || n_workers == 0 {
+        let (tx, rx) = K::bounded(cap);
+        let queues = (0..n_workers)
+            .map(|_| WorkQueue {
+                own: rx.clone(),
+                steal_from: Vec::new(),
+            })
+            .collect();
+        return (
+            Dispatcher {
+                queues: vec![tx],
+                next: 0,
+            },
+            queues,
+        );
+    }
+
+    let (senders, receivers): (Vec<_>, Vec<_>) = (0..n_workers).map(|_| K::bounded(cap)).unzip();
+    let queues = (0..n_workers)
+        .map(|i| WorkQueue {
+            own: receivers[i].clone(),
+            steal_from: if schedule == Schedule::Stealing {
+                // Start with the next worker along so that thieves
+                // spread out over their victims.
+                (1..n_workers)
+                    .map(|j| receivers[(i + j) % n_workers].clone())
+                    .collect()
+            } else {
+                Vec::new()
+            },
+        })
+        .collect();
+    (
+        Dispatcher {
+            queues: senders,
+            next: 0,
+        },
+        queues,
+    )
+}
@@ -1,11 +1,28 @@
-use {super::mapper::Mapper, std::collections::VecDeque};
+use {
+    super::{
+        builder::PipelineBuilder,
+        mapper::Mapper,
+        pipeline::{self, InFlight, Pipeline, PipelineConfig, PipelineParts},
+        spawner::Spawner,
+        workers::Workers,
+    },
+    std::{
+        fmt,
+        iter::{FromIterator, FusedIterator},
+        marker::PhantomData,
+    },
+};
 
 /// ScopedPipeline is a wrapper around a worker pool and implements
 /// iterator. Usually they should be created via the PipelineMap
 /// extension trait and calling plmap on an iterator.
 ///
-/// ScopedPipeline differs from Pipeline in that it uses a std::thread::Scope
-/// and allows non 'static lifetimes.
+/// ScopedPipeline differs from Pipeline in that it uses a thread scope
+/// and allows non 'static lifetimes. It is a Pipeline whose workers are
+/// spawned on the scope, see Spawner. Both std::thread::Scope and, with
+/// the crossbeam-scope feature, crossbeam_utils::thread::Scope can be
+/// used. PipelineBuilder::build_scoped starts one with any of the
+/// builder's options.
 pub struct ScopedPipeline<'scope, 'env, I, M>
 where
     I: Iterator,
@@ -13,12 +30,8 @@ where
     M: Mapper<I::Item> + Clone + Send + 'env,
     M::Out: Send + 'env,
 {
-    mapper: M,
-    input: I,
-    queue: VecDeque<crossbeam_channel::Receiver<M::Out>>,
-    dispatch: crossbeam_channel::Sender<(I::Item, crossbeam_channel::Sender<M::Out>)>,
-    _worker_scope: &'scope crossbeam_utils::thread::Scope<'env>,
-    workers: Vec<crossbeam_utils::thread::ScopedJoinHandle<'scope, ()>>,
+    pipeline: Pipeline<I, M>,
+    _worker_scope: PhantomData<&'scope &'env ()>,
 }
 
 impl<'scope, 'env, I, M> ScopedPipeline<'scope, 'env, I, M>
@@ -28,54 +41,102 @@ where
     M: Mapper<I::Item> + Clone + Send + 'env,
     M::Out: Send + 'env,
 {
-    pub fn new(
-        worker_scope: &'scope crossbeam_utils::thread::Scope<'env>,
-        n_workers: usize,
+    pub fn new<S>(
+        worker_scope: &'scope S,
+        n_workers: impl Into<Workers>,
         mapper: M,
         input: I,
-    ) -> ScopedPipeline<'scope, 'env, I, M> {
-        let (dispatch, dispatch_rx): (
-            crossbeam_channel::Sender<(_, crossbeam_channel::Sender<M::Out>)>,
-            _,
-        ) = crossbeam_channel::bounded(0);
-        let mut workers = Vec::with_capacity(n_workers);
-
-        for _ in 0..n_workers {
-            let mut mapper = mapper.clone();
-            let dispatch_rx = dispatch_rx.clone();
-            let handle = worker_scope.spawn(move |_| {
-                while let Ok((in_val, respond)) = dispatch_rx.recv() {
-                    let out_val = mapper.apply(in_val);
-                    respond.send(out_val).unwrap();
-                }
-            });
-            workers.push(handle)
-        }
+    ) -> ScopedPipeline<'scope, 'env, I, M>
+    where
+        &'scope S: Spawner<'env>,
+    {
+        Self::with_builder(worker_scope, PipelineBuilder::new(n_workers), mapper, input)
+    }
 
+    /// Start a scoped pipeline configured by builder.
+    pub(crate) fn with_builder<S>(
+        worker_scope: &'scope S,
+        builder: PipelineBuilder,
+        mapper: M,
+        input: I,
+    ) -> ScopedPipeline<'scope, 'env, I, M>
+    where
+        &'scope S: Spawner<'env>,
+    {
         ScopedPipeline {
-            mapper,
-            input,
-            dispatch,
-            workers,
-            _worker_scope: worker_scope,
-            queue: VecDeque::with_capacity(n_workers + 1),
+            pipeline: builder.build_with(worker_scope, input, mapper),
+            _worker_scope: PhantomData,
         }
     }
+
+    /// Skip the next n outputs without mapping them, see
+    /// Pipeline::pl_skip.
+    pub fn pl_skip(mut self, n: usize) -> Self {
+        self.pipeline = self.pipeline.pl_skip(n);
+        self
+    }
+
+    /// Like Iterator::nth, but values before the nth are skipped
+    /// without mapping them, see pl_skip.
+    pub fn pl_nth(&mut self, n: usize) -> Option<<M as Mapper<I::Item>>::Out> {
+        self.pipeline.pl_nth(n)
+    }
+
+    /// Stop the pipeline, returning the remaining input and the
+    /// mapper, see Pipeline::into_inner.
+    pub fn into_inner(
+        self,
+        in_flight: InFlight,
+    ) -> PipelineParts<I, M, <M as Mapper<I::Item>>::Out> {
+        self.pipeline.into_inner(in_flight)
+    }
+
+    /// Return a reference to the next result without consuming it, see
+    /// Pipeline::peek.
+    pub fn peek(&mut self) -> Option<&<M as Mapper<I::Item>>::Out> {
+        self.pipeline.peek()
+    }
+
+    /// The number of workers mapping values, see Pipeline::n_workers.
+    pub fn n_workers(&self) -> usize {
+        self.pipeline.n_workers()
+    }
+
+    /// The most values that can be in flight at once, see
+    /// Pipeline::in_flight_capacity.
+    pub fn in_flight_capacity(&self) -> usize {
+        self.pipeline.in_flight_capacity()
+    }
+
+    /// The configuration the pipeline is running with, see
+    /// Pipeline::config.
+    pub fn config(&self) -> PipelineConfig {
+        self.pipeline.config()
+    }
+
+    /// The number of values being mapped, see Pipeline::in_flight.
+    pub fn in_flight(&self) -> usize {
+        self.pipeline.in_flight()
+    }
+
+    /// The number of results ready but not yet taken, see
+    /// Pipeline::queue_len.
+    pub fn queue_len(&self) -> usize {
+        self.pipeline.queue_len()
+    }
 }
 
-impl<'scope, 'env, I, M> Drop for ScopedPipeline<'scope, 'env, I, M>
+impl<'scope, 'env, I, M> fmt::Debug for ScopedPipeline<'scope, 'env, I, M>
 where
     I: Iterator,
     I::Item: Send + 'env,
     M: Mapper<I::Item> + Clone + Send + 'env,
     M::Out: Send + 'env,
 {
-    fn drop(&mut self) {
-        let (dummy, _) = crossbeam_channel::bounded(1);
-        self.dispatch = dummy;
-        for worker in self.workers.drain(..) {
-            worker.join().unwrap();
-        }
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ScopedPipeline")
+            .field(&self.pipeline)
+            .finish()
     }
 }
 
@@ -89,65 +150,209 @@ where
     type Item = <M as Mapper<I::Item>>::Out;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.workers.is_empty() {
-            return self.input.next().map(|v| self.mapper.apply(v));
-        }
+        self.pipeline.next()
+    }
 
-        while self.queue.len() < self.workers.len() + 1 {
-            match self.input.next() {
-                Some(v) => {
-                    let (tx, rx) = crossbeam_channel::bounded(1);
-                    self.queue.push_back(rx);
-                    self.dispatch.send((v, tx)).unwrap();
-                }
-                None => break,
-            }
-        }
+    fn fold<B, F>(self, init: B, f: F) -> B
+    where
+        F: FnMut(B, Self::Item) -> B,
+    {
+        self.pipeline.fold(init, f)
+    }
+}
 
-        self.queue.pop_front().map(|rx| rx.recv().unwrap())
+impl<'scope, 'env, I, M> DoubleEndedIterator for ScopedPipeline<'scope, 'env, I, M>
+where
+    I: DoubleEndedIterator,
+    I::Item: Send + 'env,
+    M: Mapper<I::Item> + Clone + Send + 'env,
+    M::Out: Send + 'env,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.pipeline.next_back()
     }
 }
 
-/// ScopedPipelineMap can be imported to add the scoped_plmap function to iterators.
-pub trait ScopedPipelineMap<'scope, 'env, I, M>
+impl<'scope, 'env, I, M> FusedIterator for ScopedPipeline<'scope, 'env, I, M>
 where
     I: Iterator,
     I::Item: Send + 'env,
     M: Mapper<I::Item> + Clone + Send + 'env,
     M::Out: Send + 'env,
 {
-    fn scoped_plmap(
-        self,
-        worker_scope: &'scope crossbeam_utils::thread::Scope<'env>,
-        n_workers: usize,
-        m: M,
-    ) -> ScopedPipeline<'scope, 'env, I, M>;
 }
 
-impl<'scope, 'env, I, M> ScopedPipelineMap<'scope, 'env, I, M> for I
+/// ScopedPipelineMap can be imported to add the scoped_plmap function to iterators,
+/// and to anything else that can be turned into one, see PipelineMap.
+pub trait ScopedPipelineMap<'scope, 'env, I, M>
 where
     I: Iterator,
     I::Item: Send + 'env,
     M: Mapper<I::Item> + Clone + Send + 'env,
     M::Out: Send + 'env,
 {
-    fn scoped_plmap(
+    fn scoped_plmap<S>(
         self,
-        worker_scope: &'scope crossbeam_utils::thread::Scope<'env>,
-        n_workers: usize,
+        worker_scope: &'scope S,
+        n_workers: impl Into<Workers>,
         m: M,
-    ) -> ScopedPipeline<'scope, 'env, I, M> {
-        ScopedPipeline::new(worker_scope, n_workers, m, self)
+    ) -> ScopedPipeline<'scope, 'env, I, M>
+    where
+        &'scope S: Spawner<'env>;
+
+    /// Like scoped_plmap, but results are yielded as soon as they are
+    /// ready, in any order, see PipelineBuilder::unordered.
+    fn scoped_plmap_unordered<S>(
+        self,
+        worker_scope: &'scope S,
+        n_workers: impl Into<Workers>,
+        m: M,
+    ) -> ScopedPipeline<'scope, 'env, I, M>
+    where
+        &'scope S: Spawner<'env>;
+
+    /// Like scoped_plmap followed by collecting into Result<C, E>, but
+    /// no more values are dispatched once an error is taken, see
+    /// PipelineMap::pl_try_collect.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::ScopedPipelineMap;
+    ///
+    /// let words = vec!["1", "2", "x", "4"];
+    /// std::thread::scope(|s| {
+    ///     let v: Result<Vec<i32>, _> = words.iter().scoped_try_plmap(s, 2, |w: &&str| w.parse::<i32>());
+    ///     assert!(v.is_err());
+    /// });
+    /// ```
+    fn scoped_try_plmap<S, C, T, E>(
+        self,
+        worker_scope: &'scope S,
+        n_workers: impl Into<Workers>,
+        m: M,
+    ) -> Result<C, E>
+    where
+        &'scope S: Spawner<'env>,
+        M: Mapper<I::Item, Out = Result<T, E>>,
+        T: Send + 'env,
+        E: Send + 'env,
+        C: FromIterator<T>;
+}
+
+impl<'scope, 'env, T, M> ScopedPipelineMap<'scope, 'env, T::IntoIter, M> for T
+where
+    T: IntoIterator,
+    T::Item: Send + 'env,
+    M: Mapper<T::Item> + Clone + Send + 'env,
+    M::Out: Send + 'env,
+{
+    fn scoped_plmap<S>(
+        self,
+        worker_scope: &'scope S,
+        n_workers: impl Into<Workers>,
+        m: M,
+    ) -> ScopedPipeline<'scope, 'env, T::IntoIter, M>
+    where
+        &'scope S: Spawner<'env>,
+    {
+        ScopedPipeline::new(worker_scope, n_workers, m, self.into_iter())
+    }
+
+    fn scoped_plmap_unordered<S>(
+        self,
+        worker_scope: &'scope S,
+        n_workers: impl Into<Workers>,
+        m: M,
+    ) -> ScopedPipeline<'scope, 'env, T::IntoIter, M>
+    where
+        &'scope S: Spawner<'env>,
+    {
+        let builder = PipelineBuilder::new(n_workers).unordered();
+        ScopedPipeline::with_builder(worker_scope, builder, m, self.into_iter())
+    }
+
+    fn scoped_try_plmap<S, C, U, E>(
+        self,
+        worker_scope: &'scope S,
+        n_workers: impl Into<Workers>,
+        m: M,
+    ) -> Result<C, E>
+    where
+        &'scope S: Spawner<'env>,
+        M: Mapper<T::Item, Out = Result<U, E>>,
+        U: Send + 'env,
+        E: Send + 'env,
+        C: FromIterator<U>,
+    {
+        let p = PipelineBuilder::new(n_workers).build_with(worker_scope, self.into_iter(), m);
+        pipeline::try_collect(p)
     }
 }
 
+/// Map input with mapper on n_workers threads of a new std::thread
+/// scope, handing the results to f, so that the input and mapper can
+/// borrow from the caller without setting up a scope.
+///
+/// The scope, and so the call, ends once f returns and the workers
+/// have stopped.
+///
+/// # Examples
+///
+/// ```
+/// let v: Vec<i32> = (0..100).collect();
+/// let offset = 1;
+/// let total = plmap::plmap_scope(&v, 4, |x: &i32| x + offset, |outputs| outputs.sum::<i32>());
+/// assert_eq!(total, 5050);
+/// ```
+pub fn plmap_scope<'env, T, M, F, R>(input: T, n_workers: impl Into<Workers>, mapper: M, f: F) -> R
+where
+    T: IntoIterator,
+    T::Item: Send + 'env,
+    M: Mapper<T::Item> + Clone + Send + 'env,
+    M::Out: Send + 'env,
+    F: FnOnce(ScopedPipeline<'_, 'env, T::IntoIter, M>) -> R,
+{
+    std::thread::scope(|s| f(input.scoped_plmap(s, n_workers, mapper)))
+}
+
+/// Transform data in place, chunk_size values at a time, with f run on
+/// n_workers threads of a new std::thread scope, returning once every
+/// chunk is done. The last chunk is shorter if chunk_size does not
+/// divide data's length.
+///
+/// # Panics
+///
+/// Panics if chunk_size is zero.
+///
+/// # Examples
+///
+/// ```
+/// let mut frame = vec![1u8; 1 << 16];
+/// plmap::pl_map_in_place(&mut frame, 4096, 4, |chunk: &mut [u8]| {
+///     for px in chunk {
+///         *px *= 3;
+///     }
+/// });
+/// assert!(frame.iter().all(|&px| px == 3));
+/// ```
+pub fn pl_map_in_place<T, F>(data: &mut [T], chunk_size: usize, n_workers: impl Into<Workers>, f: F)
+where
+    T: Send,
+    F: FnMut(&mut [T]) + Clone + Send,
+{
+    plmap_scope(data.chunks_mut(chunk_size), n_workers, f, |p| {
+        p.for_each(drop)
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_scoped_parallel_pipeline() {
-        crossbeam_utils::thread::scope(|s| {
+        std::thread::scope(|s| {
             for w in 0..3 {
                 for (i, v) in (0..100).scoped_plmap(s, w, |x| x * 2).enumerate() {
                     let i = i as i32;
@@ -156,6 +361,237 @@ mod tests {
                 assert_eq!((0..100).scoped_plmap(s, w, |x| x * 2).count(), 100);
             }
         })
+    }
+
+    #[test]
+    fn test_scoped_pipeline_into_iterator() {
+        let v: Vec<i32> = (0..100).collect();
+        std::thread::scope(|s| {
+            for w in 0..3 {
+                let doubled: Vec<i32> = (&v).scoped_plmap(s, w, |x: &i32| x * 2).collect();
+                assert_eq!(doubled, v.iter().map(|x| x * 2).collect::<Vec<_>>());
+                let doubled: Vec<i32> = v.clone().scoped_plmap(s, w, |x| x * 2).collect();
+                assert_eq!(doubled.len(), 100);
+            }
+        })
+    }
+
+    struct Unfused(i32);
+
+    impl Iterator for Unfused {
+        type Item = i32;
+
+        fn next(&mut self) -> Option<i32> {
+            self.0 += 1;
+            if self.0 % 3 == 0 {
+                None
+            } else {
+                Some(self.0)
+            }
+        }
+    }
+
+    #[test]
+    fn test_scoped_pipeline_in_flight() {
+        std::thread::scope(|s| {
+            let mut p = (0..10).scoped_plmap(s, 2, |x| x);
+            assert_eq!((p.in_flight(), p.queue_len()), (0, 0));
+            assert_eq!(p.by_ref().count(), 10);
+            assert_eq!((p.in_flight(), p.queue_len()), (0, 0));
+            let s = format!("{:?}", p);
+            assert!(
+                s.starts_with("ScopedPipeline(Pipeline { n_workers: 2,"),
+                "{}",
+                s
+            );
+        });
+    }
+
+    #[test]
+    fn test_scoped_pipeline_fused() {
+        std::thread::scope(|s| {
+            for w in 0..3 {
+                let mut p = Unfused(0).scoped_plmap(s, w, |x| x * 2);
+                assert_eq!(p.next(), Some(2));
+                assert_eq!(p.next(), Some(4));
+                for _ in 0..5 {
+                    assert_eq!(p.next(), None);
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn test_scoped_pipeline_skip_unmapped() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let calls = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            let mut p = (0..100)
+                .scoped_plmap(s, 2, |x| {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    x * 2
+                })
+                .pl_skip(50);
+            assert_eq!(p.next(), Some(100));
+            assert_eq!(p.pl_nth(10), Some(122));
+        });
+        assert!(calls.load(Ordering::SeqCst) <= 6);
+    }
+
+    #[test]
+    fn test_scoped_pipeline_peek() {
+        std::thread::scope(|s| {
+            for w in 0..3 {
+                let mut p = (0..10).scoped_plmap(s, w, |x| x * 2);
+                assert_eq!(p.peek(), Some(&0));
+                assert_eq!(p.next(), Some(0));
+                assert_eq!(p.peek(), Some(&2));
+                assert_eq!(p.sum::<i32>(), 90);
+            }
+        })
+    }
+
+    #[test]
+    fn test_scoped_pipeline_into_inner() {
+        let v: Vec<i32> = (0..100).collect();
+        std::thread::scope(|s| {
+            let mut p = v.iter().scoped_plmap(s, 2, |x: &i32| x * 2);
+            assert_eq!(p.next(), Some(0));
+            let parts = p.into_inner(InFlight::Drain);
+            let mut rest = parts.results;
+            rest.extend(parts.input.map(parts.mapper.unwrap()));
+            assert_eq!(rest, (1..100).map(|x| x * 2).collect::<Vec<_>>());
+        })
+    }
+
+    #[test]
+    fn test_scoped_pipeline_fold() {
+        std::thread::scope(|s| {
+            for w in 0..3 {
+                let v: Vec<i32> = (0..100).scoped_plmap(s, w, |x| x * 2).collect();
+                assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+            }
+        })
+    }
+
+    #[test]
+    fn test_scoped_pipeline_double_ended() {
+        std::thread::scope(|s| {
+            for w in 0..3 {
+                let mut p = (0..101).scoped_plmap(s, w, |x| x * 2);
+                for i in 0..50 {
+                    assert_eq!(p.next_back(), Some((100 - i) * 2));
+                    assert_eq!(p.next(), Some(i * 2));
+                }
+                assert_eq!(p.next(), Some(100));
+                assert_eq!(p.next_back(), None);
+            }
+        })
+    }
+
+    #[test]
+    fn test_scoped_pipeline_unordered() {
+        let names: Vec<String> = (0..100).map(|x| x.to_string()).collect();
+        std::thread::scope(|s| {
+            for w in 0..3 {
+                let mut v: Vec<usize> = names
+                    .iter()
+                    .scoped_plmap_unordered(s, w, |n: &String| n.len())
+                    .collect();
+                v.sort();
+                assert_eq!(v, names.iter().map(|n| n.len()).collect::<Vec<_>>());
+            }
+        })
+    }
+
+    #[test]
+    fn test_scoped_try_plmap() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let v: Vec<i32> = (0..1000).collect();
+        let calls = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for w in 0..3 {
+                let ok: Result<Vec<i32>, i32> =
+                    v.iter().scoped_try_plmap(s, w, |x: &i32| Ok(x * 2));
+                assert_eq!(ok, Ok(v.iter().map(|x| x * 2).collect()));
+
+                calls.store(0, Ordering::SeqCst);
+                let err: Result<Vec<i32>, i32> = v.iter().scoped_try_plmap(s, w, |x: &i32| {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    if *x == 10 {
+                        Err(*x)
+                    } else {
+                        Ok(*x)
+                    }
+                });
+                assert_eq!(err, Err(10));
+                assert!(calls.load(Ordering::SeqCst) < 100);
+            }
+        })
+    }
+
+    #[test]
+    fn test_scoped_pipeline_builder() {
+        let v: Vec<i32> = (0..100).collect();
+        std::thread::scope(|s| {
+            for w in 0..3 {
+                let p = PipelineBuilder::new(w)
+                    .adaptive_in_flight(1, 8)
+                    .reorder_window(4)
+                    .build_scoped(s, v.iter(), |x: &i32| x * 2);
+                let config = p.config();
+                assert_eq!(config.adaptive_in_flight, Some((1, 8)));
+                assert_eq!(config.reorder_window, 4);
+                let mut out: Vec<i32> = p.collect();
+                out.sort();
+                assert_eq!(out, v.iter().map(|x| x * 2).collect::<Vec<_>>());
+            }
+        })
+    }
+
+    #[test]
+    fn test_plmap_scope() {
+        let v: Vec<i32> = (0..100).collect();
+        for w in 0..3 {
+            let doubled: Vec<i32> = plmap_scope(&v, w, |x: &i32| x * 2, |p| p.collect());
+            assert_eq!(doubled, v.iter().map(|x| x * 2).collect::<Vec<_>>());
+        }
+        let n = plmap_scope(
+            v.chunks(10),
+            2,
+            |c: &[i32]| c.len(),
+            |mut p| {
+                assert_eq!(p.next(), Some(10));
+                p.count()
+            },
+        );
+        assert_eq!(n, 9);
+    }
+
+    #[test]
+    fn test_pl_map_in_place() {
+        for w in 0..3 {
+            let mut v: Vec<i32> = (0..1000).collect();
+            pl_map_in_place(&mut v, 64, w, |c: &mut [i32]| {
+                c.iter_mut().for_each(|x| *x *= 2)
+            });
+            assert_eq!(v, (0..1000).map(|x| x * 2).collect::<Vec<_>>());
+        }
+        let mut empty: [i32; 0] = [];
+        pl_map_in_place(&mut empty, 8, 2, |_: &mut [i32]| panic!("no chunks"));
+    }
+
+    #[cfg(feature = "crossbeam-scope")]
+    #[test]
+    fn test_crossbeam_scoped_pipeline() {
+        crossbeam_utils::thread::scope(|s| {
+            for w in 0..3 {
+                let v: Vec<i32> = (0..100).scoped_plmap(s, w, |x| x * 2).collect();
+                assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+            }
+        })
         .unwrap()
     }
 }
@@ -0,0 +1,166 @@
+use std::{
+    fmt,
+    sync::{Condvar, Mutex},
+};
+
+/// Semaphore is a limit on some resource shared between pipelines,
+/// see PipelineBuilder::semaphore. A pipeline acquires a permit before
+/// dispatching each value and releases it once the value's result
+/// comes back.
+///
+/// CountingSemaphore is a simple implementation, the trait lets
+/// pipelines share a limit with anything else that counts the same
+/// resource.
+pub trait Semaphore: Send + Sync {
+    /// Wait for a permit and take it.
+    fn acquire(&self);
+
+    /// Take a permit if one is free, without waiting.
+    fn try_acquire(&self) -> bool;
+
+    /// Give back a permit taken by acquire or try_acquire.
+    fn release(&self);
+}
+
+impl fmt::Debug for dyn Semaphore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Semaphore")
+    }
+}
+
+/// CountingSemaphore is a Semaphore with a fixed number of permits.
+///
+/// # Examples
+///
+/// ```
+/// use {
+///     plmap::{CountingSemaphore, PipelineBuilder},
+///     std::{sync::Arc, thread},
+/// };
+///
+/// // At most four values are mapped at once across both pipelines.
+/// let gpus = Arc::new(CountingSemaphore::new(4));
+/// let handles: Vec<_> = (0..2)
+///     .map(|_| {
+///         let builder = PipelineBuilder::new(4).semaphore(gpus.clone());
+///         thread::spawn(move || builder.build(0..100, |x: u64| x * 2).sum::<u64>())
+///     })
+///     .collect();
+/// for h in handles {
+///     assert_eq!(h.join().unwrap(), 9900);
+/// }
+/// assert_eq!(gpus.available(), 4);
+/// ```
+#[derive(Debug)]
+pub struct CountingSemaphore {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl CountingSemaphore {
+    /// Create a semaphore with n permits.
+    pub fn new(n: usize) -> CountingSemaphore {
+        CountingSemaphore {
+            available: Mutex::new(n),
+            released: Condvar::new(),
+        }
+    }
+
+    /// The number of permits not currently taken.
+    pub fn available(&self) -> usize {
+        *self.available.lock().unwrap()
+    }
+}
+
+impl Semaphore for CountingSemaphore {
+    fn acquire(&self) {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= 1;
+    }
+
+    fn try_acquire(&self) -> bool {
+        let mut available = self.available.lock().unwrap();
+        if *available == 0 {
+            return false;
+        }
+        *available -= 1;
+        true
+    }
+
+    fn release(&self) {
+        *self.available.lock().unwrap() += 1;
+        self.released.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::PipelineBuilder,
+        std::{
+            sync::{
+                atomic::{AtomicUsize, Ordering},
+                Arc,
+            },
+            thread,
+            time::Duration,
+        },
+    };
+
+    #[test]
+    fn test_semaphore() {
+        for w in 0..3 {
+            let sem = Arc::new(CountingSemaphore::new(2));
+            let running = Arc::new(AtomicUsize::new(0));
+            let peak = Arc::new(AtomicUsize::new(0));
+            let handles: Vec<_> = (0..3)
+                .map(|_| {
+                    let builder = PipelineBuilder::new(w).in_flight(8).semaphore(sem.clone());
+                    let (running, peak) = (running.clone(), peak.clone());
+                    thread::spawn(move || {
+                        builder
+                            .build(0..30, move |x: usize| {
+                                let n = running.fetch_add(1, Ordering::SeqCst) + 1;
+                                peak.fetch_max(n, Ordering::SeqCst);
+                                thread::sleep(Duration::from_micros(200));
+                                running.fetch_sub(1, Ordering::SeqCst);
+                                x
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            for h in handles {
+                assert_eq!(h.join().unwrap(), (0..30).collect::<Vec<_>>());
+            }
+            assert!(peak.load(Ordering::SeqCst) <= 2);
+            assert_eq!(sem.available(), 2);
+
+            // Dropping a pipeline gives back the permits of values it
+            // still had in flight.
+            let mut p = PipelineBuilder::new(w)
+                .semaphore(sem.clone())
+                .build(0.., |x: u64| x);
+            assert_eq!(p.next(), Some(0));
+            drop(p);
+            assert_eq!(sem.available(), 2);
+
+            // Mapping from both ends with a single permit.
+            let sem = Arc::new(CountingSemaphore::new(1));
+            let mut p = PipelineBuilder::new(w)
+                .semaphore(sem.clone())
+                .build(0..10, |x: u64| x);
+            assert_eq!(p.next_back(), Some(9));
+            assert_eq!(p.next(), Some(0));
+            assert_eq!(
+                p.rev().collect::<Vec<_>>(),
+                (1..9).rev().collect::<Vec<_>>()
+            );
+            assert_eq!(sem.available(), 1);
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use {
+    signal_hook::{consts::SIGINT, flag, SigId},
+    std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// Interrupt is set by Ctrl-C while a pipeline built with
+/// PipelineBuilder::cancel_on_ctrl_c lives, its handler is
+/// unregistered when it is dropped.
+pub(crate) struct Interrupt {
+    flag: Arc<AtomicBool>,
+    id: SigId,
+}
+
+impl Interrupt {
+    pub(crate) fn register() -> Interrupt {
+        let flag = Arc::new(AtomicBool::new(false));
+        let id = flag::register(SIGINT, flag.clone()).expect("SIGINT can be handled");
+        Interrupt { flag, id }
+    }
+
+    pub(crate) fn is_set(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+    }
+
+    /// Set the flag as Ctrl-C would, without raising SIGINT in the
+    /// process the tests share.
+    #[cfg(test)]
+    pub(crate) fn set(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Interrupt {
+    fn drop(&mut self) {
+        signal_hook::low_level::unregister(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::PipelineBuilder;
+
+    #[test]
+    fn test_cancel_on_ctrl_c() {
+        for w in 0..3 {
+            let mut p = PipelineBuilder::new(w)
+                .in_flight(4)
+                .cancel_on_ctrl_c()
+                .build(0.., |x: u64| x * 2);
+            assert_eq!(p.next(), Some(0));
+            p.interrupt().set();
+            // Results already dispatched are still yielded in order.
+            let rest: Vec<u64> = p.collect();
+            assert!(rest.len() <= 4, "{:?}", rest);
+            assert_eq!(
+                rest,
+                (1..=rest.len() as u64).map(|x| x * 2).collect::<Vec<_>>()
+            );
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use {
+    super::sync,
+    std::{cell::Cell, fmt, sync::Arc, thread},
+};
+
+/// Spawner starts the worker threads of a pipeline, implement it to run
+/// workers somewhere other than on new std threads.
+///
+/// Each worker is a long running loop which returns once the pipeline
+/// signals shutdown by closing its work queue, after which the
+/// pipeline waits for every worker to return before it is dropped.
+/// The spawner must run each worker on its own thread, as workers
+/// block waiting for work. A worker that is dropped without being run
+/// is treated as having returned.
+///
+/// # Examples
+///
+/// ```
+/// use plmap::{PipelineBuilder, Spawner};
+///
+/// struct Named;
+///
+/// impl Spawner<'static> for Named {
+///     fn spawn<F>(&self, worker: F)
+///     where
+///         F: FnOnce() + Send + 'static,
+///     {
+///         std::thread::Builder::new()
+///             .name("plmap-worker".to_string())
+///             .spawn(worker)
+///             .unwrap();
+///     }
+/// }
+///
+/// let total: i32 = PipelineBuilder::new(4)
+///     .build_with(Named, 0..100, |x| x * 2)
+///     .sum();
+/// assert_eq!(total, 9900);
+/// ```
+pub trait Spawner<'env> {
+    /// Start running worker on a thread of its own.
+    fn spawn<F>(&self, worker: F)
+    where
+        F: FnOnce() + Send + 'env;
+}
+
+/// ThreadSpawner runs each worker on a new std thread, it is the
+/// spawner used by plmap.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ThreadSpawner;
+
+impl Spawner<'static> for ThreadSpawner {
+    fn spawn<F>(&self, worker: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        sync::thread::spawn(worker);
+    }
+}
+
+/// Whether the target can start threads. On wasm32 without the atomics
+/// target feature, as in most browser builds, it can't, and every
+/// pipeline maps inline instead, whatever its spawner.
+pub(crate) const HAS_THREADS: bool =
+    !cfg!(all(target_arch = "wasm32", not(target_feature = "atomics")));
+
+/// Worker is a pipeline worker loop, as passed to a function given to
+/// PipelineBuilder::spawn_fn.
+pub type Worker = Box<dyn FnOnce() + Send>;
+
+/// SpawnFn is a user supplied function for starting workers, see
+/// PipelineBuilder::spawn_fn.
+#[derive(Clone)]
+pub(crate) struct SpawnFn(pub(crate) Arc<dyn Fn(String, Worker) + Send + Sync>);
+
+impl SpawnFn {
+    /// A spawner for one pipeline, naming its workers in turn.
+    pub(crate) fn spawner(&self) -> NamedSpawner<'_> {
+        NamedSpawner {
+            spawn: self,
+            next: Cell::new(0),
+        }
+    }
+}
+
+impl fmt::Debug for SpawnFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SpawnFn")
+    }
+}
+
+pub(crate) struct NamedSpawner<'a> {
+    spawn: &'a SpawnFn,
+    next: Cell<usize>,
+}
+
+impl Spawner<'static> for NamedSpawner<'_> {
+    fn spawn<F>(&self, worker: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let n = self.next.replace(self.next.get() + 1);
+        (self.spawn.0)(format!("plmap-worker-{}", n), Box::new(worker));
+    }
+}
+
+/// Workers spawned on a std scope may borrow from outside it, see
+/// ScopedPipeline.
+impl<'scope, 'env> Spawner<'env> for &'scope thread::Scope<'scope, 'env> {
+    fn spawn<F>(&self, worker: F)
+    where
+        F: FnOnce() + Send + 'env,
+    {
+        thread::Scope::spawn(self, worker);
+    }
+}
+
+/// Workers spawned on a crossbeam scope may borrow from outside it, see
+/// ScopedPipeline.
+#[cfg(feature = "crossbeam-scope")]
+impl<'env> Spawner<'env> for &crossbeam_utils::thread::Scope<'env> {
+    fn spawn<F>(&self, worker: F)
+    where
+        F: FnOnce() + Send + 'env,
+    {
+        crossbeam_utils::thread::Scope::spawn(self, move |_| worker());
+    }
+}
+
+/// Workers run as blocking tasks on a tokio runtime, sharing its
+/// blocking thread pool. If the runtime shuts down before a worker has
+/// started, the pipeline panics on the consumer instead of waiting for
+/// results that will never come.
+///
+/// A pipeline dropped on a multi-threaded runtime waits for its
+/// workers through block_in_place, so other tasks move to another
+/// thread. On a current-thread runtime the wait blocks every task,
+/// stop the pipeline with Pipeline::close instead.
+///
+/// # Examples
+///
+/// ```
+/// use plmap::PipelineBuilder;
+///
+/// let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+/// let total: i32 = PipelineBuilder::new(4)
+///     .build_with(rt.handle().clone(), 0..100, |x| x * 2)
+///     .sum();
+/// assert_eq!(total, 9900);
+/// ```
+#[cfg(feature = "tokio")]
+impl Spawner<'static> for tokio::runtime::Handle {
+    fn spawn<F>(&self, worker: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.spawn_blocking(worker);
+    }
+}
+
+/// SmolSpawner runs workers on the blocking thread pool used by smol
+/// and async-executor, so applications in that ecosystem don't keep a
+/// second set of threads.
+///
+/// # Examples
+///
+/// ```
+/// use plmap::{PipelineBuilder, SmolSpawner};
+///
+/// let total: i32 = PipelineBuilder::new(4)
+///     .build_with(SmolSpawner, 0..100, |x| x * 2)
+///     .sum();
+/// assert_eq!(total, 9900);
+/// ```
+#[cfg(feature = "smol")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SmolSpawner;
+
+#[cfg(feature = "smol")]
+impl Spawner<'static> for SmolSpawner {
+    fn spawn<F>(&self, worker: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        blocking::unblock(worker).detach();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::PipelineBuilder,
+        std::sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    #[test]
+    fn test_custom_spawner() {
+        struct Counting<'a>(&'a AtomicUsize);
+
+        impl Spawner<'static> for Counting<'_> {
+            fn spawn<F>(&self, worker: F)
+            where
+                F: FnOnce() + Send + 'static,
+            {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                ThreadSpawner.spawn(worker);
+            }
+        }
+
+        let spawned = AtomicUsize::new(0);
+        for w in 0..3 {
+            let v: Vec<i32> = PipelineBuilder::new(w)
+                .build_with(Counting(&spawned), 0..100, |x| x * 2)
+                .collect();
+            assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+        }
+        assert_eq!(spawned.load(Ordering::SeqCst), 3);
+
+        // Workers that are never run don't hold up the pipeline's drop.
+        struct Dropping;
+
+        impl Spawner<'static> for Dropping {
+            fn spawn<F>(&self, _worker: F)
+            where
+                F: FnOnce() + Send + 'static,
+            {
+            }
+        }
+
+        drop(PipelineBuilder::new(2).build_with(Dropping, 0..100, |x: i32| x));
+        let result = std::panic::catch_unwind(|| {
+            PipelineBuilder::new(2)
+                .build_with(Dropping, 0..100, |x: i32| x)
+                .count()
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_spawn_fn() {
+        use std::sync::Mutex;
+
+        let names = Arc::new(Mutex::new(Vec::new()));
+        let n = names.clone();
+        let v: Vec<i32> = PipelineBuilder::new(3)
+            .spawn_fn(move |name, worker| {
+                n.lock().unwrap().push(name.clone());
+                thread::Builder::new().name(name).spawn(worker).unwrap();
+            })
+            .build(0..100, |x| {
+                assert!(thread::current()
+                    .name()
+                    .unwrap()
+                    .starts_with("plmap-worker-"));
+                x * 2
+            })
+            .collect();
+        assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+        assert_eq!(
+            *names.lock().unwrap(),
+            vec!["plmap-worker-0", "plmap-worker-1", "plmap-worker-2"]
+        );
+    }
+
+    #[cfg(feature = "smol")]
+    #[test]
+    fn test_smol_spawner() {
+        for w in 0..3 {
+            let v: Vec<i32> = PipelineBuilder::new(w)
+                .build_with(SmolSpawner, 0..100, |x| x * 2)
+                .collect();
+            assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_tokio_spawner() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        for w in 0..3 {
+            let v: Vec<i32> = PipelineBuilder::new(w)
+                .build_with(rt.handle().clone(), 0..100, |x| x * 2)
+                .collect();
+            assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+        }
+        // Shutdown waits for the blocking workers, which exit with
+        // their pipeline.
+        let p = PipelineBuilder::new(2).build_with(rt.handle().clone(), 0..10, |x: i32| x);
+        assert_eq!(p.sum::<i32>(), 45);
+        rt.shutdown_timeout(std::time::Duration::from_secs(5));
+
+        // Dropping a pipeline in a task on a multi-threaded runtime
+        // waits for the workers off the executor.
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .build()
+            .unwrap();
+        let task = rt.spawn(async {
+            let mut p = PipelineBuilder::new(2).build_with(
+                tokio::runtime::Handle::current(),
+                0..,
+                |x: i32| {
+                    thread::sleep(std::time::Duration::from_millis(10));
+                    x
+                },
+            );
+            assert_eq!(p.next(), Some(0));
+            drop(p);
+        });
+        let other = rt.spawn(async { 1 });
+        assert_eq!(rt.block_on(other).unwrap(), 1);
+        rt.block_on(task).unwrap();
+    }
+}
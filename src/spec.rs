@@ -0,0 +1,94 @@
+use {
+    super::{builder::PipelineBuilder, mapper::Mapper, pipeline::Pipeline},
+    std::fmt,
+};
+
+/// PipelineSpec is a pipeline's configuration and a function making
+/// its mapper, which can be run on any number of inputs, each run
+/// starting a fresh Pipeline. It lets pipelines be configured in one
+/// place and started from many.
+///
+/// # Examples
+///
+/// ```
+/// use plmap::{PipelineBuilder, PipelineSpec};
+///
+/// let spec = PipelineSpec::new(PipelineBuilder::new(4).in_flight(16), || |x: i32| x * 2);
+/// assert_eq!(spec.run(0..100).sum::<i32>(), 9900);
+/// assert_eq!(spec.run(vec![1, 2, 3]).collect::<Vec<_>>(), [2, 4, 6]);
+/// ```
+#[derive(Clone)]
+pub struct PipelineSpec<F> {
+    builder: PipelineBuilder,
+    make_mapper: F,
+}
+
+impl<F> PipelineSpec<F> {
+    /// Create a spec starting pipelines configured by builder, each
+    /// with a mapper returned by make_mapper.
+    pub fn new(builder: PipelineBuilder, make_mapper: F) -> PipelineSpec<F> {
+        PipelineSpec {
+            builder,
+            make_mapper,
+        }
+    }
+
+    /// The configuration pipelines are started with.
+    pub fn builder(&self) -> &PipelineBuilder {
+        &self.builder
+    }
+
+    /// Start a pipeline mapping input with a new mapper.
+    pub fn run<T, M>(&self, input: T) -> Pipeline<T::IntoIter, M>
+    where
+        F: Fn() -> M,
+        T: IntoIterator,
+        T::Item: Send + 'static,
+        M: Mapper<T::Item> + Clone + Send + 'static,
+        M::Out: Send + 'static,
+    {
+        self.builder
+            .clone()
+            .build(input.into_iter(), (self.make_mapper)())
+    }
+}
+
+impl<F> fmt::Debug for PipelineSpec<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PipelineSpec")
+            .field("builder", &self.builder)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    #[test]
+    fn test_pipeline_spec() {
+        let made = AtomicUsize::new(0);
+        let spec = PipelineSpec::new(PipelineBuilder::new(2).in_flight(4), || {
+            made.fetch_add(1, Ordering::SeqCst);
+            |x: i32| x * 2
+        });
+        for n in 0..5 {
+            let v: Vec<i32> = spec.run(0..n).collect();
+            assert_eq!(v, (0..n).map(|x| x * 2).collect::<Vec<_>>());
+        }
+        assert_eq!(made.load(Ordering::SeqCst), 5);
+        assert_eq!(spec.builder().initial_in_flight(), 4);
+        assert!(format!("{:?}", spec).starts_with("PipelineSpec { builder: PipelineBuilder {"));
+
+        // Runs from other threads share the spec.
+        std::thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| assert_eq!(spec.run(vec![1, 2, 3]).sum::<i32>(), 12));
+            }
+        });
+        assert_eq!(made.load(Ordering::SeqCst), 8);
+    }
+}
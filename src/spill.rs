@@ -0,0 +1,265 @@
+use {
+    super::mapper::Mapper,
+    serde::{de::DeserializeOwned, Serialize},
+    std::{
+        env, fmt, fs,
+        io::{self, Read, Write},
+        path::PathBuf,
+        process,
+        sync::{
+            atomic::{AtomicU64, AtomicUsize, Ordering},
+            Arc,
+        },
+    },
+};
+
+/// Spill is a Mapper whose results are written to temporary files,
+/// rather than held in memory, once the results waiting to be
+/// consumed take up more than a memory budget. Created by spill.
+///
+/// Results come out as Spilled values, which are loaded back with
+/// Spilled::load.
+#[derive(Clone)]
+pub struct Spill<M> {
+    mapper: M,
+    budget: Arc<Budget>,
+}
+
+/// Budget tracks the encoded size of results held in memory by a Spill
+/// and its clones.
+struct Budget {
+    limit: usize,
+    used: AtomicUsize,
+    dir: PathBuf,
+}
+
+/// Spilled is a result of a Spill mapper, either in memory or in a
+/// temporary file. The file is removed when the result is loaded or
+/// dropped.
+pub struct Spilled<T> {
+    place: Place<T>,
+}
+
+enum Place<T> {
+    Memory {
+        v: T,
+        size: usize,
+        budget: Arc<Budget>,
+    },
+    Disk(PathBuf),
+}
+
+/// Map values with mapper, spilling results to temporary files once
+/// the results not yet loaded take up more than memory_budget bytes,
+/// as measured by their encoded size, see Spill.
+///
+/// This bounds the memory used by a pipeline whose results are much
+/// larger than its inputs, when one slow value holds back the results
+/// after it.
+///
+/// # Examples
+///
+/// ```
+/// use plmap::{spill, PipelineMap};
+///
+/// let lens: Vec<usize> = (0..100)
+///     .plmap(4, spill(1 << 20, |x: usize| vec![0u8; x * 1000]))
+///     .map_output(|s| s.load().unwrap().len())
+///     .collect();
+/// assert_eq!(lens[99], 99_000);
+/// ```
+pub fn spill<M>(memory_budget: usize, mapper: M) -> Spill<M> {
+    Spill {
+        mapper,
+        budget: Arc::new(Budget {
+            limit: memory_budget,
+            used: AtomicUsize::new(0),
+            dir: env::temp_dir(),
+        }),
+    }
+}
+
+impl<M> Spill<M> {
+    /// Write spilled results to files in dir, instead of the system's
+    /// temporary directory.
+    pub fn dir(mut self, dir: impl Into<PathBuf>) -> Spill<M> {
+        let budget = Budget {
+            limit: self.budget.limit,
+            used: AtomicUsize::new(0),
+            dir: dir.into(),
+        };
+        self.budget = Arc::new(budget);
+        self
+    }
+}
+
+impl<M> fmt::Debug for Spill<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Spill")
+            .field("limit", &self.budget.limit)
+            .field("used", &self.budget.used.load(Ordering::Relaxed))
+            .field("dir", &self.budget.dir)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Budget {
+    /// Claim size bytes of the budget, false if they don't fit.
+    fn reserve(&self, size: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(size).filter(|&n| n <= self.limit)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, size: usize) {
+        self.used.fetch_sub(size, Ordering::AcqRel);
+    }
+
+    /// Write buf to a new file in dir.
+    fn write(&self, buf: &[u8]) -> io::Result<PathBuf> {
+        static SEQ: AtomicU64 = AtomicU64::new(0);
+        let seq = SEQ.fetch_add(1, Ordering::Relaxed);
+        let path = self
+            .dir
+            .join(format!("plmap-spill-{}-{}", process::id(), seq));
+        let mut f = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        if let Err(err) = f.write_all(buf) {
+            let _ = fs::remove_file(&path);
+            return Err(err);
+        }
+        Ok(path)
+    }
+}
+
+fn invalid_data(err: bincode::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+impl<In, M> Mapper<In> for Spill<M>
+where
+    M: Mapper<In>,
+    M::Out: Serialize + DeserializeOwned,
+{
+    type Out = Spilled<M::Out>;
+
+    fn apply(&mut self, v: In) -> Self::Out {
+        let out = self.mapper.apply(v);
+        let budget = &self.budget;
+        let size = match bincode::serialized_size(&out) {
+            Ok(size) => size as usize,
+            // It won't encode, so can only be kept.
+            Err(_) => 0,
+        };
+        if !budget.reserve(size) {
+            // Results that fail to be written stay in memory, over
+            // budget.
+            if let Ok(path) = bincode::serialize(&out)
+                .map_err(invalid_data)
+                .and_then(|buf| budget.write(&buf))
+            {
+                return Spilled {
+                    place: Place::Disk(path),
+                };
+            }
+            budget.used.fetch_add(size, Ordering::AcqRel);
+        }
+        Spilled {
+            place: Place::Memory {
+                v: out,
+                size,
+                budget: budget.clone(),
+            },
+        }
+    }
+}
+
+impl<T> Spilled<T>
+where
+    T: DeserializeOwned,
+{
+    /// Take the result, reading it back from its file if it was
+    /// spilled.
+    pub fn load(mut self) -> io::Result<T> {
+        match std::mem::replace(&mut self.place, Place::Disk(PathBuf::new())) {
+            Place::Memory { v, size, budget } => {
+                budget.release(size);
+                Ok(v)
+            }
+            Place::Disk(path) => {
+                let mut buf = Vec::new();
+                let read = fs::File::open(&path).and_then(|mut f| f.read_to_end(&mut buf));
+                let _ = fs::remove_file(&path);
+                read?;
+                bincode::deserialize(&buf).map_err(invalid_data)
+            }
+        }
+    }
+}
+
+impl<T> Spilled<T> {
+    /// Whether the result was written to a file.
+    pub fn is_spilled(&self) -> bool {
+        matches!(self.place, Place::Disk(_))
+    }
+}
+
+impl<T> Drop for Spilled<T> {
+    fn drop(&mut self) {
+        match &self.place {
+            Place::Memory { size, budget, .. } => budget.release(*size),
+            Place::Disk(path) if !path.as_os_str().is_empty() => {
+                let _ = fs::remove_file(path);
+            }
+            Place::Disk(_) => (),
+        }
+    }
+}
+
+impl<T> fmt::Debug for Spilled<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.place {
+            Place::Memory { size, .. } => f.debug_tuple("Memory").field(size).finish(),
+            Place::Disk(path) => f.debug_tuple("Disk").field(path).finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::PipelineMap};
+
+    #[test]
+    fn test_spill() {
+        for w in 0..3 {
+            let mut p = (0..20).plmap(w, spill(10_000, |x: usize| vec![x as u8; 1000]));
+            // Nothing is consumed until every result is mapped, so most
+            // of them go to disk.
+            let results: Vec<Spilled<Vec<u8>>> = p.by_ref().collect();
+            let spilled = results.iter().filter(|s| s.is_spilled()).count();
+            assert!(spilled >= 10, "{} spilled", spilled);
+            for (x, s) in results.into_iter().enumerate() {
+                assert_eq!(s.load().unwrap(), vec![x as u8; 1000]);
+            }
+        }
+
+        // Loading results frees up the budget again.
+        let mut m = spill(2_000, |x: usize| vec![x as u8; 1000]);
+        let a = m.apply(1);
+        let b = m.apply(2);
+        assert!(!a.is_spilled() && b.is_spilled());
+        let path = match &b.place {
+            Place::Disk(path) => path.clone(),
+            _ => unreachable!(),
+        };
+        assert!(path.exists());
+        drop(b);
+        assert!(!path.exists());
+        assert_eq!(a.load().unwrap(), vec![1; 1000]);
+        assert!(!m.apply(3).is_spilled());
+    }
+}
@@ -0,0 +1,163 @@
+use {
+    super::{builder::PipelineBuilder, chan, mapper::Mapper, pipeline::Pipeline, workers::Workers},
+    std::fmt,
+};
+
+/// Feeder pushes values into a pipeline started by Pipeline::split, for
+/// input that is produced by callbacks rather than an iterator.
+///
+/// The pipeline's input ends once every clone of the feeder has been
+/// dropped, or end has been called on each of them. The Outputs tops
+/// up its in-flight window before handing out results, so a consumer
+/// may wait for further pushes or the end of input before it sees the
+/// first result.
+pub struct Feeder<In> {
+    tx: chan::Sender<In>,
+}
+
+/// FeedInput is pipeline input pushed through a Feeder.
+pub struct FeedInput<In> {
+    rx: chan::Receiver<In>,
+}
+
+/// Outputs is the consuming half of a pipeline started by
+/// Pipeline::split, an ordinary Pipeline whose input is pushed through
+/// a Feeder.
+pub type Outputs<In, M> = Pipeline<FeedInput<In>, M>;
+
+impl<In> Feeder<In> {
+    /// Push v into the pipeline, after any values already pushed.
+    ///
+    /// Waits while the pipeline's in-flight limit worth of values are
+    /// queued waiting for the consumer, so pushing and consuming on the
+    /// same thread can deadlock. Returns v if the Outputs has been
+    /// dropped.
+    pub fn push(&self, v: In) -> Result<(), In> {
+        self.tx.send(v).map_err(|err| err.0)
+    }
+
+    /// Signal that this feeder has no more values, the same as dropping
+    /// it.
+    pub fn end(self) {}
+}
+
+impl<In> Clone for Feeder<In> {
+    fn clone(&self) -> Self {
+        Feeder {
+            tx: self.tx.clone(),
+        }
+    }
+}
+
+impl<In> fmt::Debug for Feeder<In> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Feeder").finish_non_exhaustive()
+    }
+}
+
+impl<In> Iterator for FeedInput<In> {
+    type Item = In;
+
+    fn next(&mut self) -> Option<In> {
+        self.rx.recv().ok()
+    }
+}
+
+impl<In> fmt::Debug for FeedInput<In> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FeedInput")
+            .field("queued", &self.rx.len())
+            .finish()
+    }
+}
+
+impl<In, M> Pipeline<FeedInput<In>, M>
+where
+    In: Send + 'static,
+    M: Mapper<In> + Clone + Send + 'static,
+    M::Out: Send + 'static,
+{
+    /// Start a pipeline whose input is pushed through a Feeder instead
+    /// of pulled from an iterator, so that it can be produced on a
+    /// different thread than the results are consumed on. See
+    /// PipelineBuilder::split for more options.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::Pipeline;
+    ///
+    /// let (feeder, outputs) = Pipeline::split(4, |x: i32| x * 2);
+    /// std::thread::spawn(move || {
+    ///     for x in 0..100 {
+    ///         feeder.push(x).unwrap();
+    ///     }
+    ///     feeder.end();
+    /// });
+    /// assert_eq!(outputs.sum::<i32>(), 9900);
+    /// ```
+    pub fn split(n_workers: impl Into<Workers>, mapper: M) -> (Feeder<In>, Outputs<In, M>) {
+        PipelineBuilder::new(n_workers).split(mapper)
+    }
+}
+
+impl PipelineBuilder {
+    /// Start a pipeline whose input is pushed through a Feeder, see
+    /// Pipeline::split. Up to the in-flight limit of pushed values are
+    /// queued for the consumer.
+    pub fn split<In, M>(self, mapper: M) -> (Feeder<In>, Outputs<In, M>)
+    where
+        In: Send + 'static,
+        M: Mapper<In> + Clone + Send + 'static,
+        M::Out: Send + 'static,
+    {
+        let (tx, rx) = chan::bounded(self.max_in_flight());
+        (Feeder { tx }, self.build(FeedInput { rx }, mapper))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::thread};
+
+    #[test]
+    fn test_split() {
+        for w in 0..3 {
+            let (feeder, outputs) = Pipeline::split(w, |x: i32| x * 2);
+            let producers: Vec<_> = (0..2)
+                .map(|i| {
+                    let feeder = feeder.clone();
+                    thread::spawn(move || {
+                        for x in 0..50 {
+                            feeder.push(i * 50 + x).unwrap();
+                        }
+                    })
+                })
+                .collect();
+            feeder.end();
+            let mut v: Vec<i32> = outputs.collect();
+            v.sort();
+            assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+            for p in producers {
+                p.join().unwrap();
+            }
+        }
+
+        // Pushes fail once the outputs are gone.
+        let (feeder, outputs) = PipelineBuilder::new(2).in_flight(4).split(|x: i32| x);
+        feeder.push(1).unwrap();
+        drop(outputs);
+        assert_eq!(feeder.push(2), Err(2));
+    }
+
+    #[test]
+    fn test_split_order() {
+        let (feeder, mut outputs) = Pipeline::split(2, |x: i32| x + 1);
+        feeder.push(1).unwrap();
+        feeder.push(2).unwrap();
+        drop(feeder);
+        assert_eq!(outputs.next(), Some(2));
+        assert_eq!(outputs.next(), Some(3));
+        assert_eq!(outputs.next(), None);
+    }
+}
@@ -0,0 +1,681 @@
+use {
+    crate::{latency::LatencyHistogram, metered::Stamp, sync::CachePadded, time::Instant},
+    std::{
+        fmt,
+        sync::{
+            atomic::{AtomicU64, AtomicUsize, Ordering},
+            mpsc, Arc, Mutex,
+        },
+        thread,
+        time::Duration,
+    },
+};
+
+/// PipelineReport tells where a pipeline's time went, see
+/// Pipeline::stats and Pipeline::finish.
+///
+/// Busy workers point at the mapper, idle workers with a consumer
+/// spending its time on input at the input, and idle workers with a
+/// consumer rarely waiting at the consumer itself.
+#[derive(Clone, Debug, Default)]
+pub struct PipelineReport {
+    /// Time since the pipeline started.
+    pub elapsed: Duration,
+    /// Time the consumer spent pulling values from the input.
+    pub input: Duration,
+    /// Time the consumer spent waiting for results.
+    pub waiting: Duration,
+    /// One entry per worker thread, empty when values are mapped on
+    /// the consumer or on a pool.
+    pub workers: Vec<WorkerStats>,
+    /// The time from dispatch until each value was mapped, across all
+    /// worker threads, when PipelineBuilder::track_latency is set.
+    pub latency: Option<LatencyHistogram>,
+}
+
+/// WorkerStats are the statistics of a single worker thread.
+#[derive(Clone, Debug, Default)]
+pub struct WorkerStats {
+    /// The number of values mapped.
+    pub items: u64,
+    /// Time spent mapping.
+    pub busy: Duration,
+    /// Time spent waiting for values to map.
+    pub idle: Duration,
+    /// The quickest, mean and slowest time taken to map a value, None
+    /// until one has been mapped.
+    pub min_item: Option<Duration>,
+    pub mean_item: Option<Duration>,
+    pub max_item: Option<Duration>,
+}
+
+/// PipelineState is a snapshot of what a pipeline is doing, for
+/// finding out why it is stuck, see Pipeline::dump_state.
+#[derive(Clone, Debug)]
+pub struct PipelineState {
+    /// One entry per worker thread, empty when values are mapped on
+    /// the consumer or on a pool.
+    pub workers: Vec<WorkerState>,
+    /// The number of values being mapped.
+    pub in_flight: usize,
+    /// The number of results which have come back, but are held in the
+    /// reorder buffer until the results before them arrive.
+    pub reorder_len: usize,
+    /// Whether the next result is still being mapped while later
+    /// results wait behind it.
+    pub head_of_line_blocked: bool,
+    /// Whether the input has run out.
+    pub input_done: bool,
+}
+
+/// WorkerState is what a worker thread is doing, see PipelineState.
+#[derive(Clone, Debug, Default)]
+pub struct WorkerState {
+    /// The index of the value being mapped, counting from whichever
+    /// end of the input it was taken from, None when idle.
+    pub item: Option<usize>,
+    /// How long the value has been mapping for.
+    pub busy_for: Option<Duration>,
+}
+
+/// Stats are shared between a pipeline and its worker threads.
+pub(crate) struct Stats {
+    started: Instant,
+    input: Duration,
+    waiting: Duration,
+    /// Each worker's own, padded so that workers recording their
+    /// values don't write to each other's cache lines.
+    workers: Vec<Arc<CachePadded<WorkerSlot>>>,
+    latency: bool,
+}
+
+/// WorkerSlot is a worker thread's statistics and what it is mapping.
+struct WorkerSlot {
+    /// When the pipeline started, which current times are counted from.
+    started: Instant,
+    current: Current,
+    stats: Mutex<Worker>,
+}
+
+/// Current is the value a worker is mapping, kept out of the mutex so
+/// that marking it costs a worker two stores rather than a lock.
+struct Current {
+    /// The index of the value being mapped, IDLE when there is none.
+    item: AtomicUsize,
+    /// When it started, in nanoseconds since WorkerSlot::started.
+    since: AtomicU64,
+}
+
+impl Current {
+    const IDLE: usize = usize::MAX;
+}
+
+impl WorkerSlot {
+    /// The index of the value being mapped and when it started. The
+    /// two are read separately, so a worker moving on to the next value
+    /// in between can pair an index with a slightly later start.
+    fn current(&self) -> Option<(usize, Instant)> {
+        let item = self.current.item.load(Ordering::Acquire);
+        if item == Current::IDLE {
+            return None;
+        }
+        let since = self.current.since.load(Ordering::Relaxed);
+        Some((item, self.started + Duration::from_nanos(since)))
+    }
+}
+
+#[derive(Default)]
+struct Worker {
+    items: u64,
+    busy: Duration,
+    min: Option<Duration>,
+    max: Option<Duration>,
+    latency: Option<LatencyHistogram>,
+    /// When the worker returned, idle time stops counting.
+    stopped: Option<Instant>,
+}
+
+impl Stats {
+    /// Track stats, and the latency of each value if latency is set.
+    pub(crate) fn new(latency: bool) -> Stats {
+        Stats {
+            started: Instant::now(),
+            input: Duration::ZERO,
+            waiting: Duration::ZERO,
+            workers: Vec::new(),
+            latency,
+        }
+    }
+
+    /// Stamp a value being dispatched.
+    pub(crate) fn stamp(&self) -> Stamp {
+        Stamp::now(self.latency)
+    }
+
+    /// Add a worker thread, returning the handle it records with.
+    pub(crate) fn worker(&mut self) -> WorkerRecorder {
+        let worker = Arc::new(CachePadded(WorkerSlot {
+            started: self.started,
+            current: Current {
+                item: AtomicUsize::new(Current::IDLE),
+                since: AtomicU64::new(0),
+            },
+            stats: Mutex::new(Worker {
+                latency: self.latency.then(LatencyHistogram::new),
+                ..Worker::default()
+            }),
+        }));
+        self.workers.push(worker.clone());
+        WorkerRecorder(worker)
+    }
+
+    /// Pull a value from the input with f, timing it.
+    pub(crate) fn input<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        let start = Instant::now();
+        let v = f();
+        self.input += start.elapsed();
+        v
+    }
+
+    /// Wait for a result with f, timing it.
+    pub(crate) fn wait<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        let start = Instant::now();
+        let v = f();
+        self.waiting += start.elapsed();
+        v
+    }
+
+    /// What each worker thread is doing.
+    pub(crate) fn worker_states(&self) -> Vec<WorkerState> {
+        self.workers
+            .iter()
+            .map(|w| match w.current() {
+                Some((item, start)) => WorkerState {
+                    item: Some(item),
+                    busy_for: Some(start.elapsed()),
+                },
+                None => WorkerState::default(),
+            })
+            .collect()
+    }
+
+    /// Start a thread watching for worker threads which have been
+    /// mapping a value for longer than slow allows.
+    pub(crate) fn watch_slow(&self, slow: SlowFn) -> SlowWatch {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let workers = self.workers.clone();
+        let SlowFn(threshold, f) = slow;
+        // Check often enough that values are reported soon after they
+        // become slow.
+        let every = (threshold / 4).max(Duration::from_millis(1));
+        thread::spawn(move || {
+            // The value each worker was last reported for.
+            let mut reported = vec![None; workers.len()];
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(every) {
+                for (worker, w) in workers.iter().enumerate() {
+                    let current = w.current();
+                    if let Some((item, start)) = current {
+                        let elapsed = start.elapsed();
+                        if elapsed >= threshold && reported[worker] != current {
+                            reported[worker] = current;
+                            f(item, worker, elapsed);
+                        }
+                    }
+                }
+            }
+        });
+        SlowWatch { _stop: stop }
+    }
+
+    pub(crate) fn report(&self) -> PipelineReport {
+        let now = Instant::now();
+        let latency = self.latency.then(|| {
+            let mut latency = LatencyHistogram::new();
+            for w in &self.workers {
+                if let Some(l) = &w.stats.lock().unwrap().latency {
+                    latency.merge(l);
+                }
+            }
+            latency
+        });
+        PipelineReport {
+            elapsed: now - self.started,
+            input: self.input,
+            waiting: self.waiting,
+            workers: self
+                .workers
+                .iter()
+                .map(|w| {
+                    let w = w.stats.lock().unwrap();
+                    let lifetime = w.stopped.unwrap_or(now) - self.started;
+                    WorkerStats {
+                        items: w.items,
+                        busy: w.busy,
+                        idle: lifetime.saturating_sub(w.busy),
+                        min_item: w.min,
+                        mean_item: (w.items > 0).then(|| {
+                            Duration::from_nanos((w.busy.as_nanos() / w.items as u128) as u64)
+                        }),
+                        max_item: w.max,
+                    }
+                })
+                .collect(),
+            latency,
+        }
+    }
+}
+
+/// WorkerRecorder is how a worker thread records its statistics.
+pub(crate) struct WorkerRecorder(Arc<CachePadded<WorkerSlot>>);
+
+impl WorkerRecorder {
+    /// Map value number index, dispatched at stamp, with f, timing it.
+    pub(crate) fn map<R>(&self, index: usize, stamp: Stamp, f: impl FnOnce() -> R) -> R {
+        let start = Instant::now();
+        let since = start.saturating_duration_since(self.0.started).as_nanos() as u64;
+        self.0.current.since.store(since, Ordering::Relaxed);
+        self.0.current.item.store(index, Ordering::Release);
+        let v = f();
+        let end = Instant::now();
+        let took = end - start;
+        self.0.current.item.store(Current::IDLE, Ordering::Relaxed);
+        let mut w = self.0.stats.lock().unwrap();
+        if let (Some(latency), Some(at)) = (&mut w.latency, stamp.0) {
+            latency.record(end.saturating_duration_since(at));
+        }
+        w.items += 1;
+        w.busy += took;
+        w.min = Some(w.min.map_or(took, |min| min.min(took)));
+        w.max = Some(w.max.map_or(took, |max| max.max(took)));
+        v
+    }
+}
+
+impl Drop for WorkerRecorder {
+    fn drop(&mut self) {
+        self.0.stats.lock().unwrap().stopped = Some(Instant::now());
+    }
+}
+
+/// ProgressFn is a user supplied progress callback and how often to
+/// call it, see PipelineBuilder::on_progress.
+#[derive(Clone)]
+pub(crate) struct ProgressFn(
+    pub(crate) usize,
+    pub(crate) Arc<dyn Fn(usize, Option<usize>, Duration) + Send + Sync>,
+);
+
+impl fmt::Debug for ProgressFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ProgressFn").field(&self.0).finish()
+    }
+}
+
+/// SlowFn is a user supplied callback for values that take longer
+/// than a threshold to map, see PipelineBuilder::warn_slow.
+#[derive(Clone)]
+pub(crate) struct SlowFn(
+    pub(crate) Duration,
+    pub(crate) Arc<dyn Fn(usize, usize, Duration) + Send + Sync>,
+);
+
+impl fmt::Debug for SlowFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SlowFn").field(&self.0).finish()
+    }
+}
+
+/// SlowWatch stops the thread started by Stats::watch_slow when
+/// dropped.
+pub(crate) struct SlowWatch {
+    _stop: mpsc::Sender<()>,
+}
+
+/// CompleteFn is a user supplied completion callback, see
+/// PipelineBuilder::on_complete.
+#[derive(Clone)]
+pub(crate) struct CompleteFn(pub(crate) Arc<dyn Fn(PipelineReport) + Send + Sync>);
+
+impl fmt::Debug for CompleteFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CompleteFn")
+    }
+}
+
+/// CheckpointFn is a user supplied checkpoint callback and how often
+/// to call it, see PipelineBuilder::on_checkpoint.
+#[derive(Clone)]
+pub(crate) struct CheckpointFn(
+    pub(crate) usize,
+    pub(crate) Arc<dyn Fn(usize) + Send + Sync>,
+);
+
+impl fmt::Debug for CheckpointFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("CheckpointFn").field(&self.0).finish()
+    }
+}
+
+/// Checkpoint calls a CheckpointFn as the prefix of the input whose
+/// results have been taken grows.
+pub(crate) struct Checkpoint {
+    f: CheckpointFn,
+    /// The index at the last call.
+    reported: usize,
+}
+
+impl Checkpoint {
+    /// Report on a pipeline resumed from index start.
+    pub(crate) fn new(f: CheckpointFn, start: usize) -> Checkpoint {
+        Checkpoint { f, reported: start }
+    }
+
+    /// The first index index values of the input are done with.
+    pub(crate) fn done(&mut self, index: usize) {
+        if index >= self.reported.saturating_add(self.f.0) {
+            self.report(index);
+        }
+    }
+
+    /// Report the final index, unless it has been already.
+    pub(crate) fn finish(&mut self, index: usize) {
+        if index != self.reported {
+            self.report(index);
+        }
+    }
+
+    fn report(&mut self, index: usize) {
+        self.reported = index;
+        (self.f.1)(index);
+    }
+}
+
+/// Progress calls a ProgressFn as the consumer takes results.
+pub(crate) struct Progress {
+    f: ProgressFn,
+    total: Option<usize>,
+    started: Instant,
+    completed: usize,
+    /// The completed count at the last call.
+    reported: usize,
+}
+
+impl Progress {
+    /// Report on a pipeline expected to produce total results, if
+    /// known.
+    pub(crate) fn new(f: ProgressFn, total: Option<usize>) -> Progress {
+        Progress {
+            f,
+            total,
+            started: Instant::now(),
+            completed: 0,
+            reported: 0,
+        }
+    }
+
+    pub(crate) fn completed(&mut self) {
+        self.completed += 1;
+        if self.completed % self.f.0 == 0 {
+            self.report();
+        }
+    }
+
+    /// Report the final count, unless it has been already.
+    pub(crate) fn finish(&mut self) {
+        if self.reported != self.completed {
+            self.report();
+        }
+    }
+
+    fn report(&mut self) {
+        self.reported = self.completed;
+        (self.f.1)(self.completed, self.total, self.started.elapsed());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::{PipelineBuilder, PipelineMap},
+        std::{
+            sync::{Arc, Mutex},
+            thread,
+            time::Duration,
+        },
+    };
+
+    #[test]
+    fn test_stats() {
+        let slow = |x: i32| {
+            thread::sleep(Duration::from_millis(2));
+            x
+        };
+        let mut p = (0..20).plmap(2, slow);
+        assert_eq!(p.stats().workers.len(), 2);
+        assert_eq!(p.by_ref().take(10).count(), 10);
+        let live = p.stats();
+        assert!(live.workers.iter().map(|w| w.items).sum::<u64>() >= 10);
+
+        assert_eq!(p.by_ref().count(), 10);
+        let report = p.finish();
+        assert_eq!(report.workers.iter().map(|w| w.items).sum::<u64>(), 20);
+        for w in &report.workers {
+            if w.items > 0 {
+                let (min, mean, max) = (
+                    w.min_item.unwrap(),
+                    w.mean_item.unwrap(),
+                    w.max_item.unwrap(),
+                );
+                assert!(min >= Duration::from_millis(2));
+                assert!(min <= mean && mean <= max);
+            }
+            assert!(w.busy + w.idle <= report.elapsed);
+        }
+        // The consumer did little but wait on the slow mapper.
+        assert!(report.waiting > report.input);
+
+        let report = PipelineBuilder::new(0).build(0..10, |x| x).finish();
+        assert!(report.workers.is_empty());
+        assert!(report.latency.is_none());
+    }
+
+    #[test]
+    fn test_dump_state() {
+        use std::sync::Barrier;
+
+        let barrier = Arc::new(Barrier::new(2));
+        let b = barrier.clone();
+        let mut p = PipelineBuilder::new(2).in_flight(4).build(0..4, move |x| {
+            if x == 0 {
+                b.wait();
+            }
+            x
+        });
+        let state = p.dump_state();
+        assert_eq!(state.workers.len(), 2);
+        assert!(state.workers.iter().all(|w| w.item.is_none()));
+        assert!(!state.head_of_line_blocked);
+
+        assert_eq!(p.try_next(), std::task::Poll::Pending);
+        let start = std::time::Instant::now();
+        let state = loop {
+            let state = p.dump_state();
+            if state.reorder_len == 3 || start.elapsed() > Duration::from_secs(5) {
+                break state;
+            }
+            thread::sleep(Duration::from_millis(1));
+        };
+        // Release the head before checking, a failure would otherwise
+        // hang dropping the pipeline.
+        barrier.wait();
+        assert!(state.head_of_line_blocked);
+        assert_eq!(state.in_flight, 1);
+        assert!(!state.input_done);
+        let busy: Vec<_> = state.workers.iter().filter(|w| w.item.is_some()).collect();
+        assert_eq!(busy.len(), 1);
+        assert_eq!(busy[0].item, Some(0));
+        assert!(busy[0].busy_for.is_some());
+        assert_eq!(p.count(), 4);
+    }
+
+    #[test]
+    fn test_warn_slow() {
+        let slow = Arc::new(Mutex::new(Vec::new()));
+        let s = slow.clone();
+        let v: Vec<i32> = PipelineBuilder::new(3)
+            .warn_slow(Duration::from_millis(20), move |item, worker, elapsed| {
+                assert!(elapsed >= Duration::from_millis(20));
+                s.lock().unwrap().push((item, worker));
+            })
+            .build(0..10, |x| {
+                if x == 4 {
+                    thread::sleep(Duration::from_millis(200));
+                }
+                x
+            })
+            .collect();
+        assert_eq!(v, (0..10).collect::<Vec<_>>());
+        // Reported once, however long it took.
+        let slow = slow.lock().unwrap();
+        assert_eq!(slow.len(), 1);
+        assert_eq!(slow[0].0, 4);
+        assert!(slow[0].1 < 3);
+    }
+
+    #[test]
+    fn test_track_latency() {
+        let slow = |x: i32| {
+            thread::sleep(Duration::from_millis(2));
+            x
+        };
+        let p = PipelineBuilder::new(2).track_latency().build(0..20, slow);
+        let report = p.finish();
+        // Nothing has been dispatched yet.
+        assert_eq!(report.latency.unwrap().count(), 0);
+
+        let mut p = PipelineBuilder::new(2).track_latency().build(0..20, slow);
+        assert_eq!(p.by_ref().count(), 20);
+        let latency = p.finish().latency.unwrap();
+        assert_eq!(latency.count(), 20);
+        assert!(latency.min().unwrap() >= Duration::from_millis(2));
+        assert!(latency.percentile(50.0) <= latency.percentile(99.0));
+        assert!(latency.percentile(99.0) <= latency.max());
+    }
+
+    #[test]
+    fn test_on_progress() {
+        for w in 0..3 {
+            let calls = Arc::new(Mutex::new(Vec::new()));
+            let c = calls.clone();
+            let total: i32 = PipelineBuilder::new(w)
+                .on_progress(10, move |completed, total, _| {
+                    c.lock().unwrap().push((completed, total));
+                })
+                .build(0..25, |x| x)
+                .sum();
+            assert_eq!(total, 300);
+            let expected: Vec<_> = [10, 20, 25].iter().map(|&n| (n, Some(25))).collect();
+            assert_eq!(*calls.lock().unwrap(), expected);
+        }
+
+        // Totals are only given when the input knows its length.
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let c = calls.clone();
+        let p = PipelineBuilder::new(2)
+            .on_progress(1, move |completed, total, _| {
+                c.lock().unwrap().push((completed, total));
+            })
+            .build((0..4).filter(|_| true), |x| x);
+        assert_eq!(p.count(), 4);
+        assert_eq!(calls.lock().unwrap().last(), Some(&(4, None)));
+    }
+
+    #[test]
+    fn test_on_checkpoint() {
+        for w in 0..3 {
+            let calls = Arc::new(Mutex::new(Vec::new()));
+            let c = calls.clone();
+            let total: i32 = PipelineBuilder::new(w)
+                .on_checkpoint(10, move |index| c.lock().unwrap().push(index))
+                .build(0..25, |x| x)
+                .sum();
+            assert_eq!(total, 300);
+            assert_eq!(*calls.lock().unwrap(), [10, 20, 25]);
+
+            // Skipped values are done with, and resuming counts from
+            // the start of the input.
+            let calls = Arc::new(Mutex::new(Vec::new()));
+            let c = calls.clone();
+            let mut p = PipelineBuilder::new(w)
+                .on_checkpoint(5, move |index| c.lock().unwrap().push(index))
+                .resume_from(10)
+                .build(0..40, |x| x)
+                .pl_skip(3);
+            assert_eq!(p.next(), Some(13));
+            assert_eq!(p.by_ref().take(4).last(), Some(17));
+            assert_eq!(*calls.lock().unwrap(), [15]);
+            assert_eq!(p.count(), 22);
+            assert_eq!(*calls.lock().unwrap(), [15, 20, 25, 30, 35, 40]);
+        }
+    }
+
+    #[test]
+    fn test_on_complete() {
+        for w in 0..3 {
+            let reports = Arc::new(Mutex::new(Vec::new()));
+            let r = reports.clone();
+            let mut p = PipelineBuilder::new(w)
+                .on_complete(move |report| r.lock().unwrap().push(report))
+                .build(0..10, |x| x);
+            assert_eq!(p.by_ref().take(10).count(), 10);
+            // The last result has been taken, but the end has not
+            // been reached.
+            assert!(reports.lock().unwrap().is_empty());
+            assert_eq!(p.next(), None);
+            assert_eq!(p.next(), None);
+            let reports = reports.lock().unwrap();
+            assert_eq!(reports.len(), 1);
+            let mapped: u64 = reports[0].workers.iter().map(|w| w.items).sum();
+            assert_eq!(mapped, if w > 0 { 10 } else { 0 });
+        }
+
+        // Consuming with fold completes too, dropping early does not.
+        let calls = Arc::new(Mutex::new(0));
+        let c = calls.clone();
+        let builder = PipelineBuilder::new(2).on_complete(move |_| *c.lock().unwrap() += 1);
+        let total: i32 = builder.clone().build(0..10, |x| x).sum();
+        assert_eq!(total, 45);
+        assert_eq!(*calls.lock().unwrap(), 1);
+        let mut p = builder.build(0..10, |x| x);
+        assert_eq!(p.next(), Some(0));
+        drop(p);
+        assert_eq!(*calls.lock().unwrap(), 1);
+
+        // So does consuming from the back.
+        for w in 0..3 {
+            let calls = Arc::new(Mutex::new(0));
+            let c = calls.clone();
+            let v: Vec<i32> = PipelineBuilder::new(w)
+                .on_complete(move |_| *c.lock().unwrap() += 1)
+                .build(0..10, |x| x)
+                .rev()
+                .collect();
+            assert_eq!(v, (0..10).rev().collect::<Vec<_>>());
+            assert_eq!(*calls.lock().unwrap(), 1);
+        }
+    }
+
+    #[cfg(feature = "indicatif")]
+    #[test]
+    fn test_progress_bar() {
+        let bar = indicatif::ProgressBar::hidden();
+        let total: i32 = PipelineBuilder::new(2)
+            .progress_bar(bar.clone())
+            .build(0..50, |x| x)
+            .sum();
+        assert_eq!(total, 1225);
+        assert_eq!(bar.length(), Some(50));
+        assert_eq!(bar.position(), 50);
+        assert!(bar.is_finished());
+    }
+}
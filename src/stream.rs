@@ -0,0 +1,672 @@
+use {
+    super::{
+        buffered::Buffered, builder::PipelineBuilder, mapper::Mapper, pipeline::Pipeline,
+        stats::PipelineReport, workers::Workers,
+    },
+    futures_core::Stream,
+    futures_sink::Sink,
+    std::{
+        future::Future,
+        iter,
+        pin::Pin,
+        task::{Context, Poll},
+    },
+};
+
+/// Pipelines are streams too, so their results can be consumed from
+/// async code. Waiting for a result never blocks, the task is woken
+/// once the next result is ready.
+///
+/// Input is still pulled from the iterator on the polling task, and a
+/// pipeline without workers maps values there too, blocking the task.
+impl<I, M> Stream for Pipeline<I, M>
+where
+    I: Iterator,
+    I::Item: Send,
+    M: Mapper<I::Item> + Clone + Send,
+    M::Out: Send,
+    Self: Unpin,
+{
+    type Item = <M as Mapper<I::Item>>::Out;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_next(cx)
+    }
+}
+
+impl<I, M> Pipeline<I, M>
+where
+    I: Iterator,
+    I::Item: Send,
+    M: Mapper<I::Item> + Clone + Send,
+    M::Out: Send,
+    Self: Unpin,
+{
+    /// Send every result to sink in order, then close it. See Forward.
+    pub fn forward_into<Si>(self, sink: Si) -> Forward<Self, Si>
+    where
+        Si: Sink<M::Out> + Unpin,
+    {
+        Forward::new(self, sink)
+    }
+
+    /// Stop the pipeline from async code, see Close. Dropping a
+    /// pipeline waits for its workers to return, which blocks the
+    /// executor thread when they are still mapping.
+    pub fn close(self) -> Close<I, M> {
+        Close(Some(self))
+    }
+}
+
+/// Close is a future stopping a pipeline, created by Pipeline::close.
+/// It resolves once the pipeline's workers have returned, like
+/// Pipeline::finish, but the task is woken rather than blocked while
+/// they finish mapping the values they have started on. Values still
+/// in flight are dropped.
+#[must_use = "futures do nothing unless polled"]
+pub struct Close<I, M>(Option<Pipeline<I, M>>)
+where
+    I: Iterator,
+    I::Item: Send,
+    M: Mapper<I::Item> + Clone + Send,
+    M::Out: Send;
+
+impl<I, M> Future for Close<I, M>
+where
+    I: Iterator,
+    I::Item: Send,
+    M: Mapper<I::Item> + Clone + Send,
+    M::Out: Send,
+    Pipeline<I, M>: Unpin,
+{
+    type Output = PipelineReport;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<PipelineReport> {
+        let this = self.get_mut();
+        let pipeline = this.0.as_mut().expect("Close polled after completion");
+        match pipeline.poll_shut_down(cx) {
+            Poll::Ready(()) => {
+                let report = pipeline.stats();
+                this.0 = None;
+                Poll::Ready(report)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// StreamPipeline maps the values of an async stream on worker
+/// threads, and is itself a stream of the results in order. Usually
+/// they should be created via the StreamPipelineMap extension trait.
+///
+/// The input stream is polled for more values whenever there is room
+/// in the in-flight window, so the mapper runs on the workers while
+/// the task is free to do other things.
+pub struct StreamPipeline<S, M>
+where
+    S: Stream,
+    S::Item: Send,
+    M: Mapper<S::Item> + Clone + Send,
+    M::Out: Send,
+{
+    /// None once the input has ended, or the pipeline is cancelled.
+    input: Option<Pin<Box<S>>>,
+    /// Fed with push_input, its iterator is never read. The pipeline
+    /// is told when the stream ends with end_input, so it is not done
+    /// just because the stream is pending with nothing in flight.
+    pipeline: Pipeline<iter::Empty<S::Item>, M>,
+}
+
+impl<S, M> StreamPipeline<S, M>
+where
+    S: Stream,
+    S::Item: Send + 'static,
+    M: Mapper<S::Item> + Clone + Send + 'static,
+    M::Out: Send + 'static,
+{
+    pub(crate) fn start(builder: PipelineBuilder, mapper: M, input: S) -> StreamPipeline<S, M> {
+        let mut pipeline = Pipeline::start(builder, mapper, iter::empty());
+        pipeline.feed();
+        StreamPipeline {
+            input: Some(Box::pin(input)),
+            pipeline,
+        }
+    }
+}
+
+impl<S, M> Stream for StreamPipeline<S, M>
+where
+    S: Stream,
+    S::Item: Send,
+    M: Mapper<S::Item> + Clone + Send,
+    M::Out: Send,
+    Self: Unpin,
+{
+    type Item = <M as Mapper<S::Item>>::Out;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.pipeline.is_cancelled() {
+            this.input = None;
+        }
+        while let Some(input) = this.input.as_mut() {
+            if !this.pipeline.has_room() {
+                break;
+            }
+            match input.as_mut().poll_next(cx) {
+                Poll::Ready(Some(v)) => this.pipeline.push_input(v),
+                Poll::Ready(None) => {
+                    this.input = None;
+                    this.pipeline.end_input();
+                }
+                Poll::Pending => break,
+            }
+        }
+        match this.pipeline.poll_next(cx) {
+            // Nothing is in flight, but the input will wake us when it
+            // has more.
+            Poll::Ready(None) if this.input.is_some() && !this.pipeline.poll_cancelled(cx) => {
+                Poll::Pending
+            }
+            poll => poll,
+        }
+    }
+}
+
+impl<S, M> StreamPipeline<S, M>
+where
+    S: Stream,
+    S::Item: Send,
+    M: Mapper<S::Item> + Clone + Send,
+    M::Out: Send,
+    Self: Unpin,
+{
+    /// Send every result to sink in order, then close it. See Forward.
+    pub fn forward_into<Si>(self, sink: Si) -> Forward<Self, Si>
+    where
+        Si: Sink<M::Out> + Unpin,
+    {
+        Forward::new(self, sink)
+    }
+}
+
+/// Forward is a future that drives a pipeline to completion, sending
+/// its results to a sink and closing the sink at the end. It resolves
+/// to the first error from the sink, if any.
+///
+/// Backpressure is respected: no more results are taken from the
+/// pipeline while the sink isn't ready for them, so the pipeline
+/// stops once its in-flight window fills up. The sink is flushed
+/// whenever the pipeline has nothing ready. A panic in the mapper is
+/// resumed in the task polling the future.
+#[must_use = "futures do nothing unless polled"]
+pub struct Forward<St, Si>
+where
+    St: Stream,
+{
+    stream: Option<St>,
+    sink: Si,
+    /// A result waiting for the sink to be ready.
+    pending: Option<St::Item>,
+}
+
+impl<St, Si> Forward<St, Si>
+where
+    St: Stream,
+{
+    fn new(stream: St, sink: Si) -> Forward<St, Si> {
+        Forward {
+            stream: Some(stream),
+            sink,
+            pending: None,
+        }
+    }
+}
+
+// The pending result is never pinned.
+impl<St, Si> Unpin for Forward<St, Si>
+where
+    St: Stream + Unpin,
+    Si: Unpin,
+{
+}
+
+impl<St, Si> Future for Forward<St, Si>
+where
+    St: Stream + Unpin,
+    Si: Sink<St::Item> + Unpin,
+{
+    type Output = Result<(), Si::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut sink = Pin::new(&mut this.sink);
+        loop {
+            if this.pending.is_some() {
+                match sink.as_mut().poll_ready(cx) {
+                    Poll::Ready(Ok(())) => {
+                        sink.as_mut().start_send(this.pending.take().unwrap())?
+                    }
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+            let stream = match this.stream.as_mut() {
+                Some(stream) => stream,
+                None => return sink.as_mut().poll_close(cx),
+            };
+            match Pin::new(stream).poll_next(cx) {
+                Poll::Ready(Some(v)) => this.pending = Some(v),
+                // Drop the pipeline before closing the sink, its
+                // workers are done.
+                Poll::Ready(None) => this.stream = None,
+                Poll::Pending => {
+                    return match sink.as_mut().poll_flush(cx) {
+                        Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+                        _ => Poll::Pending,
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// StreamPipelineMap can be imported to add the plmap function to
+/// async streams.
+pub trait StreamPipelineMap<S, M>
+where
+    S: Stream,
+    S::Item: Send + 'static,
+    M: Mapper<S::Item> + Clone + Send + 'static,
+    M::Out: Send + 'static,
+{
+    /// Like PipelineMap::plmap, but for an async stream. The mapper
+    /// runs on n_workers threads while the stream is driven by the
+    /// task consuming the results, which come out in order.
+    fn plmap(self, n_workers: impl Into<Workers>, m: M) -> StreamPipeline<S, M>;
+}
+
+impl<S, M> StreamPipelineMap<S, M> for S
+where
+    S: Stream,
+    S::Item: Send + 'static,
+    M: Mapper<S::Item> + Clone + Send + 'static,
+    M::Out: Send + 'static,
+{
+    fn plmap(self, n_workers: impl Into<Workers>, m: M) -> StreamPipeline<S, M> {
+        PipelineBuilder::new(n_workers).build_stream(self, m)
+    }
+}
+
+/// Buffered is a stream too, its background thread feeds async code
+/// without ever blocking the task. A panic on the background thread is
+/// resumed on the task once the values before it have been consumed.
+impl<T> Stream for Buffered<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.get_mut().poll_next(cx)
+    }
+}
+
+/// Map input with mapper on n_workers threads, returning the results
+/// in order as a stream, for async code that needs to run a blocking
+/// iterator or mapper.
+///
+/// Unlike the Pipeline's own Stream impl, input is pulled on a
+/// background thread rather than the polling task, so neither the
+/// input nor the mapper can block the task. Up to n_workers + 1
+/// results are buffered ahead of the task. Dropping the stream stops
+/// the background thread once its next result is ready.
+///
+/// # Examples
+///
+/// ```
+/// use futures_core::Stream;
+///
+/// // The input is a blocking iterator, e.g. lines of a file.
+/// let lines = (0..100).map(|x| x.to_string());
+/// let mut results = plmap::plmap_stream(lines, 4, |s: String| s.len());
+/// # let _: &dyn Stream<Item = usize> = &results;
+/// // In async code: while let Some(n) = results.next().await { .. }
+/// assert_eq!(results.sum::<usize>(), 190);
+/// ```
+pub fn plmap_stream<I, M>(input: I, n_workers: impl Into<Workers>, mapper: M) -> Buffered<M::Out>
+where
+    I: Iterator + Send + 'static,
+    I::Item: Send + 'static,
+    M: Mapper<I::Item> + Clone + Send + 'static,
+    M::Out: Send + 'static,
+{
+    let n_workers = n_workers.into().count();
+    Pipeline::new(n_workers, mapper, input).pl_buffer(n_workers + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{PipelineMap, PlmapPool},
+        std::{
+            collections::VecDeque,
+            convert::Infallible,
+            sync::{
+                atomic::{AtomicUsize, Ordering},
+                Arc,
+            },
+            task::{Wake, Waker},
+            thread,
+            time::Duration,
+        },
+    };
+
+    struct Unpark {
+        thread: thread::Thread,
+        woken: AtomicUsize,
+    }
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.woken.fetch_add(1, Ordering::SeqCst);
+            self.thread.unpark();
+        }
+    }
+
+    /// Run f on this thread, parking while it is pending, returning
+    /// its output and how many times the task was woken.
+    fn block_on<F: Future + Unpin>(mut f: F) -> (F::Output, usize) {
+        let unpark = Arc::new(Unpark {
+            thread: thread::current(),
+            woken: AtomicUsize::new(0),
+        });
+        let waker = Waker::from(unpark.clone());
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match Pin::new(&mut f).poll(&mut cx) {
+                Poll::Ready(v) => return (v, unpark.woken.load(Ordering::SeqCst)),
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    /// Collect a stream on this thread, see block_on.
+    fn collect<S: Stream + Unpin>(mut s: S) -> (Vec<S::Item>, usize) {
+        let mut v = Vec::new();
+        let f = std::future::poll_fn(move |cx| loop {
+            match Pin::new(&mut s).poll_next(cx) {
+                Poll::Ready(Some(x)) => v.push(x),
+                Poll::Ready(None) => return Poll::Ready(std::mem::take(&mut v)),
+                Poll::Pending => return Poll::Pending,
+            }
+        });
+        block_on(Box::pin(f))
+    }
+
+    #[test]
+    fn test_plmap_stream() {
+        for w in 0..3 {
+            let (v, _) = collect(plmap_stream(0..100, w, |x| x * 2));
+            assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+        }
+
+        // The background thread stops once the stream is dropped.
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let p = pulled.clone();
+        let input = (0..).inspect(move |_| {
+            p.fetch_add(1, Ordering::SeqCst);
+        });
+        let mut s = plmap_stream(input, 2, |x: u64| x);
+        assert_eq!(s.next(), Some(0));
+        drop(s);
+        thread::sleep(std::time::Duration::from_millis(20));
+        let n = pulled.load(Ordering::SeqCst);
+        thread::sleep(std::time::Duration::from_millis(20));
+        assert_eq!(pulled.load(Ordering::SeqCst), n);
+
+        // Mapper panics are resumed on the task.
+        let s = plmap_stream(0..10, 2, |x| {
+            if x == 5 {
+                panic!("boom");
+            }
+            x
+        });
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| collect(s)));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_stream() {
+        for w in 0..3 {
+            let (v, _) = collect((0..100).plmap(w, |x| x * 2));
+            assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+        }
+        let pool = PlmapPool::new(2);
+        let (v, _) = collect((0..100).plmap_on(&pool, |x| x * 2));
+        assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+
+        // A slow mapper leaves the stream pending until it is woken.
+        let (v, woken) = collect(PipelineBuilder::new(2).build(0..4, |x| {
+            thread::sleep(std::time::Duration::from_millis(20));
+            x
+        }));
+        assert_eq!(v, vec![0, 1, 2, 3]);
+        assert!(woken > 0);
+    }
+
+    /// A stream of 0..n that is pending every other poll, waking
+    /// itself straight away.
+    struct Flaky {
+        next: i32,
+        n: i32,
+        pending: bool,
+    }
+
+    impl Stream for Flaky {
+        type Item = i32;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<i32>> {
+            self.pending = !self.pending;
+            if self.pending {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            if self.next == self.n {
+                return Poll::Ready(None);
+            }
+            self.next += 1;
+            Poll::Ready(Some(self.next - 1))
+        }
+    }
+
+    #[test]
+    fn test_stream_plmap() {
+        let flaky = |n| Flaky {
+            next: 0,
+            n,
+            pending: false,
+        };
+        for w in 0..3 {
+            let (v, _) = collect(flaky(100).plmap(w, |x| x * 2));
+            assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+        }
+        let (v, _) = collect(
+            PipelineBuilder::new(2)
+                .in_flight(5)
+                .build_stream(flaky(0), |x: i32| x),
+        );
+        assert!(v.is_empty());
+    }
+
+    #[test]
+    fn test_stream_plmap_on_complete() {
+        // Every result is taken each time the input is pending, which
+        // must not be mistaken for the end of the input.
+        for w in 0..3 {
+            let completed = Arc::new(AtomicUsize::new(0));
+            let c = completed.clone();
+            let input = Flaky {
+                next: 0,
+                n: 20,
+                pending: false,
+            };
+            let s = PipelineBuilder::new(w)
+                .on_complete(move |_| {
+                    c.fetch_add(1, Ordering::SeqCst);
+                })
+                .build_stream(input, |x: i32| x * 2);
+            let (v, _) = collect(s);
+            assert_eq!(v, (0..20).map(|x| x * 2).collect::<Vec<_>>());
+            assert_eq!(completed.load(Ordering::SeqCst), 1);
+        }
+    }
+
+    #[test]
+    fn test_stream_plmap_finish() {
+        // Progress and checkpoints are only finished at the end of the
+        // input stream, not whenever it is pending with nothing in
+        // flight.
+        for w in 0..3 {
+            let progress = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let checkpoints = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let (p, cp) = (progress.clone(), checkpoints.clone());
+            let input = Flaky {
+                next: 0,
+                n: 20,
+                pending: false,
+            };
+            let s = PipelineBuilder::new(w)
+                .on_progress(100, move |n, _, _| p.lock().unwrap().push(n))
+                .on_checkpoint(100, move |i| cp.lock().unwrap().push(i))
+                .build_stream(input, |x: i32| x);
+            let (v, _) = collect(s);
+            assert_eq!(v.len(), 20);
+            assert_eq!(*progress.lock().unwrap(), [20]);
+            assert_eq!(*checkpoints.lock().unwrap(), [20]);
+        }
+    }
+
+    /// A sink holding at most cap items, which is pending while full.
+    #[derive(Default)]
+    struct Slow {
+        cap: usize,
+        queued: VecDeque<i32>,
+        flushed: Vec<i32>,
+        closed: bool,
+    }
+
+    impl Sink<i32> for &mut Slow {
+        type Error = Infallible;
+
+        fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            if self.queued.len() < self.cap {
+                return Poll::Ready(Ok(()));
+            }
+            // Pretend the queue drains in the background, waking the
+            // task once there is room.
+            let this = self.get_mut();
+            this.flushed.extend(this.queued.drain(..));
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+
+        fn start_send(self: Pin<&mut Self>, v: i32) -> Result<(), Infallible> {
+            assert!(self.queued.len() < self.cap, "sent while not ready");
+            self.get_mut().queued.push_back(v);
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            let this = self.get_mut();
+            this.flushed.extend(this.queued.drain(..));
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Infallible>> {
+            let result = self.as_mut().poll_flush(cx);
+            self.get_mut().closed = true;
+            result
+        }
+    }
+
+    #[test]
+    fn test_forward_into() {
+        for w in 0..3 {
+            let mut sink = Slow {
+                cap: 3,
+                ..Slow::default()
+            };
+            let (result, _) = block_on((0..100).plmap(w, |x| x * 2).forward_into(&mut sink));
+            assert!(result.is_ok());
+            assert!(sink.closed);
+            assert_eq!(sink.flushed, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+        }
+
+        let mut sink = Slow {
+            cap: 1,
+            ..Slow::default()
+        };
+        let input = Flaky {
+            next: 0,
+            n: 10,
+            pending: false,
+        };
+        let (result, _) = block_on(input.plmap(2, |x| x + 1).forward_into(&mut sink));
+        assert!(result.is_ok());
+        assert_eq!(sink.flushed, (1..11).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_close() {
+        for w in 0..3 {
+            let live = Arc::new(());
+            let l = live.clone();
+            let mut p = (0..).plmap(w, move |x: u64| {
+                let _l = &l;
+                if x > 0 {
+                    thread::sleep(Duration::from_millis(20));
+                }
+                x
+            });
+            assert_eq!(p.next(), Some(0));
+            let (report, _) = block_on(p.close());
+            // The workers, and their mappers, are gone.
+            assert_eq!(Arc::strong_count(&live), 1);
+            assert_eq!(report.workers.len(), w);
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_close_current_thread() {
+        use {crate::PipelineBuilder, std::sync::atomic::AtomicBool, tokio::runtime};
+
+        let rt = runtime::Builder::new_current_thread().build().unwrap();
+        let live = Arc::new(());
+        let ran = Arc::new(AtomicBool::new(false));
+        rt.block_on(async {
+            let l = live.clone();
+            let mut p = PipelineBuilder::new(2).build_with(
+                runtime::Handle::current(),
+                0..,
+                move |x: u64| {
+                    let _l = &l;
+                    if x > 0 {
+                        thread::sleep(Duration::from_millis(100));
+                    }
+                    x
+                },
+            );
+            assert_eq!(p.next(), Some(0));
+            let r = ran.clone();
+            tokio::spawn(async move { r.store(true, Ordering::SeqCst) });
+            // The workers are still mapping, other tasks on the executor
+            // thread run while they finish.
+            p.close().await;
+            assert!(ran.load(Ordering::SeqCst));
+        });
+        assert_eq!(Arc::strong_count(&live), 1);
+    }
+}
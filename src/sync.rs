@@ -0,0 +1,63 @@
+//! The synchronisation primitives under the channels and worker
+//! threads. These are loom's or shuttle's when built with --cfg loom or
+//! --cfg shuttle, so that dispatch, result delivery and shutdown can be
+//! model checked. Loom exhaustively explores small models:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg loom" cargo test --release --lib loom
+//! ```
+//!
+//! Shuttle explores random schedules of whole pipelines, including
+//! worker panics and dropping a pipeline with values in flight:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg shuttle" cargo test --release --lib shuttle
+//! ```
+//!
+//! Under either the channels use their own backend, see chan, and only
+//! the matching tests can be run.
+
+#[cfg(loom)]
+pub(crate) use loom::{
+    hint,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+};
+#[cfg(all(shuttle, not(loom)))]
+pub(crate) use shuttle::{
+    hint,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+};
+#[cfg(not(any(loom, shuttle)))]
+pub(crate) use std::{
+    hint,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+/// CachePadded gives a value cache lines of its own, so that state
+/// written on one thread doesn't share a line with state written on
+/// another, and each write doesn't take the line from the other core.
+/// 128 bytes covers the pairs of lines prefetched together on x86_64
+/// and the larger lines of some aarch64 cores.
+#[repr(align(128))]
+#[derive(Default)]
+pub(crate) struct CachePadded<T>(pub(crate) T);
+
+impl<T> std::ops::Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
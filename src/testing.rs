@@ -0,0 +1,736 @@
+//! Helpers for testing code that uses pipelines, see the testing
+//! feature.
+
+use {
+    super::{chan, mapper::Mapper, rng::Rng},
+    std::{
+        fmt,
+        panic::{self, AssertUnwindSafe},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        thread,
+        time::Duration,
+    },
+};
+
+/// Faults wraps a mapper, injecting delays, panics and errors at given
+/// item indices or with given probabilities, for testing that a
+/// consumer copes with slow and failing workers.
+///
+/// Items are numbered from 0 in the order they reach the mapper,
+/// counted across all of a pipeline's workers. This is the input order
+/// with one worker or none, and close to it with more. Whether a fault
+/// with a probability fires depends only on the seed and the index, so
+/// runs with the same seed inject the same faults.
+///
+/// # Examples
+///
+/// ```
+/// use plmap::{testing::Faults, PipelineMap};
+/// use std::{panic, time::Duration};
+///
+/// let faults = Faults::new(|x: i32| -> Result<i32, String> { Ok(x * 2) })
+///     .delay_with_probability(0.1, Duration::from_millis(1))
+///     .error_at(3, |i| Err(format!("item {} timed out", i)))
+///     .panic_at(5);
+/// let mut results = Vec::new();
+/// let r = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+///     for v in (0..10).plmap(1, faults) {
+///         results.push(v);
+///     }
+/// }));
+/// assert!(r.is_err());
+/// assert_eq!(results, [Ok(0), Ok(2), Ok(4), Err("item 3 timed out".to_string()), Ok(8)]);
+/// ```
+pub struct Faults<M, Out> {
+    mapper: M,
+    next: Arc<AtomicUsize>,
+    seed: u64,
+    delays: Vec<(Trigger, Duration)>,
+    panics: Vec<Trigger>,
+    errors: Vec<(Trigger, ErrorFn<Out>)>,
+}
+
+type ErrorFn<Out> = Arc<dyn Fn(usize) -> Out + Send + Sync>;
+
+/// When a fault fires.
+#[derive(Clone, Copy, Debug)]
+enum Trigger {
+    At(usize),
+    Probability(f64),
+}
+
+impl Trigger {
+    /// Whether the fault fires for the item at index, k tells faults
+    /// apart so that their draws are independent.
+    fn fires(self, seed: u64, index: usize, k: usize) -> bool {
+        match self {
+            Trigger::At(i) => i == index,
+            Trigger::Probability(p) => {
+                Rng::new(seed ^ (index as u64).rotate_left(32) ^ k as u64).chance(p)
+            }
+        }
+    }
+}
+
+impl<M, Out> Faults<M, Out> {
+    /// Wrap mapper, injecting no faults until some are added.
+    pub fn new(mapper: M) -> Faults<M, Out> {
+        Faults {
+            mapper,
+            next: Arc::new(AtomicUsize::new(0)),
+            seed: 0,
+            delays: Vec::new(),
+            panics: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    /// Seed the faults injected with a probability, the default is 0.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Sleep for delay before mapping the item at index.
+    pub fn delay_at(mut self, index: usize, delay: Duration) -> Self {
+        self.delays.push((Trigger::At(index), delay));
+        self
+    }
+
+    /// Sleep for delay before mapping each item with probability p.
+    pub fn delay_with_probability(mut self, p: f64, delay: Duration) -> Self {
+        self.delays.push((Trigger::Probability(p), delay));
+        self
+    }
+
+    /// Panic instead of mapping the item at index.
+    pub fn panic_at(mut self, index: usize) -> Self {
+        self.panics.push(Trigger::At(index));
+        self
+    }
+
+    /// Panic instead of mapping each item with probability p.
+    pub fn panic_with_probability(mut self, p: f64) -> Self {
+        self.panics.push(Trigger::Probability(p));
+        self
+    }
+
+    /// Output error(index) instead of mapping the item at index.
+    pub fn error_at<F>(mut self, index: usize, error: F) -> Self
+    where
+        F: Fn(usize) -> Out + Send + Sync + 'static,
+    {
+        self.errors.push((Trigger::At(index), Arc::new(error)));
+        self
+    }
+
+    /// Output error(index) instead of mapping each item with
+    /// probability p.
+    pub fn error_with_probability<F>(mut self, p: f64, error: F) -> Self
+    where
+        F: Fn(usize) -> Out + Send + Sync + 'static,
+    {
+        self.errors.push((Trigger::Probability(p), Arc::new(error)));
+        self
+    }
+}
+
+impl<In, M> Mapper<In> for Faults<M, M::Out>
+where
+    M: Mapper<In>,
+{
+    type Out = M::Out;
+
+    fn apply(&mut self, v: In) -> M::Out {
+        let index = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut k = 0;
+        let mut fires = |trigger: Trigger| {
+            k += 1;
+            trigger.fires(self.seed, index, k)
+        };
+        let delay = self
+            .delays
+            .iter()
+            .filter(|(trigger, _)| fires(*trigger))
+            .map(|(_, delay)| *delay)
+            .sum::<Duration>();
+        if delay > Duration::ZERO {
+            thread::sleep(delay);
+        }
+        if self.panics.iter().any(|trigger| fires(*trigger)) {
+            panic!("injected panic mapping item {}", index);
+        }
+        let error = self.errors.iter().find(|(trigger, _)| fires(*trigger));
+        match error {
+            Some((_, error)) => error(index),
+            None => self.mapper.apply(v),
+        }
+    }
+}
+
+impl<M: Clone, Out> Clone for Faults<M, Out> {
+    /// The clone shares the item count, like a pipeline's workers.
+    fn clone(&self) -> Self {
+        Faults {
+            mapper: self.mapper.clone(),
+            next: self.next.clone(),
+            seed: self.seed,
+            delays: self.delays.clone(),
+            panics: self.panics.clone(),
+            errors: self.errors.clone(),
+        }
+    }
+}
+
+impl<M, Out> fmt::Debug for Faults<M, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Faults")
+            .field("next", &self.next.load(Ordering::Relaxed))
+            .field("seed", &self.seed)
+            .field("delays", &self.delays)
+            .field("panics", &self.panics)
+            .field(
+                "errors",
+                &self.errors.iter().map(|(t, _)| t).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+/// Assert that a pipeline over (index, value) pairs, numbered from 0,
+/// for example with enumerate and with_context, yields them in input
+/// order, returning the values.
+///
+/// # Examples
+///
+/// ```
+/// use plmap::{testing::assert_order_preserved, with_context, PipelineMap};
+///
+/// let squares = assert_order_preserved(
+///     (0..100)
+///         .enumerate()
+///         .plmap(4, with_context(|_: &usize, x: i32| x * x)),
+/// );
+/// assert_eq!(squares[9], 81);
+/// ```
+///
+/// # Panics
+///
+/// If an index is out of place, naming it and where it was yielded.
+#[track_caller]
+pub fn assert_order_preserved<I, T>(pipeline: I) -> Vec<T>
+where
+    I: IntoIterator<Item = (usize, T)>,
+{
+    pipeline
+        .into_iter()
+        .enumerate()
+        .map(|(position, (index, v))| {
+            assert_eq!(
+                index, position,
+                "item {} was yielded at position {}",
+                index, position
+            );
+            v
+        })
+        .collect()
+}
+
+/// Probe wraps a mapper, recording how many values were mapped and the
+/// most that were being mapped at once, to check a pipeline really
+/// runs with the parallelism expected. Clones share their records, so
+/// keep one to inspect after the pipeline has a clone.
+///
+/// Only values being mapped at the same time are counted, a mapper that
+/// returns quickly may see little concurrency on any number of workers.
+///
+/// # Examples
+///
+/// ```
+/// use plmap::{testing::Probe, PipelineMap};
+/// use std::{thread, time::Duration};
+///
+/// let probe = Probe::new(|x: i32| {
+///     thread::sleep(Duration::from_millis(20));
+///     x
+/// });
+/// let _: Vec<i32> = (0..16).plmap(4, probe.clone()).collect();
+/// assert_eq!(probe.mapped(), 16);
+/// assert!(probe.max_concurrency() <= 4);
+/// ```
+#[derive(Clone)]
+pub struct Probe<M> {
+    mapper: M,
+    records: Arc<ProbeRecords>,
+}
+
+#[derive(Default)]
+struct ProbeRecords {
+    active: AtomicUsize,
+    max: AtomicUsize,
+    mapped: AtomicUsize,
+}
+
+impl<M> Probe<M> {
+    /// Wrap mapper.
+    pub fn new(mapper: M) -> Probe<M> {
+        Probe {
+            mapper,
+            records: Arc::default(),
+        }
+    }
+
+    /// The number of values mapped so far, including any that
+    /// panicked.
+    pub fn mapped(&self) -> usize {
+        self.records.mapped.load(Ordering::SeqCst)
+    }
+
+    /// The most values that were being mapped at the same time.
+    pub fn max_concurrency(&self) -> usize {
+        self.records.max.load(Ordering::SeqCst)
+    }
+}
+
+impl<In, M> Mapper<In> for Probe<M>
+where
+    M: Mapper<In>,
+{
+    type Out = M::Out;
+
+    fn apply(&mut self, v: In) -> M::Out {
+        /// Leaves, when dropped, even if the mapper panics.
+        struct Active<'a>(&'a ProbeRecords);
+
+        impl Drop for Active<'_> {
+            fn drop(&mut self) {
+                self.0.active.fetch_sub(1, Ordering::SeqCst);
+                self.0.mapped.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let records = &*self.records;
+        let active = records.active.fetch_add(1, Ordering::SeqCst) + 1;
+        records.max.fetch_max(active, Ordering::SeqCst);
+        let _active = Active(records);
+        self.mapper.apply(v)
+    }
+}
+
+impl<M> fmt::Debug for Probe<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Probe")
+            .field("mapped", &self.mapped())
+            .field("max_concurrency", &self.max_concurrency())
+            .finish()
+    }
+}
+
+/// MockExecutor stands in for a pipeline's workers, letting a test
+/// decide when each dispatched value is mapped, see
+/// PipelineBuilder::mock.
+///
+/// Values are numbered from 0 in the order the pipeline dispatches
+/// them. They wait until completed with complete, which maps the value
+/// on the calling thread and hands its result to the pipeline, as if a
+/// worker had just finished it. A consumer that would wait for a result
+/// which has not been completed panics instead.
+///
+/// # Examples
+///
+/// ```
+/// use plmap::{testing::MockExecutor, PipelineBuilder};
+/// use std::task::Poll;
+///
+/// let mock = MockExecutor::new();
+/// let mut p = PipelineBuilder::new(4)
+///     .in_flight(3)
+///     .mock(&mock)
+///     .build(0..10, |x| x * 2);
+/// assert_eq!(p.try_next(), Poll::Pending);
+/// assert_eq!(mock.dispatched(), [0, 1, 2]);
+///
+/// // Item 1 is done first, but is held back until item 0 is.
+/// mock.complete(1);
+/// assert_eq!(p.try_next(), Poll::Pending);
+/// assert_eq!(p.queue_len(), 1);
+/// mock.complete(0);
+/// assert_eq!(p.next(), Some(0));
+/// assert_eq!(p.next(), Some(2));
+/// assert_eq!(mock.dispatched(), [2, 3]);
+/// ```
+#[derive(Clone, Default)]
+pub struct MockExecutor(Arc<Mutex<Option<SharedQueue>>>);
+
+type SharedQueue = Arc<Mutex<dyn MockQueue>>;
+
+/// The queue of a mocked pipeline, as seen by its MockExecutor.
+pub(crate) trait MockQueue: Send {
+    fn dispatched(&self) -> Vec<usize>;
+
+    /// Complete the value at index, or return false if it is not
+    /// queued.
+    fn complete(&mut self, index: usize) -> bool;
+}
+
+impl MockExecutor {
+    /// A MockExecutor for one pipeline.
+    pub fn new() -> MockExecutor {
+        MockExecutor::default()
+    }
+
+    /// Set the queue of the pipeline being mocked.
+    pub(crate) fn attach(&self, queue: SharedQueue) {
+        *self.0.lock().unwrap() = Some(queue);
+    }
+
+    fn queue(&self) -> SharedQueue {
+        self.0
+            .lock()
+            .unwrap()
+            .clone()
+            .expect("no pipeline has been built with this MockExecutor")
+    }
+
+    /// The values dispatched which have not been completed, in the
+    /// order they were dispatched.
+    pub fn dispatched(&self) -> Vec<usize> {
+        self.queue().lock().unwrap().dispatched()
+    }
+
+    /// Map the value at index, passing its result, or its panic, to
+    /// the pipeline.
+    ///
+    /// # Panics
+    ///
+    /// If the value at index is not waiting to be mapped.
+    pub fn complete(&self, index: usize) {
+        let queue = self.queue();
+        let completed = queue.lock().unwrap().complete(index);
+        assert!(completed, "item {} is not waiting to be mapped", index);
+    }
+
+    /// Map every value waiting to be mapped, in dispatch order.
+    pub fn complete_all(&self) {
+        let queue = self.queue();
+        let mut queue = queue.lock().unwrap();
+        for index in queue.dispatched() {
+            queue.complete(index);
+        }
+    }
+}
+
+impl fmt::Debug for MockExecutor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let queue = self.0.lock().unwrap().clone();
+        f.debug_struct("MockExecutor")
+            .field(
+                "dispatched",
+                &queue.map(|queue| queue.lock().unwrap().dispatched()),
+            )
+            .finish()
+    }
+}
+
+/// Simulation stands in for a pipeline's workers, mapping values on
+/// the consumer in an order chosen by a seeded PRNG, see
+/// PipelineBuilder::simulate, or by a MockExecutor.
+///
+/// Dispatched values wait in a queue. When simulating, some are
+/// completed as soon as they are dispatched, as if a worker was idle,
+/// the rest when the consumer needs a result, one at a time picked at
+/// random from the queue, as if the workers finished in that order.
+pub(crate) struct Simulation<T, In, M>
+where
+    M: Mapper<In>,
+{
+    /// None when mocked.
+    rng: Option<Rng>,
+    mapper: M,
+    next: usize,
+    queue: Vec<(usize, T, In)>,
+    results: chan::Sender<(T, thread::Result<M::Out>)>,
+}
+
+impl<T, In, M> Simulation<T, In, M>
+where
+    M: Mapper<In>,
+{
+    pub(crate) fn new(
+        seed: u64,
+        mapper: M,
+        results: chan::Sender<(T, thread::Result<M::Out>)>,
+    ) -> Simulation<T, In, M> {
+        Simulation {
+            rng: Some(Rng::new(seed)),
+            ..Simulation::mock(mapper, results)
+        }
+    }
+
+    /// A simulation which only completes values it is told to, see
+    /// MockExecutor.
+    pub(crate) fn mock(
+        mapper: M,
+        results: chan::Sender<(T, thread::Result<M::Out>)>,
+    ) -> Simulation<T, In, M> {
+        Simulation {
+            rng: None,
+            mapper,
+            next: 0,
+            queue: Vec::new(),
+            results,
+        }
+    }
+
+    pub(crate) fn dispatch(&mut self, tag: T, v: In) {
+        self.queue.push((self.next, tag, v));
+        self.next += 1;
+        if let Some(rng) = &mut self.rng {
+            if rng.below(2) == 0 {
+                self.step();
+            }
+        }
+    }
+
+    /// Complete a random queued value, sending its result.
+    pub(crate) fn step(&mut self) {
+        let i = match &mut self.rng {
+            Some(rng) if !self.queue.is_empty() => rng.below(self.queue.len()),
+            _ => return,
+        };
+        self.complete_at(i);
+    }
+
+    pub(crate) fn dispatched(&self) -> Vec<usize> {
+        self.queue.iter().map(|(index, _, _)| *index).collect()
+    }
+
+    fn complete_at(&mut self, i: usize) {
+        let (_, tag, v) = self.queue.remove(i);
+        let mapper = &mut self.mapper;
+        // Panics are passed on to the consumer, like a worker's.
+        let out = panic::catch_unwind(AssertUnwindSafe(|| mapper.apply(v)));
+        let _ = self.results.send((tag, out));
+    }
+
+    /// Drop the queued values, as workers would stop.
+    pub(crate) fn close(&mut self) {
+        self.queue.clear();
+    }
+}
+
+impl<T, In, M> MockQueue for Simulation<T, In, M>
+where
+    T: Send,
+    In: Send,
+    M: Mapper<In> + Send,
+    M::Out: Send,
+{
+    fn dispatched(&self) -> Vec<usize> {
+        Simulation::dispatched(self)
+    }
+
+    fn complete(&mut self, index: usize) -> bool {
+        match self.queue.iter().position(|(i, _, _)| *i == index) {
+            Some(i) => {
+                self.complete_at(i);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{PipelineBuilder, PipelineMap},
+        std::{
+            sync::{Barrier, Mutex},
+            task::Poll,
+            time::Instant,
+        },
+    };
+
+    /// Run a simulated pipeline, returning the order values were mapped
+    /// in and the order results were yielded in.
+    fn simulate(seed: u64, window: usize) -> (Vec<i32>, Vec<i32>) {
+        let mapped = Arc::new(Mutex::new(Vec::new()));
+        let m = mapped.clone();
+        let yielded = PipelineBuilder::new(4)
+            .reorder_window(window)
+            .simulate(seed)
+            .build(0..100, move |x| {
+                m.lock().unwrap().push(x);
+                x
+            })
+            .collect();
+        let mapped = mapped.lock().unwrap().clone();
+        (mapped, yielded)
+    }
+
+    #[test]
+    fn test_simulate() {
+        let ordered: Vec<i32> = (0..100).collect();
+        let (a, v) = simulate(1, 0);
+        assert_eq!(v, ordered);
+        assert_ne!(a, ordered);
+        // The same seed replays the same interleaving.
+        assert_eq!(simulate(1, 0), (a.clone(), v));
+        assert_ne!(simulate(2, 0).0, a);
+
+        let (_, v) = simulate(3, 4);
+        assert_ne!(v, ordered);
+        assert_eq!(simulate(3, 4).1, v);
+        let mut sorted = v;
+        sorted.sort_unstable();
+        assert_eq!(sorted, ordered);
+    }
+
+    #[test]
+    fn test_faults() {
+        // Errors and panics at indices.
+        let faults = Faults::new(|x: i32| -> Result<i32, usize> { Ok(x) })
+            .error_at(2, Err)
+            .error_at(4, Err)
+            .panic_at(6);
+        let mut seen = Vec::new();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            for v in (0..10).plmap(1, faults) {
+                seen.push(v);
+            }
+        }));
+        let payload = result.unwrap_err();
+        assert_eq!(
+            payload.downcast_ref::<String>().unwrap(),
+            "injected panic mapping item 6"
+        );
+        assert_eq!(seen, [Ok(0), Ok(1), Err(2), Ok(3), Err(4), Ok(5)]);
+
+        // Delays.
+        let faults = Faults::new(|x: i32| x).delay_at(1, Duration::from_millis(50));
+        let start = Instant::now();
+        let v: Vec<i32> = (0..3).plmap(2, faults).collect();
+        assert_eq!(v, [0, 1, 2]);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // The same seed injects the same faults, independently of
+        // each other.
+        let errors = |seed| -> Vec<Result<i32, i32>> {
+            let faults = Faults::new(|x: i32| Ok(x))
+                .seed(seed)
+                .error_with_probability(0.3, |i| Err(i as i32))
+                .delay_with_probability(0.3, Duration::from_micros(1));
+            (0..1000).plmap(0, faults).collect()
+        };
+        let a = errors(1);
+        assert_eq!(errors(1), a);
+        assert_ne!(errors(2), a);
+        let n = a.iter().filter(|v| v.is_err()).count();
+        assert!((200..400).contains(&n), "{}", n);
+    }
+
+    #[test]
+    fn test_assert_order_preserved() {
+        for w in 0..3 {
+            let v = assert_order_preserved((0..50).enumerate().plmap(w, |(i, x)| (i, x * 2)));
+            assert_eq!(v, (0..50).map(|x| x * 2).collect::<Vec<_>>());
+        }
+        let result = panic::catch_unwind(|| assert_order_preserved(vec![(0, 'a'), (2, 'c')]));
+        let payload = result.unwrap_err();
+        let message = payload.downcast_ref::<String>().unwrap();
+        assert!(
+            message.contains("item 2 was yielded at position 1"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn test_probe() {
+        // The barrier only lets values through four at a time.
+        let barrier = Arc::new(Barrier::new(4));
+        let probe = Probe::new(move |x: i32| {
+            barrier.wait();
+            x
+        });
+        let v: Vec<i32> = (0..8).plmap(4, probe.clone()).collect();
+        assert_eq!(v, (0..8).collect::<Vec<_>>());
+        assert_eq!(probe.mapped(), 8);
+        assert_eq!(probe.max_concurrency(), 4);
+
+        let probe = Probe::new(|x: i32| x);
+        let _: Vec<i32> = (0..20).plmap(0, probe.clone()).collect();
+        assert_eq!(probe.mapped(), 20);
+        assert_eq!(probe.max_concurrency(), 1);
+    }
+
+    #[test]
+    fn test_mock_executor() {
+        let mock = MockExecutor::new();
+        let mut p = PipelineBuilder::new(2)
+            .in_flight(2)
+            .mock(&mock)
+            .build(0..5, |x: i32| {
+                if x == 3 {
+                    panic!("boom");
+                }
+                x * 2
+            });
+        assert_eq!(p.try_next(), Poll::Pending);
+        assert_eq!(mock.dispatched(), [0, 1]);
+        assert_eq!(p.in_flight(), 2);
+
+        mock.complete(1);
+        assert_eq!(p.try_next(), Poll::Pending);
+        let state = p.dump_state();
+        assert_eq!((state.in_flight, state.reorder_len), (1, 1));
+        assert!(state.head_of_line_blocked);
+
+        // Waiting for a value nobody will complete.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| p.next()));
+        let payload = result.unwrap_err();
+        assert!(payload
+            .downcast_ref::<String>()
+            .unwrap()
+            .starts_with("the pipeline is waiting for one of items [0]"));
+
+        mock.complete(0);
+        assert_eq!(p.next(), Some(0));
+        assert_eq!(p.next(), Some(2));
+        assert_eq!(mock.dispatched(), [2]);
+        mock.complete_all();
+        assert_eq!(p.next(), Some(4));
+        assert_eq!(mock.dispatched(), [3]);
+
+        // The mapper's panic reaches the consumer.
+        mock.complete(3);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| p.next()));
+        assert_eq!(result.unwrap_err().downcast_ref::<&str>(), Some(&"boom"));
+
+        let result = panic::catch_unwind(|| mock.complete(7));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_simulate_panic() {
+        let p = PipelineBuilder::new(2).simulate(7).build(0..10, |x| {
+            if x == 5 {
+                panic!("boom");
+            }
+            x
+        });
+        let mut seen = Vec::new();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            for v in p {
+                seen.push(v);
+            }
+        }));
+        assert!(result.is_err());
+        assert_eq!(seen, [0, 1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,72 @@
+use {
+    super::time::Instant,
+    std::{thread, time::Duration},
+};
+
+/// Throttle spaces out a pipeline's dispatches by a minimum interval,
+/// see PipelineBuilder::throttle.
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    interval: Duration,
+    /// When the next dispatch may happen, None before the first.
+    next: Option<Instant>,
+}
+
+impl Throttle {
+    pub(crate) fn new(interval: Duration) -> Throttle {
+        Throttle {
+            interval,
+            next: None,
+        }
+    }
+
+    /// Wait until the interval since the last dispatch has passed.
+    pub(crate) fn wait(&mut self) {
+        let now = match self.next {
+            Some(next) => {
+                let now = Instant::now();
+                if next > now {
+                    thread::sleep(next - now);
+                    next
+                } else {
+                    now
+                }
+            }
+            None => Instant::now(),
+        };
+        self.next = Some(now + self.interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::PipelineBuilder,
+        std::{
+            sync::{Arc, Mutex},
+            time::{Duration, Instant},
+        },
+    };
+
+    #[test]
+    fn test_throttle() {
+        for w in 0..3 {
+            let starts = Arc::new(Mutex::new(Vec::new()));
+            let s = starts.clone();
+            let n = PipelineBuilder::new(w)
+                .throttle(Duration::from_millis(10))
+                .build(0..10, move |x| {
+                    s.lock().unwrap().push(Instant::now());
+                    x
+                })
+                .count();
+            assert_eq!(n, 10);
+            let mut starts = starts.lock().unwrap().clone();
+            starts.sort();
+            // Dispatches are at least the interval apart, workers may
+            // start on them a little later.
+            let total = *starts.last().unwrap() - starts[0];
+            assert!(total >= Duration::from_millis(80), "{:?}", total);
+        }
+    }
+}
@@ -0,0 +1,8 @@
+//! Instant, from std, or from web-time on wasm32-unknown-unknown, where
+//! std::time::Instant::now panics. Pipelines there map inline, see
+//! spawner::HAS_THREADS, but still time their input and results.
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub(crate) use std::time::Instant;
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+pub(crate) use web_time::Instant;
@@ -0,0 +1,168 @@
+use {
+    super::{driver::DriverHandle, mapper::Mapper, pipeline::Pipeline, workers::Workers},
+    tokio::sync::mpsc,
+};
+
+/// TokioInput is pipeline input received from a tokio channel, see
+/// Pipeline::from_tokio_receiver.
+///
+/// Values are received with blocking_recv, so the pipeline must be
+/// consumed off the runtime, e.g. in spawn_blocking or on a thread of
+/// its own, as receiving would otherwise panic. The input ends when
+/// every sender has been dropped.
+pub struct TokioInput<T> {
+    rx: Rx<T>,
+}
+
+enum Rx<T> {
+    Bounded(mpsc::Receiver<T>),
+    Unbounded(mpsc::UnboundedReceiver<T>),
+}
+
+impl<T> From<mpsc::Receiver<T>> for TokioInput<T> {
+    fn from(rx: mpsc::Receiver<T>) -> TokioInput<T> {
+        TokioInput {
+            rx: Rx::Bounded(rx),
+        }
+    }
+}
+
+impl<T> From<mpsc::UnboundedReceiver<T>> for TokioInput<T> {
+    fn from(rx: mpsc::UnboundedReceiver<T>) -> TokioInput<T> {
+        TokioInput {
+            rx: Rx::Unbounded(rx),
+        }
+    }
+}
+
+impl<T> Iterator for TokioInput<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        match &mut self.rx {
+            Rx::Bounded(rx) => rx.blocking_recv(),
+            Rx::Unbounded(rx) => rx.blocking_recv(),
+        }
+    }
+}
+
+impl<T, M> Pipeline<TokioInput<T>, M>
+where
+    T: Send + 'static,
+    M: Mapper<T> + Clone + Send + 'static,
+    M::Out: Send + 'static,
+{
+    /// Like Pipeline::new, mapping values received from a tokio
+    /// channel, see TokioInput.
+    ///
+    /// Async tasks can send to a bounded channel with backpressure, the
+    /// pipeline only receives as it dispatches.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::Pipeline;
+    ///
+    /// let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    /// let (tx, rx) = tokio::sync::mpsc::channel(8);
+    /// let results = rt.spawn_blocking(|| Pipeline::from_tokio_receiver(4, |x: i32| x * 2, rx).sum());
+    /// let total: i32 = rt.block_on(async move {
+    ///     for x in 0..100 {
+    ///         tx.send(x).await.unwrap();
+    ///     }
+    ///     drop(tx);
+    ///     results.await.unwrap()
+    /// });
+    /// assert_eq!(total, 9900);
+    /// ```
+    pub fn from_tokio_receiver(
+        n_workers: impl Into<Workers>,
+        mapper: M,
+        rx: impl Into<TokioInput<T>>,
+    ) -> Pipeline<TokioInput<T>, M> {
+        Pipeline::new(n_workers, mapper, rx.into())
+    }
+}
+
+impl<I, M> Pipeline<I, M>
+where
+    I: Iterator + Send + 'static,
+    I::Item: Send + 'static,
+    M: Mapper<I::Item> + Clone + Send + 'static,
+    M::Out: Send + 'static,
+{
+    /// Like into_receiver, but sending the results to a tokio channel
+    /// buffering up to n of them, to be received by async tasks.
+    ///
+    /// The driver waits while the channel is full, so a slow task
+    /// holds back the pipeline. Dropping the receiver stops the driver
+    /// once the next result is ready.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use plmap::PipelineMap;
+    ///
+    /// let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    /// let (mut rx, handle) = (0..100).plmap(4, |x| x * 2).into_tokio_receiver(16);
+    /// let total = rt.block_on(async move {
+    ///     let mut total = 0;
+    ///     while let Some(v) = rx.recv().await {
+    ///         total += v;
+    ///     }
+    ///     total
+    /// });
+    /// assert_eq!(total, 9900);
+    /// handle.join().unwrap();
+    /// ```
+    pub fn into_tokio_receiver(self, n: usize) -> (mpsc::Receiver<M::Out>, DriverHandle) {
+        let (tx, rx) = mpsc::channel(n);
+        (rx, self.drive_into(tx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::PipelineMap, std::thread};
+
+    #[test]
+    fn test_tokio_channels() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        for w in 0..3 {
+            let (tx, rx) = mpsc::channel(4);
+            let (mut out, handle) =
+                Pipeline::from_tokio_receiver(w, |x: i32| x * 2, rx).into_tokio_receiver(4);
+            let v = rt.block_on(async move {
+                let send = tokio::spawn(async move {
+                    for x in 0..100 {
+                        tx.send(x).await.unwrap();
+                    }
+                });
+                let mut v = Vec::new();
+                while let Some(x) = out.recv().await {
+                    v.push(x);
+                }
+                send.await.unwrap();
+                v
+            });
+            assert_eq!(v, (0..100).map(|x| x * 2).collect::<Vec<_>>());
+            assert!(handle.join().is_ok());
+        }
+
+        // Dropping the receiver stops the driver.
+        let (out, handle) = (0..).plmap(2, |x: u64| x).into_tokio_receiver(2);
+        thread::sleep(std::time::Duration::from_millis(10));
+        drop(out);
+        assert!(handle.join().is_ok());
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        for x in 0..10 {
+            tx.send(x).unwrap();
+        }
+        drop(tx);
+        let total: i32 = Pipeline::from_tokio_receiver(2, |x: i32| x, rx).sum();
+        assert_eq!(total, 45);
+    }
+}
@@ -0,0 +1,140 @@
+//! Tracing spans for pipelines, see the tracing feature.
+//!
+//! Without the feature the types here are stand-ins which do nothing,
+//! so the rest of the crate never needs to check.
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::Span;
+
+#[cfg(not(feature = "tracing"))]
+#[derive(Clone)]
+pub(crate) struct Span;
+
+#[cfg(feature = "tracing")]
+pub(crate) fn pipeline_span(n_workers: usize) -> Span {
+    tracing::info_span!("plmap::pipeline", workers = n_workers)
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn pipeline_span(_n_workers: usize) -> Span {
+    Span
+}
+
+/// WorkerSpan is the span of a worker, created on the thread starting
+/// the pipeline, so that the worker reports to the same subscriber
+/// even if it is the thread's default rather than the global one.
+pub(crate) struct WorkerSpan {
+    #[cfg(feature = "tracing")]
+    dispatch: tracing::Dispatch,
+    #[cfg(feature = "tracing")]
+    span: Span,
+}
+
+impl WorkerSpan {
+    pub(crate) fn new(_pipeline: &Span, _worker: usize) -> WorkerSpan {
+        WorkerSpan {
+            #[cfg(feature = "tracing")]
+            dispatch: tracing::dispatcher::get_default(|d| d.clone()),
+            #[cfg(feature = "tracing")]
+            span: tracing::info_span!(parent: _pipeline, "plmap::worker", worker = _worker),
+        }
+    }
+
+    /// Run the worker's loop f inside the span.
+    pub(crate) fn run<R>(self, f: impl FnOnce() -> R) -> R {
+        #[cfg(feature = "tracing")]
+        return tracing::dispatcher::with_default(&self.dispatch, || self.span.in_scope(f));
+        #[cfg(not(feature = "tracing"))]
+        f()
+    }
+}
+
+/// Map item number index with f, in a span if every is set and index
+/// is a multiple of it, see PipelineBuilder::trace_items.
+pub(crate) fn in_item_span<R>(
+    _every: Option<usize>,
+    _index: usize,
+    _worker: usize,
+    f: impl FnOnce() -> R,
+) -> R {
+    #[cfg(feature = "tracing")]
+    if let Some(every) = _every {
+        if _index % every == 0 {
+            return tracing::trace_span!("plmap::item", index = _index, worker = _worker)
+                .in_scope(f);
+        }
+    }
+    f()
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use {
+        crate::{PipelineBuilder, PipelineMap},
+        std::sync::{Arc, Mutex},
+        tracing::{
+            span::{Attributes, Id, Record},
+            Event, Metadata, Subscriber,
+        },
+    };
+
+    /// Records the name of every span created.
+    #[derive(Clone, Default)]
+    struct Spans(Arc<Mutex<Vec<&'static str>>>);
+
+    impl Spans {
+        fn count(&self, name: &str) -> usize {
+            self.0
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|&&n| n == name)
+                .count()
+        }
+    }
+
+    impl Subscriber for Spans {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut spans = self.0.lock().unwrap();
+            spans.push(span.metadata().name());
+            Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _: &Id, _: &Record<'_>) {}
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, _: &Event<'_>) {}
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[test]
+    fn test_tracing_spans() {
+        let spans = Spans::default();
+        tracing::subscriber::with_default(spans.clone(), || {
+            let total: i32 = (0..100).plmap(3, |x| x * 2).sum();
+            assert_eq!(total, 9900);
+        });
+        assert_eq!(spans.count("plmap::pipeline"), 1);
+        assert_eq!(spans.count("plmap::worker"), 3);
+        assert_eq!(spans.count("plmap::item"), 0);
+
+        let spans = Spans::default();
+        tracing::subscriber::with_default(spans.clone(), || {
+            let total: i32 = PipelineBuilder::new(2)
+                .trace_items(10)
+                .build(0..100, |x| x * 2)
+                .sum();
+            assert_eq!(total, 9900);
+        });
+        assert_eq!(spans.count("plmap::worker"), 2);
+        assert_eq!(spans.count("plmap::item"), 10);
+    }
+}
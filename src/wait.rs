@@ -0,0 +1,56 @@
+use super::{
+    chan::{Receiver, RecvError, TryRecvError},
+    sync::{hint, thread},
+};
+
+/// WaitStrategy controls how workers wait for values to be
+/// dispatched, and how the consumer waits for results.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WaitStrategy {
+    /// Park the thread until there is something to do, using no cpu
+    /// while waiting. This is the default.
+    #[default]
+    Block,
+    /// Busy wait for up to the given number of polls before parking,
+    /// trading cpu time for lower latency on short waits.
+    SpinThenPark(u32),
+    /// Poll in a loop, yielding to the OS scheduler between polls.
+    /// This gives the lowest latency but keeps a core busy for as long
+    /// as the pipeline runs.
+    Yield,
+}
+
+impl WaitStrategy {
+    /// Receive from rx, waiting in the manner of this strategy.
+    pub(crate) fn recv<T>(self, rx: &Receiver<T>) -> Result<T, RecvError> {
+        self.wait(|| match rx.try_recv() {
+            Ok(v) => Some(Ok(v)),
+            Err(TryRecvError::Disconnected) => Some(Err(RecvError)),
+            Err(TryRecvError::Empty) => None,
+        })
+        .unwrap_or_else(|| rx.recv())
+    }
+
+    /// Poll until poll returns a value or the strategy decides it is time
+    /// to park, returning None in the latter case.
+    pub(crate) fn wait<T>(self, mut poll: impl FnMut() -> Option<T>) -> Option<T> {
+        match self {
+            WaitStrategy::Block => None,
+            WaitStrategy::SpinThenPark(spins) => {
+                for _ in 0..spins {
+                    if let Some(v) = poll() {
+                        return Some(v);
+                    }
+                    hint::spin_loop();
+                }
+                None
+            }
+            WaitStrategy::Yield => loop {
+                if let Some(v) = poll() {
+                    return Some(v);
+                }
+                thread::yield_now();
+            },
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use std::{num::NonZeroUsize, thread};
+
+/// Workers is how many workers a pipeline maps values with, accepted
+/// anywhere a worker count is.
+///
+/// A plain usize still converts, with zero meaning Inline, but a count
+/// computed at runtime that happens to be zero then silently maps on
+/// the consumer. Fixed, Auto or PerCore make the intent explicit.
+///
+/// # Examples
+///
+/// ```
+/// use plmap::{PipelineMap, Workers};
+///
+/// let total: i32 = (0..100).plmap(Workers::Auto, |x| x * 2).sum();
+/// assert_eq!(total, 9900);
+/// assert_eq!(Workers::from(0).count(), 0);
+/// assert!(Workers::PerCore(0.5).count() >= 1);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Workers {
+    /// Map values on the consuming thread, without workers.
+    Inline,
+    /// A fixed number of workers.
+    Fixed(NonZeroUsize),
+    /// One worker per available cpu, see
+    /// std::thread::available_parallelism.
+    Auto,
+    /// The given number of workers per available cpu, rounded, at
+    /// least one and at most MAX_PER_CORE. count panics unless the
+    /// number is finite and greater than zero.
+    PerCore(f32),
+}
+
+impl Workers {
+    /// The most workers PerCore gives, however many cpus there are.
+    pub const MAX_PER_CORE: usize = 1024;
+
+    /// The number of workers, zero for Inline.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a PerCore number is not finite and greater than zero.
+    pub fn count(self) -> usize {
+        match self {
+            Workers::Inline => 0,
+            Workers::Fixed(n) => n.get(),
+            Workers::Auto => cores(),
+            Workers::PerCore(n) => {
+                assert!(
+                    n.is_finite() && n > 0.0,
+                    "Workers::PerCore({}) must be finite and greater than zero",
+                    n
+                );
+                let n = (cores() as f64 * f64::from(n)).round();
+                (n as usize).clamp(1, Workers::MAX_PER_CORE)
+            }
+        }
+    }
+}
+
+fn cores() -> usize {
+    thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}
+
+impl From<usize> for Workers {
+    fn from(n: usize) -> Workers {
+        match NonZeroUsize::new(n) {
+            Some(n) => Workers::Fixed(n),
+            None => Workers::Inline,
+        }
+    }
+}
+
+impl From<NonZeroUsize> for Workers {
+    fn from(n: NonZeroUsize) -> Workers {
+        Workers::Fixed(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_workers_count() {
+        assert_eq!(Workers::Inline.count(), 0);
+        assert_eq!(Workers::from(0), Workers::Inline);
+        assert_eq!(Workers::from(3).count(), 3);
+        assert_eq!(Workers::from(NonZeroUsize::new(2).unwrap()).count(), 2);
+        assert_eq!(Workers::Auto.count(), cores());
+        assert_eq!(Workers::PerCore(2.0).count(), cores() * 2);
+        assert_eq!(Workers::PerCore(0.01).count(), 1);
+        assert_eq!(Workers::PerCore(1e30).count(), Workers::MAX_PER_CORE);
+    }
+
+    #[test]
+    fn test_workers_per_core_invalid() {
+        for n in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+            let result = std::panic::catch_unwind(|| Workers::PerCore(n).count());
+            assert!(result.is_err(), "{}", n);
+        }
+    }
+}